@@ -0,0 +1,43 @@
+//cargo bench --features test-utilities --bench blockchain_bench
+use bitcoin::blockchain::Blockchain;
+use bitcoin::types::block::generate_random_block;
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+fn chain_of(len: usize) -> Blockchain {
+    let mut blockchain = Blockchain::new();
+    for _ in 0..len {
+        let block = generate_random_block(&blockchain.tip());
+        blockchain.insert(&block);
+    }
+    return blockchain;
+}
+
+/// The average time of a call over repeated runs, a single one is dominated by cold caches
+fn average(f: &dyn Fn()) -> Duration {
+    let runs = 100;
+    let start = Instant::now();
+    for _ in 0..runs {
+        f();
+    }
+    return start.elapsed() / runs;
+}
+
+fn longest_chain(c: &mut Criterion) {
+    let blockchain = chain_of(10_000);
+    assert_eq!(blockchain.walk_longest_chain(), blockchain.longest_chain());
+
+    //what every caller of the longest chain now gets is a borrow of the list insert keeps
+    let walk = average(&|| { black_box(blockchain.walk_longest_chain()); });
+    let cached = average(&|| { black_box(blockchain.longest_chain()); });
+    assert!(walk >= cached * 100, "the cached list took {:?}, walking parents {:?}", cached, walk);
+
+    let mut group = c.benchmark_group("longest chain of 10,000 blocks");
+    group.bench_function("walking parents", |b| b.iter(|| blockchain.walk_longest_chain()));
+    group.bench_function("cached list", |b| b.iter(|| blockchain.longest_chain().len()));
+    group.finish();
+}
+
+criterion_group!(benches, longest_chain);
+criterion_main!(benches);
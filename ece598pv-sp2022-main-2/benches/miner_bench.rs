@@ -0,0 +1,43 @@
+//cargo bench --features test-utilities --bench miner_bench
+use bitcoin::miner::search_nonce;
+use bitcoin::types::block::generate_random_block;
+use bitcoin::types::hash::{H256, Hashable};
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::hint::black_box;
+
+fn block_hash(c: &mut Criterion) {
+    let block = generate_random_block(&H256::from([0; 32]));
+    //the chain looks the same block's hash up over and over, which the block caches
+    let mut group = c.benchmark_group("block hash");
+    group.bench_function("rehashing the header", |b| b.iter(|| black_box(block.header()).hash()));
+    group.bench_function("cached", |b| b.iter(|| black_box(&block).hash()));
+    group.finish();
+}
+
+fn nonce_search(c: &mut Criterion) {
+    //the miner hashes a fresh header for every nonce, so the cached block hash never helps it
+    let mut header = generate_random_block(&H256::from([0; 32])).header().clone();
+    header.difficulty = H256::from([0; 32]);
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+    c.bench_function("searching 10,000 nonces", |b| b.iter(|| search_nonce(&pool, &header, 0, 10_000)));
+
+    //about one nonce in 65,536 meets this
+    let mut difficulty = [255u8; 32];
+    difficulty[0] = 0;
+    difficulty[1] = 0;
+    header.difficulty = H256::from(difficulty);
+    let mut group = c.benchmark_group("finding a nonce");
+    for threads in [1, 4] {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+        let mut round: u32 = 0;
+        group.bench_function(format!("{} thread(s)", threads), |b| b.iter(|| {
+            round = round.wrapping_add(1);
+            let search = search_nonce(&pool, &header, round.wrapping_mul(2_654_435_761), u32::MAX / threads as u32);
+            assert!(search.nonce.is_some());
+        }));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, block_hash, nonce_search);
+criterion_main!(benches);
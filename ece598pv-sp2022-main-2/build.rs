@@ -0,0 +1,7 @@
+//the test-utilities feature turns on the test_utilities cfg the random block, hash and transaction generators are gated on
+fn main() {
+    println!("cargo:rustc-check-cfg=cfg(test_utilities)");
+    if std::env::var_os("CARGO_FEATURE_TEST_UTILITIES").is_some() {
+        println!("cargo:rustc-cfg=test_utilities");
+    }
+}
@@ -1,29 +1,116 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use crate::blockchain::Blockchain;
 use crate::miner::Handle as MinerHandle;
+use crate::miner::OperatingState as MinerState;
+use crate::miner::{Mempool, MempoolAdmission};
 use crate::transaction_generator::Handle as TxGeneratorHandle;
 use crate::network::server::Handle as NetworkServerHandle;
-use crate::network::message::Message;
+use crate::network::server::Greeting;
+use crate::network::peer::Direction as PeerDirection;
+use crate::network::impairment::{parse_loss_class, Impairment};
+use crate::network::propagation::PropagationStats;
+use crate::network::queue::QueueStats;
+use crate::network::traffic::{PeerTraffic, TrafficBreakdown};
+use crate::network::worker::SyncStatus;
 use crate::types::address::Address;
 use crate::types::block::BlockState;
+use crate::types::block::Block;
+use crate::types::transaction::{IntegrityError, SignedTransaction};
+use crate::network::message::Message;
 use crate::types::hash::{H256, Hashable};
+use crate::{ShutdownTrigger, NODE_VERSION};
 
-use log::{info};
+use axum::body::{Body, Bytes};
+use axum::extract::ws::rejection::WebSocketUpgradeRejection;
+use axum::extract::ws::{Message as WsMessage, WebSocket, WebSocketUpgrade};
+use axum::extract::{Query, State};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{HeaderMap, Method, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use futures::StreamExt;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tracing::{debug, error, info};
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
-use tiny_http::Header;
-use tiny_http::Response;
-use tiny_http::Server as HTTPServer;
+use std::time::{Duration, Instant};
 use url::Url;
 
+//events queued for a subscriber that isn't reading them; past this it is disconnected
+pub static EVENT_QUEUE_SIZE: usize = 1000;
+//samples /network/propagation lists unless ?recent= says otherwise
+pub static PROPAGATION_RECENT: usize = 20;
+//blocks /blockchain/block-time-stats averages over unless ?window= says otherwise
+pub static BLOCK_TIME_WINDOW: usize = 100;
+
+/// A JSON object pushed to every /events subscriber
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    NewBlock { hash: String, height: u32 },
+    //only sent to subscribers that asked for transactions
+    NewTransaction { hash: String },
+}
+
+struct Subscriber {
+    sender: Sender<Event>,
+    transactions: bool,
+}
+
+/// The /events WebSocket subscribers, shared with the workers that publish events
+#[derive(Clone, Default)]
+pub struct Events {
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+impl Events {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    fn subscribe(&self, transactions: bool) -> Receiver<Event> {
+        let (sender, receiver) = mpsc::channel(EVENT_QUEUE_SIZE);
+        self.subscribers.lock().unwrap().push(Subscriber { sender, transactions });
+        return receiver;
+    }
+
+    /// Queue an event for every interested subscriber, dropping the ones that are gone or too far behind
+    pub fn publish(&self, event: Event) {
+        let is_transaction = matches!(event, Event::NewTransaction { .. });
+        self.subscribers.lock().unwrap().retain(|subscriber| {
+            if is_transaction && !subscriber.transactions {
+                return true;
+            }
+            return match subscriber.sender.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) | Err(TrySendError::Closed(_)) => false,
+            };
+        });
+    }
+}
+
 pub struct Server {
-    handle: HTTPServer,
+    addr: SocketAddr,
     miner: MinerHandle,
     tx_generator: TxGeneratorHandle,
     network: NetworkServerHandle,
-    blockchain: Arc<Mutex<Blockchain>>,
-    block_state: Arc<Mutex<BlockState>>
+    blockchain: Arc<RwLock<Blockchain>>,
+    block_state: Arc<Mutex<BlockState>>,
+    mempool: Arc<Mutex<Mempool>>,
+    sync: SyncStatus,
+    propagation: PropagationStats,
+    //first message written to peers connected through /network/connect
+    greeting: Greeting,
+    //handlers still running after this are answered with a 503
+    timeout: Duration,
+    events: Events,
+    shutdown: ShutdownTrigger,
+    //when the API server started, for uptime
+    started: Instant
 }
 
 #[derive(Serialize)]
@@ -32,200 +119,1236 @@ struct ApiResponse {
     message: String,
 }
 
+#[derive(Serialize)]
+struct TxSubmitResponse {
+    success: bool,
+    //set when the transaction was accepted
+    hash: Option<String>,
+    //set when it wasn't: bad_request, invalid_encoding, invalid_signature, sender_mismatch,
+    //insufficient_balance or rejected
+    error: Option<String>,
+    message: String,
+}
+
+/// JSON body accepted by /tx/submit
+#[derive(Deserialize)]
+struct TxSubmitRequest {
+    hex: String,
+}
+
+#[derive(Serialize)]
+struct NodeStatusResponse {
+    tip: String,
+    height: u32,
+    //still downloading the chain from a peer that was ahead of us when it connected
+    syncing: bool,
+}
+
+#[derive(Serialize)]
+struct NodeInfoResponse {
+    version: String,
+    uptime_secs: u64,
+    connected_peers: usize,
+    chain_height: u32,
+    mempool_size: usize,
+    mining_active: bool,
+}
+
+#[derive(Serialize)]
+struct HeightResponse {
+    height: u32,
+}
+
+#[derive(Serialize)]
+struct BlockTimeStatsResponse {
+    mean_ms: f64,
+    std_dev_ms: f64,
+    //number of inter-block intervals the stats are taken over
+    sample_size: usize,
+}
+
+#[derive(Serialize)]
+struct ForkResponse {
+    height: u32,
+    //longest chain blocks built on top of this height, a reorg replacing them has to be longer
+    depth: u32,
+    //the block at this height on the longest chain, then the ones competing with it
+    main: String,
+    competing: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct DifficultyResponse {
+    //leading zero bits of the tip's difficulty target
+    bits: u32,
+    target: String,
+}
+
+#[derive(Serialize)]
+struct BlockchainStatsResponse {
+    height: u32,
+    difficulty: DifficultyResponse,
+    //transactions confirmed in the longest chain
+    total_transactions: usize,
+}
+
+#[derive(Serialize)]
+struct BlockResponse {
+    hash: String,
+    height: u32,
+    //on the longest chain, otherwise on a fork that lost to it
+    main_chain: bool,
+    //longest chain blocks from this one up to the tip, 0 for a block off the longest chain
+    confirmations: u32,
+    header: BlockHeaderResponse,
+    transactions: Vec<BlockTransactionResponse>,
+}
+
+#[derive(Serialize)]
+struct BlockHeaderResponse {
+    parent: String,
+    nonce: u32,
+    difficulty: String,
+    timestamp: u128,
+    merkle_root: String,
+}
+
+#[derive(Serialize)]
+struct BlockTransactionResponse {
+    hash: String,
+    sender: String,
+    account_nonce: u32,
+    outputs: Vec<TransactionOutputResponse>,
+    fee: u32,
+}
+
+#[derive(Serialize)]
+struct TransactionOutputResponse {
+    receiver: String,
+    value: u32,
+}
+
+#[derive(Serialize)]
+struct ConfirmationCountResponse {
+    //0 while the transaction is only in the mempool, -1 if it is unknown
+    confirmations: i64,
+}
+
+#[derive(Serialize)]
+struct BannedPeerResponse {
+    ip: String,
+    expires_in_secs: u64,
+    //unix time in seconds the ban ends, it outlasts a restart when the node keeps a --data-dir
+    expires_at: u64,
+}
+
+#[derive(Serialize)]
+struct MinerStatusResponse {
+    state: String,
+    lambda: Option<u64>,
+    hashes: u64,
+    hash_rate: f64,
+}
+
+#[derive(Serialize)]
+struct MinerStatsResponse {
+    address: String,
+    blocks_found: u64,
+    first_block_height: Option<u32>,
+    last_block_height: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct PeersResponse {
+    inbound: usize,
+    max_inbound: usize,
+    outbound: usize,
+    max_outbound: usize,
+    peers: Vec<PeerResponse>,
+}
+
+#[derive(Serialize)]
+struct NetworkStatsResponse {
+    queue: QueueStats,
+    //traffic with every peer address below, including the ones no longer connected
+    totals: TrafficBreakdown,
+    peers: Vec<PeerTraffic>,
+}
+
+#[derive(Serialize)]
+struct PropagationResponse {
+    recorded: u64,
+    //blocks stamped after they arrived, counted with a delay of 0
+    negative: u64,
+    p50_ms: Option<f64>,
+    p90_ms: Option<f64>,
+    p99_ms: Option<f64>,
+    max_ms: Option<f64>,
+    recent: Vec<PropagationSampleResponse>,
+}
+
+#[derive(Serialize)]
+struct PropagationSampleResponse {
+    block: String,
+    delay_ms: f64,
+    received_at: u128,
+}
+
+#[derive(Serialize)]
+struct PeerResponse {
+    addr: String,
+    direction: String,
+    connected_since: u128,
+    messages_sent: u64,
+    messages_received: u64,
+    latency_ms: Option<f64>,
+    average_latency_ms: Option<f64>,
+    compression: bool,
+    raw_bytes_sent: u64,
+    compressed_bytes_sent: u64,
+    raw_bytes_received: u64,
+    compressed_bytes_received: u64,
+}
+
 macro_rules! respond_result {
-    ( $req:expr, $success:expr, $message:expr ) => {{
-        let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+    ( $success:expr, $message:expr ) => {{
         let payload = ApiResponse {
             success: $success,
             message: $message.to_string(),
         };
-        let resp = Response::from_string(serde_json::to_string_pretty(&payload).unwrap())
-            .with_header(content_type);
-        $req.respond(resp).unwrap();
+        ([(CONTENT_TYPE, "application/json")], serde_json::to_string_pretty(&payload).unwrap()).into_response()
+    }};
+}
+macro_rules! respond_error {
+    ( $status:expr, $message:expr ) => {{
+        let payload = ApiResponse {
+            success: false,
+            message: $message.to_string(),
+        };
+        (StatusCode::from_u16($status).unwrap(), [(CONTENT_TYPE, "application/json")], serde_json::to_string_pretty(&payload).unwrap()).into_response()
+    }};
+}
+macro_rules! respond_tx_error {
+    ( $error:expr, $message:expr ) => {{
+        let payload = TxSubmitResponse {
+            success: false,
+            hash: None,
+            error: Some($error.to_string()),
+            message: $message.to_string(),
+        };
+        (StatusCode::BAD_REQUEST, [(CONTENT_TYPE, "application/json")], serde_json::to_string_pretty(&payload).unwrap()).into_response()
     }};
 }
 macro_rules! respond_json {
-    ( $req:expr, $message:expr ) => {{
-        let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
-        let resp = Response::from_string(serde_json::to_string(&$message).unwrap())
-            .with_header(content_type);
-        $req.respond(resp).unwrap();
+    ( $message:expr ) => {{
+        ([(CONTENT_TYPE, "application/json")], serde_json::to_string(&$message).unwrap()).into_response()
     }};
 }
 
+/// Triggers shutdown once dropped, which hyper does with a response body after writing it
+struct ShutdownOnDrop(ShutdownTrigger);
+
+impl Drop for ShutdownOnDrop {
+    fn drop(&mut self) {
+        self.0.trigger();
+    }
+}
+
 impl Server {
     pub fn start(
         addr: std::net::SocketAddr,
         miner: &MinerHandle,
         tx_generator: &TxGeneratorHandle,
         network: &NetworkServerHandle,
-        blockchain: &Arc<Mutex<Blockchain>>,
-        block_state: &Arc<Mutex<BlockState>>
+        blockchain: &Arc<RwLock<Blockchain>>,
+        block_state: &Arc<Mutex<BlockState>>,
+        mempool: &Arc<Mutex<Mempool>>,
+        sync: &SyncStatus,
+        propagation: &PropagationStats,
+        greeting: &Greeting,
+        timeout: Duration,
+        events: &Events,
+        shutdown: &ShutdownTrigger
     ) {
-        let handle = HTTPServer::http(&addr).unwrap();
-        let server = Self {
-            handle,
+        let listener = TcpListener::bind(addr).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let server = Arc::new(Self {
+            addr,
             miner: miner.clone(),
             tx_generator: tx_generator.clone(),
             network: network.clone(),
             blockchain: Arc::clone(blockchain),
-            block_state: Arc::clone(block_state)
+            block_state: Arc::clone(block_state),
+            mempool: Arc::clone(mempool),
+            sync: sync.clone(),
+            propagation: propagation.clone(),
+            greeting: Arc::clone(greeting),
+            timeout,
+            events: events.clone(),
+            shutdown: shutdown.clone(),
+            started: Instant::now()
+        });
+        let app = Router::new()
+            .route("/events", get(Self::events))
+            .fallback(Self::dispatch)
+            .with_state(server);
+        thread::Builder::new().name("api-server".to_string()).spawn(move || {
+            let runtime = tokio::runtime::Builder::new_multi_thread()
+                .thread_name("api-worker")
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async move {
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                if let Err(e) = axum::serve(listener, app).await {
+                    error!("API server stopped: {}", e);
+                }
+            });
+        }).unwrap();
+        info!("API server listening at {}", &addr);
+    }
+
+    /// Run the handler on the blocking pool, since it takes the blockchain and mempool locks, and
+    /// answer with a 503 if it is still running after the timeout
+    async fn dispatch(State(server): State<Arc<Server>>, method: Method, uri: Uri, headers: HeaderMap, body: Bytes) -> Response {
+        let timeout = server.timeout;
+        //the handler is left to finish on its own, its late response is discarded
+        let handler = tokio::task::spawn_blocking(move || server.handle(&method, &uri, &headers, &body));
+        match tokio::time::timeout(timeout, handler).await {
+            Ok(Ok(response)) => return response,
+            Ok(Err(e)) => {
+                debug!("API handler failed: {}", e);
+                return respond_error!(500, "handler failed");
+            }
+            Err(_) => return respond_error!(503, "handler timeout"),
+        }
+    }
+
+    /// Upgrade to a WebSocket that pushes every published event, pass transactions=true to also get
+    /// every transaction entering the mempool
+    async fn events(
+        State(server): State<Arc<Server>>,
+        Query(params): Query<HashMap<String, String>>,
+        upgrade: Result<WebSocketUpgrade, WebSocketUpgradeRejection>
+    ) -> Response {
+        let upgrade = match upgrade {
+            Ok(upgrade) => upgrade,
+            Err(_) => return respond_error!(400, "events expects a WebSocket upgrade request"),
+        };
+        let transactions = params.get("transactions").map(|v| v == "true").unwrap_or(false);
+        //subscribe before the handshake completes so the client can't miss events sent right after
+        let receiver = server.events.subscribe(transactions);
+        return upgrade.on_upgrade(move |socket| Self::push_events(socket, receiver));
+    }
+
+    async fn push_events(mut socket: WebSocket, mut receiver: Receiver<Event>) {
+        while let Some(event) = receiver.recv().await {
+            if let Err(e) = socket.send(WsMessage::Text(serde_json::to_string(&event).unwrap())).await {
+                debug!("Events subscriber disconnected: {}", e);
+                return;
+            }
+        }
+        let _ = socket.close().await;
+    }
+
+    fn handle(&self, method: &Method, uri: &Uri, headers: &HeaderMap, body: &[u8]) -> Response {
+        let miner = &self.miner;
+        let tx_generator = &self.tx_generator;
+        let network = &self.network;
+        let blockchain = &self.blockchain;
+        let block_state_map = &self.block_state;
+        let mempool = &self.mempool;
+        let sync = &self.sync;
+        let propagation = &self.propagation;
+        let events = &self.events;
+        let shutdown = &self.shutdown;
+        let started = self.started;
+        // a valid url requires a base
+        let base_url = Url::parse(&format!("http://{}/", &self.addr)).unwrap();
+        let url = match base_url.join(&uri.to_string()) {
+            Ok(u) => u,
+            Err(e) => {
+                return respond_result!(false, format!("error parsing url: {}", e));
+            }
         };
-        thread::spawn(move || {
-            for req in server.handle.incoming_requests() {
-                let miner = server.miner.clone();
-                let tx_generator = server.tx_generator.clone();
-                let network = server.network.clone();
-                let blockchain = Arc::clone(&server.blockchain);
-                let block_state_map = Arc::clone(&server.block_state);
-                thread::spawn(move || {
-                    // a valid url requires a base
-                    let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
-                    let url = match base_url.join(req.url()) {
-                        Ok(u) => u,
+        match url.path() {
+            "/miner/start" => {
+                let params = url.query_pairs();
+                let params: HashMap<_, _> = params.into_owned().collect();
+                let lambda = match params.get("lambda") {
+                    Some(v) => v,
+                    None => {
+                        return respond_result!(false, "missing lambda");
+                    }
+                };
+                let lambda = match lambda.parse::<u64>() {
+                    Ok(v) => v,
+                    Err(e) => {
+                        return respond_result!(false,
+                            format!("error parsing lambda: {}", e)
+                        );
+                    }
+                };
+                match miner.start(lambda) {
+                    Ok(()) => return respond_result!(true, "ok"),
+                    Err(e) => return respond_error!(503, format!("miner unavailable: {}", e)),
+                }
+            }
+            "/miner/status" => {
+                let (state, lambda) = match miner.status() {
+                    MinerState::Paused => ("paused", None),
+                    MinerState::Run(lambda) => ("running", Some(lambda)),
+                    MinerState::ShutDown => ("shutdown", None),
+                };
+                let status = MinerStatusResponse {
+                    state: state.to_string(),
+                    lambda,
+                    hashes: miner.hashes(),
+                    hash_rate: miner.hash_rate(),
+                };
+                return respond_json!(status);
+            }
+            "/miner/stats" => {
+                let stats = miner.stats();
+                return respond_json!(MinerStatsResponse {
+                    address: stats.address.to_string(),
+                    blocks_found: stats.blocks_found,
+                    first_block_height: stats.first_block_height,
+                    last_block_height: stats.last_block_height,
+                });
+            }
+            "/miner/get-template" => {
+                return respond_json!(miner.get_template());
+            }
+            "/miner/submit-block" => {
+                //body is the hex encoded bincode of the solved block
+                if *method != Method::POST {
+                    return respond_result!(false, "submit-block expects a POST request");
+                }
+                let body = String::from_utf8_lossy(body);
+                let bytes = match hex::decode(body.trim()) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        return respond_result!(false, format!("error decoding hex: {}", e));
+                    }
+                };
+                let block = match bincode::deserialize::<Block>(&bytes) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        return respond_result!(false, format!("error parsing block: {}", e));
+                    }
+                };
+                match miner.submit_block(block) {
+                    Ok(()) => return respond_result!(true, "ok"),
+                    Err(e) => return respond_result!(false, e),
+                }
+            }
+            "/tx/submit" => {
+                //body is the bincode of a signed transaction: raw bytes with an application/octet-stream
+                //content type, {"hex": ...} with application/json, otherwise hex text
+                if *method != Method::POST {
+                    return respond_tx_error!("bad_request", "tx/submit expects a POST request");
+                }
+                let content_type = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or_default();
+                let parsed = if content_type.starts_with("application/octet-stream") {
+                    SignedTransaction::from_bytes(&body)
+                } else if content_type.starts_with("application/json") {
+                    match serde_json::from_slice::<TxSubmitRequest>(&body) {
+                        Ok(request) => SignedTransaction::from_hex(&request.hex),
                         Err(e) => {
-                            respond_result!(req, false, format!("error parsing url: {}", e));
-                            return;
-                        }
-                    };
-                    match url.path() {
-                        "/miner/start" => {
-                            let params = url.query_pairs();
-                            let params: HashMap<_, _> = params.into_owned().collect();
-                            let lambda = match params.get("lambda") {
-                                Some(v) => v,
-                                None => {
-                                    respond_result!(req, false, "missing lambda");
-                                    return;
-                                }
-                            };
-                            let lambda = match lambda.parse::<u64>() {
-                                Ok(v) => v,
-                                Err(e) => {
-                                    respond_result!(
-                                        req,
-                                        false,
-                                        format!("error parsing lambda: {}", e)
-                                    );
-                                    return;
-                                }
-                            };
-                            miner.start(lambda);
-                            respond_result!(req, true, "ok");
-                        }
-                        "/tx-generator/start" => {
-                            let params = url.query_pairs();
-                            let params: HashMap<_, _> = params.into_owned().collect();
-                            let theta = match params.get("theta") {
-                                Some(v) => v,
-                                None => {
-                                    respond_result!(req, false, "missing theta");
-                                    return;
-                                }
-                            };
-                            let theta = match theta.parse::<u64>() {
-                                Ok(v) => v,
-                                Err(e) => {
-                                    respond_result!(
-                                        req,
-                                        false,
-                                        format!("error parsing theta: {}", e)
-                                    );
-                                    return;
-                                }
-                            };
-                            tx_generator.start(5000*theta);
-                            respond_result!(req, true, "ok");
+                            return respond_tx_error!("bad_request", format!("error parsing body: {}", e));
                         }
-                        "/network/ping" => {
-                            network.broadcast(Message::Ping(String::from("Test ping")));
-                            respond_result!(req, true, "ok");
-                        }
-                        "/blockchain/longest-chain" => {
-                            let v = blockchain.lock().unwrap().all_blocks_in_longest_chain().clone();
-                            let v_string: Vec<String> = v.into_iter().map(|h|h.to_string()).collect();
-                            respond_json!(req, v_string);
-                        }
-                        "/blockchain/longest-chain-tx" => {
-                            let blocks = blockchain.lock().unwrap().all_blocks_in_longest_chain().clone();
-                            let block_map = blockchain.lock().unwrap().block_map.clone();
-                            let mut txs = Vec::<Vec::<H256>>::new();
-                            for block_hash in blocks.clone() {
-                                let mut txs2 = Vec::<H256>::new();
-                                let (block, _) = block_map.get(&block_hash).unwrap();
-                                for transaction in block.get_content().data.clone() {
-                                    txs2.push(transaction.hash());
-                                }
-                                txs.push(txs2);
-                            }
-                            let mut txs_string: Vec<Vec<String>> = Vec::<Vec<String>>::new();
-                            for vec in txs {
-                                let vecs: Vec<String> = vec.into_iter().map(|h|h.to_string()).collect();
-                                txs_string.push(vecs);
-                            }
-                            respond_json!(req, txs_string);
+                    }
+                } else {
+                    SignedTransaction::from_hex(&String::from_utf8_lossy(&body))
+                };
+                let tx = match parsed {
+                    Ok(v) => v,
+                    Err(e) => {
+                        return respond_tx_error!("invalid_encoding", format!("error parsing transaction: {}", e));
+                    }
+                };
+                match tx.verify_integrity() {
+                    Ok(()) => {}
+                    Err(e @ IntegrityError::InvalidSignature) => {
+                        return respond_tx_error!("invalid_signature", e);
+                    }
+                    Err(e @ IntegrityError::SenderMismatch) => {
+                        return respond_tx_error!("sender_mismatch", e);
+                    }
+                }
+                let tip = blockchain.read().unwrap().tip();
+                let tip_state = block_state_map.lock().unwrap().block_state_map.get(&tip).unwrap().clone();
+                //later nonces may still overdraw once earlier ones confirm, blocks are checked in full
+                let balance = tip_state.get(&tx.transaction.sender).map(|(_, balance)| *balance).unwrap_or(0);
+                if tx.transaction.total_output() + tx.transaction.fee() as u64 > balance as u64 {
+                    return respond_tx_error!("insufficient_balance", "outputs and fee exceed the sender's balance");
+                }
+                let admission = mempool.lock().unwrap().insert_validated(&tx, &tip_state);
+                match admission {
+                    Ok(MempoolAdmission::Pooled { promoted }) => {
+                        let mut hashes = vec![tx.hash()];
+                        hashes.extend(promoted);
+                        for hash in hashes.iter() {
+                            events.publish(Event::NewTransaction { hash: hash.to_string() });
                         }
-                        "/blockchain/longest-chain-tx-count" => {
-                            respond_result!(req, false, "unimplemented!");
+                        network.broadcast(Message::NewTransactionHashes(hashes));
+                    }
+                    //announced once the missing earlier nonce arrives
+                    Ok(MempoolAdmission::Orphaned) => {}
+                    Err(e) => {
+                        return respond_tx_error!("rejected", format!("transaction rejected: {:?}", e));
+                    }
+                }
+                let accepted = TxSubmitResponse {
+                    success: true,
+                    hash: Some(tx.hash().to_string()),
+                    error: None,
+                    message: "ok".to_string(),
+                };
+                return respond_json!(accepted);
+            }
+            path if path.starts_with("/tx/raw/") => {
+                let hash = match path["/tx/raw/".len()..].parse::<H256>() {
+                    Ok(v) => v,
+                    Err(_) => {
+                        return respond_error!(400, "hash must be 32 hex encoded bytes");
+                    }
+                };
+                //pending transactions first, then the ones mined on the longest chain
+                let mut found = mempool.lock().unwrap().transaction_map.get(&hash).cloned();
+                if found.is_none() {
+                    found = blockchain.read().unwrap().transaction(&hash);
+                }
+                match found {
+                    Some(tx) => return respond_json!(tx.to_hex()),
+                    None => return respond_error!(404, "transaction not found"),
+                }
+            }
+            "/tx-generator/start" => {
+                let params = url.query_pairs();
+                let params: HashMap<_, _> = params.into_owned().collect();
+                let theta = match params.get("theta") {
+                    Some(v) => v,
+                    None => {
+                        return respond_result!(false, "missing theta");
+                    }
+                };
+                let theta = match theta.parse::<u64>() {
+                    Ok(v) => v,
+                    Err(e) => {
+                        return respond_result!(false,
+                            format!("error parsing theta: {}", e)
+                        );
+                    }
+                };
+                match tx_generator.start(5000*theta) {
+                    Ok(()) => return respond_result!(true, "ok"),
+                    Err(e) => return respond_error!(503, format!("transaction generator unavailable: {}", e)),
+                }
+            }
+            "/tx-generator/stop" => {
+                match tx_generator.stop() {
+                    Ok(()) => return respond_result!(true, "ok"),
+                    Err(e) => return respond_error!(503, format!("transaction generator unavailable: {}", e)),
+                }
+            }
+            "/node/status" => {
+                let blockchain = blockchain.read().unwrap();
+                let status = NodeStatusResponse {
+                    tip: blockchain.tip().to_string(),
+                    height: blockchain.height,
+                    syncing: sync.is_syncing(),
+                };
+                return respond_json!(status);
+            }
+            "/node/info" => {
+                let chain_height = blockchain.read().unwrap().height;
+                let mempool_size = mempool.lock().unwrap().transaction_map.len();
+                let info = NodeInfoResponse {
+                    version: NODE_VERSION.to_string(),
+                    uptime_secs: started.elapsed().as_secs(),
+                    connected_peers: network.peer_info().len(),
+                    chain_height,
+                    mempool_size,
+                    mining_active: matches!(miner.status(), MinerState::Run(_)),
+                };
+                return respond_json!(info);
+            }
+            "/node/exit" => {
+                //the node may exit as soon as shutdown is triggered, so only trigger it once the reply is written
+                let (parts, body) = respond_result!(true, "ok").into_parts();
+                let on_drop = ShutdownOnDrop(shutdown.clone());
+                let body = Body::from_stream(body.into_data_stream().map(move |chunk| {
+                    let _ = &on_drop;
+                    return chunk;
+                }));
+                return Response::from_parts(parts, body);
+            }
+            "/network/ping" => {
+                network.ping_peers();
+                return respond_result!(true, "ok");
+            }
+            "/network/peers" => {
+                let peers: Vec<PeerResponse> = network.peer_info().into_iter().map(|peer| PeerResponse {
+                    addr: peer.addr.to_string(),
+                    direction: match peer.direction {
+                        PeerDirection::Incoming => "inbound".to_string(),
+                        PeerDirection::Outgoing => "outbound".to_string(),
+                    },
+                    connected_since: peer.connected_since,
+                    messages_sent: peer.messages_sent,
+                    messages_received: peer.messages_received,
+                    latency_ms: peer.latency.map(|latency| latency.latest.as_secs_f64() * 1000.0),
+                    average_latency_ms: peer.latency.map(|latency| latency.average.as_secs_f64() * 1000.0),
+                    compression: peer.compression.enabled,
+                    raw_bytes_sent: peer.compression.raw_bytes_sent,
+                    compressed_bytes_sent: peer.compression.compressed_bytes_sent,
+                    raw_bytes_received: peer.compression.raw_bytes_received,
+                    compressed_bytes_received: peer.compression.compressed_bytes_received,
+                }).collect();
+                let counts = network.connection_counts();
+                let peers = PeersResponse {
+                    inbound: counts.inbound,
+                    max_inbound: counts.max_inbound,
+                    outbound: counts.outbound,
+                    max_outbound: counts.max_outbound,
+                    peers,
+                };
+                return respond_json!(peers);
+            }
+            "/network/stats" => {
+                let traffic = network.traffic_stats();
+                return respond_json!(NetworkStatsResponse { queue: network.queue_stats(), totals: traffic.totals, peers: traffic.peers });
+            }
+            "/network/propagation" => {
+                let params = url.query_pairs();
+                let params: HashMap<_, _> = params.into_owned().collect();
+                let recent = match params.get("recent") {
+                    Some(v) => match v.parse::<usize>() {
+                        Ok(v) => v,
+                        Err(e) => {
+                            return respond_result!(false,
+                                format!("error parsing recent: {}", e)
+                            );
                         }
-                        "/blockchain/state" => {
-                            let params = url.query_pairs();
-                            let params: HashMap<_, _> = params.into_owned().collect();
-                            let block = match params.get("block") {
-                                Some(v) => v,
-                                None => {
-                                    respond_result!(req, false, "missing block");
-                                    return;
-                                }
-                            };
-                            let block = match block.parse::<usize>() {
-                                Ok(v) => v,
-                                Err(e) => {
-                                    respond_result!(
-                                        req,
-                                        false,
-                                        format!("error parsing block: {}", e)
-                                    );
-                                    return;
-                                }
-                            };
-                            //so that ordering is consistent across API calls
-                            let accounts: [Address; 3] = [
-                                Address::from_public_key_bytes(&[59, 106, 39, 188, 206, 182, 164, 45, 98, 163, 168, 208, 42, 111, 13, 115, 101, 50, 21, 119, 29, 226, 67, 166, 58, 192, 72, 161, 139, 89, 218, 41]),
-                                Address::from_public_key_bytes(&[138, 136, 227, 221, 116, 9, 241, 149, 253, 82, 219, 45, 60, 186, 93, 114, 202, 103, 9, 191, 29, 148, 18, 27, 243, 116, 136, 1, 180, 15, 111, 92]),
-                                Address::from_public_key_bytes(&[129, 57, 119, 14, 168, 125, 23, 95, 86, 163, 84, 102, 195, 76, 126, 204, 203, 141, 138, 145, 180, 238, 55, 162, 93, 246, 15, 91, 143, 201, 179, 148])
-                            ];
-                            let longest_chain = blockchain.lock().unwrap().all_blocks_in_longest_chain().clone();
-                            let block_hash = longest_chain.get(block).unwrap();
-                            let blk_state = block_state_map.lock().unwrap().block_state_map.get(block_hash).unwrap().clone();
-                            let mut result: Vec<String> = Vec::new();
-                            for account in accounts {
-                                if blk_state.contains_key(&account) {
-                                    let (nonce, balance) = blk_state.get(&account).unwrap();
-                                    let s = String::from("(".to_owned() + account.to_string().as_str() + ", " + &nonce.to_string() + ", " + &balance.to_string() + ")");
-                                    result.push(s);
-                                }
-                            }
-                            respond_json!(req, result);
+                    },
+                    None => PROPAGATION_RECENT,
+                };
+                let summary = propagation.summary(recent);
+                let millis = |delay: Duration| delay.as_secs_f64() * 1000.0;
+                let response = PropagationResponse {
+                    recorded: summary.recorded,
+                    negative: summary.negative,
+                    p50_ms: summary.p50.map(millis),
+                    p90_ms: summary.p90.map(millis),
+                    p99_ms: summary.p99.map(millis),
+                    max_ms: summary.max.map(millis),
+                    recent: summary.recent.into_iter().map(|sample| PropagationSampleResponse {
+                        block: sample.block.to_string(),
+                        delay_ms: millis(sample.delay),
+                        received_at: sample.received_at,
+                    }).collect(),
+                };
+                return respond_json!(response);
+            }
+            "/network/connect" | "/network/disconnect" => {
+                let params = url.query_pairs();
+                let params: HashMap<_, _> = params.into_owned().collect();
+                let addr = match params.get("addr") {
+                    Some(v) => v,
+                    None => {
+                        return respond_result!(false, "missing addr");
+                    }
+                };
+                //a host name is left to the proxy if there is one, the peer is known by the same stand-in address as when dialed
+                let addr = match network.resolve_peer(addr) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        return respond_result!(false,
+                            format!("error parsing addr: {}", e)
+                        );
+                    }
+                };
+                if url.path() == "/network/disconnect" {
+                    if !network.disconnect(addr) {
+                        return respond_result!(false, format!("not connected to {}", addr));
+                    }
+                    return respond_result!(true, "ok");
+                }
+                //persistent=true keeps the peer like a --connect one, the server redials it with backoff if this attempt
+                //fails or the connection drops
+                if params.get("persistent").map(|v| v == "true").unwrap_or(false) {
+                    return match network.add_persistent_peer_now(addr, Arc::clone(&self.greeting)) {
+                        Ok(()) => respond_result!(true, "ok"),
+                        Err(e) => respond_result!(false,
+                            format!("error connecting to {}, retrying with backoff: {}", addr, e)
+                        ),
+                    };
+                }
+                return match network.connect(addr) {
+                    Ok(mut peer) => {
+                        peer.write((self.greeting)(&addr));
+                        respond_result!(true, "ok")
+                    }
+                    Err(e) => respond_result!(false,
+                        format!("error connecting to {}: {}", addr, e)
+                    ),
+                };
+            }
+            "/network/impair" => {
+                //degrades the link to one peer, reset=true makes it follow the node-wide --inject-* flags again
+                let params = url.query_pairs();
+                let params: HashMap<_, _> = params.into_owned().collect();
+                let addr = match params.get("addr") {
+                    Some(v) => v,
+                    None => {
+                        return respond_result!(false, "missing addr");
+                    }
+                };
+                //a host name is left to the proxy if there is one, the peer is known by the same stand-in address as when dialed
+                let addr = match network.resolve_peer(addr) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        return respond_result!(false,
+                            format!("error parsing addr: {}", e)
+                        );
+                    }
+                };
+                if params.get("reset").map(|v| v == "true").unwrap_or(false) {
+                    network.set_peer_impairment(addr, None);
+                    return respond_result!(true, "ok");
+                }
+                let latency_ms = match params.get("latency_ms").map(|v| v.parse::<u64>()).unwrap_or(Ok(0)) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        return respond_result!(false,
+                            format!("error parsing latency_ms: {}", e)
+                        );
+                    }
+                };
+                let loss_pct = match params.get("loss_pct").map(|v| v.parse::<f64>()).unwrap_or(Ok(0.0)) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        return respond_result!(false,
+                            format!("error parsing loss_pct: {}", e)
+                        );
+                    }
+                };
+                let loss_class = params.get("loss_only").map(|v| v.as_str()).unwrap_or("all");
+                let impairment = match parse_loss_class(loss_class).and_then(|loss_class| Impairment::new(latency_ms, loss_pct, loss_class)) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        return respond_result!(false, e);
+                    }
+                };
+                network.set_peer_impairment(addr, Some(impairment));
+                return respond_result!(true, "ok");
+            }
+            "/network/banned" => {
+                let banned: Vec<BannedPeerResponse> = network.banned().into_iter().map(|peer| BannedPeerResponse {
+                    ip: peer.ip.to_string(),
+                    expires_in_secs: peer.expires_in.as_secs(),
+                    expires_at: peer.expires_at / 1000,
+                }).collect();
+                return respond_json!(banned);
+            }
+            "/network/ban" => {
+                let params = url.query_pairs();
+                let params: HashMap<_, _> = params.into_owned().collect();
+                let addr = match params.get("addr") {
+                    Some(v) => v,
+                    None => {
+                        return respond_result!(false, "missing addr");
+                    }
+                };
+                //accept either ip:port or a bare ip, the ban applies to the whole ip
+                let addr = match addr.parse::<SocketAddr>() {
+                    Ok(v) => v,
+                    Err(_) => match addr.parse::<IpAddr>() {
+                        Ok(ip) => SocketAddr::new(ip, 0),
+                        Err(e) => {
+                            return respond_result!(false,
+                                format!("error parsing addr: {}", e)
+                            );
                         }
-                        _ => {
-                            let content_type =
-                                "Content-Type: application/json".parse::<Header>().unwrap();
-                            let payload = ApiResponse {
-                                success: false,
-                                message: "endpoint not found".to_string(),
-                            };
-                            let resp = Response::from_string(
-                                serde_json::to_string_pretty(&payload).unwrap(),
-                            )
-                            .with_header(content_type)
-                            .with_status_code(404);
-                            req.respond(resp).unwrap();
+                    }
+                };
+                network.ban(addr);
+                return respond_result!(true, "ok");
+            }
+            "/blockchain/longest-chain" => {
+                let v_string: Vec<String> = blockchain.read().unwrap().longest_chain().iter().map(|h|h.to_string()).collect();
+                return respond_json!(v_string);
+            }
+            "/blockchain/longest-chain-tx" => {
+                let blockchain = blockchain.read().unwrap();
+                let mut txs_string: Vec<Vec<String>> = Vec::<Vec<String>>::new();
+                for block_hash in blockchain.longest_chain() {
+                    let (block, _) = blockchain.block_map.get(block_hash).unwrap();
+                    txs_string.push(block.content.data.iter().map(|transaction| transaction.hash().to_string()).collect());
+                }
+                return respond_json!(txs_string);
+            }
+            "/blockchain/height" => {
+                let height = blockchain.read().unwrap().height;
+                return respond_json!(HeightResponse { height });
+            }
+            "/blockchain/forks" => {
+                let blockchain = blockchain.read().unwrap();
+                let longest_chain = blockchain.longest_chain();
+                let mut forks = Vec::new();
+                for (height, main) in longest_chain.iter().enumerate() {
+                    let height = height as u32;
+                    let blocks = blockchain.blocks_at_height(height);
+                    if blocks.len() < 2 {
+                        continue;
+                    }
+                    forks.push(ForkResponse {
+                        height,
+                        depth: blockchain.height - height,
+                        main: main.to_string(),
+                        competing: blocks.iter().filter(|hash| *hash != main).map(|hash| hash.to_string()).collect(),
+                    });
+                }
+                return respond_json!(forks);
+            }
+            "/blockchain/difficulty" => {
+                let target = {
+                    let blockchain = blockchain.read().unwrap();
+                    blockchain.block_map.get(&blockchain.tip()).unwrap().0.get_difficulty()
+                };
+                let difficulty = DifficultyResponse {
+                    bits: target.leading_zero_bits(),
+                    target: format!("0x{}", target),
+                };
+                return respond_json!(difficulty);
+            }
+            "/blockchain/stats" => {
+                let blockchain = blockchain.read().unwrap();
+                let target = blockchain.block_map.get(&blockchain.tip()).unwrap().0.get_difficulty();
+                let stats = BlockchainStatsResponse {
+                    height: blockchain.height,
+                    difficulty: DifficultyResponse {
+                        bits: target.leading_zero_bits(),
+                        target: format!("0x{}", target),
+                    },
+                    total_transactions: blockchain.total_transactions(),
+                };
+                return respond_json!(stats);
+            }
+            "/blockchain/block-time-stats" => {
+                let params = url.query_pairs();
+                let params: HashMap<_, _> = params.into_owned().collect();
+                let window = match params.get("window") {
+                    Some(v) => match v.parse::<usize>() {
+                        Ok(v) => v,
+                        Err(e) => {
+                            return respond_result!(false,
+                                format!("error parsing window: {}", e)
+                            );
                         }
+                    },
+                    None => BLOCK_TIME_WINDOW,
+                };
+                let blockchain = blockchain.read().unwrap();
+                let stats = BlockTimeStatsResponse {
+                    mean_ms: blockchain.block_times.mean_block_time_millis(window),
+                    std_dev_ms: blockchain.block_times.std_dev_millis(window),
+                    sample_size: blockchain.block_times.sample_size(window),
+                };
+                return respond_json!(stats);
+            }
+            "/blockchain/block" => {
+                let params = url.query_pairs();
+                let params: HashMap<_, _> = params.into_owned().collect();
+                let hash = match params.get("hash") {
+                    Some(v) => v,
+                    None => {
+                        return respond_error!(400, "missing hash");
                     }
-                });
+                };
+                let hash = match hash.parse::<H256>() {
+                    Ok(v) => v,
+                    Err(e) => {
+                        return respond_error!(400, format!("error parsing hash: {}", e));
+                    }
+                };
+                let blockchain = blockchain.read().unwrap();
+                let (block, height) = match blockchain.get_block(&hash) {
+                    Some(v) => v,
+                    None => {
+                        return respond_error!(404, "block not found");
+                    }
+                };
+                let depth = blockchain.depth(hash);
+                let header = block.header();
+                let response = BlockResponse {
+                    hash: hash.to_string(),
+                    height,
+                    main_chain: depth.is_some(),
+                    confirmations: depth.map(|depth| depth + 1).unwrap_or(0),
+                    header: BlockHeaderResponse {
+                        parent: header.parent.to_string(),
+                        nonce: header.nonce,
+                        difficulty: header.difficulty.to_string(),
+                        timestamp: header.timestamp,
+                        merkle_root: header.merkle_root.to_string(),
+                    },
+                    transactions: block.content.data.iter().map(|tx| BlockTransactionResponse {
+                        hash: tx.hash().to_string(),
+                        sender: tx.transaction.sender.to_string(),
+                        account_nonce: tx.transaction.account_nonce,
+                        outputs: tx.transaction.outputs.iter()
+                            .map(|(receiver, value)| TransactionOutputResponse { receiver: receiver.to_string(), value: *value })
+                            .collect(),
+                        fee: tx.transaction.fee,
+                    }).collect(),
+                };
+                return respond_json!(response);
             }
-        });
-        info!("API server listening at {}", &addr);
+            path if path.starts_with("/blockchain/confirmation-count/") => {
+                let hash = match path["/blockchain/confirmation-count/".len()..].parse::<H256>() {
+                    Ok(v) => v,
+                    Err(_) => {
+                        return respond_result!(false, "hash must be 32 hex encoded bytes");
+                    }
+                };
+                let confirmations = blockchain.read().unwrap().confirmations(&hash);
+                let confirmations = match confirmations {
+                    Some(confirmations) => confirmations as i64,
+                    None if mempool.lock().unwrap().transaction_map.contains_key(&hash) => 0,
+                    None => -1,
+                };
+                return respond_json!(ConfirmationCountResponse { confirmations });
+            }
+            "/blockchain/longest-chain-tx-count" => {
+                return respond_result!(false, "unimplemented!");
+            }
+            "/blockchain/state" => {
+                let params = url.query_pairs();
+                let params: HashMap<_, _> = params.into_owned().collect();
+                let block = match params.get("block") {
+                    Some(v) => v,
+                    None => {
+                        return respond_result!(false, "missing block");
+                    }
+                };
+                let block = match block.parse::<usize>() {
+                    Ok(v) => v,
+                    Err(e) => {
+                        return respond_result!(false,
+                            format!("error parsing block: {}", e)
+                        );
+                    }
+                };
+                //so that ordering is consistent across API calls
+                let accounts: [Address; 3] = [
+                    Address::from_public_key_bytes(&[59, 106, 39, 188, 206, 182, 164, 45, 98, 163, 168, 208, 42, 111, 13, 115, 101, 50, 21, 119, 29, 226, 67, 166, 58, 192, 72, 161, 139, 89, 218, 41]),
+                    Address::from_public_key_bytes(&[138, 136, 227, 221, 116, 9, 241, 149, 253, 82, 219, 45, 60, 186, 93, 114, 202, 103, 9, 191, 29, 148, 18, 27, 243, 116, 136, 1, 180, 15, 111, 92]),
+                    Address::from_public_key_bytes(&[129, 57, 119, 14, 168, 125, 23, 95, 86, 163, 84, 102, 195, 76, 126, 204, 203, 141, 138, 145, 180, 238, 55, 162, 93, 246, 15, 91, 143, 201, 179, 148])
+                ];
+                if block >= blockchain.read().unwrap().len() {
+                    return respond_result!(false, "block is past the tip of the longest chain");
+                }
+                let block_hash = blockchain.read().unwrap().longest_chain()[block];
+                let blk_state = block_state_map.lock().unwrap().block_state_map.get(&block_hash).unwrap().clone();
+                let mut result: Vec<String> = Vec::new();
+                for account in accounts {
+                    if blk_state.contains_key(&account) {
+                        let (nonce, balance) = blk_state.get(&account).unwrap();
+                        let s = String::from("(".to_owned() + account.to_string().as_str() + ", " + &nonce.to_string() + ", " + &balance.to_string() + ")");
+                        result.push(s);
+                    }
+                }
+                return respond_json!(result);
+            }
+            "/mempool/transactions" => {
+                let mut hashes: Vec<String> = mempool.lock().unwrap().transaction_map.keys().map(|hash| hash.to_string()).collect();
+                hashes.sort();
+                return respond_json!(hashes);
+            }
+            "/mempool/stats" => {
+                return respond_json!(mempool.lock().unwrap().statistics());
+            }
+            "/mempool/estimate-fee" => {
+                let params = url.query_pairs();
+                let params: HashMap<_, _> = params.into_owned().collect();
+                let target = match params.get("target") {
+                    Some(v) => v,
+                    None => {
+                        return respond_result!(false, "missing target");
+                    }
+                };
+                let target = match target.parse::<u32>() {
+                    Ok(v) => v,
+                    Err(e) => {
+                        return respond_result!(false,
+                            format!("error parsing target: {}", e)
+                        );
+                    }
+                };
+                let blockchain = blockchain.read().unwrap();
+                let fee = mempool.lock().unwrap().estimate_fee(&blockchain, target);
+                return respond_json!(fee);
+            }
+            _ => {
+                return respond_error!(404, "endpoint not found");
+            }
+        }
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod test {
+    use ntest::timeout;
+    use std::collections::HashMap;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::sync::{Arc, Mutex, RwLock};
+    use std::thread;
+    use std::time::Duration;
+    use crate::blockchain::Blockchain;
+    use crate::miner::{self, Mempool};
+    use crate::network::server::Handle as NetworkServerHandle;
+    use crate::network::propagation::PropagationStats;
+    use crate::network::worker::SyncStatus;
+    use crate::transaction_generator::{self, DEFAULT_MEMPOOL_HIGH_WATER};
+    use crate::types::address::Address;
+    use crate::types::block::BlockState;
+    use crate::types::key_pair;
+    use crate::types::block::generate_mined_block;
+    use crate::types::hash::generate_random_hash;
+    use crate::types::transaction::{generate_random_transaction, SignedTransaction};
+    use crate::types::hash::Hashable;
+    use crate::ShutdownTrigger;
+    use std::net::SocketAddr;
+    use crate::network::message::Message;
+    use crate::network::server::Greeting;
+    use crate::network::worker::{start_test_node, TestNode, TestNodeOptions, Worker};
+    use super::{Event, Events, Server};
+
+    fn test_greeting() -> Greeting {
+        return Arc::new(|_| Message::Ping(0));
+    }
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = loop {
+            match TcpStream::connect(addr) {
+                Ok(stream) => break stream,
+                Err(_) => thread::sleep(Duration::from_millis(50)),
+            }
+        };
+        write!(stream, "GET {} HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: close\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        return response;
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn start_on_exited_miner_returns_error() {
+        let blockchain = Arc::new(RwLock::new(Blockchain::new()));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let block_state_map = Arc::new(Mutex::new(BlockState::new()));
+        let (miner_ctx, miner, _finished_block_chan) = miner::new(&blockchain, &mempool, &block_state_map);
+        let (_generator_ctx, generator, _finished_tx_chan) = transaction_generator::new(
+            &blockchain, &mempool, &Address::from([1; 20]), key_pair::random(), &block_state_map, vec![Address::from([2; 20]), Address::from([3; 20])], DEFAULT_MEMPOOL_HIGH_WATER);
+        let (network, _network_receiver) = NetworkServerHandle::new_for_test();
+        miner.exit().unwrap();
+        miner_ctx.start().join().unwrap();
+
+        let addr = "127.0.0.1:7093".parse().unwrap();
+        Server::start(addr, &miner, &generator, &network, &blockchain, &block_state_map, &mempool, &SyncStatus::default(), &PropagationStats::new(), &test_greeting(), Duration::from_secs(5), &Events::new(), &ShutdownTrigger::new());
+        let response = get(addr, "/miner/start?lambda=0");
+        assert!(response.starts_with("HTTP/1.1 503"));
+        assert!(response.contains("\"success\": false"));
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn events_stream_mined_blocks() {
+        let blockchain = Arc::new(RwLock::new(Blockchain::new()));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let block_state_map = Arc::new(Mutex::new(BlockState::new()));
+        //the miner worker checks blocks against their parent's state
+        block_state_map.lock().unwrap().block_state_map.insert(blockchain.read().unwrap().tip(), HashMap::new());
+        let (_miner_ctx, miner, _finished_block_chan) = miner::new(&blockchain, &mempool, &block_state_map);
+        let (_generator_ctx, generator, _finished_tx_chan) = transaction_generator::new(
+            &blockchain, &mempool, &Address::from([1; 20]), key_pair::random(), &block_state_map, vec![Address::from([2; 20]), Address::from([3; 20])], DEFAULT_MEMPOOL_HIGH_WATER);
+        let (network, _network_receiver) = NetworkServerHandle::new_for_test();
+        let events = Events::new();
+        let (block_sender, block_receiver) = crossbeam::channel::unbounded();
+        let (_shutdown_sender, shutdown_receiver) = crossbeam::channel::unbounded();
+        miner::worker::Worker::new(&network, block_receiver, &blockchain, &mempool, &block_state_map, shutdown_receiver, &events).start();
+
+        let addr = "127.0.0.1:7095".parse().unwrap();
+        Server::start(addr, &miner, &generator, &network, &blockchain, &block_state_map, &mempool, &SyncStatus::default(), &PropagationStats::new(), &test_greeting(), Duration::from_secs(5), &events, &ShutdownTrigger::new());
+        let mut socket = loop {
+            match tungstenite::connect("ws://127.0.0.1:7095/events") {
+                Ok((socket, _)) => break socket,
+                Err(_) => thread::sleep(Duration::from_millis(50)),
+            }
+        };
+
+        let mut parent = blockchain.read().unwrap().tip();
+        let mut expected = Vec::new();
+        for height in 1..=3 {
+            let block = generate_mined_block(&parent);
+            parent = block.hash();
+            expected.push(Event::NewBlock { hash: block.hash().to_string(), height });
+            block_sender.send(block).unwrap();
+        }
+        for event in expected {
+            let message = socket.read().unwrap();
+            let received: serde_json::Value = serde_json::from_str(message.to_text().unwrap()).unwrap();
+            assert_eq!(received, serde_json::to_value(&event).unwrap());
+            assert_eq!(received["type"], "new_block");
+        }
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn peers_are_connected_and_disconnected_through_api() {
+        let addr_a: SocketAddr = "127.0.0.1:6117".parse().unwrap();
+        let addr_b: SocketAddr = "127.0.0.1:6118".parse().unwrap();
+        let TestNode { server: network, blockchain, .. } = start_test_node(addr_a, Blockchain::new(), TestNodeOptions::default());
+        let TestNode { server: _network_b, blockchain: blockchain_b, .. } = start_test_node(addr_b, Blockchain::new(), TestNodeOptions::default());
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let block_state_map = Arc::new(Mutex::new(BlockState::new()));
+        let (_miner_ctx, miner, _finished_block_chan) = miner::new(&blockchain, &mempool, &block_state_map);
+        let (_generator_ctx, generator, _finished_tx_chan) = transaction_generator::new(
+            &blockchain, &mempool, &Address::from([1; 20]), key_pair::random(), &block_state_map, vec![Address::from([2; 20]), Address::from([3; 20])], DEFAULT_MEMPOOL_HIGH_WATER);
+        let greeting_blockchain = Arc::clone(&blockchain);
+        let greeting: Greeting = Arc::new(move |_| Worker::version_message(&greeting_blockchain, addr_a, rand::random()));
+
+        let addr = "127.0.0.1:7099".parse().unwrap();
+        Server::start(addr, &miner, &generator, &network, &blockchain, &block_state_map, &mempool, &SyncStatus::default(), &PropagationStats::new(), &greeting, Duration::from_secs(5), &Events::new(), &ShutdownTrigger::new());
+        assert!(get(addr, "/network/connect?addr=not-an-address").contains("\"success\": false"));
+        assert!(get(addr, "/miner/stats").contains("\"blocks_found\":0,\"first_block_height\":null"));
+        assert!(get(addr, "/mempool/stats").contains("\"count\":0,\"total_bytes\":0"));
+        assert!(get(addr, "/network/connect").contains("\"message\": \"missing addr\""));
+        assert!(get(addr, "/network/connect?addr=127.0.0.1:6118").contains("\"success\": true"));
+        //nothing listens there, the server keeps redialing it
+        assert!(get(addr, "/network/connect?addr=127.0.0.1:1&persistent=true").contains("retrying with backoff"));
+        while network.handshaked_peers().len() != 1 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(get(addr, "/network/stats").contains("\"shed_tx_messages\":0"));
+        let stats = get(addr, "/network/stats");
+        assert!(stats.contains("\"totals\":{\"blocks\":"));
+        assert!(stats.contains("\"addr\":\"127.0.0.1:6118\",\"connected\":true"));
+
+        assert!(get(addr, "/network/impair?addr=127.0.0.1:6118&loss_pct=101").contains("\"success\": false"));
+        assert!(get(addr, "/network/impair?addr=127.0.0.1:6118&loss_only=votes").contains("\"success\": false"));
+        assert!(get(addr, "/network/impair?addr=127.0.0.1:6118&latency_ms=250&loss_pct=10&loss_only=transactions").contains("\"success\": true"));
+        let impairment = network.impairments().for_peer(&addr_b);
+        assert_eq!((impairment.latency_ms, impairment.loss_pct), (250, 10.0));
+        assert!(get(addr, "/network/impair?addr=127.0.0.1:6118&reset=true").contains("\"success\": true"));
+        assert!(network.impairments().per_peer.is_empty());
+
+        //blocks announced by a reach b
+        let block = generate_mined_block(&blockchain.read().unwrap().tip());
+        blockchain.write().unwrap().insert(&block);
+        network.broadcast(Message::NewBlockHashes(vec![block.hash()]));
+        while blockchain_b.read().unwrap().tip() != block.hash() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert!(get(addr, "/network/disconnect?addr=127.0.0.1:6118").contains("\"success\": true"));
+        while !network.peer_info().is_empty() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let block = generate_mined_block(&block.hash());
+        blockchain.write().unwrap().insert(&block);
+        network.broadcast(Message::NewBlockHashes(vec![block.hash()]));
+        //past the first reconnection backoff, the peer must stay disconnected
+        thread::sleep(Duration::from_millis(1500));
+        assert!(network.peer_info().is_empty());
+        assert_eq!(blockchain_b.read().unwrap().height, 1);
+        assert!(get(addr, "/network/disconnect?addr=127.0.0.1:6118").contains("\"success\": false"));
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn block_is_returned_as_json() {
+        let blockchain = Arc::new(RwLock::new(Blockchain::new()));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let block_state_map = Arc::new(Mutex::new(BlockState::new()));
+        let (_miner_ctx, miner, _finished_block_chan) = miner::new(&blockchain, &mempool, &block_state_map);
+        let (_generator_ctx, generator, _finished_tx_chan) = transaction_generator::new(
+            &blockchain, &mempool, &Address::from([1; 20]), key_pair::random(), &block_state_map, vec![Address::from([2; 20]), Address::from([3; 20])], DEFAULT_MEMPOOL_HIGH_WATER);
+        let (network, _network_receiver) = NetworkServerHandle::new_for_test();
+        let genesis = blockchain.read().unwrap().tip();
+        let mut block = generate_mined_block(&genesis);
+        let tx = SignedTransaction { transaction: generate_random_transaction(), ..Default::default() };
+        block.content.data.push(tx.clone());
+        let next = generate_mined_block(&block.hash());
+        //loses to the chain above
+        let fork = generate_mined_block(&genesis);
+        for inserted in [&block, &next, &fork] {
+            blockchain.write().unwrap().insert(inserted);
+        }
+
+        let addr = "127.0.0.1:7100".parse().unwrap();
+        Server::start(addr, &miner, &generator, &network, &blockchain, &block_state_map, &mempool, &SyncStatus::default(), &PropagationStats::new(), &test_greeting(), Duration::from_secs(5), &Events::new(), &ShutdownTrigger::new());
+        let body = |response: &str| -> serde_json::Value {
+            return serde_json::from_str(response.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        };
+        let response = get(addr, &format!("/blockchain/block?hash={}", block.hash()));
+        assert!(response.starts_with("HTTP/1.1 200"));
+        let json = body(&response);
+        assert_eq!(json["hash"], block.hash().to_string());
+        assert_eq!(json["height"], 1);
+        assert_eq!(json["main_chain"], true);
+        assert_eq!(json["confirmations"], 2);
+        assert_eq!(json["header"]["parent"], genesis.to_string());
+        assert_eq!(json["header"]["nonce"], block.get_nonce());
+        assert_eq!(json["header"]["merkle_root"], block.get_merkle_root().to_string());
+        assert_eq!(json["transactions"][0]["hash"], tx.hash().to_string());
+        assert_eq!(json["transactions"][0]["sender"], tx.transaction.sender.to_string());
+        assert_eq!(json["transactions"][0]["outputs"][0]["value"], tx.transaction.outputs[0].1);
+        let json = body(&get(addr, &format!("/blockchain/block?hash={}", fork.hash())));
+        assert_eq!((json["main_chain"].clone(), json["confirmations"].clone()), (false.into(), 0.into()));
+
+        let response = get(addr, &format!("/blockchain/block?hash={}", generate_random_hash()));
+        assert!(response.starts_with("HTTP/1.1 404"));
+        assert_eq!(body(&response)["message"], "block not found");
+        let response = get(addr, "/blockchain/block?hash=xyz");
+        assert!(response.starts_with("HTTP/1.1 400"));
+        assert_eq!(body(&response)["message"], "error parsing hash: hash is not hex encoded");
+        assert!(get(addr, "/blockchain/block").starts_with("HTTP/1.1 400"));
+
+        //mined transactions are found through the chain's index
+        let response = get(addr, &format!("/tx/raw/{}", tx.hash()));
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert_eq!(body(&response), tx.to_hex());
+        assert!(get(addr, &format!("/tx/raw/{}", generate_random_hash())).starts_with("HTTP/1.1 404"));
+        assert!(get(addr, "/tx/raw/xyz").starts_with("HTTP/1.1 400"));
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn slow_handler_times_out() {
+        let blockchain = Arc::new(RwLock::new(Blockchain::new()));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let block_state_map = Arc::new(Mutex::new(BlockState::new()));
+        let (_miner_ctx, miner, _finished_block_chan) = miner::new(&blockchain, &mempool, &block_state_map);
+        let (_generator_ctx, generator, _finished_tx_chan) = transaction_generator::new(
+            &blockchain, &mempool, &Address::from([1; 20]), key_pair::random(), &block_state_map, vec![Address::from([2; 20]), Address::from([3; 20])], DEFAULT_MEMPOOL_HIGH_WATER);
+        let (network, _network_receiver) = NetworkServerHandle::new_for_test();
+
+        let addr = "127.0.0.1:7097".parse().unwrap();
+        Server::start(addr, &miner, &generator, &network, &blockchain, &block_state_map, &mempool, &SyncStatus::default(), &PropagationStats::new(), &test_greeting(), Duration::from_millis(200), &Events::new(), &ShutdownTrigger::new());
+        //the status handler blocks on the blockchain until well after the timeout
+        let held = blockchain.write().unwrap();
+        let response = get(addr, "/node/status");
+        drop(held);
+        assert!(response.starts_with("HTTP/1.1 503"));
+        assert!(response.contains("\"message\": \"handler timeout\""));
     }
 }
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -1,4 +1,6 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{Read, Write};
+use tracing::info;
 
 use crate::types::hash::{H256, Hashable};
 use super::types::block::{Block, Content, Header};
@@ -11,12 +13,167 @@ use super::types::transaction::SignedTransaction;
 
 pub static DIFFICULTY: [u8; 32] = [0, 3, 100, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1];
 
+//first bytes of every chain snapshot written by Blockchain::export
+pub static SNAPSHOT_MAGIC: [u8; 4] = *b"BCCS";
+pub static SNAPSHOT_VERSION: u32 = 1;
+//this many of the latest intervals between a new tip and its parent are kept for block time stats
+pub static BLOCK_TIME_HISTORY: usize = 10_000;
+//largest block accepted, in serialized bytes with the header; miners may be configured to build smaller ones
+pub static MAX_BLOCK_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub enum PowError {
+    //the block claims a difficulty other than the one expected after its parent
+    WrongDifficulty,
+    //the block's hash is above its difficulty target
+    HashAboveTarget,
+}
+
+impl std::fmt::Display for PowError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let reason = match self {
+            PowError::WrongDifficulty => "block difficulty is not the one expected after its parent",
+            PowError::HashAboveTarget => "block hash does not meet the difficulty",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum InsertError {
+    //the block's parent is not in the chain
+    UnknownParent,
+    InvalidPoW(PowError),
+    //the block's merkle root doesn't match its transactions
+    MerkleRootMismatch,
+    //the block serializes to more than MAX_BLOCK_SIZE bytes
+    TooLarge(usize),
+}
+
+impl std::fmt::Display for InsertError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            InsertError::UnknownParent => write!(f, "block parent is not in the chain"),
+            InsertError::InvalidPoW(e) => write!(f, "{}", e),
+            InsertError::MerkleRootMismatch => write!(f, "block merkle root does not match its transactions"),
+            InsertError::TooLarge(size) => write!(f, "block is {} bytes, over the {} byte limit", size, MAX_BLOCK_SIZE),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ImportError {
+    //the snapshot could not be read
+    Io(std::io::Error),
+    //the snapshot doesn't start with SNAPSHOT_MAGIC
+    BadMagic,
+    //the snapshot was written by a format version we don't understand
+    UnsupportedVersion(u32),
+    //the blocks could not be deserialized
+    Malformed(bincode::Error),
+    //the first block is not our genesis block
+    WrongGenesis,
+    //a block comes before its parent, or its parent is missing
+    UnknownParent(H256),
+    //a block's hash is above the difficulty, or it claims a different difficulty
+    InvalidPoW(H256),
+    //a block's merkle root doesn't match its transactions
+    MerkleRootMismatch(H256),
+    //a block is over MAX_BLOCK_SIZE
+    TooLarge(H256),
+}
+
+impl std::fmt::Display for ImportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ImportError::Io(e) => write!(f, "can't read chain snapshot: {}", e),
+            ImportError::BadMagic => write!(f, "not a chain snapshot"),
+            ImportError::UnsupportedVersion(version) => write!(f, "unsupported chain snapshot version {}", version),
+            ImportError::Malformed(e) => write!(f, "malformed chain snapshot: {}", e),
+            ImportError::WrongGenesis => write!(f, "chain snapshot starts from a different genesis block"),
+            ImportError::UnknownParent(hash) => write!(f, "block {} has an unknown parent", hash),
+            ImportError::InvalidPoW(hash) => write!(f, "block {} fails proof of work", hash),
+            ImportError::MerkleRootMismatch(hash) => write!(f, "block {} has a wrong merkle root", hash),
+            ImportError::TooLarge(hash) => write!(f, "block {} is over the block size limit", hash),
+        }
+    }
+}
+
+/// Milliseconds between each block that became the tip and its parent, oldest first, to measure the time between blocks
+#[derive(Default)]
+pub struct BlockTimeTracker {
+    intervals: VecDeque<f64>,
+}
+
+impl BlockTimeTracker {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    /// Record the header timestamps of a new tip and its parent, in milliseconds.
+    /// The interval is negative if the block was stamped earlier than its parent
+    pub fn record(&mut self, parent_timestamp: u128, timestamp: u128) {
+        if self.intervals.len() == BLOCK_TIME_HISTORY {
+            self.intervals.pop_front();
+        }
+        self.intervals.push_back(timestamp as f64 - parent_timestamp as f64);
+    }
+
+    /// The last_n intervals recorded, oldest first
+    fn intervals(&self, last_n: usize) -> Vec<f64> {
+        let skip = self.intervals.len().saturating_sub(last_n);
+        return self.intervals.iter().skip(skip).copied().collect();
+    }
+
+    /// Number of intervals the stats over last_n blocks are taken from
+    pub fn sample_size(&self, last_n: usize) -> usize {
+        return self.intervals(last_n).len();
+    }
+
+    /// Average time between the last_n tips and their parents, 0 before any was recorded
+    pub fn mean_block_time_millis(&self, last_n: usize) -> f64 {
+        let intervals = self.intervals(last_n);
+        if intervals.is_empty() {
+            return 0.0;
+        }
+        return intervals.iter().sum::<f64>() / intervals.len() as f64;
+    }
+
+    /// Population standard deviation of the same intervals
+    pub fn std_dev_millis(&self, last_n: usize) -> f64 {
+        let intervals = self.intervals(last_n);
+        if intervals.is_empty() {
+            return 0.0;
+        }
+        let mean = intervals.iter().sum::<f64>() / intervals.len() as f64;
+        let variance = intervals.iter().map(|interval| (interval - mean).powi(2)).sum::<f64>() / intervals.len() as f64;
+        return variance.sqrt();
+    }
+}
+
+/// How the longest chain changed when the tip moved to a block that doesn't extend the old tip
+#[derive(Debug, PartialEq)]
+pub struct ReorgInfo {
+    //blocks that left the longest chain, from the old tip back to the fork point
+    pub rolled_back: Vec<H256>,
+    //blocks that joined it, from the fork point up to the new tip
+    pub adopted: Vec<H256>,
+}
+
 pub struct Blockchain {
     //map a block's hash to a tuple of (the block itself, height in blockchain)
     pub block_map: HashMap<H256, (Block, u32)>,
     pub tip: H256,
+    pub genesis: H256,
     //each block's height will be stored too but store overall height for clarity
-    pub height: u32
+    pub height: u32,
+    //map a transaction's hash to the longest chain block containing it and its position in that block
+    pub transaction_index: HashMap<H256, (H256, usize)>,
+    //map a height to the hash of every block at it, more than one while forks compete
+    pub height_to_blocks: HashMap<u32, Vec<H256>>,
+    pub block_times: BlockTimeTracker,
+    //hashes of the longest chain from genesis to the tip, kept up to date by insert
+    longest_chain: Vec<H256>
 }
 
 impl Blockchain {
@@ -41,10 +198,7 @@ impl Blockchain {
             data: Vec::<SignedTransaction>::new()
         };
         
-        let genesis_block = Block {
-            header: genesis_header,
-            content: genesis_content
-        };
+        let genesis_block = Block::new(genesis_header, genesis_content);
 
         let mut storage = HashMap::<H256, (Block, u32)>::new();
         storage.insert(genesis_block.clone().hash(), (genesis_block.clone(), genesis_height));
@@ -52,15 +206,22 @@ impl Blockchain {
         return Self {
             block_map: storage,
             tip: genesis_block.clone().hash(),
-            height: genesis_height
+            genesis: genesis_block.clone().hash(),
+            height: genesis_height,
+            transaction_index: HashMap::new(),
+            height_to_blocks: HashMap::from([(genesis_height, vec![genesis_block.hash()])]),
+            block_times: BlockTimeTracker::new(),
+            longest_chain: vec![genesis_block.hash()]
         };
     }
 
     /// Insert a block into blockchain
     pub fn insert(&mut self, block: &Block) {
+        let old_tip = self.tip;
         let new_block_hash = block.hash();
         let new_block_parent_hash = block.get_parent();
-        let (_, new_block_parent_height) = self.block_map.get(&new_block_parent_hash).unwrap();
+        let (new_block_parent, new_block_parent_height) = self.block_map.get(&new_block_parent_hash).unwrap();
+        let new_block_parent_timestamp = new_block_parent.get_timestamp();
         let new_block_height;
 
         //means we are inserting a new block to the current tip -> UPDATE tip and height
@@ -85,6 +246,122 @@ impl Blockchain {
         }
 
         self.block_map.insert(new_block_hash, ((*block).clone(), new_block_height));
+        self.height_to_blocks.entry(new_block_height).or_default().push(new_block_hash);
+        if self.tip != old_tip {
+            //blocks off the longest chain, or arriving after their children during sync, would skew the intervals.
+            //The genesis timestamp is fixed, so the first block's time since it means nothing
+            if new_block_parent_hash != self.genesis {
+                self.block_times.record(new_block_parent_timestamp, block.get_timestamp());
+            }
+            if new_block_parent_hash == old_tip {
+                self.longest_chain.push(new_block_hash);
+                self.index_transactions(&new_block_hash);
+            } else {
+                let reorg = self.reorg(&old_tip, &self.tip);
+                info!(rolled_back = reorg.rolled_back.len(), adopted = reorg.adopted.len(), "Reorganized longest chain");
+                for hash in reorg.rolled_back.iter() {
+                    self.deindex_transactions(hash);
+                }
+                for hash in reorg.adopted.iter() {
+                    self.index_transactions(hash);
+                }
+                //only the blocks above the fork point change
+                self.longest_chain.truncate(self.longest_chain.len() - reorg.rolled_back.len());
+                self.longest_chain.extend(reorg.adopted);
+            }
+        }
+        info!(
+            block.hash = %new_block_hash,
+            block.height = new_block_height,
+            block.tx_count = block.content.data.len(),
+            chain.tip = %self.tip,
+            chain.height = self.height,
+            "Inserted block"
+        );
+    }
+
+    /// Check a block's size, that its parent is known, its proof of work and its merkle root, without inserting it
+    pub fn validate(&self, block: &Block) -> Result<(), InsertError> {
+        let size = block.size_bytes();
+        if size > MAX_BLOCK_SIZE {
+            return Err(InsertError::TooLarge(size));
+        }
+        if !self.block_map.contains_key(&block.get_parent()) {
+            return Err(InsertError::UnknownParent);
+        }
+        self.verify_pow(block).map_err(InsertError::InvalidPoW)?;
+        if MerkleTree::new(&block.content.data).root() != block.get_merkle_root() {
+            return Err(InsertError::MerkleRootMismatch);
+        }
+        return Ok(());
+    }
+
+    /// Insert a block only if it passes validate, the chain is left untouched otherwise
+    pub fn validate_and_insert(&mut self, block: &Block) -> Result<(), InsertError> {
+        self.validate(block)?;
+        self.insert(block);
+        return Ok(());
+    }
+
+    /// Blocks leaving and joining the longest chain when the tip moves from old_tip to new_tip
+    pub fn reorg(&self, old_tip: &H256, new_tip: &H256) -> ReorgInfo {
+        let height = |hash: &H256| self.block_map.get(hash).unwrap().1;
+        let parent = |hash: &H256| self.block_map.get(hash).unwrap().0.get_parent();
+        let (mut old, mut new) = (*old_tip, *new_tip);
+        let mut rolled_back = Vec::new();
+        let mut adopted = Vec::new();
+        while height(&old) > height(&new) {
+            rolled_back.push(old);
+            old = parent(&old);
+        }
+        while height(&new) > height(&old) {
+            adopted.push(new);
+            new = parent(&new);
+        }
+        while old != new {
+            rolled_back.push(old);
+            adopted.push(new);
+            old = parent(&old);
+            new = parent(&new);
+        }
+        adopted.reverse();
+        return ReorgInfo { rolled_back, adopted };
+    }
+
+    fn index_transactions(&mut self, block_hash: &H256) {
+        let (block, _) = self.block_map.get(block_hash).unwrap();
+        for (position, tx) in block.content.data.iter().enumerate() {
+            self.transaction_index.insert(tx.hash(), (*block_hash, position));
+        }
+    }
+
+    fn deindex_transactions(&mut self, block_hash: &H256) {
+        let (block, _) = self.block_map.get(block_hash).unwrap();
+        for tx in block.content.data.iter() {
+            if matches!(self.transaction_index.get(&tx.hash()), Some((indexed, _)) if indexed == block_hash) {
+                self.transaction_index.remove(&tx.hash());
+            }
+        }
+    }
+
+    /// The longest chain block holding a transaction and its position in the block
+    pub fn find_transaction(&self, tx_hash: &H256) -> Option<(H256, usize)> {
+        return self.transaction_index.get(tx_hash).copied();
+    }
+
+    /// Number of transactions confirmed in the longest chain, from the index kept over it
+    pub fn total_transactions(&self) -> usize {
+        return self.transaction_index.len();
+    }
+
+    /// Hashes of every block at height `h`, on the longest chain or not, in the order they arrived
+    pub fn blocks_at_height(&self, h: u32) -> Vec<H256> {
+        return self.height_to_blocks.get(&h).cloned().unwrap_or_default();
+    }
+
+    /// Whether the block with this hash was inserted, on the longest chain or a fork
+    pub fn contains(&self, hash: &H256) -> bool {
+        return self.block_map.contains_key(hash);
     }
 
     /// Get the last block's hash of the longest chain
@@ -92,8 +369,34 @@ impl Blockchain {
         return self.tip;
     }
 
+    /// Hash of the genesis block, fixed when the chain is created
+    pub fn genesis_hash(&self) -> H256 {
+        return self.genesis;
+    }
+
+    /// Number of blocks in the longest chain, genesis included
+    pub fn len(&self) -> usize {
+        return self.height as usize + 1;
+    }
+
+    /// Always false, a blockchain holds at least the genesis block
+    pub fn is_empty(&self) -> bool {
+        return false;
+    }
+
     /// Get all blocks' hashes of the longest chain, ordered from genesis to the tip
     pub fn all_blocks_in_longest_chain(&self) -> Vec<H256> {
+        return self.longest_chain.clone();
+    }
+
+    /// Hashes of the longest chain from genesis to the tip, borrowed from the list insert keeps
+    pub fn longest_chain(&self) -> &[H256] {
+        return &self.longest_chain;
+    }
+
+    /// The longest chain found by walking parents back from the tip, what the cached list must always match
+    #[cfg(any(test, test_utilities))]
+    pub fn walk_longest_chain(&self) -> Vec<H256> {
         let mut chain: Vec<H256> = Vec::<H256>::new();
         let tip: &H256 = &self.tip();
         chain.push(*tip);
@@ -108,6 +411,157 @@ impl Blockchain {
         chain.reverse();
         return chain;
     }
+
+    /// A known block and its height, on the longest chain or not
+    pub fn get_block(&self, hash: &H256) -> Option<(&Block, u32)> {
+        return self.block_map.get(hash).map(|(block, height)| (block, *height));
+    }
+
+    /// Whether the block is an ancestor of the tip, or the tip itself
+    pub fn is_in_longest_chain(&self, hash: &H256) -> bool {
+        return match self.block_map.get(hash) {
+            Some((_, height)) => self.longest_chain.get(*height as usize) == Some(hash),
+            None => false,
+        };
+    }
+
+    /// Number of blocks built on top of this one up to the tip, 0 for the tip itself.
+    /// None if the block is on a side chain or unknown
+    pub fn depth(&self, hash: H256) -> Option<u32> {
+        if !self.is_in_longest_chain(&hash) {
+            return None;
+        }
+        let (_, height) = self.block_map.get(&hash).unwrap();
+        return Some(self.height - height);
+    }
+
+    /// Number of longest chain blocks from the one containing the transaction up to the tip, if it was mined on it
+    pub fn confirmations(&self, tx_hash: &H256) -> Option<u32> {
+        let (block_hash, _) = self.find_transaction(tx_hash)?;
+        return Some(self.depth(block_hash)? + 1);
+    }
+
+    /// A transaction mined on the longest chain, so peers rebuilding a compact block can still fetch it
+    pub fn transaction(&self, tx_hash: &H256) -> Option<SignedTransaction> {
+        let (block_hash, position) = self.find_transaction(tx_hash)?;
+        let (block, _) = self.block_map.get(&block_hash)?;
+        return block.content.data.get(position).cloned();
+    }
+
+    /// Hashes telling a peer where our longest chain is: the latest blocks, then exponentially sparser ones back to genesis
+    pub fn locator(&self) -> Vec<H256> {
+        let chain = self.longest_chain();
+        let mut locator = Vec::<H256>::new();
+        let mut index = self.len() - 1;
+        let mut step = 1;
+        loop {
+            locator.push(chain[index]);
+            if index == 0 {
+                break;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            index = index.saturating_sub(step);
+        }
+        return locator;
+    }
+
+    /// Up to limit hashes of the longest chain following the first locator hash on it, ordered from parent to child
+    /// and ending at stop_hash if it comes first
+    fn hashes_after(&self, locator: &[H256], stop_hash: Option<H256>, limit: usize) -> Vec<H256> {
+        let chain = self.longest_chain();
+        let positions: HashMap<&H256, usize> = chain.iter().enumerate().map(|(i, hash)| (hash, i)).collect();
+        //every peer shares our genesis, so start right after it if none of the hashes are on our chain
+        let fork_point = locator.iter().find_map(|hash| positions.get(hash)).copied().unwrap_or(0);
+        let mut hashes: Vec<H256> = chain[fork_point + 1..].iter().take(limit).cloned().collect();
+        if let Some(stop) = stop_hash.and_then(|stop| hashes.iter().position(|hash| *hash == stop)) {
+            hashes.truncate(stop + 1);
+        }
+        return hashes;
+    }
+
+    /// Up to limit blocks of the longest chain following the first locator hash on it, ordered from parent to child
+    /// and ending at stop_hash if it comes first
+    pub fn blocks_after(&self, locator: &[H256], stop_hash: Option<H256>, limit: usize) -> Vec<Block> {
+        return self.hashes_after(locator, stop_hash, limit).iter()
+            .map(|hash| self.block_map.get(hash).unwrap().0.clone())
+            .collect();
+    }
+
+    /// Like blocks_after without a stop hash, but only the headers
+    pub fn headers_after(&self, locator: &[H256], limit: usize) -> Vec<Header> {
+        return self.hashes_after(locator, None, limit).iter()
+            .map(|hash| self.block_map.get(hash).unwrap().0.get_header())
+            .collect();
+    }
+
+    /// Difficulty a child of parent must be mined at, which is fixed for now
+    pub fn expected_difficulty(&self, _parent: &H256) -> H256 {
+        return DIFFICULTY.into();
+    }
+
+    /// Check a block's hash meets its difficulty, and that it didn't pick an easier difficulty than its parent calls for
+    pub fn verify_pow(&self, block: &Block) -> Result<(), PowError> {
+        let difficulty = block.get_difficulty();
+        if difficulty != self.expected_difficulty(&block.get_parent()) {
+            return Err(PowError::WrongDifficulty);
+        }
+        if block.hash() > difficulty {
+            return Err(PowError::HashAboveTarget);
+        }
+        return Ok(());
+    }
+
+    /// Write every known block to writer in height order, returning how many blocks were written
+    pub fn export(&self, writer: &mut impl Write) -> Result<usize, std::io::Error> {
+        let longest_chain: HashSet<&H256> = self.longest_chain().iter().collect();
+        let mut blocks: Vec<(&H256, &(Block, u32))> = self.block_map.iter().collect();
+        //a fork as tall as the longest chain must come after it, so re-inserting keeps the same tip
+        blocks.sort_by_key(|(hash, (_, height))| (*height, !longest_chain.contains(hash), **hash));
+        let blocks: Vec<&Block> = blocks.into_iter().map(|(_, (block, _))| block).collect();
+
+        writer.write_all(&SNAPSHOT_MAGIC)?;
+        writer.write_all(&SNAPSHOT_VERSION.to_be_bytes())?;
+        bincode::serialize_into(&mut *writer, &blocks).map_err(|e| match *e {
+            bincode::ErrorKind::Io(e) => e,
+            e => std::io::Error::other(e),
+        })?;
+        return Ok(blocks.len());
+    }
+
+    /// Rebuild a blockchain from a snapshot written by export, checking every block's proof of work and merkle root
+    pub fn import(reader: &mut impl Read) -> Result<Blockchain, ImportError> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic).map_err(ImportError::Io)?;
+        if magic != SNAPSHOT_MAGIC {
+            return Err(ImportError::BadMagic);
+        }
+        let mut version = [0u8; 4];
+        reader.read_exact(&mut version).map_err(ImportError::Io)?;
+        let version = u32::from_be_bytes(version);
+        if version != SNAPSHOT_VERSION {
+            return Err(ImportError::UnsupportedVersion(version));
+        }
+        let blocks: Vec<Block> = bincode::deserialize_from(reader).map_err(ImportError::Malformed)?;
+
+        let mut blockchain = Blockchain::new();
+        let mut blocks = blocks.iter();
+        match blocks.next() {
+            Some(genesis) if genesis.hash() == blockchain.genesis_hash() => {}
+            _ => return Err(ImportError::WrongGenesis),
+        }
+        for block in blocks {
+            let hash = block.hash();
+            blockchain.validate_and_insert(block).map_err(|e| match e {
+                InsertError::UnknownParent => ImportError::UnknownParent(hash),
+                InsertError::InvalidPoW(_) => ImportError::InvalidPoW(hash),
+                InsertError::MerkleRootMismatch => ImportError::MerkleRootMismatch(hash),
+                InsertError::TooLarge(_) => ImportError::TooLarge(hash),
+            })?;
+        }
+        return Ok(blockchain);
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
@@ -115,8 +569,95 @@ impl Blockchain {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::types::block::generate_random_block;
+    use crate::types::block::{generate_mined_block, generate_random_block};
+    use crate::types::hash::generate_random_hash;
+    use crate::types::transaction::generate_random_transaction;
     use crate::types::hash::Hashable;
+    use ntest::timeout;
+    use rand::Rng;
+
+    #[test]
+    fn transactions_are_indexed_and_deindexed_on_reorg() {
+        let mut blockchain = Blockchain::new();
+        let genesis = blockchain.tip();
+        let block_with = |parent: &H256, txs: Vec<SignedTransaction>| {
+            let mut block = generate_random_block(parent);
+            block.content.data = txs;
+            return block;
+        };
+        let txs = |count: usize| -> Vec<SignedTransaction> {
+            return (0..count).map(|_| SignedTransaction { transaction: generate_random_transaction(), signature: vec![], public_key: vec![] }).collect();
+        };
+        let a1_tx = txs(1).remove(0);
+        let a2_txs = txs(2);
+        let b1_txs = txs(3);
+
+        let a1 = block_with(&genesis, vec![a1_tx.clone()]);
+        let a2 = block_with(&a1.hash(), a2_txs.clone());
+        blockchain.insert(&a1);
+        blockchain.insert(&a2);
+        assert_eq!(blockchain.find_transaction(&a1_tx.hash()), Some((a1.hash(), 0)));
+        assert_eq!(blockchain.find_transaction(&a2_txs[1].hash()), Some((a2.hash(), 1)));
+
+        //a fork that isn't longer yet leaves the index alone
+        let b1 = block_with(&genesis, b1_txs.clone());
+        let b2 = block_with(&b1.hash(), vec![]);
+        blockchain.insert(&b1);
+        blockchain.insert(&b2);
+        assert_eq!(blockchain.find_transaction(&b1_txs[0].hash()), None);
+
+        //once it is longer, a1 and a2 are rolled back and b1 is adopted
+        let b3 = block_with(&b2.hash(), vec![a2_txs[0].clone()]);
+        blockchain.insert(&b3);
+        assert_eq!(blockchain.reorg(&a2.hash(), &b3.hash()), ReorgInfo { rolled_back: vec![a2.hash(), a1.hash()], adopted: vec![b1.hash(), b2.hash(), b3.hash()] });
+        assert_eq!(blockchain.find_transaction(&a1_tx.hash()), None);
+        assert_eq!(blockchain.find_transaction(&a2_txs[1].hash()), None);
+        assert_eq!(blockchain.find_transaction(&b1_txs[2].hash()), Some((b1.hash(), 2)));
+        //a transaction mined on both branches now points at the new one
+        assert_eq!(blockchain.find_transaction(&a2_txs[0].hash()), Some((b3.hash(), 0)));
+    }
+
+    #[test]
+    fn three_way_fork_at_height_two() {
+        let mut blockchain = Blockchain::new();
+        let genesis = blockchain.tip();
+        let block1 = generate_random_block(&genesis);
+        blockchain.insert(&block1);
+        let forks: Vec<Block> = (0..3).map(|_| generate_random_block(&block1.hash())).collect();
+        for block in forks.iter() {
+            blockchain.insert(block);
+        }
+        let expected: Vec<H256> = forks.iter().map(|block| block.hash()).collect();
+        assert_eq!(blockchain.blocks_at_height(2), expected);
+        assert_eq!(blockchain.blocks_at_height(1), vec![block1.hash()]);
+        assert_eq!(blockchain.blocks_at_height(0), vec![genesis]);
+        assert!(blockchain.blocks_at_height(3).is_empty());
+    }
+
+    #[test]
+    fn block_time_stats_over_known_timestamps() {
+        let mut blockchain = Blockchain::new();
+        //blocks alternate between coming 500 and 1500 ms after their parent
+        let mut timestamp = 1_000_000;
+        for i in 0..20 {
+            timestamp += if i % 2 == 0 { 500 } else { 1500 };
+            let mut block = generate_random_block(&blockchain.tip());
+            block.header_mut().timestamp = timestamp;
+            blockchain.insert(&block);
+        }
+        //a block that doesn't move the tip isn't counted
+        let mut fork = generate_random_block(&blockchain.genesis_hash());
+        fork.header_mut().timestamp = 1;
+        blockchain.insert(&fork);
+        let block_times = &blockchain.block_times;
+        assert_eq!(block_times.sample_size(100), 19);
+        assert_eq!(block_times.mean_block_time_millis(18), 1000.0);
+        assert_eq!(block_times.std_dev_millis(18), 500.0);
+        //the last interval was 1500 ms
+        assert_eq!(block_times.mean_block_time_millis(1), 1500.0);
+        assert_eq!(block_times.std_dev_millis(1), 0.0);
+        assert_eq!(BlockTimeTracker::new().mean_block_time_millis(100), 0.0);
+    }
 
     #[test]
     fn insert_one() {
@@ -151,35 +692,406 @@ mod tests {
         let mut vec = Vec::<H256>::from([genesis_hash, block1.hash(), block2.hash(), block3.hash()]);
         assert_eq!(blockchain.tip(), block3.hash());
         assert_eq!(vec, blockchain.all_blocks_in_longest_chain());
+        assert_eq!(blockchain.len(), vec.len());
 
         //TEST case where a new chain is created which has same length as current longest chain -> keep current longest chain
         blockchain.insert(&block4);
         blockchain.insert(&block5);
         assert_eq!(blockchain.tip(), block3.hash());
         assert_eq!(vec, blockchain.all_blocks_in_longest_chain());
+        assert_eq!(blockchain.len(), vec.len());
 
         //TEST case where new block is inserted to a chain that is shorter than longest chain length
         blockchain.insert(&block6);
         assert_eq!(blockchain.tip(), block3.hash());
         assert_eq!(vec, blockchain.all_blocks_in_longest_chain());
+        assert_eq!(blockchain.len(), vec.len());
 
         //TEST case where new block is inserted to a chain that is longer than current longest chain -> switch to new chain
         blockchain.insert(&block7);
         vec = Vec::<H256>::from([genesis_hash, block1.hash(), block4.hash(), block5.hash(), block7.hash()]);
         assert_eq!(blockchain.tip(), block7.hash());
         assert_eq!(vec, blockchain.all_blocks_in_longest_chain());
+        assert_eq!(blockchain.len(), vec.len());
 
         //TEST mix of cases as before
         blockchain.insert(&block8);
         assert_eq!(blockchain.tip(), block7.hash());
         assert_eq!(vec, blockchain.all_blocks_in_longest_chain());
+        assert_eq!(blockchain.len(), vec.len());
         blockchain.insert(&block9);
         assert_eq!(blockchain.tip(), block7.hash());
         assert_eq!(vec, blockchain.all_blocks_in_longest_chain());
+        assert_eq!(blockchain.len(), vec.len());
         blockchain.insert(&block10);
         vec = Vec::<H256>::from([genesis_hash, block1.hash(), block6.hash(), block8.hash(), block9.hash(), block10.hash()]);
         assert_eq!(blockchain.tip(), block10.hash());
         assert_eq!(vec, blockchain.all_blocks_in_longest_chain());
+        assert_eq!(blockchain.len(), vec.len());
+    }
+
+    #[test]
+    fn genesis_hash_is_first_of_longest_chain() {
+        let mut blockchain = Blockchain::new();
+        let genesis = blockchain.genesis_hash();
+        for _ in 0..10 {
+            let block = generate_random_block(&blockchain.tip());
+            blockchain.insert(&block);
+        }
+        assert_eq!(blockchain.genesis_hash(), genesis);
+        assert_eq!(blockchain.genesis_hash(), blockchain.all_blocks_in_longest_chain()[0]);
+    }
+
+    #[test]
+    fn locator_thins_out_towards_genesis() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let mut chain = vec![genesis_hash];
+        for _ in 0..30 {
+            let block = generate_random_block(chain.last().unwrap());
+            blockchain.insert(&block);
+            chain.push(block.hash());
+        }
+        let locator = blockchain.locator();
+        //the 10 latest blocks, then stepping back 2, 4, 8 and 16 blocks until genesis
+        let expected: Vec<H256> = [30, 29, 28, 27, 26, 25, 24, 23, 22, 21, 19, 15, 7, 0].iter().map(|i| chain[*i]).collect();
+        assert_eq!(locator, expected);
+        assert_eq!(Blockchain::new().locator(), vec![genesis_hash]);
+    }
+
+    #[test]
+    fn blocks_after_follows_longest_chain() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block1 = generate_random_block(&genesis_hash);
+        let block2 = generate_random_block(&block1.hash());
+        let block3 = generate_random_block(&block2.hash());
+        let fork = generate_random_block(&block1.hash());
+        for block in [&block1, &block2, &block3, &fork] {
+            blockchain.insert(block);
+        }
+        let hashes = |blocks: Vec<Block>| blocks.iter().map(|b| b.hash()).collect::<Vec<H256>>();
+        assert_eq!(hashes(blockchain.blocks_after(&[genesis_hash], None, 10)), vec![block1.hash(), block2.hash(), block3.hash()]);
+        assert_eq!(hashes(blockchain.blocks_after(&[genesis_hash], None, 2)), vec![block1.hash(), block2.hash()]);
+        //a peer on the fork gets our side of it
+        assert_eq!(hashes(blockchain.blocks_after(&[fork.hash(), block1.hash(), genesis_hash], None, 10)), vec![block2.hash(), block3.hash()]);
+        assert!(blockchain.blocks_after(&[block3.hash()], None, 10).is_empty());
+        //unknown hashes fall back to genesis
+        assert_eq!(hashes(blockchain.blocks_after(&[generate_random_block(&genesis_hash).hash()], None, 1)), vec![block1.hash()]);
+        assert_eq!(hashes(blockchain.blocks_after(&[genesis_hash], Some(block2.hash()), 10)), vec![block1.hash(), block2.hash()]);
+        //a stop hash off the longest chain is never reached
+        assert_eq!(hashes(blockchain.blocks_after(&[genesis_hash], Some(fork.hash()), 10)), vec![block1.hash(), block2.hash(), block3.hash()]);
+        let headers: Vec<H256> = blockchain.headers_after(&[block1.hash()], 10).iter().map(|h| h.hash()).collect();
+        assert_eq!(headers, vec![block2.hash(), block3.hash()]);
+    }
+
+    #[test]
+    fn depth_counts_blocks_up_to_the_tip() {
+        let mut blockchain = Blockchain::new();
+        let genesis = blockchain.tip();
+        assert_eq!(blockchain.depth(genesis), Some(0));
+        let mut main = vec![];
+        let mut parent = genesis;
+        for _ in 0..4 {
+            let block = generate_random_block(&parent);
+            blockchain.insert(&block);
+            parent = block.hash();
+            main.push(parent);
+        }
+        assert_eq!(blockchain.depth(genesis), Some(4));
+        assert_eq!(blockchain.depth(main[0]), Some(3));
+        assert_eq!(blockchain.depth(main[3]), Some(0));
+
+        //blocks off the longest chain have no depth
+        let side = generate_random_block(&main[0]);
+        blockchain.insert(&side);
+        assert_eq!(blockchain.depth(side.hash()), None);
+        assert_eq!(blockchain.depth(generate_random_hash()), None);
+    }
+
+    #[test]
+    fn confirmations_grow_with_chain() {
+        let mut blockchain = Blockchain::new();
+        let mut block = generate_random_block(&blockchain.tip());
+        let tx = SignedTransaction { transaction: generate_random_transaction(), ..Default::default() };
+        block.content.data.push(tx.clone());
+        blockchain.insert(&block);
+        assert_eq!(blockchain.confirmations(&tx.hash()), Some(1));
+        assert_eq!(blockchain.confirmations(&generate_random_hash()), None);
+
+        let mut parent = block.hash();
+        for expected in 2..5 {
+            let next = generate_random_block(&parent);
+            blockchain.insert(&next);
+            parent = next.hash();
+            assert_eq!(blockchain.confirmations(&tx.hash()), Some(expected));
+        }
+
+        //a longer fork without the transaction leaves it unconfirmed
+        let mut parent = blockchain.genesis;
+        for _ in 0..5 {
+            let next = generate_random_block(&parent);
+            blockchain.insert(&next);
+            parent = next.hash();
+        }
+        assert!(!blockchain.is_in_longest_chain(&block.hash()));
+        assert_eq!(blockchain.confirmations(&tx.hash()), None);
+    }
+
+    #[test]
+    fn verify_pow_checks_hash_and_difficulty() {
+        let blockchain = Blockchain::new();
+        let block = generate_mined_block(&blockchain.tip());
+        assert_eq!(blockchain.verify_pow(&block), Ok(()));
+
+        let mut unsolved = block.clone();
+        while unsolved.hash() <= unsolved.get_difficulty() {
+            unsolved.header_mut().nonce = unsolved.get_nonce().wrapping_add(1);
+        }
+        assert_eq!(blockchain.verify_pow(&unsolved), Err(PowError::HashAboveTarget));
+
+        //any hash meets this target, but it isn't the one the chain asks for
+        let mut easy = block.clone();
+        easy.header_mut().difficulty = H256::from([255; 32]);
+        assert_eq!(blockchain.verify_pow(&easy), Err(PowError::WrongDifficulty));
+    }
+
+    #[test]
+    fn validate_and_insert_leaves_chain_untouched_on_error() {
+        let mut blockchain = Blockchain::new();
+        let genesis = blockchain.tip();
+
+        let orphan = generate_mined_block(&generate_random_hash());
+        assert_eq!(blockchain.validate_and_insert(&orphan), Err(InsertError::UnknownParent));
+
+        let mut unsolved = generate_mined_block(&genesis);
+        while unsolved.hash() <= unsolved.get_difficulty() {
+            unsolved.header_mut().nonce = unsolved.get_nonce().wrapping_add(1);
+        }
+        assert_eq!(blockchain.validate_and_insert(&unsolved), Err(InsertError::InvalidPoW(PowError::HashAboveTarget)));
+
+        let mut tampered = generate_mined_block(&genesis);
+        tampered.content.data.push(SignedTransaction { transaction: generate_random_transaction(), signature: vec![], public_key: vec![] });
+        assert_eq!(blockchain.validate_and_insert(&tampered), Err(InsertError::MerkleRootMismatch));
+
+        //checked before anything else, the block's other faults don't matter
+        let mut oversized = generate_random_block(&genesis);
+        let tx = SignedTransaction { signature: vec![0; 64], public_key: vec![0; 32], ..Default::default() };
+        let tx_size = bincode::serialized_size(&tx).unwrap() as usize;
+        oversized.content.data = vec![tx; MAX_BLOCK_SIZE / tx_size + 1];
+        assert_eq!(blockchain.validate_and_insert(&oversized), Err(InsertError::TooLarge(oversized.size_bytes())));
+        assert_eq!(blockchain.tip(), genesis);
+        assert_eq!(blockchain.block_map.len(), 1);
+
+        let block = generate_mined_block(&genesis);
+        assert_eq!(blockchain.validate_and_insert(&block), Ok(()));
+        assert_eq!(blockchain.tip(), block.hash());
+    }
+
+    #[test]
+    fn export_import_round_trip() {
+        let mut blockchain = Blockchain::new();
+        let mut parent = blockchain.tip();
+        for _ in 0..50 {
+            let block = generate_mined_block(&parent);
+            blockchain.insert(&block);
+            parent = block.hash();
+        }
+        //a fork as tall as the longest chain must not take over the tip
+        let fork = generate_mined_block(&blockchain.block_map.get(&parent).unwrap().0.get_parent());
+        blockchain.insert(&fork);
+
+        let mut snapshot = Vec::new();
+        assert_eq!(blockchain.export(&mut snapshot).unwrap(), 52);
+        let imported = Blockchain::import(&mut snapshot.as_slice()).unwrap();
+        assert_eq!(imported.tip(), blockchain.tip());
+        assert_eq!(imported.height, 50);
+        assert_eq!(imported.all_blocks_in_longest_chain(), blockchain.all_blocks_in_longest_chain());
+        let mut imported_hashes: Vec<H256> = imported.block_map.keys().copied().collect();
+        let mut hashes: Vec<H256> = blockchain.block_map.keys().copied().collect();
+        imported_hashes.sort();
+        hashes.sort();
+        assert_eq!(imported_hashes, hashes);
+    }
+
+    #[test]
+    fn total_transactions_grows_by_each_block_on_the_tip() {
+        let mut rng = rand::thread_rng();
+        let mut blockchain = Blockchain::new();
+        assert_eq!(blockchain.total_transactions(), 0);
+        for _ in 0..50 {
+            let n = rng.gen_range(0..20);
+            let mut block = generate_random_block(&blockchain.tip());
+            block.content.data = (0..n).map(|_| SignedTransaction { transaction: generate_random_transaction(), signature: vec![], public_key: vec![] }).collect();
+            let before = blockchain.total_transactions();
+            blockchain.insert(&block);
+            assert_eq!(blockchain.total_transactions(), before + n);
+        }
+        let summed: usize = blockchain.all_blocks_in_longest_chain().iter()
+            .map(|hash| blockchain.block_map[hash].0.content.data.len())
+            .sum();
+        assert_eq!(blockchain.total_transactions(), summed);
+
+        //a longer fork takes over, only its transactions count from the fork point on
+        let fork_point = blockchain.all_blocks_in_longest_chain()[45];
+        let mut parent = fork_point;
+        for _ in 0..6 {
+            let mut block = generate_random_block(&parent);
+            block.content.data = vec![SignedTransaction { transaction: generate_random_transaction(), signature: vec![], public_key: vec![] }];
+            blockchain.insert(&block);
+            parent = block.hash();
+        }
+        let summed: usize = blockchain.all_blocks_in_longest_chain().iter()
+            .map(|hash| blockchain.block_map[hash].0.content.data.len())
+            .sum();
+        assert_eq!(blockchain.tip(), parent);
+        assert_eq!(blockchain.total_transactions(), summed);
+    }
+
+    #[test]
+    fn import_rejects_bad_snapshots() {
+        let mut blockchain = Blockchain::new();
+        let block = generate_mined_block(&blockchain.tip());
+        blockchain.insert(&block);
+        let mut snapshot = Vec::new();
+        blockchain.export(&mut snapshot).unwrap();
+
+        let mut bad_magic = snapshot.clone();
+        bad_magic[0] ^= 1;
+        assert!(matches!(Blockchain::import(&mut bad_magic.as_slice()), Err(ImportError::BadMagic)));
+        let mut bad_version = snapshot.clone();
+        bad_version[7] = 2;
+        assert!(matches!(Blockchain::import(&mut bad_version.as_slice()), Err(ImportError::UnsupportedVersion(2))));
+        assert!(matches!(Blockchain::import(&mut &snapshot[..snapshot.len() - 1]), Err(ImportError::Malformed(_))));
+
+        //a block that isn't mined
+        let mut unmined = Blockchain::new();
+        unmined.insert(&generate_random_block(&unmined.tip()));
+        let mut snapshot = Vec::new();
+        unmined.export(&mut snapshot).unwrap();
+        assert!(matches!(Blockchain::import(&mut snapshot.as_slice()), Err(ImportError::InvalidPoW(_))));
+    }
+
+    #[test]
+    fn import_rejects_wrong_merkle_root() {
+        let mut block = generate_mined_block(&Blockchain::new().tip());
+        block.header_mut().merkle_root = H256::from([1; 32]);
+        //keep proof of work valid so only the merkle root is wrong
+        while block.hash() > block.get_difficulty() {
+            block.header_mut().nonce = block.get_nonce().wrapping_add(1);
+        }
+        let blocks = vec![Blockchain::new().block_map.values().next().unwrap().0.clone(), block];
+        let mut snapshot = Vec::new();
+        snapshot.extend_from_slice(&SNAPSHOT_MAGIC);
+        snapshot.extend_from_slice(&SNAPSHOT_VERSION.to_be_bytes());
+        snapshot.extend(bincode::serialize(&blocks).unwrap());
+        assert!(matches!(Blockchain::import(&mut snapshot.as_slice()), Err(ImportError::MerkleRootMismatch(_))));
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn readers_see_consistent_chain_while_writer_inserts() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::{Arc, RwLock};
+        use std::thread;
+
+        let blockchain = Arc::new(RwLock::new(Blockchain::new()));
+        let done = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..10).map(|_| {
+            let blockchain = Arc::clone(&blockchain);
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                let mut last_height = 0;
+                while !done.load(Ordering::SeqCst) {
+                    let blockchain = blockchain.read().unwrap();
+                    //the tip is always a stored block at the recorded height, and never goes back
+                    let (_, height) = blockchain.block_map[&blockchain.tip()];
+                    assert_eq!(height, blockchain.height);
+                    assert!(height >= last_height);
+                    last_height = height;
+                }
+            })
+        }).collect();
+        let writer = {
+            let blockchain = Arc::clone(&blockchain);
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    let mut blockchain = blockchain.write().unwrap();
+                    let block = generate_random_block(&blockchain.tip());
+                    blockchain.insert(&block);
+                }
+            })
+        };
+        writer.join().unwrap();
+        done.store(true, Ordering::SeqCst);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(blockchain.read().unwrap().height, 50);
+    }
+
+    #[test]
+    fn longer_fork_wins_whatever_the_insertion_order() {
+        use rand::seq::SliceRandom;
+        let mut rng = rand::thread_rng();
+        for _ in 0..20 {
+            let mut blockchain = Blockchain::new();
+            let genesis = blockchain.tip();
+            let build_chain = |length: usize| -> Vec<Block> {
+                let mut parent = genesis;
+                return (0..length).map(|_| {
+                    let block = generate_random_block(&parent);
+                    parent = block.hash();
+                    return block;
+                }).collect();
+            };
+            let short = build_chain(3);
+            let long = build_chain(5);
+
+            //interleave the two branches randomly, each block still comes after its parent
+            let mut order: Vec<bool> = [vec![false; short.len()], vec![true; long.len()]].concat();
+            order.shuffle(&mut rng);
+            let (mut next_short, mut next_long) = (short.iter(), long.iter());
+            let mut deepest = 0;
+            for from_long in order {
+                let block = if from_long { next_long.next() } else { next_short.next() }.unwrap();
+                blockchain.insert(block);
+                deepest = deepest.max(blockchain.block_map[&block.hash()].1);
+                let (_, tip_height) = blockchain.block_map[&blockchain.tip()];
+                assert_eq!(tip_height, deepest);
+            }
+            let long_hashes: Vec<H256> = std::iter::once(genesis).chain(long.iter().map(|block| block.hash())).collect();
+            assert_eq!(blockchain.tip(), long.last().unwrap().hash());
+            assert_eq!(blockchain.all_blocks_in_longest_chain(), long_hashes);
+
+            //bringing the shorter branch level only ties, the longer one got there first and stays
+            let mut parent = short.last().unwrap().hash();
+            for _ in 0..2 {
+                let block = generate_random_block(&parent);
+                blockchain.insert(&block);
+                parent = block.hash();
+            }
+            assert_eq!(blockchain.block_map[&parent].1, 5);
+            assert_eq!(blockchain.tip(), long.last().unwrap().hash());
+            assert_eq!(blockchain.all_blocks_in_longest_chain(), long_hashes);
+        }
+    }
+
+    #[test]
+    fn cached_longest_chain_matches_walking_parents() {
+        let mut rng = rand::thread_rng();
+        let mut blockchain = Blockchain::new();
+        let mut hashes = vec![blockchain.tip()];
+        //random parents grow competing branches, some overtake the tip and reorganize deep into the chain
+        for _ in 0..300 {
+            let parent = hashes[rng.gen_range(0..hashes.len())];
+            let block = generate_random_block(&parent);
+            blockchain.insert(&block);
+            hashes.push(block.hash());
+            assert_eq!(blockchain.longest_chain(), blockchain.walk_longest_chain().as_slice());
+        }
+        assert_eq!(blockchain.all_blocks_in_longest_chain(), blockchain.walk_longest_chain());
+        assert_eq!(blockchain.longest_chain().len(), blockchain.len());
     }
 }
 
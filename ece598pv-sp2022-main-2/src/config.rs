@@ -0,0 +1,117 @@
+use serde::Deserialize;
+use std::net::SocketAddr;
+
+use crate::blockchain::MAX_BLOCK_SIZE;
+use crate::miner::BLOCK_SIZE_LIMIT;
+
+/// Node parameters read from the `--config` TOML file. Every field is optional, flags given on the
+/// command line take precedence over the file.
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub peer_addr: Option<SocketAddr>,
+    pub api_addr: Option<SocketAddr>,
+    pub p2p_workers: Option<usize>,
+    //start mining / generating transactions right away with these parameters
+    pub lambda: Option<u64>,
+    pub theta: Option<u64>,
+    pub min_tx_fee: Option<u32>,
+    pub block_size_limit: Option<usize>,
+    pub mempool_max_bytes: Option<usize>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    //the file could not be read
+    Io(std::io::Error),
+    //the file is not valid TOML, or a field is unknown or has the wrong type
+    Parse(toml::de::Error),
+    //the fields parse but their values can't be used together
+    Invalid(String),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "can't read config file: {}", e),
+            ConfigError::Parse(e) => write!(f, "malformed config file: {}", e),
+            ConfigError::Invalid(reason) => write!(f, "invalid config: {}", reason),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Self, ConfigError> {
+        let contents = std::fs::read_to_string(path).map_err(ConfigError::Io)?;
+        return Self::parse(&contents);
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, ConfigError> {
+        let config: Config = toml::from_str(contents).map_err(ConfigError::Parse)?;
+        config.validate()?;
+        return Ok(config);
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.p2p_workers == Some(0) {
+            return Err(ConfigError::Invalid("p2p_workers must be at least 1".to_string()));
+        }
+        if self.block_size_limit == Some(0) {
+            return Err(ConfigError::Invalid("block_size_limit must be at least 1".to_string()));
+        }
+        let block_size_limit = self.block_size_limit.unwrap_or(BLOCK_SIZE_LIMIT);
+        if block_size_limit > MAX_BLOCK_SIZE {
+            return Err(ConfigError::Invalid(format!(
+                "block_size_limit ({}) must be at most {}, peers reject larger blocks",
+                block_size_limit, MAX_BLOCK_SIZE
+            )));
+        }
+        if let Some(mempool_max_bytes) = self.mempool_max_bytes {
+            if mempool_max_bytes < block_size_limit {
+                return Err(ConfigError::Invalid(format!(
+                    "mempool_max_bytes ({}) must be at least block_size_limit ({})",
+                    mempool_max_bytes, block_size_limit
+                )));
+            }
+        }
+        return Ok(());
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, ConfigError};
+
+    #[test]
+    fn sample_config_parses() {
+        let config = Config::parse(include_str!("../config.toml")).unwrap();
+        assert_eq!(config.peer_addr, Some("127.0.0.1:6000".parse().unwrap()));
+        assert_eq!(config.p2p_workers, Some(4));
+        assert_eq!(config.block_size_limit, Some(4000));
+    }
+
+    #[test]
+    fn missing_fields_are_left_unset() {
+        let config = Config::parse("min_tx_fee = 3").unwrap();
+        assert_eq!(config, Config { min_tx_fee: Some(3), ..Default::default() });
+    }
+
+    #[test]
+    fn malformed_fields_are_rejected() {
+        assert!(matches!(Config::parse("peer_addr = \"not an address\""), Err(ConfigError::Parse(_))));
+        assert!(matches!(Config::parse("p2p_workers = \"four\""), Err(ConfigError::Parse(_))));
+        //a typo must not be silently ignored
+        assert!(matches!(Config::parse("min_fee = 3"), Err(ConfigError::Parse(_))));
+    }
+
+    #[test]
+    fn inconsistent_values_are_rejected() {
+        assert!(matches!(Config::parse("p2p_workers = 0"), Err(ConfigError::Invalid(_))));
+        assert!(matches!(Config::parse("block_size_limit = 8000\nmempool_max_bytes = 4000"), Err(ConfigError::Invalid(_))));
+        assert!(matches!(Config::parse("block_size_limit = 2000000"), Err(ConfigError::Invalid(_))));
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -0,0 +1,133 @@
+use ring::signature::ED25519_PUBLIC_KEY_LEN;
+use serde::Deserialize;
+use std::collections::HashSet;
+
+use crate::types::transaction::ICO;
+
+/// One account funded at genesis, as listed in the `--genesis-config` JSON file
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct GenesisAccount {
+    pubkey_hex: String,
+    balance: u32,
+}
+
+/// The accounts funded by the genesis ICO, read from the `--genesis-config` JSON file
+#[derive(Debug, PartialEq)]
+pub struct GenesisConfig {
+    //(public key, balance) of every funded account
+    pub accounts: Vec<(Vec<u8>, u32)>,
+}
+
+#[derive(Debug)]
+pub enum GenesisConfigError {
+    //the file could not be read
+    Io(std::io::Error),
+    //the file is not valid JSON, or an account is missing a field
+    Parse(serde_json::Error),
+    //the accounts parse but can't be used as a genesis
+    Invalid(String),
+}
+
+impl std::fmt::Display for GenesisConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            GenesisConfigError::Io(e) => write!(f, "can't read genesis config file: {}", e),
+            GenesisConfigError::Parse(e) => write!(f, "malformed genesis config file: {}", e),
+            GenesisConfigError::Invalid(reason) => write!(f, "invalid genesis config: {}", reason),
+        }
+    }
+}
+
+impl GenesisConfig {
+    pub fn load(path: &str) -> Result<Self, GenesisConfigError> {
+        let contents = std::fs::read_to_string(path).map_err(GenesisConfigError::Io)?;
+        return Self::parse(&contents);
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, GenesisConfigError> {
+        let accounts: Vec<GenesisAccount> = serde_json::from_str(contents).map_err(GenesisConfigError::Parse)?;
+        if accounts.is_empty() {
+            return Err(GenesisConfigError::Invalid("at least one account must be funded".to_string()));
+        }
+        let mut decoded = Vec::new();
+        let mut seen = HashSet::new();
+        let mut total_supply: u32 = 0;
+        for account in accounts {
+            let pubkey = match hex::decode(&account.pubkey_hex) {
+                Ok(pubkey) if pubkey.len() == ED25519_PUBLIC_KEY_LEN => pubkey,
+                _ => {
+                    return Err(GenesisConfigError::Invalid(format!(
+                        "pubkey_hex {:?} is not a {} byte hex public key",
+                        account.pubkey_hex, ED25519_PUBLIC_KEY_LEN
+                    )));
+                }
+            };
+            if !seen.insert(pubkey.clone()) {
+                return Err(GenesisConfigError::Invalid(format!("pubkey_hex {} is listed twice", account.pubkey_hex)));
+            }
+            total_supply = match total_supply.checked_add(account.balance) {
+                Some(total) => total,
+                None => {
+                    return Err(GenesisConfigError::Invalid(format!("total supply overflows {}", u32::MAX)));
+                }
+            };
+            decoded.push((pubkey, account.balance));
+        }
+        return Ok(GenesisConfig { accounts: decoded });
+    }
+
+    pub fn ico(&self) -> ICO {
+        let accounts: Vec<(&[u8], u32)> = self.accounts.iter().map(|(pubkey, balance)| (pubkey.as_slice(), *balance)).collect();
+        return ICO::new_multi(&accounts);
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod tests {
+    use super::{GenesisConfig, GenesisConfigError};
+    use crate::types::address::Address;
+
+    #[test]
+    fn accounts_are_funded() {
+        let pubkey = [7u8; 32];
+        let config = GenesisConfig::parse(&format!("[{{\"pubkey_hex\": \"{}\", \"balance\": 500}}]", hex::encode(pubkey))).unwrap();
+        let ico = config.ico();
+        assert_eq!(ico.state.len(), 1);
+        assert_eq!(ico.state.get(&Address::from_public_key_bytes(&pubkey)), Some(&(0, 500)));
+    }
+
+    #[test]
+    fn malformed_accounts_are_rejected() {
+        assert!(matches!(GenesisConfig::parse("[{\"pubkey_hex\": \"00\"}]"), Err(GenesisConfigError::Parse(_))));
+        assert!(matches!(GenesisConfig::parse("{\"pubkey_hex\": \"00\", \"balance\": 1}"), Err(GenesisConfigError::Parse(_))));
+        assert!(matches!(GenesisConfig::parse("[{\"pubkey_hex\": \"00\", \"balance\": -1}]"), Err(GenesisConfigError::Parse(_))));
+    }
+
+    #[test]
+    fn invalid_accounts_are_rejected() {
+        assert!(matches!(GenesisConfig::parse("[]"), Err(GenesisConfigError::Invalid(_))));
+        //wrong length and not hex
+        assert!(matches!(GenesisConfig::parse("[{\"pubkey_hex\": \"0011\", \"balance\": 1}]"), Err(GenesisConfigError::Invalid(_))));
+        let not_hex = "zz".repeat(32);
+        assert!(matches!(GenesisConfig::parse(&format!("[{{\"pubkey_hex\": \"{}\", \"balance\": 1}}]", not_hex)), Err(GenesisConfigError::Invalid(_))));
+        let duplicate = hex::encode([1u8; 32]);
+        assert!(matches!(
+            GenesisConfig::parse(&format!("[{{\"pubkey_hex\": \"{0}\", \"balance\": 1}}, {{\"pubkey_hex\": \"{0}\", \"balance\": 2}}]", duplicate)),
+            Err(GenesisConfigError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn total_supply_overflow_is_rejected() {
+        let json = format!(
+            "[{{\"pubkey_hex\": \"{}\", \"balance\": {}}}, {{\"pubkey_hex\": \"{}\", \"balance\": 1}}]",
+            hex::encode([1u8; 32]), u32::MAX, hex::encode([2u8; 32])
+        );
+        assert!(matches!(GenesisConfig::parse(&json), Err(GenesisConfigError::Invalid(_))));
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
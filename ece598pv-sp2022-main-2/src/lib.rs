@@ -0,0 +1,45 @@
+#[cfg(test)]
+#[macro_use]
+extern crate hex_literal;
+
+pub mod api;
+pub mod blockchain;
+pub mod config;
+pub mod genesis;
+pub mod types;
+pub mod miner;
+pub mod network;
+pub mod transaction_generator;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+//reported by --version and /node/info
+pub static NODE_VERSION: &str = "0.1";
+
+/// Lets the API and the Ctrl-C handler ask the main thread to shut the node down
+#[derive(Clone)]
+pub struct ShutdownTrigger {
+    requested: Arc<AtomicBool>,
+    main_thread: thread::Thread,
+}
+
+impl ShutdownTrigger {
+    /// Must be created on the main thread, which is the one woken up on trigger
+    pub fn new() -> Self {
+        return ShutdownTrigger {
+            requested: Arc::new(AtomicBool::new(false)),
+            main_thread: thread::current(),
+        };
+    }
+
+    pub fn trigger(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+        self.main_thread.unpark();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        return self.requested.load(Ordering::SeqCst);
+    }
+}
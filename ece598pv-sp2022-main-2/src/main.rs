@@ -1,51 +1,145 @@
-#[cfg(test)]
-#[macro_use]
-extern crate hex_literal;
-
-pub mod api;
-pub mod blockchain;
-pub mod types;
-pub mod miner;
-pub mod network;
-pub mod transaction_generator;
-
-use blockchain::Blockchain;
+use bitcoin::{api, miner, network, transaction_generator, types};
+use bitcoin::{ShutdownTrigger, NODE_VERSION};
+use bitcoin::blockchain::Blockchain;
 use clap::clap_app;
 use miner::Mempool;
 use ring::signature::KeyPair;
-use smol::channel;
-use log::{error, info};
-use api::Server as ApiServer;
+use tracing::{error, info, Level};
+use api::{Events, Server as ApiServer};
+use bitcoin::config::Config;
+use bitcoin::genesis::GenesisConfig;
 use types::transaction::ICO;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
 use std::net;
 use std::process;
-use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
-use crate::types::address::Address;
-use crate::types::block::BlockState;
-use crate::types::key_pair::given;
+use types::address::Address;
+use types::block::{apply_block_to_state, Block, BlockState};
+use types::hash::Hashable;
+use types::key_pair::given;
+
+/// The value of a flag given on the command line, otherwise the config file's value, otherwise the flag's default
+fn setting<T>(matches: &clap::ArgMatches, name: &str, config_value: Option<T>, description: &str) -> T
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    if matches.occurrences_of(name) == 0 {
+        if let Some(value) = config_value {
+            return value;
+        }
+    }
+    return matches
+        .value_of(name)
+        .unwrap()
+        .parse::<T>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing {}: {}", description, e);
+            process::exit(1);
+        });
+}
 
 fn main() {
     // parse command line arguments
     let matches = clap_app!(Bitcoin =>
-     (version: "0.1")
+     (version: NODE_VERSION)
      (about: "Bitcoin client")
      (@arg verbose: -v ... "Increases the verbosity of logging")
-     (@arg peer_addr: --p2p [ADDR] default_value("127.0.0.1:6000") "Sets the IP address and the port of the P2P server")
+     (@arg log_format: --("log-format") [FORMAT] possible_values(&["text", "json"]) default_value("text") "Sets whether logs are written as plain text or as one JSON object per line")
+     (@arg config: --config [FILE] "Loads settings from a TOML file, flags given on the command line take precedence")
+     (@arg genesis_config: --("genesis-config") [FILE] "Loads the accounts funded at genesis from a JSON file instead of the 3 built-in ones")
+     (@arg peer_addr: --p2p ... [ADDR] number_of_values(1) default_value("127.0.0.1:6000") "Sets an IP address and port the P2P server listens on, IPv6 ones as [ADDR]:PORT, give it again to listen on several")
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
+     (@arg api_timeout_ms: --("api-timeout-ms") [MS] default_value("5000") "Sets how long an API request may take before it is answered with a 503")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
+     (@arg data_dir: --("data-dir") [DIR] "Keeps the peer addresses learned and the peers banned in DIR, so both survive a restart")
+     (@arg proxy: --proxy [ADDR] "Connects to peers through the SOCKS5 proxy at ADDR, which also resolves peer host names")
+     (@arg p2p_tls: --("p2p-tls") "Encrypts every P2P connection, peers running without this flag are disconnected")
+     (@arg push_blocks: --("push-blocks") "Asks peers to push new blocks in full instead of announcing their hashes")
+     (@arg compact_blocks: --("compact-blocks") "Asks peers to relay new blocks as compact blocks, rebuilt from the mempool")
+     (@arg relay_fanout: --("relay-fanout") [INT] default_value("0") "Sets how many randomly picked peers each new transaction is relayed to, 0 relays it to every peer")
+     (@arg relay_fanout_blocks: --("relay-fanout-blocks") "Relays new blocks to --relay-fanout random peers too, instead of to every peer")
+     (@arg no_mempool_sync: --("no-mempool-sync") "Doesn't ask peers for their pending transactions after connecting, saving bandwidth")
+     (@arg keepalive_idle_secs: --("keepalive-idle-secs") [SECS] default_value("60") "Sets how long a peer may stay silent before it is pinged")
+     (@arg keepalive_timeout_secs: --("keepalive-timeout-secs") [SECS] default_value("20") "Sets how long a silent peer has to answer a ping before it is disconnected")
+     (@arg max_inbound: --("max-inbound") [INT] default_value("117") "Sets how many peers may connect to us, extra ones are turned away")
+     (@arg max_outbound: --("max-outbound") [INT] default_value("8") "Sets how many peers we connect to ourselves, persistent peers are always dialed")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg miner_threads: --("miner-threads") [INT] default_value("1") "Sets the number of threads searching for a nonce")
+     (@arg version_tolerance: --("version-tolerance") [INT] default_value("0") "Sets how many protocol versions outside the range we speak a peer may be before it is disconnected")
+     (@arg min_tx_fee: --("min-tx-fee") [INT] default_value("1") "Sets the minimum fee a transaction must pay to enter the mempool")
+     (@arg max_msgs_per_sec: --("max-msgs-per-sec-per-peer") [INT] default_value("100") "Sets the number of messages other than block and transaction ones a peer may send per second, extra ones are dropped")
+     (@arg max_block_msgs_per_sec: --("max-block-msgs-per-sec-per-peer") [INT] default_value("50") "Sets the number of block messages per second a peer may send, extra ones are dropped")
+     (@arg max_tx_msgs_per_sec: --("max-tx-msgs-per-sec-per-peer") [INT] default_value("50") "Sets the number of transaction messages per second a peer may send, extra ones are dropped")
+     (@arg tx_queue_high_water: --("tx-queue-high-water") [INT] default_value("5000") "Sets how many transaction messages may wait for the P2P workers, more are dropped while blocks never are")
+     (@arg max_message_size: --("max-message-size") [BYTES] default_value("4194304") "Sets the largest P2P message accepted from a peer, larger frames are dropped")
+     (@arg inject_latency_ms: --("inject-latency-ms") [MS] default_value("0") "Holds every P2P message we send back by about MS milliseconds, to study propagation over slow links")
+     (@arg inject_loss_pct: --("inject-loss-pct") [PCT] default_value("0") "Drops PCT percent of the P2P messages we send, handshake messages excepted")
+     (@arg inject_loss_only: --("inject-loss-only") [CLASS] possible_values(&["all", "blocks", "transactions", "compressed", "control"]) default_value("all") "Limits --inject-loss-pct to one class of messages")
+     (@arg mempool_high_water: --("mempool-high-water") [INT] default_value("50000") "Sets how many pending transactions make the transaction generator wait for the miners")
+     (@arg announce_batch_ms: --("announce-batch-ms") [MS] default_value("50") "Sets how long generated transaction hashes are collected before they are announced to peers")
+     (@arg announce_batch_size: --("announce-batch-size") [INT] default_value("500") "Sets how many generated transaction hashes are announced in one message at most")
+     (@arg ban_duration_secs: --("ban-duration-secs") [SECS] default_value("600") "Sets how long a misbehaving peer's IP is refused after being banned")
+     (@subcommand export =>
+      (about: "Runs the node and writes its blocks to a chain snapshot on shutdown")
+      (@arg output: --output <FILE> "Sets the file the chain snapshot is written to")
+     )
+     (@subcommand import =>
+      (about: "Runs the node starting from the blocks of a chain snapshot")
+      (@arg input: --input <FILE> "Sets the chain snapshot file to read")
+     )
     )
     .get_matches();
 
     // init logger
-    let verbosity = matches.occurrences_of("verbose") as usize;
-    stderrlog::new().verbosity(verbosity).init().unwrap();
-    let blockchain = Blockchain::new();
-    let blockchain = Arc::new(Mutex::new(blockchain));
-    let mempool = Mempool::new();
+    let level = match matches.occurrences_of("verbose") {
+        0 => Level::ERROR,
+        1 => Level::WARN,
+        2 => Level::INFO,
+        3 => Level::DEBUG,
+        _ => Level::TRACE,
+    };
+    let subscriber = tracing_subscriber::fmt().with_max_level(level).with_writer(std::io::stderr);
+    match matches.value_of("log_format").unwrap() {
+        "json" => subscriber.json().init(),
+        _ => subscriber.init(),
+    }
+    let blockchain = match matches.subcommand_matches("import") {
+        Some(import) => {
+            let path = import.value_of("input").unwrap();
+            let mut file = File::open(path).unwrap_or_else(|e| {
+                error!("Error opening chain snapshot {}: {}", path, e);
+                process::exit(1);
+            });
+            let blockchain = Blockchain::import(&mut BufReader::new(&mut file)).unwrap_or_else(|e| {
+                error!("Error importing chain snapshot {}: {}", path, e);
+                process::exit(1);
+            });
+            info!("Imported {} blocks from {}", blockchain.block_map.len(), path);
+            blockchain
+        }
+        None => Blockchain::new(),
+    };
+    let blockchain = Arc::new(RwLock::new(blockchain));
+    let config = match matches.value_of("config") {
+        Some(path) => Config::load(path).unwrap_or_else(|e| {
+            error!("Error loading config file {}: {}", path, e);
+            process::exit(1);
+        }),
+        None => Config::default(),
+    };
+    let min_tx_fee = setting::<u32>(&matches, "min_tx_fee", config.min_tx_fee, "minimum transaction fee");
+    let mut mempool = Mempool::new();
+    mempool.set_min_fee(min_tx_fee);
+    if let Some(block_size_limit) = config.block_size_limit {
+        mempool.set_block_size_limit(block_size_limit);
+    }
+    if let Some(mempool_max_bytes) = config.mempool_max_bytes {
+        mempool.set_max_bytes(mempool_max_bytes);
+    }
     let mempool = Arc::new(Mutex::new(mempool));
     // create 3 key-pairs for nodes
     let pair0 = given(&[0; 32]);
@@ -57,128 +151,397 @@ fn main() {
     let pair2 = given(&[2; 32]);
     let key2: &[u8] = pair2.public_key().as_ref();
     let account2 = Address::from_public_key_bytes(&key2);
-    let ico = Arc::new(Mutex::new(ICO::new(&key0)));
+    let ico = match matches.value_of("genesis_config") {
+        Some(path) => GenesisConfig::load(path).unwrap_or_else(|e| {
+            error!("Error loading genesis config file {}: {}", path, e);
+            process::exit(1);
+        }).ico(),
+        None => ICO::new_multi(&[(key0, 1000000), (key1, 1000000), (key2, 1000000)]),
+    };
+    let ico = Arc::new(Mutex::new(ico));
     let block_state_map = Arc::new(Mutex::new(BlockState::new()));
-    let genesis_hash = blockchain.lock().unwrap().tip();
+    let genesis_hash = blockchain.read().unwrap().genesis_hash();
     //record genesis block's state
-    block_state_map.lock().unwrap().block_state_map.insert(genesis_hash, ico.lock().unwrap().state.clone());
+    block_state_map.lock().unwrap().apply_genesis(genesis_hash, &ico.lock().unwrap()).unwrap();
+    //an imported chain's states are rebuilt in height order so every parent's state exists first
+    {
+        let blockchain = blockchain.read().unwrap();
+        let mut block_state_map = block_state_map.lock().unwrap();
+        let mut blocks: Vec<&(Block, u32)> = blockchain.block_map.values().filter(|(_, height)| *height > 0).collect();
+        blocks.sort_by_key(|(_, height)| *height);
+        for (block, _) in blocks {
+            let parent_state = &block_state_map.block_state_map[&block.get_parent()];
+            let state = apply_block_to_state(parent_state, block).unwrap_or_else(|e| {
+                error!("Error replaying imported block {}: {:?}", block.hash(), e);
+                process::exit(1);
+            });
+            block_state_map.block_state_map.insert(block.hash(), state);
+        }
+    }
+
+    // parse p2p server addresses, the first one picks our account and is advertised when none matches a peer's family
+    let p2p_addrs: Vec<net::SocketAddr> = match config.peer_addr {
+        Some(addr) if matches.occurrences_of("peer_addr") == 0 => vec![addr],
+        _ => matches.values_of("peer_addr").unwrap().map(|addr| {
+            network::server::parse_addr(addr).unwrap_or_else(|e| {
+                error!("Error parsing P2P server address: {}", e);
+                process::exit(1);
+            })
+        }).collect(),
+    };
+    let address_to_use = p2p_addrs[0].port() % 10;
+
+    // parse api server address
+    let api_addr = setting::<net::SocketAddr>(&matches, "api_addr", config.api_addr, "API server address");
 
-    // parse p2p server address
-    let p2p_addr = matches
-        .value_of("peer_addr")
+    // create channels between server and worker, blocks and transactions in separate lanes
+    let tx_queue_high_water = matches
+        .value_of("tx_queue_high_water")
         .unwrap()
-        .parse::<net::SocketAddr>()
+        .parse::<usize>()
         .unwrap_or_else(|e| {
-            error!("Error parsing P2P server address: {}", e);
+            error!("Error parsing transaction queue high-water mark: {}", e);
             process::exit(1);
         });
-    let address_to_use = p2p_addr.port() % 10;
+    let (msg_tx, msg_rx) = network::queue::channel(tx_queue_high_water);
 
-    // parse api server address
-    let api_addr = matches
-        .value_of("api_addr")
+    // start the p2p server
+    let max_message_size = matches
+        .value_of("max_message_size")
         .unwrap()
-        .parse::<net::SocketAddr>()
+        .parse::<usize>()
         .unwrap_or_else(|e| {
-            error!("Error parsing API server address: {}", e);
+            error!("Error parsing max message size: {}", e);
             process::exit(1);
         });
-
-    // create channels between server and worker
-    let (msg_tx, msg_rx) = channel::bounded(10000);
-
-    // start the p2p server
-    let (server_ctx, server) = network::server::new(p2p_addr, msg_tx).unwrap();
+    let ban_duration_secs = matches
+        .value_of("ban_duration_secs")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing ban duration: {}", e);
+            process::exit(1);
+        });
+    let (mut server_ctx, server) = network::server::new(p2p_addrs.clone(), msg_tx, max_message_size).unwrap();
+    server_ctx.set_ban_duration(Duration::from_secs(ban_duration_secs));
+    let max_inbound = matches
+        .value_of("max_inbound")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing max inbound peers: {}", e);
+            process::exit(1);
+        });
+    let max_outbound = matches
+        .value_of("max_outbound")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing max outbound peers: {}", e);
+            process::exit(1);
+        });
+    server_ctx.set_connection_limits(max_inbound, max_outbound);
+    let keepalive_idle_secs = matches
+        .value_of("keepalive_idle_secs")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing keepalive idle interval: {}", e);
+            process::exit(1);
+        });
+    let keepalive_timeout_secs = matches
+        .value_of("keepalive_timeout_secs")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing keepalive timeout: {}", e);
+            process::exit(1);
+        });
+    server_ctx.set_keepalive(Duration::from_secs(keepalive_idle_secs), Duration::from_secs(keepalive_timeout_secs));
+    let inject_latency_ms = matches
+        .value_of("inject_latency_ms")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing injected latency: {}", e);
+            process::exit(1);
+        });
+    let inject_loss_pct = matches
+        .value_of("inject_loss_pct")
+        .unwrap()
+        .parse::<f64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing injected loss: {}", e);
+            process::exit(1);
+        });
+    let impairment = network::impairment::parse_loss_class(matches.value_of("inject_loss_only").unwrap())
+        .and_then(|loss_class| network::impairment::Impairment::new(inject_latency_ms, inject_loss_pct, loss_class))
+        .unwrap_or_else(|e| {
+            error!("Error setting up injected latency and loss: {}", e);
+            process::exit(1);
+        });
+    server_ctx.set_impairment(impairment);
+    let relay_fanout = matches
+        .value_of("relay_fanout")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing relay fan-out: {}", e);
+            process::exit(1);
+        });
+    if relay_fanout > 0 {
+        server_ctx.set_relay_fanout(relay_fanout, matches.is_present("relay_fanout_blocks"));
+    }
+    if matches.is_present("p2p_tls") {
+        server_ctx.enable_encryption();
+    }
+    if let Some(proxy) = matches.value_of("proxy") {
+        let proxy = network::server::parse_addr(proxy).unwrap_or_else(|e| {
+            error!("Error parsing proxy address: {}", e);
+            process::exit(1);
+        });
+        server_ctx.set_proxy(proxy);
+    }
+    //through a proxy, peer host names are resolved by the proxy rather than here
+    let mut known_peers = vec![];
+    for peer in matches.values_of("known_peer").into_iter().flatten() {
+        match server_ctx.resolve_peer(peer) {
+            Ok(addr) => known_peers.push(addr),
+            Err(e) => error!("Error parsing peer address {}: {}", peer, e),
+        }
+    }
+    if let Some(data_dir) = matches.value_of("data_dir") {
+        let data_dir = std::path::Path::new(data_dir);
+        if let Err(e) = std::fs::create_dir_all(data_dir) {
+            error!("Error creating data directory {}: {}", data_dir.display(), e);
+            process::exit(1);
+        }
+        let addr_book_path = data_dir.join("peers.json");
+        server_ctx.set_addr_book(addr_book_path.clone()).unwrap_or_else(|e| {
+            error!("Error loading peer addresses {}: {}", addr_book_path.display(), e);
+            process::exit(1);
+        });
+        let ban_list_path = data_dir.join("bans.json");
+        server_ctx.set_ban_list(ban_list_path.clone()).unwrap_or_else(|e| {
+            error!("Error loading banned peers {}: {}", ban_list_path.display(), e);
+            process::exit(1);
+        });
+    }
     server_ctx.start().unwrap();
 
     // start the worker
-    let p2p_workers = matches
-        .value_of("p2p_workers")
+    let p2p_workers = setting::<usize>(&matches, "p2p_workers", config.p2p_workers, "P2P workers");
+    let max_msgs_per_sec = matches
+        .value_of("max_msgs_per_sec")
         .unwrap()
-        .parse::<usize>()
+        .parse::<u32>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing max messages per second per peer: {}", e);
+            process::exit(1);
+        });
+    let max_block_msgs_per_sec = matches
+        .value_of("max_block_msgs_per_sec")
+        .unwrap()
+        .parse::<u32>()
         .unwrap_or_else(|e| {
-            error!("Error parsing P2P workers: {}", e);
+            error!("Error parsing max block messages per second per peer: {}", e);
             process::exit(1);
         });
-    let worker_ctx = network::worker::Worker::new(
+    let max_tx_msgs_per_sec = matches
+        .value_of("max_tx_msgs_per_sec")
+        .unwrap()
+        .parse::<u32>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing max transaction messages per second per peer: {}", e);
+            process::exit(1);
+        });
+    let version_tolerance = matches
+        .value_of("version_tolerance")
+        .unwrap()
+        .parse::<u32>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing version tolerance: {}", e);
+            process::exit(1);
+        });
+    let mut worker_ctx = network::worker::Worker::new(
         p2p_workers,
         msg_rx,
         &server,
         &blockchain,
         &mempool,
-        &block_state_map
+        &block_state_map,
+        max_msgs_per_sec,
+        max_block_msgs_per_sec,
+        max_tx_msgs_per_sec,
+        p2p_addrs.clone(),
+        version_tolerance
     );
-    worker_ctx.start();
+    worker_ctx.set_push_blocks(matches.is_present("push_blocks"));
+    worker_ctx.set_compact_blocks(matches.is_present("compact_blocks"));
+    worker_ctx.set_mempool_sync(!matches.is_present("no_mempool_sync"));
+    let node_nonce = worker_ctx.node_nonce();
+    let sync = worker_ctx.sync_status();
+    let propagation = worker_ctx.propagation_stats();
+    let network_worker_threads = worker_ctx.start();
 
     // start generating transactions BEFORE miner
     let mut chosen_address = account0;
     let mut chosen_keypair = pair0;
-    let mut receiver_addresses = [account1, account2];
     if address_to_use == 1 {
         chosen_address = account1;
         chosen_keypair = pair1;
-        receiver_addresses = [account0, account2];
     } else if address_to_use == 2 {
         chosen_address = account2;
         chosen_keypair = pair2;
-        receiver_addresses = [account0, account1];
     }
+    //every account receives, the sender included
+    let receiver_addresses = vec![account0, account1, account2];
+    let mempool_high_water = matches
+        .value_of("mempool_high_water")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing mempool high-water mark: {}", e);
+            process::exit(1);
+        });
     let (generator_ctx, generator, finished_tx_chan) =
-        transaction_generator::new(&blockchain, &chosen_address, chosen_keypair, &block_state_map, receiver_addresses.clone());
-    let generator_worker_ctx = transaction_generator::worker::Worker::new(&server, finished_tx_chan, &mempool);
-    generator_ctx.start();
-    generator_worker_ctx.start();
+        transaction_generator::new(&blockchain, &mempool, &chosen_address, chosen_keypair, &block_state_map, receiver_addresses, mempool_high_water);
+    //pushed to /events subscribers by the miner and transaction generator workers
+    let events = Events::new();
+    let announce_batch_ms = matches
+        .value_of("announce_batch_ms")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing announcement batch delay: {}", e);
+            process::exit(1);
+        });
+    let announce_batch_size = matches
+        .value_of("announce_batch_size")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing announcement batch size: {}", e);
+            process::exit(1);
+        });
+    let mut generator_worker_ctx = transaction_generator::worker::Worker::new(&server, finished_tx_chan, &blockchain, &mempool, &block_state_map, &events);
+    generator_worker_ctx.set_announce_batch(Duration::from_millis(announce_batch_ms), announce_batch_size);
+    let generator_thread = generator_ctx.start();
+    let generator_worker_thread = generator_worker_ctx.start();
 
     // start the miner
-    let (miner_ctx, miner, finished_block_chan) = miner::new(&blockchain, &mempool, &block_state_map);
-    let miner_worker_ctx = miner::worker::Worker::new(&server, finished_block_chan, &blockchain);
-    miner_ctx.start();
-    miner_worker_ctx.start();
-
-    // connect to known peers
-    if let Some(known_peers) = matches.values_of("known_peer") {
-        let known_peers: Vec<String> = known_peers.map(|x| x.to_owned()).collect();
-        let server = server.clone();
-        thread::spawn(move || {
-            for peer in known_peers {
-                loop {
-                    let addr = match peer.parse::<net::SocketAddr>() {
-                        Ok(x) => x,
-                        Err(e) => {
-                            error!("Error parsing peer address {}: {}", &peer, e);
-                            break;
-                        }
-                    };
-                    match server.connect(addr) {
-                        Ok(_) => {
-                            info!("Connected to outgoing peer {}", &addr);
-                            break;
-                        }
-                        Err(e) => {
-                            error!(
-                                "Error connecting to peer {}, retrying in one second: {}",
-                                addr, e
-                            );
-                            thread::sleep(time::Duration::from_millis(1000));
-                            continue;
-                        }
-                    }
-                }
-            }
+    let (mut miner_ctx, miner, finished_block_chan) = miner::new(&blockchain, &mempool, &block_state_map);
+    let miner_threads = matches
+        .value_of("miner_threads")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing miner threads: {}", e);
+            process::exit(1);
         });
+    miner_ctx.set_threads(miner_threads);
+    miner_ctx.set_address(chosen_address);
+    //dropping the sender tells the miner worker to stop
+    let (miner_worker_shutdown, miner_worker_shutdown_chan) = crossbeam::channel::bounded::<()>(0);
+    let miner_worker_ctx = miner::worker::Worker::new(&server, finished_block_chan, &blockchain, &mempool, &block_state_map, miner_worker_shutdown_chan, &events);
+    let miner_thread = miner_ctx.start();
+    let miner_worker_thread = miner_worker_ctx.start();
+
+    // start mining and generating transactions right away if the config file asks for it
+    if let Some(theta) = config.theta {
+        generator.start(theta).unwrap();
+    }
+    if let Some(lambda) = config.lambda {
+        miner.start(lambda).unwrap();
+    }
+
+    //every peer we dial is greeted with our Version, announcing the address of its family
+    let greeting_blockchain = Arc::clone(&blockchain);
+    let greeting_addrs = p2p_addrs.clone();
+    let greeting: network::server::Greeting = Arc::new(move |peer_addr| {
+        network::worker::Worker::version_message(&greeting_blockchain, network::worker::Worker::advertised_addr(&greeting_addrs, peer_addr), node_nonce)
+    });
+
+    // dial the peers we knew before the restart in the background, alongside the configured ones
+    let restored = server.connect_from_addr_book(Arc::clone(&greeting));
+    if restored > 0 {
+        info!("Dialing {} stored peers", restored);
+    }
+
+    // connect to known peers, the server reconnects on its own whenever one of them drops
+    for addr in known_peers {
+        //open the handshake, the peer answers with its own Version and a VerAck
+        server.add_persistent_peer(addr, Arc::clone(&greeting));
     }
 
+    // shut down on Ctrl-C or on a /node/exit API request
+    let shutdown = ShutdownTrigger::new();
+    let ctrlc_shutdown = shutdown.clone();
+    ctrlc::set_handler(move || ctrlc_shutdown.trigger()).unwrap_or_else(|e| {
+        error!("Error setting Ctrl-C handler: {}", e);
+        process::exit(1);
+    });
+
     // start the API server
+    //peers connected through the API are greeted like the known ones
+    let api_timeout_ms = matches
+        .value_of("api_timeout_ms")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing API timeout: {}", e);
+            process::exit(1);
+        });
     ApiServer::start(
         api_addr,
         &miner,
         &generator,
         &server,
         &blockchain,
-        &block_state_map
+        &block_state_map,
+        &mempool,
+        &sync,
+        &propagation,
+        &greeting,
+        Duration::from_millis(api_timeout_ms),
+        &events,
+        &shutdown
     );
 
-    loop {
+    while !shutdown.is_triggered() {
         std::thread::park();
     }
+
+    // stop producing blocks and transactions first, then stop the threads consuming them
+    info!("Shutting down");
+    //an error only means the thread is already gone, joining it below still works
+    if let Err(e) = miner.exit() {
+        error!("Error stopping miner: {}", e);
+    }
+    if let Err(e) = generator.exit() {
+        error!("Error stopping transaction generator: {}", e);
+    }
+    miner_thread.join().unwrap();
+    generator_thread.join().unwrap();
+    drop(miner_worker_shutdown);
+    miner_worker_thread.join().unwrap();
+    generator_worker_thread.join().unwrap();
+    server.shutdown();
+    for handle in network_worker_threads {
+        handle.join().unwrap();
+    }
+    if let Some(export) = matches.subcommand_matches("export") {
+        let path = export.value_of("output").unwrap();
+        let written = File::create(path).and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            let written = blockchain.read().unwrap().export(&mut writer)?;
+            writer.flush()?;
+            return Ok(written);
+        });
+        match written {
+            Ok(written) => info!("Exported {} blocks to {}", written, path),
+            Err(e) => error!("Error exporting chain snapshot {}: {}", path, e),
+        }
+    }
+    info!("Shutdown complete");
 }
@@ -1,19 +1,24 @@
 pub mod worker;
 
-use log::info;
+use tracing::info;
 
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 use std::collections::HashMap;
 use std::collections::HashSet;
+use bloomfilter::Bloom;
+use rayon::ThreadPool;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
 use std::time;
 
 use std::thread;
 
-use crate::types::block::BlockState;
+use crate::types::address::Address;
+use crate::types::block::{BlockState, apply_block_to_state};
 use crate::types::block::{Block, Header, Content};
 use crate::blockchain::{Blockchain, DIFFICULTY};
-use crate::types::transaction::SignedTransaction;
-use std::sync::{Arc, Mutex};
+use crate::types::transaction::{IntegrityError, SignedTransaction};
+use serde::{Serialize, Deserialize};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{SystemTime, UNIX_EPOCH};
 use crate::types::hash::{H256, Hashable};
 use rand::Rng;
@@ -22,42 +27,520 @@ use crate::types::merkle::MerkleTree;
 enum ControlSignal {
     Start(u64), // the number controls the lambda of interval between block generation
     Update, // update the block in mining, it may due to new blockchain tip or new transaction
+    Pause,
     Exit,
 }
 
-enum OperatingState {
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OperatingState {
     Paused,
     Run(u64),
     ShutDown,
 }
 
+const STATE_PAUSED: u8 = 0;
+const STATE_RUN: u8 = 1;
+const STATE_SHUTDOWN: u8 = 2;
+
+/// Miner state shared between the mining thread, which writes it on every transition, and its handles
+#[derive(Default)]
+pub struct SharedStatus {
+    state: AtomicU8,
+    lambda: AtomicU64,
+    //number of nonces tried and time spent trying them, for hash rate
+    hashes: AtomicU64,
+    mining_micros: AtomicU64,
+}
+
+impl SharedStatus {
+    fn set(&self, state: OperatingState) {
+        match state {
+            OperatingState::Paused => self.state.store(STATE_PAUSED, Ordering::SeqCst),
+            OperatingState::Run(lambda) => {
+                self.lambda.store(lambda, Ordering::SeqCst);
+                self.state.store(STATE_RUN, Ordering::SeqCst);
+            }
+            OperatingState::ShutDown => self.state.store(STATE_SHUTDOWN, Ordering::SeqCst),
+        }
+    }
+
+    fn get(&self) -> OperatingState {
+        match self.state.load(Ordering::SeqCst) {
+            STATE_RUN => OperatingState::Run(self.lambda.load(Ordering::SeqCst)),
+            STATE_SHUTDOWN => OperatingState::ShutDown,
+            _ => OperatingState::Paused,
+        }
+    }
+}
+
+//maximum serialized size of the blocks the miner builds, header included
+pub static BLOCK_SIZE_LIMIT: usize = 4000;
+//default cap on the serialized size of all pending transactions
+pub static MEMPOOL_MAX_BYTES: usize = 16 * 1024 * 1024;
+//number of most recent main-chain blocks whose fees feed the fee estimate
+pub static FEE_HISTORY_BLOCKS: usize = 10;
+//threads searching for a nonce unless set_threads says otherwise
+pub static MINER_THREADS: usize = 1;
+//nonces each thread tries per round before the miner checks for control signals and a new tip
+pub static NONCES_PER_ROUND: u32 = 1000;
+//fee estimate returned when there is no fee history to go on
+pub static MIN_FEE_ESTIMATE: u32 = 1;
+
+//how far past the sender's current account nonce a pending transaction's nonce may be
+pub static MAX_NONCE_GAP: u32 = 16;
+//transactions waiting for an earlier nonce, per sender and in total
+pub static MAX_ORPHANS_PER_SENDER: usize = 8;
+pub static MAX_ORPHANS: usize = 1000;
+//the filter over seen transactions is sized for this many hashes, and rebuilt twice as large when it holds more
+pub static SEEN_FILTER_CAPACITY: usize = 100_000;
+pub static SEEN_FILTER_FP_RATE: f64 = 0.01;
+
+#[derive(Debug, PartialEq)]
+pub enum MempoolRejection {
+    //transaction has already been seen
+    Duplicate,
+    //nonce is at or below the sender's nonce in the tip state, so it can never confirm
+    StaleNonce,
+    //nonce is more than MAX_NONCE_GAP ahead of the sender's nonce in the tip state
+    FutureNonce,
+    //another pending transaction from the sender uses the same nonce and pays at least as much
+    Conflict,
+    //fee is below the mempool's minimum
+    FeeTooLow,
+    //the mempool is at its size limit
+    Full,
+    //the transaction waits for an earlier nonce, but the sender or the whole orphan pool has too many waiting already
+    OrphanPoolFull,
+}
+
+/// Where Mempool::insert_validated put an accepted transaction
+#[derive(Debug, PartialEq)]
+pub enum MempoolAdmission {
+    //in the pool, along with the hashes of the orphans it made executable, in nonce order
+    Pooled { promoted: Vec<H256> },
+    //an earlier nonce of the sender is missing, it waits in the orphan pool until that arrives
+    Orphaned,
+}
+
+/// What Mempool::insert did with a transaction
+#[derive(Debug, PartialEq)]
+pub enum MempoolInsertResult {
+    Inserted,
+    //transaction has already been seen, nothing changed
+    Duplicate,
+    //pays a higher fee than the pending transaction with the same sender and nonce, which was evicted
+    Replaced(H256),
+    //the pending transaction with this hash has the same sender and nonce and pays at least as much, so it is kept
+    Conflict(H256),
+    //fee is below the minimum set with set_min_fee
+    FeeTooLow,
+    //adding the transaction would take the mempool past the limit set with set_max_bytes
+    Full,
+}
+
+/// Size and fee spread of the pending transactions, fees are 0 when the mempool is empty
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct MempoolStats {
+    pub count: usize,
+    //serialized size of all pending transactions
+    pub total_bytes: usize,
+    pub min_fee: u32,
+    pub max_fee: u32,
+    //the mean of the two middle fees when the count is even, rounded down
+    pub median_fee: u32,
+}
+
 pub struct Mempool {
     //map is used to store Txs not added yet to the blockchain
     pub transaction_map: HashMap<H256, SignedTransaction>,
     //set is used as a record for all transactions added to blockchain
-    pub transaction_set: HashSet<H256>
+    transaction_set: HashSet<H256>,
+    //rules out most hashes that were never added without looking them up in transaction_set
+    seen_filter: Bloom<H256>,
+    seen_filter_capacity: usize,
+    //(sender, nonce) of every transaction in the map, at most one pending transaction per pair can confirm
+    pub nonce_index: HashMap<(Address, u32), H256>,
+    //transactions paying less are not accepted, keeps spam out
+    pub min_fee: u32,
+    //most bytes of transactions picked for one block
+    pub block_size_limit: usize,
+    //serialized size of the transactions in the map, kept under max_bytes
+    total_bytes: usize,
+    max_bytes: usize,
+    //transactions whose sender has an earlier nonce neither confirmed nor pending, keyed by (sender, nonce)
+    orphans: HashMap<(Address, u32), SignedTransaction>
 }
 //implement Mempool like Blockchain
 impl Mempool {
     pub fn new() -> Self {
         return Mempool {
             transaction_map: HashMap::<H256, SignedTransaction>::new(),
-            transaction_set: HashSet::<H256>::new()
+            transaction_set: HashSet::<H256>::new(),
+            seen_filter: Bloom::new_for_fp_rate(SEEN_FILTER_CAPACITY, SEEN_FILTER_FP_RATE),
+            seen_filter_capacity: SEEN_FILTER_CAPACITY,
+            nonce_index: HashMap::<(Address, u32), H256>::new(),
+            min_fee: 0,
+            block_size_limit: BLOCK_SIZE_LIMIT,
+            total_bytes: 0,
+            max_bytes: MEMPOOL_MAX_BYTES,
+            orphans: HashMap::new()
+        }
+    }
+
+    pub fn set_min_fee(&mut self, fee: u32) {
+        self.min_fee = fee;
+    }
+
+    pub fn set_block_size_limit(&mut self, bytes: usize) {
+        self.block_size_limit = bytes;
+    }
+
+    pub fn set_max_bytes(&mut self, bytes: usize) {
+        self.max_bytes = bytes;
+    }
+
+    /// Serialized size of all pending transactions
+    pub fn total_bytes(&self) -> usize {
+        return self.total_bytes;
+    }
+
+    /// Whether the transaction was ever added, including ones since confirmed or replaced
+    pub fn has_seen(&self, hash: &H256) -> bool {
+        if !self.seen_filter.check(hash) {
+            return false;
         }
+        return self.transaction_set.contains(hash);
     }
 
-    pub fn insert(&mut self, transaction: &SignedTransaction) {
-        if self.transaction_set.contains(&transaction.hash()) {
-            return;
+    fn mark_seen(&mut self, hash: H256) {
+        self.transaction_set.insert(hash);
+        if self.transaction_set.len() > self.seen_filter_capacity {
+            //past its capacity the false positive rate climbs, so start over with a larger filter
+            self.seen_filter_capacity *= 2;
+            self.seen_filter = Bloom::new_for_fp_rate(self.seen_filter_capacity, SEEN_FILTER_FP_RATE);
+            for seen in self.transaction_set.iter() {
+                self.seen_filter.set(seen);
+            }
+        } else {
+            self.seen_filter.set(&hash);
+        }
+    }
+
+    /// Add a transaction, resolving a conflict with a pending transaction of the same sender and
+    /// nonce by keeping the one with the higher fee, or the first one on a tie
+    pub fn insert(&mut self, transaction: &SignedTransaction) -> MempoolInsertResult {
+        let hash = transaction.hash();
+        if self.has_seen(&hash) {
+            return MempoolInsertResult::Duplicate;
+        }
+        if transaction.transaction.fee() < self.min_fee {
+            return MempoolInsertResult::FeeTooLow;
         }
-        self.transaction_map.insert(transaction.hash(), transaction.clone());
-        self.transaction_set.insert(transaction.hash());
+        let key = (transaction.transaction.sender, transaction.transaction.account_nonce);
+        let size = bincode::serialized_size(transaction).unwrap() as usize;
+        let mut replaced = None;
+        let mut freed = 0;
+        if let Some(existing_hash) = self.nonce_index.get(&key).cloned() {
+            let existing = self.transaction_map.get(&existing_hash).unwrap();
+            if transaction.transaction.fee() <= existing.transaction.fee() {
+                return MempoolInsertResult::Conflict(existing_hash);
+            }
+            freed = bincode::serialized_size(existing).unwrap() as usize;
+            replaced = Some(existing_hash);
+        }
+        if self.total_bytes - freed + size > self.max_bytes {
+            return MempoolInsertResult::Full;
+        }
+        let mut result = MempoolInsertResult::Inserted;
+        if let Some(existing_hash) = replaced {
+            self.remove(&existing_hash);
+            result = MempoolInsertResult::Replaced(existing_hash);
+        }
+        self.transaction_map.insert(hash, transaction.clone());
+        self.mark_seen(hash);
+        self.nonce_index.insert(key, hash);
+        self.total_bytes += size;
+        return result;
+    }
+
+    /// Number of transactions waiting in the orphan pool
+    pub fn orphan_count(&self) -> usize {
+        return self.orphans.len();
+    }
+
+    /// Insert a transaction only if its nonce can still be confirmed on top of the given tip state.
+    /// One whose previous nonce is neither confirmed nor pending is parked as an orphan instead
+    pub fn insert_validated(&mut self, transaction: &SignedTransaction, tip_state: &HashMap<Address, (u32, u32)>) -> Result<MempoolAdmission, MempoolRejection> {
+        let hash = transaction.hash();
+        let sender = transaction.transaction.sender;
+        if self.has_seen(&hash) {
+            return Err(MempoolRejection::Duplicate);
+        }
+        let current_nonce = match tip_state.get(&transaction.transaction.sender) {
+            Some((nonce, _)) => *nonce,
+            None => 0
+        };
+        let nonce = transaction.transaction.account_nonce;
+        if nonce <= current_nonce {
+            return Err(MempoolRejection::StaleNonce);
+        }
+        if nonce > current_nonce + MAX_NONCE_GAP {
+            return Err(MempoolRejection::FutureNonce);
+        }
+        if nonce > current_nonce + 1 && !self.nonce_index.contains_key(&(sender, nonce - 1)) {
+            return self.insert_orphan(transaction);
+        }
+        match self.insert(transaction) {
+            MempoolInsertResult::Inserted | MempoolInsertResult::Replaced(_) => {
+                let promoted = self.promote_after(sender, nonce);
+                return Ok(MempoolAdmission::Pooled { promoted });
+            }
+            MempoolInsertResult::Duplicate => return Err(MempoolRejection::Duplicate),
+            MempoolInsertResult::Conflict(_) => return Err(MempoolRejection::Conflict),
+            MempoolInsertResult::FeeTooLow => return Err(MempoolRejection::FeeTooLow),
+            MempoolInsertResult::Full => return Err(MempoolRejection::Full),
+        }
+    }
+
+    fn insert_orphan(&mut self, transaction: &SignedTransaction) -> Result<MempoolAdmission, MempoolRejection> {
+        if transaction.transaction.fee() < self.min_fee {
+            return Err(MempoolRejection::FeeTooLow);
+        }
+        let key = (transaction.transaction.sender, transaction.transaction.account_nonce);
+        if let Some(existing) = self.orphans.get(&key) {
+            if existing.hash() == transaction.hash() {
+                return Err(MempoolRejection::Duplicate);
+            }
+            if transaction.transaction.fee() <= existing.transaction.fee() {
+                return Err(MempoolRejection::Conflict);
+            }
+        } else {
+            let from_sender = self.orphans.keys().filter(|(sender, _)| *sender == key.0).count();
+            if from_sender >= MAX_ORPHANS_PER_SENDER || self.orphans.len() >= MAX_ORPHANS {
+                return Err(MempoolRejection::OrphanPoolFull);
+            }
+        }
+        self.orphans.insert(key, transaction.clone());
+        return Ok(MempoolAdmission::Orphaned);
+    }
+
+    /// Move the sender's orphans following nonce into the pool for as long as their nonces are consecutive
+    fn promote_after(&mut self, sender: Address, nonce: u32) -> Vec<H256> {
+        let mut promoted = Vec::new();
+        let mut next = nonce + 1;
+        while let Some(orphan) = self.orphans.remove(&(sender, next)) {
+            match self.insert(&orphan) {
+                MempoolInsertResult::Inserted | MempoolInsertResult::Replaced(_) => promoted.push(orphan.hash()),
+                //the nonce is taken or there is no room, later orphans stay parked
+                _ => break,
+            }
+            next += 1;
+        }
+        return promoted;
+    }
+
+    /// Once a block has advanced senders' nonces, drop orphans that can no longer confirm and move the
+    /// ones that became executable into the pool, returning the hashes of those
+    pub fn promote_orphans(&mut self, tip_state: &HashMap<Address, (u32, u32)>) -> Vec<H256> {
+        let current_nonce = |sender: &Address| tip_state.get(sender).map(|(nonce, _)| *nonce).unwrap_or(0);
+        self.orphans.retain(|(sender, nonce), _| *nonce > current_nonce(sender));
+        let ready: Vec<(Address, u32)> = self.orphans.keys()
+            .filter(|(sender, nonce)| *nonce == current_nonce(sender) + 1)
+            .cloned()
+            .collect();
+        let mut promoted = Vec::new();
+        for (sender, nonce) in ready {
+            let orphan = self.orphans.remove(&(sender, nonce)).unwrap();
+            match self.insert(&orphan) {
+                MempoolInsertResult::Inserted | MempoolInsertResult::Replaced(_) => {
+                    promoted.push(orphan.hash());
+                    promoted.extend(self.promote_after(sender, nonce));
+                }
+                _ => {}
+            }
+        }
+        return promoted;
+    }
+
+    /// Estimate the fee needed to be included within `target_blocks` blocks, based on the fees paid
+    /// in the last FEE_HISTORY_BLOCKS blocks of the longest chain and how many blocks worth of
+    /// transactions are already waiting in the mempool
+    pub fn estimate_fee(&self, blockchain: &Blockchain, target_blocks: u32) -> u32 {
+        let mut fees = Vec::<u32>::new();
+        let longest_chain = blockchain.longest_chain();
+        for block_hash in longest_chain.iter().rev().take(FEE_HISTORY_BLOCKS) {
+            let (block, _) = blockchain.block_map.get(block_hash).unwrap();
+            for tx in block.content.data.iter() {
+                fees.push(tx.transaction.fee());
+            }
+        }
+        if fees.is_empty() {
+            return MIN_FEE_ESTIMATE;
+        }
+        fees.sort();
+
+        //with the pool holding more blocks worth of transactions than the target, only the
+        //best paying target/pending share of them gets in on time, so bid for that share
+        let target_blocks = std::cmp::max(target_blocks, 1) as f64;
+        let pending_blocks = self.total_bytes as f64 / self.block_size_limit as f64;
+        let quantile = if pending_blocks <= target_blocks {
+            0.5
+        } else {
+            1.0 - target_blocks / pending_blocks
+        };
+        let index = (quantile * (fees.len() - 1) as f64).round() as usize;
+        return std::cmp::max(fees[index], MIN_FEE_ESTIMATE);
+    }
+
+    pub fn statistics(&self) -> MempoolStats {
+        let mut fees: Vec<u32> = self.transaction_map.values().map(|tx| tx.transaction.fee()).collect();
+        if fees.is_empty() {
+            return MempoolStats::default();
+        }
+        fees.sort();
+        let middle = fees.len() / 2;
+        let median_fee = if fees.len() % 2 == 0 {
+            ((fees[middle - 1] as u64 + fees[middle] as u64) / 2) as u32
+        } else {
+            fees[middle]
+        };
+        return MempoolStats {
+            count: fees.len(),
+            total_bytes: self.transaction_map.values().map(|tx| bincode::serialized_size(tx).unwrap() as usize).sum(),
+            min_fee: fees[0],
+            max_fee: fees[fees.len() - 1],
+            median_fee,
+        };
     }
 
     pub fn remove(&mut self, transaction_hash: &H256) {
-        if self.transaction_map.contains_key(&transaction_hash) {
-            self.transaction_map.remove(&transaction_hash);
+        if let Some(tx) = self.transaction_map.remove(&transaction_hash) {
+            self.total_bytes -= bincode::serialized_size(&tx).unwrap() as usize;
+            let key = (tx.transaction.sender, tx.transaction.account_nonce);
+            if self.nonce_index.get(&key) == Some(transaction_hash) {
+                self.nonce_index.remove(&key);
+            }
+        }
+    }
+}
+
+/// Pick transactions from the mempool that are valid on top of `state`, as many as fit in a block of the mempool's
+/// block_size_limit bytes, applying each one to `state` as it is picked. Used by the miner and for external block templates.
+pub fn select_transactions(mempool: &mut Mempool, state: &mut HashMap<Address, (u32, u32)>) -> Vec<SignedTransaction> {
+    //the header and the transaction count in front of the transactions have the same size whatever the block holds,
+    //so the block grows by each transaction's own size
+    let empty_header = Header { parent: H256::from([0; 32]), nonce: 0, difficulty: H256::from([0; 32]), timestamp: 0, merkle_root: H256::from([0; 32]) };
+    let mut size = Block::new(empty_header, Content { data: Vec::new() }).size_bytes();
+    let mut selected = Vec::new();
+    let block_size_limit = mempool.block_size_limit;
+    //a sender's transactions only apply in nonce order
+    let mut pending: Vec<SignedTransaction> = mempool.transaction_map.values().cloned().collect();
+    pending.sort_by_key(|tx| tx.transaction.account_nonce);
+    for tx in pending.iter() {
+        let tx_size = bincode::serialized_size(tx).unwrap() as usize;
+        if size + tx_size > block_size_limit {
+            break;
+        }
+        /////////State checks///////////
+        let transaction = &tx.transaction;
+        let sender_state;
+        if state.contains_key(&transaction.sender) {
+            sender_state = state.get(&transaction.sender).unwrap().clone();
+        } else {
+            sender_state = (0, 0);
         }
+        if transaction.validate_against_state(state).is_err() {
+            //remove Txs with nonce lower than current, otherwise keep (out-of-order Txs, etc.)
+            if transaction.account_nonce < sender_state.1 {
+                mempool.remove(&tx.hash());
+            }
+            continue;
+        }
+        //at this point the transaction is valid so update local state copy
+        transaction.apply_to_state(state);
+        size += tx_size;
+        selected.push(tx.clone());
+        ////////////////////////////////
+    }
+    return selected;
+}
+
+/// Everything an external miner needs to search for a nonce; the header to hash is
+/// Header { parent, nonce, difficulty, timestamp, merkle_root }
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BlockTemplate {
+    pub parent: String,
+    pub difficulty: String,
+    pub timestamp: u128,
+    pub merkle_root: String,
+    //hex encoded bincode of the Vec<SignedTransaction> making up the block content
+    pub transactions: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SubmitBlockError {
+    //parent is no longer the tip of the longest chain
+    Stale,
+    InvalidProofOfWork,
+    MerkleRootMismatch,
+    InvalidSignature,
+    //a transaction is signed by a key that doesn't own its sender address
+    SenderMismatch,
+    //a transaction overspends or is out of nonce order
+    InvalidTransaction,
+    //the parent's balances and nonces aren't known
+    UnknownParentState,
+    //the miner worker is gone
+    Disconnected,
+}
+
+impl std::fmt::Display for SubmitBlockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let reason = match self {
+            SubmitBlockError::Stale => "stale block: parent is no longer the tip",
+            SubmitBlockError::InvalidProofOfWork => "block hash does not meet the difficulty",
+            SubmitBlockError::MerkleRootMismatch => "merkle root does not match the transactions",
+            SubmitBlockError::InvalidSignature => "transaction signature is invalid",
+            SubmitBlockError::SenderMismatch => "transaction is signed by a key that doesn't own its sender address",
+            SubmitBlockError::InvalidTransaction => "transaction is not valid on top of the parent state",
+            SubmitBlockError::UnknownParentState => "state of the parent block is unknown",
+            SubmitBlockError::Disconnected => "miner worker is not running",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+/// Returned by handle methods when the thread they control is no longer running
+#[derive(Debug, PartialEq)]
+pub enum ControlError {
+    //the thread exited or panicked and dropped its end of the control channel
+    Disconnected,
+}
+
+impl std::fmt::Display for ControlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let reason = match self {
+            ControlError::Disconnected => "thread is not running",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+/// Blocks this node mined, to tell how much of the chain each node of a network contributed
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct MinerStats {
+    //the account of the node the blocks are credited to
+    pub address: Address,
+    pub blocks_found: u64,
+    pub first_block_height: Option<u32>,
+    pub last_block_height: Option<u32>,
+}
+
+impl MinerStats {
+    fn record(&mut self, height: u32) {
+        self.blocks_found += 1;
+        self.first_block_height.get_or_insert(height);
+        self.last_block_height = Some(height);
     }
 }
 
@@ -66,77 +549,248 @@ pub struct Context {
     control_chan: Receiver<ControlSignal>,
     operating_state: OperatingState,
     finished_block_chan: Sender<Block>,
-    blockchain: Arc<Mutex<Blockchain>>,
+    blockchain: Arc<RwLock<Blockchain>>,
     mempool: Arc<Mutex<Mempool>>,
     block_state_map: Arc<Mutex<BlockState>>,
+    status: Arc<SharedStatus>,
+    stats: Arc<Mutex<MinerStats>>,
+    //each thread of the pool searches its own chunk of the nonce space
+    pool: ThreadPool,
 }
 
 #[derive(Clone)]
 pub struct Handle {
     /// Channel for sending signal to the miner thread
     control_chan: Sender<ControlSignal>,
+    status: Arc<SharedStatus>,
+    stats: Arc<Mutex<MinerStats>>,
+    /// Externally mined blocks are handed to the miner worker through the same channel as our own
+    finished_block_chan: Sender<Block>,
+    blockchain: Arc<RwLock<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+    block_state_map: Arc<Mutex<BlockState>>,
 }
 
-pub fn new(blockchain: &Arc<Mutex<Blockchain>>,
+pub fn new(blockchain: &Arc<RwLock<Blockchain>>,
            mempool: &Arc<Mutex<Mempool>>,
            block_state_map: &Arc<Mutex<BlockState>>) -> (Context, Handle, Receiver<Block>) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
     let (finished_block_sender, finished_block_receiver) = unbounded();
+    let status = Arc::new(SharedStatus::default());
+    let stats = Arc::new(Mutex::new(MinerStats::default()));
 
     let ctx = Context {
         control_chan: signal_chan_receiver,
         operating_state: OperatingState::Paused,
-        finished_block_chan: finished_block_sender,
+        finished_block_chan: finished_block_sender.clone(),
         blockchain: Arc::clone(blockchain),
         mempool: Arc::clone(mempool),
         block_state_map: Arc::clone(block_state_map),
+        status: Arc::clone(&status),
+        stats: Arc::clone(&stats),
+        pool: nonce_search_pool(MINER_THREADS),
     };
 
     let handle = Handle {
         control_chan: signal_chan_sender,
+        status: status,
+        stats: stats,
+        finished_block_chan: finished_block_sender,
+        blockchain: Arc::clone(blockchain),
+        mempool: Arc::clone(mempool),
+        block_state_map: Arc::clone(block_state_map),
     };
 
     (ctx, handle, finished_block_receiver)
 }
 
-#[cfg(any(test,test_utilities))]
+#[cfg(test)]
 fn test_new() -> (Context, Handle, Receiver<Block>) {
     let blockchain = Blockchain::new();
-    let blockchain = Arc::new(Mutex::new(blockchain));
+    let blockchain = Arc::new(RwLock::new(blockchain));
     let mempool = Mempool::new();
     let mempool = Arc::new(Mutex::new(mempool));
     let block_state_map = Arc::new(Mutex::new(BlockState::new()));
+    let genesis_hash = blockchain.read().unwrap().tip();
+    block_state_map.lock().unwrap().block_state_map.insert(genesis_hash, HashMap::new());
     return new(&blockchain, &mempool, &block_state_map);
 }
 
 impl Handle {
-    pub fn exit(&self) {
-        self.control_chan.send(ControlSignal::Exit).unwrap();
+    pub fn exit(&self) -> Result<(), ControlError> {
+        return self.control_chan.send(ControlSignal::Exit).map_err(|_| ControlError::Disconnected);
     }
 
-    pub fn start(&self, lambda: u64) {
-        self.control_chan
+    pub fn start(&self, lambda: u64) -> Result<(), ControlError> {
+        return self.control_chan
             .send(ControlSignal::Start(lambda))
-            .unwrap();
+            .map_err(|_| ControlError::Disconnected);
+    }
+
+    pub fn update(&self) -> Result<(), ControlError> {
+        return self.control_chan.send(ControlSignal::Update).map_err(|_| ControlError::Disconnected);
+    }
+
+    pub fn pause(&self) -> Result<(), ControlError> {
+        return self.control_chan.send(ControlSignal::Pause).map_err(|_| ControlError::Disconnected);
+    }
+
+    /// Operating state as last recorded by the miner thread
+    pub fn status(&self) -> OperatingState {
+        return self.status.get();
+    }
+
+    /// Total number of nonces tried so far
+    pub fn hashes(&self) -> u64 {
+        return self.status.hashes.load(Ordering::Relaxed);
     }
 
-    pub fn update(&self) {
-        self.control_chan.send(ControlSignal::Update).unwrap();
+    /// Nonces tried per second of time spent running
+    pub fn hash_rate(&self) -> f64 {
+        let micros = self.status.mining_micros.load(Ordering::Relaxed);
+        if micros == 0 {
+            return 0.0;
+        }
+        return self.hashes() as f64 * 1_000_000.0 / micros as f64;
+    }
+
+    /// Blocks mined by this node so far
+    pub fn stats(&self) -> MinerStats {
+        return *self.stats.lock().unwrap();
+    }
+
+    /// Build a block template on top of the current tip for an external miner
+    pub fn get_template(&self) -> BlockTemplate {
+        let parent = self.blockchain.read().unwrap().tip();
+        let mut state = self.block_state_map.lock().unwrap().block_state_map.get(&parent).unwrap().clone();
+        let transactions = select_transactions(&mut self.mempool.lock().unwrap(), &mut state);
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_millis();
+        let difficulty: H256 = DIFFICULTY.into();
+        return BlockTemplate {
+            parent: parent.to_string(),
+            difficulty: difficulty.to_string(),
+            timestamp: timestamp,
+            merkle_root: MerkleTree::new(&transactions).root().to_string(),
+            transactions: hex::encode(bincode::serialize(&transactions).unwrap()),
+        };
+    }
+
+    /// Validate a block solved by an external miner and hand it to the miner worker, which
+    /// inserts and broadcasts it like one of our own
+    pub fn submit_block(&self, block: Block) -> Result<(), SubmitBlockError> {
+        let parent = block.get_parent();
+        let blockchain = self.blockchain.read().unwrap();
+        if parent != blockchain.tip() {
+            return Err(SubmitBlockError::Stale);
+        }
+        if blockchain.verify_pow(&block).is_err() {
+            return Err(SubmitBlockError::InvalidProofOfWork);
+        }
+        drop(blockchain);
+        if MerkleTree::new(&block.content.data).root() != block.get_merkle_root() {
+            return Err(SubmitBlockError::MerkleRootMismatch);
+        }
+        for tx in block.content.data.iter() {
+            match tx.verify_integrity() {
+                Ok(()) => {}
+                Err(IntegrityError::InvalidSignature) => return Err(SubmitBlockError::InvalidSignature),
+                Err(IntegrityError::SenderMismatch) => return Err(SubmitBlockError::SenderMismatch),
+            }
+        }
+        //only checked here, the miner worker stores the state once the block is inserted
+        let block_state_map = self.block_state_map.lock().unwrap();
+        let parent_state = match block_state_map.block_state_map.get(&parent) {
+            Some(state) => state,
+            None => return Err(SubmitBlockError::UnknownParentState),
+        };
+        if apply_block_to_state(parent_state, &block).is_err() {
+            return Err(SubmitBlockError::InvalidTransaction);
+        }
+        drop(block_state_map);
+        if self.finished_block_chan.send(block).is_err() {
+            return Err(SubmitBlockError::Disconnected);
+        }
+        return Ok(());
     }
 }
 
+fn nonce_search_pool(threads: usize) -> ThreadPool {
+    return rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(|i| format!("miner-{}", i))
+        .build()
+        .unwrap();
+}
+
+/// Result of one round of nonce search
+#[derive(Debug, PartialEq)]
+pub struct NonceSearch {
+    pub nonce: Option<u32>,
+    pub tried: u64,
+}
+
+/// Split the nonce space into one chunk per pool thread and try `per_thread` nonces at the start of each,
+/// offset by `start`. Stops every thread as soon as one finds a nonce that meets the header's difficulty
+pub fn search_nonce(pool: &ThreadPool, header: &Header, start: u32, per_thread: u32) -> NonceSearch {
+    let threads = pool.current_num_threads() as u32;
+    let chunk = u32::MAX / threads;
+    let found = AtomicBool::new(false);
+    let nonce: Mutex<Option<u32>> = Mutex::new(None);
+    let tried = AtomicU64::new(0);
+    pool.scope(|scope| {
+        for i in 0..threads {
+            let (found, nonce, tried) = (&found, &nonce, &tried);
+            let mut header = header.clone();
+            scope.spawn(move |_| {
+                let first = start.wrapping_add(i * chunk);
+                for offset in 0..per_thread {
+                    if found.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    header.nonce = first.wrapping_add(offset);
+                    tried.fetch_add(1, Ordering::Relaxed);
+                    //only the first thread to find a nonce gets to report it
+                    if header.hash() <= header.difficulty && !found.swap(true, Ordering::Relaxed) {
+                        *nonce.lock().unwrap() = Some(header.nonce);
+                    }
+                }
+            });
+        }
+    });
+    return NonceSearch { nonce: nonce.into_inner().unwrap(), tried: tried.into_inner() };
+}
+
 impl Context {
-    pub fn start(mut self) {
-        thread::Builder::new()
+    /// Search for nonces on this many threads
+    pub fn set_threads(&mut self, threads: usize) {
+        self.pool = nonce_search_pool(threads.max(1));
+    }
+
+    /// Credit the blocks mined to `address` in the stats
+    pub fn set_address(&mut self, address: Address) {
+        self.stats.lock().unwrap().address = address;
+    }
+
+    pub fn start(mut self) -> thread::JoinHandle<()> {
+        let handle = thread::Builder::new()
             .name("miner".to_string())
             .spawn(move || {
                 self.miner_loop();
             })
             .unwrap();
         info!("Miner initialized into paused mode");
+        return handle;
+    }
+
+    fn set_operating_state(&mut self, state: OperatingState) {
+        self.operating_state = state;
+        self.status.set(state);
     }
 
     fn miner_loop(&mut self) {
+        //hashes tried and time spent on them since the last solved block, logged with the next one
+        let mut attempts: u64 = 0;
+        let mut mining_time = time::Duration::ZERO;
         // main mining loop
         loop {
             // check and react to control signals
@@ -146,13 +800,13 @@ impl Context {
                     match signal {
                         ControlSignal::Exit => {
                             info!("Miner shutting down");
-                            self.operating_state = OperatingState::ShutDown;
+                            self.set_operating_state(OperatingState::ShutDown);
                         }
                         ControlSignal::Start(i) => {
                             info!("Miner starting in continuous mode with lambda {}", i);
-                            self.operating_state = OperatingState::Run(i);
+                            self.set_operating_state(OperatingState::Run(i));
                         }
-                        ControlSignal::Update => {
+                        ControlSignal::Update | ControlSignal::Pause => {
                             // in paused state, don't need to update
                         }
                     };
@@ -166,15 +820,20 @@ impl Context {
                         match signal {
                             ControlSignal::Exit => {
                                 info!("Miner shutting down");
-                                self.operating_state = OperatingState::ShutDown;
+                                self.set_operating_state(OperatingState::ShutDown);
                             }
                             ControlSignal::Start(i) => {
                                 info!("Miner starting in continuous mode with lambda {}", i);
-                                self.operating_state = OperatingState::Run(i);
+                                self.set_operating_state(OperatingState::Run(i));
                             }
                             ControlSignal::Update => {
                                 unimplemented!()
                             }
+                            ControlSignal::Pause => {
+                                info!("Miner paused");
+                                self.set_operating_state(OperatingState::Paused);
+                                continue;
+                            }
                         };
                     }
                     Err(TryRecvError::Empty) => {}
@@ -184,72 +843,54 @@ impl Context {
             if let OperatingState::ShutDown = self.operating_state {
                 return;
             }
-            let parent_ = self.blockchain.lock().unwrap().tip();
+            let attempt_start = std::time::Instant::now();
+            let (parent_, parent_height) = {
+                let blockchain = self.blockchain.read().unwrap();
+                (blockchain.tip(), blockchain.height)
+            };
             let start = SystemTime::now();
             let mut rng = rand::thread_rng();
             let timestamp_ = start.duration_since(UNIX_EPOCH).expect("Time went backwards").as_millis();
             let difficulty_: H256 = DIFFICULTY.into();
             let mut tip_state = self.block_state_map.lock().unwrap().block_state_map.get(&parent_).unwrap().clone();
             /////////Transaction Logic - add transactions from mempool to block/////////
-            let mut transactions = Vec::<SignedTransaction>::new();
-            let mut mempool = self.mempool.lock().unwrap();
-            let block_limit = 4000;
-            let mut current_size = 0;
-            let mut bytes: Vec<u8>;
-            for (_, tx) in mempool.transaction_map.clone().iter() {
-                bytes = bincode::serialize(&tx).unwrap();
-                if current_size + bytes.len() > block_limit {
-                    break;
-                }
-                /////////State checks///////////
-                let transaction = &tx.transaction;
-                let sender_state;
-                if tip_state.contains_key(&transaction.sender) {
-                    sender_state = tip_state.get(&transaction.sender).unwrap().clone();
-                } else {
-                    sender_state = (0, 0);
-                }
-                if (transaction.value > sender_state.1) || (transaction.account_nonce != sender_state.0 + 1) {
-                    //remove Txs with nonce lower than current, otherwise keep (out-of-order Txs, etc.)
-                    if transaction.account_nonce < sender_state.1 {
-                        mempool.remove(&tx.hash());
-                    }
-                    continue;
-                }
-                //at this point the transaction is valid so update local state copy
-                tip_state.insert(transaction.sender, (sender_state.0 + 1, sender_state.1 - &transaction.value));
-                let receiver_state;
-                if tip_state.contains_key(&transaction.receiver) {
-                    receiver_state = tip_state.get(&transaction.receiver).unwrap().clone();
-                } else {
-                    receiver_state = (0, 0);
-                }
-                tip_state.insert(transaction.receiver, (receiver_state.0, receiver_state.1 + &transaction.value));
-                ////////////////////////////////
-                current_size += bytes.len();
-                let x = &*tx;
-                transactions.push(x.clone());
-            }
+            //the mempool stays free for the network and the API while the nonce search runs
+            let transactions = select_transactions(&mut self.mempool.lock().unwrap(), &mut tip_state);
             ////////////////////////////////////////////////////////////////////////////
 
             let merkle_tree_ = MerkleTree::new(&transactions);
-            let nonce_ = rng.gen::<u32>();
-            let header_ = Header {
+            let mut header_ = Header {
                 parent: parent_,
-                nonce: nonce_,
+                nonce: 0,
                 difficulty: difficulty_,
                 timestamp: timestamp_,
                 merkle_root: merkle_tree_.root()
             };
-            let content_ = Content {
-                data: transactions
+            //a throttled miner still tries a single nonce per thread between sleeps
+            let per_thread = match self.operating_state {
+                OperatingState::Run(0) => NONCES_PER_ROUND,
+                _ => 1
             };
-            let block = Block {
-                header: header_,
-                content: content_
-            };
-            if block.hash() <= difficulty_ {
+            let search = search_nonce(&self.pool, &header_, rng.gen::<u32>(), per_thread);
+            attempts += search.tried;
+            if let Some(nonce_) = search.nonce {
+                header_.nonce = nonce_;
+                let content_ = Content {
+                    data: transactions
+                };
+                let block = Block::new(header_, content_);
+                let elapsed = mining_time + attempt_start.elapsed();
+                info!(
+                    block.hash = %block.hash(),
+                    block.tx_count = block.content.data.len(),
+                    attempts,
+                    elapsed_ms = elapsed.as_millis() as u64,
+                    "Mined block"
+                );
+                attempts = 0;
+                mining_time = time::Duration::ZERO;
                 //Remove transactions from mempool
+                let mut mempool = self.mempool.lock().unwrap();
                 for tx in block.content.data.clone() {
                     mempool.remove(&tx.hash());
                 }
@@ -259,14 +900,22 @@ impl Context {
                 for (_, tx) in mempool.transaction_map.clone().iter() {
                     let sender = tx.transaction.sender;
                     let sender_state = tip_state.get(&sender).unwrap().clone();
-                    if (tx.transaction.value > sender_state.1) || (tx.transaction.account_nonce != sender_state.0 + 1) {
+                    if tx.transaction.validate_against_state(&tip_state).is_err() {
                         if tx.transaction.account_nonce < sender_state.1 {
                             mempool.remove(&tx.hash());
                         }
                     }
                 }
-                self.finished_block_chan.send(block.clone()).expect("Send finished block error");
+                //not holding the mempool while the chain is locked
+                drop(mempool);
+                debug_assert!(self.blockchain.read().unwrap().validate(&block).is_ok(), "mined an invalid block {}", block.hash());
+                self.stats.lock().unwrap().record(parent_height + 1);
+                self.finished_block_chan.send(block).expect("Send finished block error");
+            } else {
+                mining_time += attempt_start.elapsed();
             }
+            self.status.hashes.fetch_add(search.tried, Ordering::Relaxed);
+            self.status.mining_micros.fetch_add(attempt_start.elapsed().as_micros() as u64, Ordering::Relaxed);
 
             if let OperatingState::Run(i) = self.operating_state {
                 if i != 0 {
@@ -283,14 +932,47 @@ impl Context {
 #[cfg(test)]
 mod test {
     use ntest::timeout;
+    use std::collections::HashMap;
+    use crate::types::address::Address;
     use crate::types::hash::Hashable;
+    use crate::blockchain::Blockchain;
+    use crate::types::block::generate_random_block;
+    use crate::types::transaction::{SignedTransaction, Transaction};
+    use crate::types::block::{Block, Header, Content};
+    use crate::types::hash::H256;
+    use super::{Mempool, MempoolAdmission, MempoolStats, MempoolRejection, MempoolInsertResult, MAX_ORPHANS, MAX_ORPHANS_PER_SENDER, select_transactions, OperatingState, BlockTemplate, SubmitBlockError, MAX_NONCE_GAP, BLOCK_SIZE_LIMIT, MIN_FEE_ESTIMATE, SEEN_FILTER_CAPACITY, SEEN_FILTER_FP_RATE, search_nonce, NonceSearch};
+    use crate::blockchain::DIFFICULTY;
+
+    fn transaction_with_nonce(sender: Address, account_nonce: u32) -> SignedTransaction {
+        let transaction = Transaction {
+            sender: sender,
+            account_nonce: account_nonce,
+            outputs: vec![(Address::from([1; 20]), 1)],
+            fee: 0
+        };
+        return SignedTransaction { transaction: transaction, signature: vec![], public_key: vec![] };
+    }
+
+    fn transaction_with_fee(fee: u32) -> SignedTransaction {
+        //a fresh sender each time so the transactions never conflict on nonce
+        let mut tx = transaction_with_nonce(Address::from(rand::random::<[u8; 20]>()), 1);
+        tx.transaction.fee = fee;
+        return tx;
+    }
+
+    /// Extend the blockchain's tip with a block holding transactions paying the given fees
+    fn insert_block_with_fees(blockchain: &mut Blockchain, fees: &[u32]) {
+        let mut block = generate_random_block(&blockchain.tip());
+        block.content.data = fees.iter().map(|fee| transaction_with_fee(*fee)).collect();
+        blockchain.insert(&block);
+    }
 
     #[test]
     #[timeout(60000)]
     fn miner_three_block() {
         let (miner_ctx, miner_handle, finished_block_chan) = super::test_new();
         miner_ctx.start();
-        miner_handle.start(0);
+        miner_handle.start(0).unwrap();
         let mut block_prev = finished_block_chan.recv().unwrap();
         for _ in 0..2 {
             let block_next = finished_block_chan.recv().unwrap();
@@ -304,7 +986,7 @@ mod test {
     fn miner_ten_block() {
         let (miner_ctx, miner_handle, finished_block_chan) = super::test_new();
         miner_ctx.start();
-        miner_handle.start(0);
+        miner_handle.start(0).unwrap();
         let mut block_prev = finished_block_chan.recv().unwrap();
         for _ in 0..9 {
             let block_next = finished_block_chan.recv().unwrap();
@@ -312,6 +994,493 @@ mod test {
             block_prev = block_next;
         }
     }
+
+    #[test]
+    #[timeout(60000)]
+    fn miner_status_follows_control_signals() {
+        let (miner_ctx, miner_handle, finished_block_chan) = super::test_new();
+        assert_eq!(miner_handle.status(), OperatingState::Paused);
+        miner_ctx.start();
+        miner_handle.start(0).unwrap();
+        //once a block comes out the miner has processed the start signal
+        finished_block_chan.recv().unwrap();
+        assert_eq!(miner_handle.status(), OperatingState::Run(0));
+        assert!(miner_handle.hashes() > 0);
+        miner_handle.pause().unwrap();
+        while miner_handle.status() != OperatingState::Paused {
+            std::thread::yield_now();
+        }
+        miner_handle.exit().unwrap();
+        while miner_handle.status() != OperatingState::ShutDown {
+            std::thread::yield_now();
+        }
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn stats_count_mined_blocks() {
+        let (mut miner_ctx, miner_handle, finished_block_chan) = super::test_new();
+        miner_ctx.set_address(Address::from([7; 20]));
+        assert_eq!(miner_handle.stats().blocks_found, 0);
+        assert_eq!(miner_handle.stats().first_block_height, None);
+        miner_ctx.start();
+        miner_handle.start(0).unwrap();
+        for _ in 0..10 {
+            finished_block_chan.recv().unwrap();
+        }
+        miner_handle.pause().unwrap();
+        while miner_handle.status() != OperatingState::Paused {
+            std::thread::yield_now();
+        }
+        //a block sent after the 10th could only have been mined before the pause
+        let blocks_found = 10 + finished_block_chan.try_iter().count() as u64;
+        let stats = miner_handle.stats();
+        assert_eq!(stats.blocks_found, blocks_found);
+        assert_eq!(stats.address, Address::from([7; 20]));
+        //nothing inserts the blocks into the chain here, every one of them is mined on genesis
+        assert_eq!(stats.first_block_height, Some(1));
+        assert_eq!(stats.last_block_height, Some(1));
+    }
+
+    #[test]
+    fn stats_remember_first_and_last_height() {
+        let mut stats = super::MinerStats::default();
+        for height in [3, 4, 4, 6] {
+            stats.record(height);
+        }
+        assert_eq!(stats.blocks_found, 4);
+        assert_eq!(stats.first_block_height, Some(3));
+        assert_eq!(stats.last_block_height, Some(6));
+    }
+
+    fn parse_hash(hex_string: &str) -> H256 {
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(&hex::decode(hex_string).unwrap());
+        return H256::from(bytes);
+    }
+
+    /// Stand-in for an external miner: search nonces for a template and return the solved block
+    fn solve_template(template: &BlockTemplate) -> Block {
+        let transactions: Vec<SignedTransaction> = bincode::deserialize(&hex::decode(&template.transactions).unwrap()).unwrap();
+        let difficulty = parse_hash(&template.difficulty);
+        let mut header = Header {
+            parent: parse_hash(&template.parent),
+            nonce: 0,
+            difficulty: difficulty,
+            timestamp: template.timestamp,
+            merkle_root: parse_hash(&template.merkle_root)
+        };
+        while header.hash() > difficulty {
+            header.nonce += 1;
+        }
+        return Block::new(header, Content { data: transactions });
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn submit_solved_template() {
+        let (_miner_ctx, miner_handle, finished_block_chan) = super::test_new();
+        let template = miner_handle.get_template();
+        let block = solve_template(&template);
+        assert_eq!(miner_handle.submit_block(block.clone()), Ok(()));
+        //the block is handed to the miner worker like a locally mined one
+        assert_eq!(finished_block_chan.recv().unwrap().hash(), block.hash());
+        //which stores its state once it's inserted
+        assert!(!miner_handle.block_state_map.lock().unwrap().block_state_map.contains_key(&block.hash()));
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn submit_rejects_stale_and_unsolved_blocks() {
+        let (_miner_ctx, miner_handle, _finished_block_chan) = super::test_new();
+        let template = miner_handle.get_template();
+        let mut unsolved = solve_template(&template);
+        while unsolved.hash() <= unsolved.get_difficulty() {
+            unsolved.header_mut().nonce += 1;
+        }
+        assert_eq!(miner_handle.submit_block(unsolved), Err(SubmitBlockError::InvalidProofOfWork));
+
+        let block = solve_template(&template);
+        //another block extends the tip first
+        let competing = generate_random_block(&block.get_parent());
+        miner_handle.blockchain.write().unwrap().insert(&competing);
+        assert_eq!(miner_handle.submit_block(block), Err(SubmitBlockError::Stale));
+    }
+
+    #[test]
+    fn insert_validated_rejects_stale_nonce() {
+        let sender = Address::from([7; 20]);
+        let mut tip_state = HashMap::new();
+        tip_state.insert(sender, (3, 100));
+        let mut mempool = Mempool::new();
+        //nonce equal to the current one has already been used
+        assert_eq!(mempool.insert_validated(&transaction_with_nonce(sender, 3), &tip_state), Err(MempoolRejection::StaleNonce));
+        assert_eq!(mempool.insert_validated(&transaction_with_nonce(sender, 0), &tip_state), Err(MempoolRejection::StaleNonce));
+        //next nonce is the lowest one accepted
+        assert_eq!(mempool.insert_validated(&transaction_with_nonce(sender, 4), &tip_state), Ok(MempoolAdmission::Pooled { promoted: vec![] }));
+        assert_eq!(mempool.transaction_map.len(), 1);
+    }
+
+    #[test]
+    fn insert_validated_rejects_far_future_nonce() {
+        let sender = Address::from([7; 20]);
+        let mut tip_state = HashMap::new();
+        tip_state.insert(sender, (3, 100));
+        let mut mempool = Mempool::new();
+        //accepted, but it waits for the nonces before it
+        assert_eq!(mempool.insert_validated(&transaction_with_nonce(sender, 3 + MAX_NONCE_GAP), &tip_state), Ok(MempoolAdmission::Orphaned));
+        assert_eq!(mempool.insert_validated(&transaction_with_nonce(sender, 4 + MAX_NONCE_GAP), &tip_state), Err(MempoolRejection::FutureNonce));
+        //unknown senders start at nonce 0
+        let new_sender = Address::from([8; 20]);
+        assert_eq!(mempool.insert_validated(&transaction_with_nonce(new_sender, 1), &tip_state), Ok(MempoolAdmission::Pooled { promoted: vec![] }));
+        assert_eq!(mempool.insert_validated(&transaction_with_nonce(new_sender, MAX_NONCE_GAP + 1), &tip_state), Err(MempoolRejection::FutureNonce));
+        //a transaction already in the pool is reported as a duplicate
+        assert_eq!(mempool.insert_validated(&transaction_with_nonce(new_sender, 1), &tip_state), Err(MempoolRejection::Duplicate));
+    }
+
+    #[test]
+    fn out_of_order_nonces_are_all_minable() {
+        let sender = Address::from([7; 20]);
+        let mut tip_state = HashMap::new();
+        tip_state.insert(sender, (0, 100));
+        let mut mempool = Mempool::new();
+        let txs: Vec<SignedTransaction> = (1..=3).map(|nonce| transaction_with_nonce(sender, nonce)).collect();
+        assert_eq!(mempool.insert_validated(&txs[2], &tip_state), Ok(MempoolAdmission::Orphaned));
+        assert_eq!(mempool.insert_validated(&txs[1], &tip_state), Ok(MempoolAdmission::Orphaned));
+        assert_eq!(mempool.orphan_count(), 2);
+        assert_eq!(
+            mempool.insert_validated(&txs[0], &tip_state),
+            Ok(MempoolAdmission::Pooled { promoted: vec![txs[1].hash(), txs[2].hash()] })
+        );
+        assert_eq!(mempool.orphan_count(), 0);
+        assert_eq!(mempool.transaction_map.len(), 3);
+
+        let mut state = tip_state.clone();
+        let selected: Vec<H256> = select_transactions(&mut mempool, &mut state).iter().map(|tx| tx.hash()).collect();
+        assert_eq!(selected, txs.iter().map(|tx| tx.hash()).collect::<Vec<H256>>());
+        assert_eq!(state[&sender], (3, 97));
+    }
+
+    #[test]
+    fn selected_transactions_fill_the_block_size_limit() {
+        let mut mempool = Mempool::new();
+        let mut state = HashMap::new();
+        let tx_size = bincode::serialized_size(&transaction_with_fee(0)).unwrap() as usize;
+        for _ in 0..(2 * BLOCK_SIZE_LIMIT / tx_size) {
+            let tx = transaction_with_fee(0);
+            state.insert(tx.transaction.sender, (0, 100));
+            mempool.insert(&tx);
+        }
+        let selected = select_transactions(&mut mempool, &mut state);
+        let mut block = generate_random_block(&H256::from([0; 32]));
+        block.content.data = selected;
+        //full, one more transaction would not fit
+        assert!(block.size_bytes() <= BLOCK_SIZE_LIMIT);
+        assert!(block.size_bytes() + tx_size > BLOCK_SIZE_LIMIT);
+    }
+
+    #[test]
+    fn advancing_nonce_promotes_orphans() {
+        let sender = Address::from([7; 20]);
+        let mut tip_state = HashMap::new();
+        tip_state.insert(sender, (0, 100));
+        let mut mempool = Mempool::new();
+        let second = transaction_with_nonce(sender, 2);
+        let third = transaction_with_nonce(sender, 3);
+        assert_eq!(mempool.insert_validated(&second, &tip_state), Ok(MempoolAdmission::Orphaned));
+        assert_eq!(mempool.insert_validated(&third, &tip_state), Ok(MempoolAdmission::Orphaned));
+        //a block confirms nonces 1 and 2 from elsewhere, so the parked nonce 2 is stale and 3 can go
+        tip_state.insert(sender, (2, 90));
+        assert_eq!(mempool.promote_orphans(&tip_state), vec![third.hash()]);
+        assert_eq!(mempool.orphan_count(), 0);
+        assert!(mempool.transaction_map.contains_key(&third.hash()));
+        assert!(!mempool.transaction_map.contains_key(&second.hash()));
+    }
+
+    #[test]
+    fn orphan_pool_is_capped() {
+        let tip_state = HashMap::new();
+        let mut mempool = Mempool::new();
+        let sender = Address::from([7; 20]);
+        for nonce in 0..MAX_ORPHANS_PER_SENDER as u32 {
+            assert_eq!(mempool.insert_validated(&transaction_with_nonce(sender, nonce + 2), &tip_state), Ok(MempoolAdmission::Orphaned));
+        }
+        let one_too_many = transaction_with_nonce(sender, MAX_ORPHANS_PER_SENDER as u32 + 2);
+        assert_eq!(mempool.insert_validated(&one_too_many, &tip_state), Err(MempoolRejection::OrphanPoolFull));
+
+        let mut senders = 0u32;
+        while mempool.orphan_count() < MAX_ORPHANS {
+            senders += 1;
+            let sender = Address::from_public_key_bytes(&senders.to_be_bytes());
+            assert_eq!(mempool.insert_validated(&transaction_with_nonce(sender, 2), &tip_state), Ok(MempoolAdmission::Orphaned));
+        }
+        let new_sender = Address::from([8; 20]);
+        assert_eq!(mempool.insert_validated(&transaction_with_nonce(new_sender, 2), &tip_state), Err(MempoolRejection::OrphanPoolFull));
+        //transactions that don't need to wait still get in
+        assert_eq!(mempool.insert_validated(&transaction_with_nonce(new_sender, 1), &tip_state), Ok(MempoolAdmission::Pooled { promoted: vec![] }));
+    }
+
+    #[test]
+    fn statistics_report_fee_spread_and_size() {
+        let mut mempool = Mempool::new();
+        assert_eq!(mempool.statistics(), MempoolStats::default());
+        let fees = [7, 3, 100, 1, 42, 9, 3, 15, 60, 2];
+        let transactions: Vec<SignedTransaction> = fees.iter().map(|fee| transaction_with_fee(*fee)).collect();
+        for tx in transactions.iter() {
+            assert_eq!(mempool.insert(tx), MempoolInsertResult::Inserted);
+        }
+        let stats = mempool.statistics();
+        assert_eq!(stats.count, 10);
+        assert_eq!(stats.total_bytes, transactions.iter().map(|tx| bincode::serialized_size(tx).unwrap() as usize).sum::<usize>());
+        assert_eq!(stats.min_fee, 1);
+        assert_eq!(stats.max_fee, 100);
+        //sorted: 1 2 3 3 7 9 15 42 60 100
+        assert_eq!(stats.median_fee, 8);
+        mempool.remove(&transactions[2].hash());
+        assert_eq!(mempool.statistics().max_fee, 60);
+        assert_eq!(mempool.statistics().median_fee, 7);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn concurrent_inserts_are_all_kept_once() {
+        use std::sync::{Arc, Barrier, Mutex};
+        use std::thread;
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let insert_all = |transactions: Vec<SignedTransaction>| -> Vec<MempoolInsertResult> {
+            //every thread waits at the barrier so the inserts race each other
+            let barrier = Arc::new(Barrier::new(transactions.len()));
+            let threads: Vec<thread::JoinHandle<MempoolInsertResult>> = transactions.into_iter().map(|tx| {
+                let mempool = Arc::clone(&mempool);
+                let barrier = Arc::clone(&barrier);
+                thread::spawn(move || {
+                    barrier.wait();
+                    return mempool.lock().unwrap().insert(&tx);
+                })
+            }).collect();
+            return threads.into_iter().map(|thread| thread.join().unwrap()).collect();
+        };
+
+        let results = insert_all((0..50).map(|i| transaction_with_fee(i)).collect());
+        assert!(results.iter().all(|result| *result == MempoolInsertResult::Inserted));
+        assert_eq!(mempool.lock().unwrap().transaction_map.len(), 50);
+        assert_eq!(mempool.lock().unwrap().transaction_set.len(), 50);
+
+        //the same transaction from 10 threads is added by exactly one of them
+        let tx = transaction_with_fee(1);
+        let results = insert_all(vec![tx.clone(); 10]);
+        assert_eq!(results.iter().filter(|result| **result == MempoolInsertResult::Inserted).count(), 1);
+        assert_eq!(results.iter().filter(|result| **result == MempoolInsertResult::Duplicate).count(), 9);
+        let mempool = mempool.lock().unwrap();
+        assert_eq!(mempool.transaction_map.len(), 51);
+        assert_eq!(mempool.transaction_set.len(), 51);
+        assert!(mempool.transaction_map.contains_key(&tx.hash()));
+    }
+
+    #[test]
+    fn estimate_fee_without_history_returns_minimum() {
+        let blockchain = Blockchain::new();
+        let mempool = Mempool::new();
+        assert_eq!(mempool.estimate_fee(&blockchain, 1), MIN_FEE_ESTIMATE);
+        assert_eq!(mempool.estimate_fee(&blockchain, 0), MIN_FEE_ESTIMATE);
+    }
+
+    #[test]
+    fn estimate_fee_uses_median_when_pool_is_shallow() {
+        let mut blockchain = Blockchain::new();
+        insert_block_with_fees(&mut blockchain, &[1, 2, 3]);
+        insert_block_with_fees(&mut blockchain, &[4, 5, 6, 7, 8, 9]);
+        let mempool = Mempool::new();
+        assert_eq!(mempool.estimate_fee(&blockchain, 1), 5);
+    }
+
+    #[test]
+    fn estimate_fee_rises_with_mempool_pressure() {
+        let mut blockchain = Blockchain::new();
+        let fees: Vec<u32> = (1..=100).collect();
+        insert_block_with_fees(&mut blockchain, &fees);
+        let mut mempool = Mempool::new();
+        //queue up several blocks worth of pending transactions
+        let tx_size = bincode::serialized_size(&transaction_with_fee(0)).unwrap() as usize;
+        for _ in 0..(8 * BLOCK_SIZE_LIMIT / tx_size) {
+            mempool.insert(&transaction_with_fee(0));
+        }
+        let next_block = mempool.estimate_fee(&blockchain, 1);
+        let within_two = mempool.estimate_fee(&blockchain, 2);
+        assert!(next_block > within_two);
+        assert!(within_two > 51);
+        //a target beyond the pool depth falls back to the median
+        assert_eq!(mempool.estimate_fee(&blockchain, 10), 51);
+    }
+
+    fn conflicting_transactions() -> (SignedTransaction, SignedTransaction) {
+        let sender = Address::from([7; 20]);
+        let cheap = transaction_with_nonce(sender, 1);
+        let mut expensive = transaction_with_nonce(sender, 1);
+        expensive.transaction.fee = 5;
+        return (cheap, expensive);
+    }
+
+    #[test]
+    fn seen_filter_has_no_false_negatives() {
+        let mut mempool = Mempool::new();
+        mempool.set_max_bytes(usize::MAX);
+        let transactions: Vec<SignedTransaction> = (0..10_000).map(|_| transaction_with_fee(1)).collect();
+        for tx in transactions.iter() {
+            assert_eq!(mempool.insert(tx), MempoolInsertResult::Inserted);
+        }
+        for tx in transactions.iter() {
+            assert!(mempool.has_seen(&tx.hash()));
+            mempool.remove(&tx.hash());
+        }
+        //still seen once they leave the pool
+        assert!(transactions.iter().all(|tx| mempool.has_seen(&tx.hash())));
+        assert!(!mempool.has_seen(&transaction_with_fee(1).hash()));
+    }
+
+    #[test]
+    fn seen_filter_grows_past_its_capacity() {
+        let mut mempool = Mempool::new();
+        let hashes: Vec<H256> = (0..SEEN_FILTER_CAPACITY + 1).map(|_| H256::from(rand::random::<[u8; 32]>())).collect();
+        for hash in hashes.iter() {
+            mempool.mark_seen(*hash);
+        }
+        assert_eq!(mempool.seen_filter_capacity, 2 * SEEN_FILTER_CAPACITY);
+        assert!(hashes.iter().all(|hash| mempool.has_seen(hash)));
+    }
+
+    /// Share of `lookups` unseen hashes the filter lets through to the set, which must turn every one away
+    fn seen_filter_false_positive_rate(mempool: &Mempool, lookups: usize) -> f64 {
+        let mut false_positives = 0;
+        for _ in 0..lookups {
+            let hash = H256::from(rand::random::<[u8; 32]>());
+            assert!(!mempool.has_seen(&hash));
+            if mempool.seen_filter.check(&hash) {
+                false_positives += 1;
+            }
+        }
+        return false_positives as f64 / lookups as f64;
+    }
+
+    #[test]
+    fn seen_filter_skips_the_set_for_unseen_hashes() {
+        let mut mempool = Mempool::new();
+        for _ in 0..SEEN_FILTER_CAPACITY {
+            mempool.mark_seen(H256::from(rand::random::<[u8; 32]>()));
+        }
+        //full, the filter still answers all but about 1% of unseen lookups without the set
+        assert!(seen_filter_false_positive_rate(&mempool, 100_000) < 2.0 * SEEN_FILTER_FP_RATE);
+        //and keeps doing so once it has grown
+        mempool.mark_seen(H256::from(rand::random::<[u8; 32]>()));
+        assert_eq!(mempool.seen_filter_capacity, 2 * SEEN_FILTER_CAPACITY);
+        assert!(seen_filter_false_positive_rate(&mempool, 100_000) < 2.0 * SEEN_FILTER_FP_RATE);
+    }
+
+    fn header_with_difficulty(difficulty: H256) -> Header {
+        return Header { parent: H256::from([0; 32]), nonce: 0, difficulty, timestamp: 0, merkle_root: H256::from([0; 32]) };
+    }
+
+    #[test]
+    fn parallel_search_finds_a_valid_nonce_and_stops() {
+        let pool = super::nonce_search_pool(4);
+        let header = header_with_difficulty(DIFFICULTY.into());
+        let search = search_nonce(&pool, &header, rand::random::<u32>(), u32::MAX / 4);
+        let mut solved = header.clone();
+        solved.nonce = search.nonce.unwrap();
+        assert!(solved.hash() <= header.difficulty);
+        //about 75 tries are needed at this difficulty, the other threads stop soon after the first find
+        assert!(search.tried < 1_000_000);
+    }
+
+    #[test]
+    fn parallel_search_tries_every_chunk_when_nothing_is_found() {
+        let pool = super::nonce_search_pool(4);
+        let search = search_nonce(&pool, &header_with_difficulty(H256::from([0; 32])), 0, 100);
+        assert_eq!(search, NonceSearch { nonce: None, tried: 400 });
+    }
+
+    #[test]
+    fn conflicting_transaction_with_higher_fee_replaces() {
+        let (cheap, expensive) = conflicting_transactions();
+        let mut mempool = Mempool::new();
+        assert_eq!(mempool.insert(&cheap), MempoolInsertResult::Inserted);
+        assert_eq!(mempool.insert(&expensive), MempoolInsertResult::Replaced(cheap.hash()));
+        assert_eq!(mempool.transaction_map.len(), 1);
+        assert!(mempool.transaction_map.contains_key(&expensive.hash()));
+        //the evicted transaction has been seen and is not taken back
+        assert_eq!(mempool.insert(&cheap), MempoolInsertResult::Duplicate);
+    }
+
+    #[test]
+    fn conflicting_transaction_with_lower_fee_is_rejected() {
+        let (cheap, expensive) = conflicting_transactions();
+        let mut mempool = Mempool::new();
+        assert_eq!(mempool.insert(&expensive), MempoolInsertResult::Inserted);
+        assert_eq!(mempool.insert(&cheap), MempoolInsertResult::Conflict(expensive.hash()));
+        assert_eq!(mempool.transaction_map.len(), 1);
+        assert!(mempool.transaction_map.contains_key(&expensive.hash()));
+    }
+
+    #[test]
+    fn conflicting_transaction_with_equal_fee_keeps_first() {
+        let (first, _) = conflicting_transactions();
+        let mut second = first.clone();
+        second.transaction.outputs[0].1 = 2;
+        let mut tip_state = HashMap::new();
+        tip_state.insert(first.transaction.sender, (0, 100));
+        let mut mempool = Mempool::new();
+        assert_eq!(mempool.insert_validated(&first, &tip_state), Ok(MempoolAdmission::Pooled { promoted: vec![] }));
+        assert_eq!(mempool.insert_validated(&second, &tip_state), Err(MempoolRejection::Conflict));
+        assert!(mempool.transaction_map.contains_key(&first.hash()));
+    }
+
+    #[test]
+    fn insert_rejects_fee_below_minimum() {
+        let mut mempool = Mempool::new();
+        mempool.set_min_fee(2);
+        assert_eq!(mempool.insert(&transaction_with_fee(1)), MempoolInsertResult::FeeTooLow);
+        assert!(mempool.transaction_map.is_empty());
+        assert_eq!(mempool.insert(&transaction_with_fee(2)), MempoolInsertResult::Inserted);
+        let tip_state = HashMap::new();
+        assert_eq!(mempool.insert_validated(&transaction_with_fee(0), &tip_state), Err(MempoolRejection::FeeTooLow));
+    }
+
+    #[test]
+    fn insert_rejects_transactions_past_max_bytes() {
+        let tx = transaction_with_fee(1);
+        let size = bincode::serialized_size(&tx).unwrap() as usize;
+        let mut mempool = Mempool::new();
+        mempool.set_max_bytes(2 * size);
+        assert_eq!(mempool.insert(&tx), MempoolInsertResult::Inserted);
+        assert_eq!(mempool.insert(&transaction_with_fee(1)), MempoolInsertResult::Inserted);
+        assert_eq!(mempool.total_bytes(), 2 * size);
+        assert_eq!(mempool.insert(&transaction_with_fee(1)), MempoolInsertResult::Full);
+        //removing a transaction makes room again
+        mempool.remove(&tx.hash());
+        assert_eq!(mempool.total_bytes(), size);
+        assert_eq!(mempool.insert(&transaction_with_fee(1)), MempoolInsertResult::Inserted);
+    }
+
+    #[test]
+    fn template_never_contains_conflicting_transactions() {
+        let sender = Address::from([7; 20]);
+        let mut mempool = Mempool::new();
+        for nonce in 1..4 {
+            for fee in 0..3 {
+                let mut tx = transaction_with_nonce(sender, nonce);
+                tx.transaction.fee = fee;
+                mempool.insert(&tx);
+            }
+        }
+        assert_eq!(mempool.transaction_map.len(), 3);
+        let mut state = HashMap::new();
+        state.insert(sender, (0, 100));
+        let transactions = select_transactions(&mut mempool, &mut state);
+        let mut seen = std::collections::HashSet::new();
+        for tx in transactions.iter() {
+            assert!(seen.insert((tx.transaction.sender, tx.transaction.account_nonce)));
+        }
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
\ No newline at end of file
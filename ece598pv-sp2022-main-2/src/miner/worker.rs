@@ -1,52 +1,117 @@
-use crossbeam::channel::{Receiver};
-use log::{info};
+use crossbeam::channel::{Receiver, select};
+use tracing::{info, warn};
 use crate::network::message::Message;
-use crate::types::hash::H256;
 use crate::types::{block::Block, hash::Hashable};
 use crate::network::server::Handle as ServerHandle;
 use std::thread;
+use crate::api::{Event, Events};
 use crate::blockchain::Blockchain;
-use std::sync::{Arc, Mutex};
+use crate::miner::Mempool;
+use crate::types::block::{BlockState, apply_block_to_state};
+use std::sync::{Arc, Mutex, RwLock};
 
 #[derive(Clone)]
 pub struct Worker {
     server: ServerHandle,
     finished_block_chan: Receiver<Block>,
-    blockchain: Arc<Mutex<Blockchain>>,
+    blockchain: Arc<RwLock<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+    block_state_map: Arc<Mutex<BlockState>>,
+    //disconnects when the node shuts down
+    shutdown_chan: Receiver<()>,
+    events: Events,
 }
 
 impl Worker {
     pub fn new(
         server: &ServerHandle,
         finished_block_chan: Receiver<Block>,
-        blockchain: &Arc<Mutex<Blockchain>>,
+        blockchain: &Arc<RwLock<Blockchain>>,
+        mempool: &Arc<Mutex<Mempool>>,
+        block_state_map: &Arc<Mutex<BlockState>>,
+        shutdown_chan: Receiver<()>,
+        events: &Events,
     ) -> Self {
         Self {
             server: server.clone(),
             finished_block_chan,
             blockchain: Arc::clone(blockchain),
+            mempool: Arc::clone(mempool),
+            block_state_map: Arc::clone(block_state_map),
+            shutdown_chan,
+            events: events.clone(),
         }
     }
 
-    pub fn start(self) {
-        thread::Builder::new()
+    pub fn start(self) -> thread::JoinHandle<()> {
+        let handle = thread::Builder::new()
             .name("miner-worker".to_string())
             .spawn(move || {
                 self.worker_loop();
             })
             .unwrap();
         info!("Miner initialized into paused mode");
+        return handle;
     }
 
     fn worker_loop(&self) {
         loop {
-            let _block = self.finished_block_chan.recv().expect("Receive finished block error");
-            let mut blockchain_ = self.blockchain.lock().unwrap();
-            blockchain_.insert(&_block);
-
-            let mut block_to_send = Vec::<H256>::new();
-            block_to_send.push(_block.hash());
-            self.server.broadcast(Message::NewBlockHashes(block_to_send));
+            //the miner handle keeps the block channel open, so wait on the shutdown channel as well
+            let _block = select! {
+                recv(self.finished_block_chan) -> block => match block {
+                    Ok(block) => block,
+                    Err(_) => return,
+                },
+                recv(self.shutdown_chan) -> _ => {
+                    info!("Miner worker shutting down");
+                    return;
+                }
+            };
+            //blocks are relayed right away, together with any others the miner has already finished
+            let mut finished = vec![_block];
+            finished.extend(self.finished_block_chan.try_iter());
+            let mut blocks = Vec::new();
+            for block in finished {
+                //our own miner stores the state of its blocks, a submitted block's is only stored once it's inserted
+                let state = {
+                    let block_state_map = self.block_state_map.lock().unwrap();
+                    if block_state_map.block_state_map.contains_key(&block.hash()) {
+                        None
+                    } else {
+                        let parent_state = block_state_map.block_state_map.get(&block.get_parent());
+                        match parent_state.map(|parent_state| apply_block_to_state(parent_state, &block)) {
+                            Some(Ok(state)) => Some(state),
+                            _ => {
+                                warn!("Dropping finished block {}: not valid on top of its parent's state", block.hash());
+                                continue;
+                            }
+                        }
+                    }
+                };
+                let mut blockchain_ = self.blockchain.write().unwrap();
+                //blocks may come from somewhere other than our own miner, never relay an invalid one
+                if let Err(e) = blockchain_.validate_and_insert(&block) {
+                    warn!("Dropping finished block {}: {}", block.hash(), e);
+                    continue;
+                }
+                if let Some(state) = state {
+                    //still holding the chain so the miner never sees the new tip without its state
+                    self.block_state_map.lock().unwrap().block_state_map.insert(block.hash(), state);
+                    let mut mempool = self.mempool.lock().unwrap();
+                    for tx in block.content.data.iter() {
+                        mempool.remove(&tx.hash());
+                    }
+                }
+                let (_, height) = blockchain_.block_map[&block.hash()];
+                drop(blockchain_);
+                self.events.publish(Event::NewBlock { hash: block.hash().to_string(), height });
+                blocks.push(block);
+            }
+            if blocks.is_empty() {
+                continue;
+            }
+            //pushed in full to peers that asked for it, the server announces the hashes to the others
+            self.server.broadcast(Message::Blocks(blocks));
         }
     }
 }
@@ -0,0 +1,222 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+//entries not seen for this long are dropped, the peer most likely left the network
+pub static ADDR_BOOK_MAX_AGE_SECS: u64 = 7 * 24 * 60 * 60;
+//most addresses remembered, past that a new one takes the place of an address we never connected to
+pub static ADDR_BOOK_CAPACITY: usize = 1000;
+//addresses that failed this many more times than they worked are dropped
+pub static ADDR_BOOK_MAX_NET_FAILURES: u32 = 5;
+
+/// What we know about one peer address
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AddrEntry {
+    //unix time in seconds we last connected to the address or heard of it
+    pub last_seen: u64,
+    //outbound connections that succeeded / failed
+    pub successes: u32,
+    pub failures: u32,
+}
+
+/// Peer addresses learned from handshakes and Addr gossip, kept on disk so a restarted node can find the network again
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct AddrBook {
+    entries: HashMap<SocketAddr, AddrEntry>,
+}
+
+pub fn unix_secs() -> u64 {
+    return SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+}
+
+impl AddrBook {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    /// Read an address book written by save, a missing file is an empty book
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::new()),
+            Err(e) => return Err(e),
+        };
+        return serde_json::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+    }
+
+    /// Write the book as JSON, through a temporary file so a crash never leaves half of it
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        return std::fs::rename(&tmp, path);
+    }
+
+    pub fn len(&self) -> usize {
+        return self.entries.len();
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<&AddrEntry> {
+        return self.entries.get(addr);
+    }
+
+    /// Remember an address we heard of. A full book makes room by evicting the address we never connected to that
+    /// failed most, the oldest one among equals; returns false if every address in it has worked
+    pub fn learn(&mut self, addr: SocketAddr, now: u64) -> bool {
+        if let Some(entry) = self.entries.get_mut(&addr) {
+            entry.last_seen = entry.last_seen.max(now);
+            return true;
+        }
+        if self.entries.len() >= ADDR_BOOK_CAPACITY {
+            let evicted = self.entries.iter()
+                .filter(|(_, entry)| entry.successes == 0)
+                .max_by_key(|(_, entry)| (entry.failures, std::cmp::Reverse(entry.last_seen)))
+                .map(|(addr, _)| *addr);
+            match evicted {
+                Some(evicted) => self.entries.remove(&evicted),
+                None => return false,
+            };
+        }
+        self.entries.insert(addr, AddrEntry { last_seen: now, successes: 0, failures: 0 });
+        return true;
+    }
+
+    pub fn record_success(&mut self, addr: SocketAddr, now: u64) {
+        let entry = self.entries.entry(addr).or_insert(AddrEntry { last_seen: now, successes: 0, failures: 0 });
+        entry.last_seen = now;
+        entry.successes += 1;
+    }
+
+    /// Count a failed connection, only for addresses already in the book. One failing far more often than it
+    /// works is dropped
+    pub fn record_failure(&mut self, addr: SocketAddr) {
+        if let Some(entry) = self.entries.get_mut(&addr) {
+            entry.failures += 1;
+            if entry.failures >= entry.successes.saturating_add(ADDR_BOOK_MAX_NET_FAILURES) {
+                self.entries.remove(&addr);
+            }
+        }
+    }
+
+    /// Drop the entries not seen for `max_age_secs`, returns how many were dropped
+    pub fn prune(&mut self, now: u64, max_age_secs: u64) -> usize {
+        let before = self.entries.len();
+        self.entries.retain(|_, entry| now.saturating_sub(entry.last_seen) <= max_age_secs);
+        return before - self.entries.len();
+    }
+
+    /// Addresses worth dialing first: the ones that worked more often than they failed, then the most recently seen
+    pub fn candidates(&self) -> Vec<SocketAddr> {
+        let mut entries: Vec<(&SocketAddr, &AddrEntry)> = self.entries.iter().collect();
+        entries.sort_by_key(|(_, entry)| (std::cmp::Reverse(entry.successes as i64 - entry.failures as i64), std::cmp::Reverse(entry.last_seen)));
+        return entries.into_iter().map(|(addr, _)| *addr).collect();
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod tests {
+    use super::{AddrBook, ADDR_BOOK_CAPACITY, ADDR_BOOK_MAX_AGE_SECS, ADDR_BOOK_MAX_NET_FAILURES};
+    use std::net::SocketAddr;
+
+    fn addr(port: u16) -> SocketAddr {
+        return SocketAddr::new("127.0.0.1".parse().unwrap(), port);
+    }
+
+    #[test]
+    fn file_round_trip() {
+        let path = std::env::temp_dir().join(format!("addr_book_round_trip_{}.json", std::process::id()));
+        let mut book = AddrBook::new();
+        book.learn(addr(1), 100);
+        book.record_success(addr(2), 200);
+        book.record_failure(addr(1));
+        book.learn("[::1]:3".parse().unwrap(), 300);
+        book.save(&path).unwrap();
+        let loaded = AddrBook::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, book);
+        assert_eq!(loaded.get(&addr(1)).unwrap().failures, 1);
+        assert_eq!(loaded.get(&addr(2)).unwrap().successes, 1);
+    }
+
+    #[test]
+    fn missing_file_is_empty_book() {
+        let path = std::env::temp_dir().join(format!("addr_book_missing_{}.json", std::process::id()));
+        assert_eq!(AddrBook::load(&path).unwrap().len(), 0);
+        std::fs::write(&path, "not json").unwrap();
+        assert_eq!(AddrBook::load(&path).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stale_entries_are_pruned() {
+        let mut book = AddrBook::new();
+        let now = 10 * ADDR_BOOK_MAX_AGE_SECS;
+        book.learn(addr(1), now - ADDR_BOOK_MAX_AGE_SECS - 1);
+        book.learn(addr(2), now - ADDR_BOOK_MAX_AGE_SECS);
+        book.record_success(addr(3), now);
+        assert_eq!(book.prune(now, ADDR_BOOK_MAX_AGE_SECS), 1);
+        assert!(book.get(&addr(1)).is_none());
+        assert_eq!(book.len(), 2);
+    }
+
+    #[test]
+    fn reliable_addresses_come_first() {
+        let mut book = AddrBook::new();
+        book.learn(addr(1), 300);
+        book.record_success(addr(2), 100);
+        book.record_success(addr(3), 200);
+        book.record_success(addr(3), 200);
+        book.record_failure(addr(3));
+        book.learn(addr(4), 400);
+        book.record_failure(addr(4));
+        assert_eq!(book.candidates(), vec![addr(3), addr(2), addr(1), addr(4)]);
+    }
+
+    #[test]
+    fn full_book_evicts_addresses_that_never_worked() {
+        let mut book = AddrBook::new();
+        for port in 0..ADDR_BOOK_CAPACITY as u16 - 2 {
+            book.record_success(addr(port), 1);
+        }
+        book.learn(addr(50000), 1);
+        book.learn(addr(50001), 2);
+        book.record_failure(addr(50001));
+        //the untried address that failed goes first
+        assert!(book.learn(addr(60000), 3));
+        assert!(book.get(&addr(50001)).is_none());
+        assert!(book.learn(addr(60001), 3));
+        assert!(book.get(&addr(50000)).is_none());
+        assert_eq!(book.len(), ADDR_BOOK_CAPACITY);
+        //once each one it holds has worked, new addresses are ignored
+        book.record_success(addr(60000), 3);
+        book.record_success(addr(60001), 3);
+        assert!(!book.learn(addr(60002), 3));
+        //known addresses are still refreshed
+        assert!(book.learn(addr(0), 4));
+        assert_eq!(book.get(&addr(0)).unwrap().last_seen, 4);
+    }
+
+    #[test]
+    fn failing_addresses_are_dropped() {
+        let mut book = AddrBook::new();
+        book.learn(addr(1), 1);
+        book.record_success(addr(2), 1);
+        for _ in 0..ADDR_BOOK_MAX_NET_FAILURES - 1 {
+            book.record_failure(addr(1));
+            book.record_failure(addr(2));
+        }
+        book.record_failure(addr(1));
+        assert!(book.get(&addr(1)).is_none());
+        //one success buys one more failure
+        book.record_failure(addr(2));
+        assert!(book.get(&addr(2)).is_some());
+        book.record_failure(addr(2));
+        assert!(book.get(&addr(2)).is_none());
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -0,0 +1,168 @@
+use rand::Rng;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use super::message::{MessageClass, MessageType};
+use super::traffic::{classify, TrafficClass};
+
+// Degraded links for experiments, applied by the server to what it writes to peers, in place of tc/netem. Every
+// message is held back by a latency drawn uniformly between half and one and a half times latency_ms, and a
+// loss_pct share of them is never written. Handshake messages are never lost, or peers would not connect at all.
+// The traffic counters see every message before it is held back or lost, they count what the node tried to send.
+
+/// How the link to a peer is degraded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Impairment {
+    pub latency_ms: u64,
+    //percentage of the messages lost, from 0 to 100
+    pub loss_pct: f64,
+    //only this class of messages is lost, all of them if None
+    pub loss_class: Option<TrafficClass>,
+}
+
+impl Default for Impairment {
+    fn default() -> Self {
+        return Impairment { latency_ms: 0, loss_pct: 0.0, loss_class: None };
+    }
+}
+
+impl Impairment {
+    pub fn new(latency_ms: u64, loss_pct: f64, loss_class: Option<TrafficClass>) -> Result<Self, String> {
+        if !(0.0..=100.0).contains(&loss_pct) {
+            return Err(format!("loss percentage {} is not between 0 and 100", loss_pct));
+        }
+        return Ok(Impairment { latency_ms, loss_pct, loss_class });
+    }
+
+    pub fn is_none(&self) -> bool {
+        return self.latency_ms == 0 && self.loss_pct == 0.0;
+    }
+
+    /// Whether to lose this serialized message instead of writing it
+    pub fn loses(&self, bytes: &[u8], rng: &mut impl Rng) -> bool {
+        if self.loss_pct == 0.0 {
+            return false;
+        }
+        if MessageType::of(bytes).map(MessageType::class) == Some(MessageClass::Handshake) {
+            return false;
+        }
+        if let Some(class) = self.loss_class {
+            if classify(bytes) != class {
+                return false;
+            }
+        }
+        return rng.gen_range(0.0..100.0) < self.loss_pct;
+    }
+
+    /// How long to hold the next message back
+    pub fn sample_latency(&self, rng: &mut impl Rng) -> Duration {
+        if self.latency_ms == 0 {
+            return Duration::ZERO;
+        }
+        let micros = self.latency_ms * 1000;
+        return Duration::from_micros(rng.gen_range(micros / 2..=micros + micros / 2));
+    }
+}
+
+/// The impairment of every link: a node-wide one, and the ones set for single peers through the API
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Impairments {
+    pub default: Impairment,
+    pub per_peer: HashMap<SocketAddr, Impairment>,
+}
+
+impl Impairments {
+    pub fn for_peer(&self, addr: &SocketAddr) -> Impairment {
+        return *self.per_peer.get(addr).unwrap_or(&self.default);
+    }
+}
+
+/// Parse the class a loss is limited to, as given on the command line and the API
+pub fn parse_loss_class(class: &str) -> Result<Option<TrafficClass>, String> {
+    return match class {
+        "all" => Ok(None),
+        "blocks" => Ok(Some(TrafficClass::Blocks)),
+        "transactions" => Ok(Some(TrafficClass::Transactions)),
+        "compressed" => Ok(Some(TrafficClass::Compressed)),
+        "control" => Ok(Some(TrafficClass::Control)),
+        _ => Err(format!("unknown message class {}, expected all, blocks, transactions, compressed or control", class)),
+    };
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_loss_class, Impairment, Impairments};
+    use crate::network::message::Message;
+    use crate::network::traffic::TrafficClass;
+    use crate::types::hash::generate_random_hash;
+    use std::time::Duration;
+
+    fn bytes(msg: &Message) -> Vec<u8> {
+        return bincode::serialize(msg).unwrap();
+    }
+
+    #[test]
+    fn handshake_is_never_lost() {
+        let version = Message::Version {
+            protocol_version: 1,
+            genesis_hash: generate_random_hash(),
+            tip_height: 0,
+            user_agent: String::new(),
+            peer_addr: "127.0.0.1:6000".parse().unwrap(),
+            node_nonce: 0,
+            max_protocol_version: 1,
+            features: 0,
+        };
+        let total_loss = Impairment::new(0, 100.0, None).unwrap();
+        let mut rng = rand::thread_rng();
+        assert!(!total_loss.loses(&bytes(&version), &mut rng));
+        assert!(!total_loss.loses(&bytes(&Message::VerAck), &mut rng));
+        assert!(total_loss.loses(&bytes(&Message::Ping(1)), &mut rng));
+    }
+
+    #[test]
+    fn loss_can_be_limited_to_one_class() {
+        let lose_transactions = Impairment::new(0, 100.0, Some(TrafficClass::Transactions)).unwrap();
+        let mut rng = rand::thread_rng();
+        assert!(lose_transactions.loses(&bytes(&Message::Transactions(vec![])), &mut rng));
+        assert!(!lose_transactions.loses(&bytes(&Message::NewBlockHashes(vec![])), &mut rng));
+        assert!(!Impairment::default().loses(&bytes(&Message::Transactions(vec![])), &mut rng));
+        assert_eq!(parse_loss_class("transactions"), Ok(Some(TrafficClass::Transactions)));
+        assert_eq!(parse_loss_class("all"), Ok(None));
+        assert!(parse_loss_class("votes").is_err());
+    }
+
+    #[test]
+    fn loss_percentage_is_checked() {
+        assert!(Impairment::new(0, 100.5, None).is_err());
+        assert!(Impairment::new(0, -1.0, None).is_err());
+        assert!(Impairment::new(0, 0.0, None).unwrap().is_none());
+        assert!(!Impairment::new(1, 0.0, None).unwrap().is_none());
+    }
+
+    #[test]
+    fn latency_is_sampled_around_the_mean() {
+        let impairment = Impairment::new(100, 0.0, None).unwrap();
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            let latency = impairment.sample_latency(&mut rng);
+            assert!(latency >= Duration::from_millis(50) && latency <= Duration::from_millis(150));
+        }
+        assert_eq!(Impairment::default().sample_latency(&mut rng), Duration::ZERO);
+    }
+
+    #[test]
+    fn peers_default_to_the_node_wide_impairment() {
+        let mut impairments = Impairments::default();
+        impairments.default = Impairment::new(10, 0.0, None).unwrap();
+        let slow = "127.0.0.1:6001".parse().unwrap();
+        impairments.per_peer.insert(slow, Impairment::new(500, 0.0, None).unwrap());
+        assert_eq!(impairments.for_peer(&slow).latency_ms, 500);
+        assert_eq!(impairments.for_peer(&"127.0.0.1:6002".parse().unwrap()).latency_ms, 10);
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
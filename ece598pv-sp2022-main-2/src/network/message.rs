@@ -1,15 +1,484 @@
 use serde::{Serialize, Deserialize};
+use std::net::SocketAddr;
 
-use crate::types::{hash::H256, block::Block, transaction::SignedTransaction};
+use crate::types::{hash::H256, block::{Block, Header}, transaction::{IntegrityError, SignedTransaction}};
+
+//bumped whenever the wire format or message semantics change
+pub static PROTOCOL_VERSION: u32 = 4;
+//the oldest protocol version this node still speaks; version 3 nodes send their Version without
+//max_protocol_version and features, and get none of the optional features
+pub static MIN_PROTOCOL_VERSION: u32 = 3;
+//optional features, announced as a bitmask in Version messages and used toward a peer only if both sides support them
+pub static FEATURE_COMPRESSION: u32 = 1 << 0;
+pub static FEATURE_PUSH_BLOCKS: u32 = 1 << 1;
+pub static FEATURE_COMPACT_BLOCKS: u32 = 1 << 2;
+//announced in Version messages, informational only
+pub static USER_AGENT: &str = concat!("bitcoin/", env!("CARGO_PKG_VERSION"));
+//Blocks and Transactions messages at least this large are compressed for peers that sent SendCompressed
+pub static COMPRESSION_THRESHOLD: usize = 1024;
+//a compressed payload that would expand past this is dropped instead of decompressed
+pub static MAX_DECOMPRESSED_SIZE: usize = 32 * 1024 * 1024;
+
+//most entries a decoded message may hold per collection, larger ones are malformed whatever their byte size
+pub static MAX_INVENTORY_ITEMS: usize = 50_000;
+pub static MAX_BLOCKS_PER_MESSAGE: usize = 2000;
+pub static MAX_HEADERS_PER_MESSAGE: usize = 2000;
+pub static MAX_ADDRS_PER_MESSAGE: usize = 1000;
+pub static MAX_USER_AGENT_LEN: usize = 256;
+//number of Message variants this node knows; a compatible peer may send newer ones, which are skipped
+pub static MESSAGE_VARIANTS: u32 = 23;
+
+/// Why a block or transaction sent by a peer was dropped
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum RejectReason {
+    InvalidPoW,
+    InvalidSignature,
+    //a transaction spends more than the sender has or uses the wrong nonce
+    InsufficientBalance,
+    DuplicateBlock,
+    MerkleRootMismatch,
+    //a transaction is signed by a key that doesn't own its sender address
+    SenderMismatch,
+    //the block is over MAX_BLOCK_SIZE
+    BlockTooLarge,
+}
+
+impl From<IntegrityError> for RejectReason {
+    fn from(error: IntegrityError) -> Self {
+        return match error {
+            IntegrityError::InvalidSignature => RejectReason::InvalidSignature,
+            IntegrityError::SenderMismatch => RejectReason::SenderMismatch,
+        };
+    }
+}
+
+/// The optional features this node supports
+pub fn supported_features() -> u32 {
+    let mut features = FEATURE_PUSH_BLOCKS | FEATURE_COMPACT_BLOCKS;
+    if cfg!(feature = "compression") {
+        features |= FEATURE_COMPRESSION;
+    }
+    return features;
+}
+
+/// The protocol versions a node speaks and the optional features it supports, as announced in its Version message
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Capabilities {
+    pub min_version: u32,
+    pub max_version: u32,
+    pub features: u32,
+}
+
+/// What a peer and this node agreed on in the handshake
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Agreement {
+    //the highest protocol version both speak
+    pub version: u32,
+    //the features both support
+    pub features: u32,
+}
+
+/// Why the protocol versions of a peer and this node don't overlap
+#[derive(Debug, PartialEq)]
+pub enum VersionMismatch {
+    //the newest version the peer speaks is older than the oldest we do
+    TooOld { peer_max: u32, our_min: u32 },
+    //the oldest version the peer speaks is newer than the newest we do
+    TooNew { peer_min: u32, our_max: u32 },
+}
+
+impl std::fmt::Display for VersionMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        return match self {
+            VersionMismatch::TooOld { peer_max, our_min } => write!(f, "speaks protocol versions up to {}, below our minimum of {}", peer_max, our_min),
+            VersionMismatch::TooNew { peer_min, our_max } => write!(f, "speaks protocol versions from {}, above our maximum of {}", peer_min, our_max),
+        };
+    }
+}
+
+impl Capabilities {
+    pub fn ours() -> Self {
+        return Capabilities { min_version: MIN_PROTOCOL_VERSION, max_version: PROTOCOL_VERSION, features: supported_features() };
+    }
+
+    /// Agree on a protocol version and feature set with a peer. `tolerance` widens our range of versions by that many
+    /// on each side, for peers just outside it
+    pub fn negotiate(&self, peer: &Capabilities, tolerance: u32) -> Result<Agreement, VersionMismatch> {
+        let our_min = self.min_version.saturating_sub(tolerance);
+        let our_max = self.max_version.saturating_add(tolerance);
+        if peer.max_version < our_min {
+            return Err(VersionMismatch::TooOld { peer_max: peer.max_version, our_min });
+        }
+        if peer.min_version > our_max {
+            return Err(VersionMismatch::TooNew { peer_min: peer.min_version, our_max });
+        }
+        return Ok(Agreement { version: self.max_version.min(peer.max_version), features: self.features & peer.features });
+    }
+}
+
+/// Why we are closing the connection to a peer
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum DisconnectReason {
+    //every inbound slot is taken
+    TooManyPeers,
+    //dropped to make room for a peer the node was configured to connect to
+    Evicted,
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
-    Ping(String),
-    Pong(String),
+    //the nonce is the sender's timestamp, echoed back in the Pong
+    Ping(u64),
+    Pong(u64),
     NewBlockHashes(Vec<H256>),
     GetBlocks(Vec<H256>),
     Blocks(Vec<Block>),
+    //asks for the blocks of the peer's longest chain after the first of these locator hashes it has, up to and
+    //including stop_hash if given, answered with Blocks
+    GetBlocksAfter { locator: Vec<H256>, stop_hash: Option<H256> },
+    //asks for the headers of the peer's longest chain after the first of these locator hashes it has, answered with Headers
+    GetHeaders(Vec<H256>),
+    Headers(Vec<Header>),
     NewTransactionHashes(Vec<H256>),
     GetTransactions(Vec<H256>),
     Transactions(Vec<SignedTransaction>),
+    //first message on a new connection; peer_addr is the sender's own P2P address, node_nonce is
+    //picked at random when the sender starts and tells us when two connections lead to the same node.
+    //the sender speaks every protocol version from protocol_version up to max_protocol_version, and
+    //supports the FEATURE_* bits set in features. The oldest version goes first, where a version 3 node looks
+    //for its own: it accepts no other without --version-tolerance, and never reads the fields after node_nonce
+    Version { protocol_version: u32, genesis_hash: H256, tip_height: u32, user_agent: String, peer_addr: SocketAddr, node_nonce: u64, max_protocol_version: u32, features: u32 },
+    VerAck,
+    //asks the peer to announce the transactions in its mempool
+    GetMempool,
+    //sent back when a block or transaction from the peer is invalid
+    Reject { rejected_hash: H256, reason: RejectReason },
+    //asks the peer for the P2P addresses of the nodes it is connected to
+    GetAddr,
+    Addr(Vec<SocketAddr>),
+    //tells the peer we can decompress, sent once the handshake is complete
+    SendCompressed,
+    //a snappy compressed, bincode serialized Blocks or Transactions message
+    Compressed(Vec<u8>),
+    //last message before we close the connection
+    Disconnect(DisconnectReason),
+    //asks the peer to push new blocks in full instead of announcing their hashes, sent once the handshake is complete
+    SendBlocks,
+    //asks the peer to relay new blocks as compact blocks, sent once the handshake is complete
+    SendCompactBlocks,
+    //a block without its transactions; the receiver fills them in from its mempool and asks for the rest with GetTransactions
+    CompactBlock { header: Header, tx_hashes: Vec<H256> },
 }
+
+/// A Message variant without its fields, in the same order so it serializes to the same variant index.
+/// Lets a serialized message be told apart without decoding it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum MessageType {
+    Ping,
+    Pong,
+    NewBlockHashes,
+    GetBlocks,
+    Blocks,
+    GetBlocksAfter,
+    GetHeaders,
+    Headers,
+    NewTransactionHashes,
+    GetTransactions,
+    Transactions,
+    Version,
+    VerAck,
+    GetMempool,
+    Reject,
+    GetAddr,
+    Addr,
+    SendCompressed,
+    Compressed,
+    Disconnect,
+    SendBlocks,
+    SendCompactBlocks,
+    CompactBlock,
+}
+
+/// What a message is about
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageClass {
+    Block,
+    Transaction,
+    Handshake,
+    //a Blocks or Transactions message, which one is only known once it is decompressed
+    Compressed,
+    //keepalive, address gossip and everything else
+    Control,
+}
+
+impl MessageType {
+    /// The type a serialized message starts with, None if it is too short or a type this node doesn't know
+    pub fn of(bytes: &[u8]) -> Option<MessageType> {
+        if bytes.len() < 4 {
+            return None;
+        }
+        return bincode::deserialize(&bytes[..4]).ok();
+    }
+
+    /// What messages of this type are about
+    pub fn class(self) -> MessageClass {
+        return match self {
+            MessageType::NewBlockHashes | MessageType::GetBlocks | MessageType::Blocks | MessageType::GetBlocksAfter
+            | MessageType::GetHeaders | MessageType::Headers | MessageType::CompactBlock => MessageClass::Block,
+            MessageType::NewTransactionHashes | MessageType::GetTransactions | MessageType::Transactions
+            | MessageType::GetMempool => MessageClass::Transaction,
+            MessageType::Version | MessageType::VerAck => MessageClass::Handshake,
+            MessageType::Compressed => MessageClass::Compressed,
+            MessageType::Ping | MessageType::Pong | MessageType::Reject | MessageType::GetAddr | MessageType::Addr
+            | MessageType::SendCompressed | MessageType::Disconnect | MessageType::SendBlocks
+            | MessageType::SendCompactBlocks => MessageClass::Control,
+        };
+    }
+}
+
+//the fields of a protocol version 3 Version message, which ended at node_nonce
+#[derive(Deserialize)]
+struct LegacyVersion(u32, H256, u32, String, SocketAddr, u64);
+
+impl Message {
+    /// Deserialize a message, reading a Version without max_protocol_version and features as one from a
+    /// version 3 node, which speaks that version only
+    pub fn decode(bytes: &[u8]) -> bincode::Result<Message> {
+        let error = match bincode::deserialize(bytes) {
+            Ok(msg) => return Ok(msg),
+            Err(e) => e,
+        };
+        if MessageType::of(bytes) != Some(MessageType::Version) {
+            return Err(error);
+        }
+        let LegacyVersion(protocol_version, genesis_hash, tip_height, user_agent, peer_addr, node_nonce) = bincode::deserialize(&bytes[4..])?;
+        return Ok(Message::Version {
+            protocol_version,
+            genesis_hash,
+            tip_height,
+            user_agent,
+            peer_addr,
+            node_nonce,
+            max_protocol_version: protocol_version,
+            features: 0,
+        });
+    }
+
+    /// The variant index a serialized message starts with, None if the payload is too short to hold one
+    pub fn variant_of(bytes: &[u8]) -> Option<u32> {
+        if bytes.len() < 4 {
+            return None;
+        }
+        return Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]));
+    }
+
+    /// The type of the message, what it is about is its class
+    pub fn message_type(&self) -> MessageType {
+        return match self {
+            Message::Ping(_) => MessageType::Ping,
+            Message::Pong(_) => MessageType::Pong,
+            Message::NewBlockHashes(_) => MessageType::NewBlockHashes,
+            Message::GetBlocks(_) => MessageType::GetBlocks,
+            Message::Blocks(_) => MessageType::Blocks,
+            Message::GetBlocksAfter { .. } => MessageType::GetBlocksAfter,
+            Message::GetHeaders(_) => MessageType::GetHeaders,
+            Message::Headers(_) => MessageType::Headers,
+            Message::NewTransactionHashes(_) => MessageType::NewTransactionHashes,
+            Message::GetTransactions(_) => MessageType::GetTransactions,
+            Message::Transactions(_) => MessageType::Transactions,
+            Message::Version { .. } => MessageType::Version,
+            Message::VerAck => MessageType::VerAck,
+            Message::GetMempool => MessageType::GetMempool,
+            Message::Reject { .. } => MessageType::Reject,
+            Message::GetAddr => MessageType::GetAddr,
+            Message::Addr(_) => MessageType::Addr,
+            Message::SendCompressed => MessageType::SendCompressed,
+            Message::Compressed(_) => MessageType::Compressed,
+            Message::Disconnect(_) => MessageType::Disconnect,
+            Message::SendBlocks => MessageType::SendBlocks,
+            Message::SendCompactBlocks => MessageType::SendCompactBlocks,
+            Message::CompactBlock { .. } => MessageType::CompactBlock,
+        };
+    }
+
+    /// Check the collections a peer sent are within the per message caps, returns what is over otherwise
+    pub fn check_limits(&self) -> Result<(), String> {
+        let over = |what: &str, len: usize, cap: usize| -> Result<(), String> {
+            if len > cap {
+                return Err(format!("{} {} over the cap of {}", len, what, cap));
+            }
+            return Ok(());
+        };
+        return match self {
+            Message::NewBlockHashes(hashes) | Message::GetBlocks(hashes) | Message::GetHeaders(hashes)
+            | Message::NewTransactionHashes(hashes) | Message::GetTransactions(hashes) => over("hashes", hashes.len(), MAX_INVENTORY_ITEMS),
+            Message::GetBlocksAfter { locator, .. } => over("locator hashes", locator.len(), MAX_INVENTORY_ITEMS),
+            Message::Blocks(blocks) => over("blocks", blocks.len(), MAX_BLOCKS_PER_MESSAGE),
+            Message::Headers(headers) => over("headers", headers.len(), MAX_HEADERS_PER_MESSAGE),
+            Message::Transactions(txs) => over("transactions", txs.len(), MAX_INVENTORY_ITEMS),
+            Message::Addr(addrs) => over("addresses", addrs.len(), MAX_ADDRS_PER_MESSAGE),
+            Message::Version { user_agent, .. } => over("user agent bytes", user_agent.len(), MAX_USER_AGENT_LEN),
+            Message::CompactBlock { tx_hashes, .. } => over("transaction hashes", tx_hashes.len(), MAX_INVENTORY_ITEMS),
+            _ => Ok(()),
+        };
+    }
+
+    /// Whether the message may be sent compressed
+    pub fn is_compressible(&self) -> bool {
+        return matches!(self, Message::Blocks(_) | Message::Transactions(_));
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DecompressError {
+    //this node was built without the compression feature
+    Unsupported,
+    //the payload would expand past MAX_DECOMPRESSED_SIZE
+    TooLarge,
+    Malformed,
+}
+
+impl std::fmt::Display for DecompressError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let reason = match self {
+            DecompressError::Unsupported => "compression is not supported by this node",
+            DecompressError::TooLarge => "the decompressed payload is too large",
+            DecompressError::Malformed => "the payload is not valid snappy data",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+/// Compress a serialized message
+#[cfg(feature = "compression")]
+pub fn compress(raw: &[u8]) -> Vec<u8> {
+    return snap::raw::Encoder::new().compress_vec(raw).unwrap();
+}
+
+/// Decompress the payload of a Compressed message back into a serialized message
+#[cfg(feature = "compression")]
+pub fn decompress(payload: &[u8]) -> Result<Vec<u8>, DecompressError> {
+    let len = snap::raw::decompress_len(payload).map_err(|_| DecompressError::Malformed)?;
+    if len > MAX_DECOMPRESSED_SIZE {
+        return Err(DecompressError::TooLarge);
+    }
+    return snap::raw::Decoder::new().decompress_vec(payload).map_err(|_| DecompressError::Malformed);
+}
+
+#[cfg(not(feature = "compression"))]
+pub fn decompress(_payload: &[u8]) -> Result<Vec<u8>, DecompressError> {
+    return Err(DecompressError::Unsupported);
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod tests {
+    use super::{Agreement, Capabilities, DisconnectReason, Message, MessageClass, MessageType, RejectReason, VersionMismatch, FEATURE_COMPACT_BLOCKS, FEATURE_COMPRESSION, FEATURE_PUSH_BLOCKS, MESSAGE_VARIANTS};
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::generate_random_hash;
+
+    #[test]
+    fn message_types_match_the_serialized_variant() {
+        let block = generate_random_block(&generate_random_hash());
+        let hashes = vec![generate_random_hash()];
+        //one of every variant, message_type would not compile with one missing
+        let messages = vec![
+            Message::Ping(1),
+            Message::Pong(1),
+            Message::NewBlockHashes(hashes.clone()),
+            Message::GetBlocks(hashes.clone()),
+            Message::Blocks(vec![block.clone()]),
+            Message::GetBlocksAfter { locator: hashes.clone(), stop_hash: None },
+            Message::GetHeaders(hashes.clone()),
+            Message::Headers(vec![block.get_header()]),
+            Message::NewTransactionHashes(hashes.clone()),
+            Message::GetTransactions(hashes.clone()),
+            Message::Transactions(vec![]),
+            Message::Version {
+                protocol_version: 3,
+                genesis_hash: hashes[0],
+                tip_height: 0,
+                user_agent: String::new(),
+                peer_addr: "127.0.0.1:6000".parse().unwrap(),
+                node_nonce: 0,
+                max_protocol_version: 4,
+                features: 0,
+            },
+            Message::VerAck,
+            Message::GetMempool,
+            Message::Reject { rejected_hash: hashes[0], reason: RejectReason::InvalidPoW },
+            Message::GetAddr,
+            Message::Addr(vec![]),
+            Message::SendCompressed,
+            Message::Compressed(vec![]),
+            Message::Disconnect(DisconnectReason::Evicted),
+            Message::SendBlocks,
+            Message::SendCompactBlocks,
+            Message::CompactBlock { header: block.get_header(), tx_hashes: hashes.clone() },
+        ];
+        assert_eq!(messages.len() as u32, MESSAGE_VARIANTS);
+        for msg in &messages {
+            assert_eq!(MessageType::of(&bincode::serialize(msg).unwrap()), Some(msg.message_type()), "{:?}", msg.message_type());
+        }
+        assert_eq!(MessageType::of(&MESSAGE_VARIANTS.to_le_bytes()), None);
+        assert_eq!(MessageType::of(&[0; 3]), None);
+    }
+
+    #[test]
+    fn messages_are_classified() {
+        assert_eq!(MessageType::CompactBlock.class(), MessageClass::Block);
+        assert_eq!(MessageType::GetHeaders.class(), MessageClass::Block);
+        assert_eq!(MessageType::GetMempool.class(), MessageClass::Transaction);
+        assert_eq!(MessageType::Version.class(), MessageClass::Handshake);
+        assert_eq!(MessageType::VerAck.class(), MessageClass::Handshake);
+        assert_eq!(MessageType::Compressed.class(), MessageClass::Compressed);
+        assert_eq!(MessageType::SendCompactBlocks.class(), MessageClass::Control);
+        assert_eq!(MessageType::Ping.class(), MessageClass::Control);
+    }
+
+    #[test]
+    fn ranges_and_features_are_intersected() {
+        let ours = Capabilities { min_version: 3, max_version: 4, features: FEATURE_PUSH_BLOCKS | FEATURE_COMPACT_BLOCKS };
+        let newer = Capabilities { min_version: 4, max_version: 6, features: FEATURE_COMPRESSION | FEATURE_PUSH_BLOCKS };
+        assert_eq!(ours.negotiate(&newer, 0), Ok(Agreement { version: 4, features: FEATURE_PUSH_BLOCKS }));
+        let legacy = Capabilities { min_version: 3, max_version: 3, features: 0 };
+        assert_eq!(ours.negotiate(&legacy, 0), Ok(Agreement { version: 3, features: 0 }));
+        let ancient = Capabilities { min_version: 1, max_version: 2, features: 0 };
+        assert_eq!(ours.negotiate(&ancient, 0), Err(VersionMismatch::TooOld { peer_max: 2, our_min: 3 }));
+        assert_eq!(ours.negotiate(&ancient, 1), Ok(Agreement { version: 2, features: 0 }));
+        let future = Capabilities { min_version: 6, max_version: 7, features: 0 };
+        assert_eq!(ours.negotiate(&future, 0), Err(VersionMismatch::TooNew { peer_min: 6, our_max: 4 }));
+        assert_eq!(ours.negotiate(&future, 0).unwrap_err().to_string(), "speaks protocol versions from 6, above our maximum of 4");
+    }
+
+    #[test]
+    fn legacy_version_is_decoded_without_features() {
+        let version = Message::Version {
+            protocol_version: 3,
+            genesis_hash: generate_random_hash(),
+            tip_height: 5,
+            user_agent: "old".to_string(),
+            peer_addr: "127.0.0.1:6000".parse().unwrap(),
+            node_nonce: 9,
+            max_protocol_version: 4,
+            features: FEATURE_PUSH_BLOCKS,
+        };
+        let bytes = bincode::serialize(&version).unwrap();
+        assert_eq!(MessageType::of(&bytes), Some(MessageType::Version));
+        //a version 3 node stopped at the nonce
+        let legacy = &bytes[..bytes.len() - 8];
+        match Message::decode(legacy).unwrap() {
+            Message::Version { protocol_version, tip_height, node_nonce, max_protocol_version, features, .. } => {
+                assert_eq!((protocol_version, tip_height, node_nonce), (3, 5, 9));
+                assert_eq!((max_protocol_version, features), (3, 0));
+            }
+            _ => panic!(),
+        }
+        assert!(matches!(Message::decode(&bytes).unwrap(), Message::Version { features, .. } if features == FEATURE_PUSH_BLOCKS));
+        //only Version gets a second try
+        assert!(Message::decode(&bincode::serialize(&Message::Ping(1)).unwrap()[..6]).is_err());
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
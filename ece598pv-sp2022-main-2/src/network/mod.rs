@@ -1,4 +1,13 @@
+pub mod addr_book;
+pub mod ban_list;
+pub mod frame;
+pub mod impairment;
 pub mod message;
+pub mod noise;
 pub mod peer;
+pub mod propagation;
+pub mod queue;
 pub mod server;
+pub mod socks5;
+pub mod traffic;
 pub mod worker;
@@ -1,30 +1,78 @@
 use super::message::Message;
 use futures::{channel::mpsc, sink::SinkExt};
-use log::trace;
+use tracing::trace;
 use smol::Async;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+/// The remote end of a connection. IPv4 peers reaching a dual-stack listener show up as
+/// IPv4-mapped IPv6 addresses, they are keyed by their plain IPv4 address like everywhere else
+pub fn remote_addr(stream: &Async<std::net::TcpStream>) -> std::io::Result<std::net::SocketAddr> {
+    let addr = stream.get_ref().peer_addr()?;
+    if let std::net::SocketAddr::V6(v6) = addr {
+        if let Some(v4) = v6.ip().to_ipv4_mapped() {
+            return Ok(std::net::SocketAddr::new(v4.into(), v6.port()));
+        }
+    }
+    return Ok(addr);
+}
 
 pub fn new(
-    stream: &Async<std::net::TcpStream>,
-) -> std::io::Result<(mpsc::UnboundedReceiver<Vec<u8>>, Handle)> {
+    addr: std::net::SocketAddr,
+    direction: Direction,
+) -> (mpsc::UnboundedReceiver<Vec<u8>>, Handle) {
     let (write_sender, write_receiver) = mpsc::unbounded();
-    let addr = stream.get_ref().peer_addr()?;
     let handle = Handle {
         write_queue: write_sender,
         addr,
+        direction,
+        compression: Arc::new(Compression::default()),
+        push_blocks: Arc::new(AtomicBool::new(false)),
+        compact_blocks: Arc::new(AtomicBool::new(false)),
+        features: Arc::new(AtomicU32::new(0)),
     };
-    Ok((write_receiver, handle))
+    (write_receiver, handle)
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Direction {
     Incoming,
     Outgoing,
 }
 
+/// Whether a peer accepts compressed messages, and the payload bytes compressed to and from it so far
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CompressionStats {
+    pub enabled: bool,
+    //serialized size of the messages that went over the wire compressed, before compression
+    pub raw_bytes_sent: u64,
+    pub compressed_bytes_sent: u64,
+    pub raw_bytes_received: u64,
+    pub compressed_bytes_received: u64,
+}
+
+//shared by every clone of a peer's handle
+#[derive(Debug, Default)]
+struct Compression {
+    enabled: AtomicBool,
+    raw_bytes_sent: AtomicU64,
+    compressed_bytes_sent: AtomicU64,
+    raw_bytes_received: AtomicU64,
+    compressed_bytes_received: AtomicU64,
+}
+
 #[derive(Clone, Debug)]
 pub struct Handle {
     addr: std::net::SocketAddr,
     write_queue: mpsc::UnboundedSender<Vec<u8>>,
+    direction: Direction,
+    compression: Arc<Compression>,
+    //the peer sent SendBlocks, new blocks go to it in full
+    push_blocks: Arc<AtomicBool>,
+    //the peer sent SendCompactBlocks, new blocks go to it as header and transaction hashes
+    compact_blocks: Arc<AtomicBool>,
+    //the FEATURE_* bits agreed on in the handshake, none before it; a feature is only used if agreed on
+    features: Arc<AtomicU32>,
 }
 
 #[cfg(any(test,test_utilities))]
@@ -34,7 +82,7 @@ pub struct TestReceiver {
 
 impl Handle {
     pub fn write(&mut self, msg: Message) {
-        let buffer = bincode::serialize(&msg).unwrap();
+        let buffer = self.encode(&msg);
         smol::block_on(async move {
             if self.write_queue.send(buffer).await.is_err() {
                 trace!("Trying to send to disconnected peer");
@@ -42,16 +90,111 @@ impl Handle {
         });
     }
 
+    /// Serialize a message, compressing it if the peer accepts compressed messages and it is worth it
+    fn encode(&self, msg: &Message) -> Vec<u8> {
+        let buffer = bincode::serialize(msg).unwrap();
+        #[cfg(feature = "compression")]
+        {
+            if self.compression.enabled.load(Ordering::Relaxed)
+                && self.has_feature(super::message::FEATURE_COMPRESSION)
+                && msg.is_compressible()
+                && buffer.len() >= super::message::COMPRESSION_THRESHOLD {
+                let compressed = super::message::compress(&buffer);
+                if compressed.len() < buffer.len() {
+                    self.compression.raw_bytes_sent.fetch_add(buffer.len() as u64, Ordering::Relaxed);
+                    self.compression.compressed_bytes_sent.fetch_add(compressed.len() as u64, Ordering::Relaxed);
+                    return bincode::serialize(&Message::Compressed(compressed)).unwrap();
+                }
+            }
+        }
+        return buffer;
+    }
+
+    /// Start compressing large messages to this peer, once it said it can decompress them
+    pub fn enable_compression(&self) {
+        self.compression.enabled.store(true, Ordering::Relaxed);
+    }
+
+    /// Account for a compressed message received from this peer
+    pub fn record_compressed_received(&self, raw_bytes: usize, compressed_bytes: usize) {
+        self.compression.raw_bytes_received.fetch_add(raw_bytes as u64, Ordering::Relaxed);
+        self.compression.compressed_bytes_received.fetch_add(compressed_bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn compression_stats(&self) -> CompressionStats {
+        return CompressionStats {
+            enabled: self.compression.enabled.load(Ordering::Relaxed),
+            raw_bytes_sent: self.compression.raw_bytes_sent.load(Ordering::Relaxed),
+            compressed_bytes_sent: self.compression.compressed_bytes_sent.load(Ordering::Relaxed),
+            raw_bytes_received: self.compression.raw_bytes_received.load(Ordering::Relaxed),
+            compressed_bytes_received: self.compression.compressed_bytes_received.load(Ordering::Relaxed),
+        };
+    }
+
+    /// Record the features agreed on with this peer in the handshake
+    pub fn set_features(&self, features: u32) {
+        self.features.store(features, Ordering::Relaxed);
+    }
+
+    pub fn features(&self) -> u32 {
+        return self.features.load(Ordering::Relaxed);
+    }
+
+    fn has_feature(&self, feature: u32) -> bool {
+        return self.features() & feature != 0;
+    }
+
+    /// Push new blocks to this peer in full, once it asked for them with SendBlocks
+    pub fn enable_push_blocks(&self) {
+        self.push_blocks.store(true, Ordering::Relaxed);
+    }
+
+    pub fn wants_pushed_blocks(&self) -> bool {
+        return self.push_blocks.load(Ordering::Relaxed) && self.has_feature(super::message::FEATURE_PUSH_BLOCKS);
+    }
+
+    /// Relay new blocks to this peer as compact blocks, once it asked for them with SendCompactBlocks
+    pub fn enable_compact_blocks(&self) {
+        self.compact_blocks.store(true, Ordering::Relaxed);
+    }
+
+    pub fn wants_compact_blocks(&self) -> bool {
+        return self.compact_blocks.load(Ordering::Relaxed) && self.has_feature(super::message::FEATURE_COMPACT_BLOCKS);
+    }
+
     pub fn addr(&self) -> &std::net::SocketAddr {
         &self.addr
     }
 
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// Close the write queue; the writer task then shuts the connection down
+    pub fn disconnect(&self) {
+        self.write_queue.close_channel();
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        self.write_queue.is_closed()
+    }
+
     #[cfg(any(test,test_utilities))]
     pub fn test_handle() -> (Handle, TestReceiver) {
+        return Self::test_handle_at(std::net::SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)), 12321));
+    }
+
+    #[cfg(any(test,test_utilities))]
+    pub fn test_handle_at(addr: std::net::SocketAddr) -> (Handle, TestReceiver) {
         let (s,r) = mpsc::unbounded();
         (Handle {
-            addr: std::net::SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)), 12321),
+            addr,
             write_queue: s,
+            direction: Direction::Incoming,
+            compression: Arc::new(Compression::default()),
+            push_blocks: Arc::new(AtomicBool::new(false)),
+            compact_blocks: Arc::new(AtomicBool::new(false)),
+            features: Arc::new(AtomicU32::new(0)),
         },
         TestReceiver {
             r
@@ -66,4 +209,11 @@ impl TestReceiver {
         let msg: Message = bincode::deserialize(&bytes).unwrap();
         msg
     }
+
+    /// Like recv, but returns None once the peer has been disconnected and all queued messages are read
+    pub fn recv_or_closed(&mut self) -> Option<Message> {
+        let bytes = smol::block_on(futures::stream::StreamExt::next(&mut self.r))?;
+        let msg: Message = bincode::deserialize(&bytes).unwrap();
+        Some(msg)
+    }
 }
\ No newline at end of file
@@ -0,0 +1,141 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::types::hash::H256;
+
+//percentiles are taken over this many of the latest blocks
+pub static PROPAGATION_WINDOW: usize = 1000;
+
+/// How long one block took to reach this node after its miner stamped it
+#[derive(Clone, Debug, PartialEq)]
+pub struct PropagationSample {
+    pub block: H256,
+    pub delay: Duration,
+    //unix time in milliseconds when the block was inserted
+    pub received_at: u128,
+}
+
+/// What /network/propagation reports
+#[derive(Clone, Debug, PartialEq)]
+pub struct PropagationSummary {
+    //every block ever recorded, including the ones that left the window
+    pub recorded: u64,
+    //blocks stamped later than they arrived, because of clock skew; counted with a delay of zero
+    pub negative: u64,
+    pub p50: Option<Duration>,
+    pub p90: Option<Duration>,
+    pub p99: Option<Duration>,
+    pub max: Option<Duration>,
+    //newest first
+    pub recent: Vec<PropagationSample>,
+}
+
+#[derive(Default)]
+struct Samples {
+    window: VecDeque<PropagationSample>,
+    recorded: u64,
+    negative: u64,
+}
+
+/// Block propagation delays, shared between the network workers that record them and the API
+#[derive(Clone, Default)]
+pub struct PropagationStats {
+    samples: Arc<Mutex<Samples>>,
+}
+
+impl PropagationStats {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    /// Record a block received from a peer, `mined_at` is the timestamp in its header in milliseconds
+    pub fn record(&self, block: H256, mined_at: u128) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+        self.record_at(block, mined_at, now);
+    }
+
+    fn record_at(&self, block: H256, mined_at: u128, now: u128) {
+        let mut samples = self.samples.lock().unwrap();
+        samples.recorded += 1;
+        if now < mined_at {
+            samples.negative += 1;
+        }
+        let delay = Duration::from_millis(now.saturating_sub(mined_at) as u64);
+        if samples.window.len() == PROPAGATION_WINDOW {
+            samples.window.pop_front();
+        }
+        samples.window.push_back(PropagationSample { block, delay, received_at: now });
+    }
+
+    /// Percentiles over the window and the `recent` latest samples
+    pub fn summary(&self, recent: usize) -> PropagationSummary {
+        let samples = self.samples.lock().unwrap();
+        let mut delays: Vec<Duration> = samples.window.iter().map(|sample| sample.delay).collect();
+        delays.sort();
+        //nearest rank
+        let percentile = |p: usize| {
+            if delays.is_empty() {
+                return None;
+            }
+            let rank = (p * delays.len()).div_ceil(100).max(1);
+            return Some(delays[rank - 1]);
+        };
+        return PropagationSummary {
+            recorded: samples.recorded,
+            negative: samples.negative,
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max: delays.last().copied(),
+            recent: samples.window.iter().rev().take(recent).cloned().collect(),
+        };
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use crate::types::hash::generate_random_hash;
+    use super::{PropagationStats, PROPAGATION_WINDOW};
+
+    #[test]
+    fn percentiles_over_window() {
+        let stats = PropagationStats::new();
+        assert_eq!(stats.summary(5).p50, None);
+        for ms in 1..=100u128 {
+            stats.record_at(generate_random_hash(), 0, ms);
+        }
+        let summary = stats.summary(3);
+        assert_eq!(summary.recorded, 100);
+        assert_eq!(summary.p50, Some(Duration::from_millis(50)));
+        assert_eq!(summary.p90, Some(Duration::from_millis(90)));
+        assert_eq!(summary.p99, Some(Duration::from_millis(99)));
+        assert_eq!(summary.max, Some(Duration::from_millis(100)));
+        let recent: Vec<Duration> = summary.recent.iter().map(|sample| sample.delay).collect();
+        assert_eq!(recent, vec![Duration::from_millis(100), Duration::from_millis(99), Duration::from_millis(98)]);
+
+        //old samples leave the window but stay counted
+        for _ in 0..PROPAGATION_WINDOW {
+            stats.record_at(generate_random_hash(), 0, 1);
+        }
+        let summary = stats.summary(0);
+        assert_eq!(summary.recorded, 100 + PROPAGATION_WINDOW as u64);
+        assert_eq!(summary.max, Some(Duration::from_millis(1)));
+    }
+
+    #[test]
+    fn clock_skew_is_clamped_and_counted() {
+        let stats = PropagationStats::new();
+        stats.record_at(generate_random_hash(), 5, 2);
+        stats.record_at(generate_random_hash(), 1, 2);
+        let summary = stats.summary(2);
+        assert_eq!(summary.negative, 1);
+        assert_eq!(summary.recent[1].delay, Duration::ZERO);
+        assert_eq!(summary.recent[0].delay, Duration::from_millis(1));
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
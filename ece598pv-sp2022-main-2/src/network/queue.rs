@@ -0,0 +1,263 @@
+use serde::Serialize;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::message::{MessageClass, MessageType};
+use super::peer;
+
+// Messages read by the server reach the workers through two lanes. Transaction traffic goes to a best-effort lane;
+// the gossip part of it is shed, and counted, once that lane holds the high-water mark, requests and replies wait for room.
+// Everything else, blocks above all, goes to a priority lane that is never shed: when it is full the server stops
+// reading from the peer until the workers catch up, and TCP pushes back on the sender. Workers always empty the
+// priority lane first, so a new block never waits behind queued transactions; each lane keeps its own order.
+
+//capacity of the priority lane before the server stops reading from peers
+pub static PRIORITY_QUEUE_CAPACITY: usize = 10000;
+//default number of queued transaction gossip messages past which new ones are shed
+pub static DEFAULT_TX_HIGH_WATER: usize = 5000;
+
+/// A serialized message and the peer it came from
+pub type Incoming = (Vec<u8>, peer::Handle);
+
+/// The workers are gone, or the server closed the queue
+#[derive(Debug, PartialEq)]
+pub struct QueueClosed;
+
+/// How the queue between the server and the workers is doing, for /network/stats
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct QueueStats {
+    pub queued_priority: usize,
+    pub queued_best_effort: usize,
+    pub tx_high_water: usize,
+    //transaction gossip dropped because the best-effort lane was full, since the node started
+    pub shed_tx_messages: u64,
+}
+
+/// Whether a serialized message is transaction traffic, which waits in the best-effort lane behind block traffic.
+/// Compressed messages never are, they may hold blocks
+pub fn is_transaction_traffic(bytes: &[u8]) -> bool {
+    return MessageType::of(bytes).map(MessageType::class) == Some(MessageClass::Transaction);
+}
+
+/// Whether a serialized message only carries transaction gossip, which may be shed under load.
+/// Transactions is not, it answers our own GetTransactions and a shed reply would look like an unanswered request
+pub fn is_best_effort(bytes: &[u8]) -> bool {
+    return MessageType::of(bytes) == Some(MessageType::NewTransactionHashes);
+}
+
+/// The server's end of the queue
+#[derive(Clone)]
+pub struct MsgSender {
+    priority: smol::channel::Sender<Incoming>,
+    best_effort: smol::channel::Sender<Incoming>,
+    tx_high_water: usize,
+    shed: Arc<AtomicU64>,
+}
+
+/// The workers' end of the queue
+#[derive(Clone)]
+pub struct MsgReceiver {
+    priority: smol::channel::Receiver<Incoming>,
+    best_effort: smol::channel::Receiver<Incoming>,
+}
+
+/// A queue shedding transaction gossip past `tx_high_water` queued messages
+pub fn channel(tx_high_water: usize) -> (MsgSender, MsgReceiver) {
+    let (priority_tx, priority_rx) = smol::channel::bounded(PRIORITY_QUEUE_CAPACITY);
+    let (best_effort_tx, best_effort_rx) = smol::channel::bounded(tx_high_water.max(1));
+    let sender = MsgSender { priority: priority_tx, best_effort: best_effort_tx, tx_high_water, shed: Arc::new(AtomicU64::new(0)) };
+    let receiver = MsgReceiver { priority: priority_rx, best_effort: best_effort_rx };
+    return (sender, receiver);
+}
+
+impl MsgSender {
+    /// Queue a message, waiting for room unless it is transaction gossip, which is shed when its lane is full
+    pub async fn send(&self, msg: Incoming) -> Result<(), QueueClosed> {
+        if !is_transaction_traffic(&msg.0) {
+            return self.priority.send(msg).await.map_err(|_| QueueClosed);
+        }
+        if !is_best_effort(&msg.0) {
+            return self.best_effort.send(msg).await.map_err(|_| QueueClosed);
+        }
+        return match self.best_effort.try_send(msg) {
+            Ok(()) => Ok(()),
+            Err(smol::channel::TrySendError::Full(_)) => {
+                self.shed.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(smol::channel::TrySendError::Closed(_)) => Err(QueueClosed),
+        };
+    }
+
+    /// Close both lanes, the workers stop once they are drained
+    pub fn close(&self) {
+        self.priority.close();
+        self.best_effort.close();
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        return QueueStats {
+            queued_priority: self.priority.len(),
+            queued_best_effort: self.best_effort.len(),
+            tx_high_water: self.tx_high_water,
+            shed_tx_messages: self.shed.load(Ordering::Relaxed),
+        };
+    }
+}
+
+impl MsgReceiver {
+    /// Whether the server closed the queue; messages may still be waiting in it
+    pub fn is_closed(&self) -> bool {
+        return self.priority.is_closed();
+    }
+
+    /// The next message, from the priority lane whenever it has one
+    pub async fn recv(&self) -> Result<Incoming, QueueClosed> {
+        if let Ok(msg) = self.priority.try_recv() {
+            return Ok(msg);
+        }
+        if let Ok(msg) = self.best_effort.try_recv() {
+            return Ok(msg);
+        }
+        //both lanes are empty, take whatever comes first; the lanes close together, so a closed one
+        //only ends the wait once the other is closed and drained too
+        let priority = async {
+            match self.priority.recv().await {
+                Ok(msg) => Ok(msg),
+                Err(_) => self.best_effort.recv().await,
+            }
+        };
+        let best_effort = async {
+            match self.best_effort.recv().await {
+                Ok(msg) => Ok(msg),
+                Err(_) => self.priority.recv().await,
+            }
+        };
+        return smol::future::or(priority, best_effort).await.map_err(|_| QueueClosed);
+    }
+
+    #[cfg(test)]
+    pub fn try_recv(&self) -> Result<Incoming, QueueClosed> {
+        return self.priority.try_recv().or_else(|_| self.best_effort.try_recv()).map_err(|_| QueueClosed);
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod tests {
+    use super::{channel, is_best_effort, is_transaction_traffic};
+    use crate::network::message::Message;
+    use crate::network::peer;
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::generate_random_hash;
+
+    fn bytes(msg: &Message) -> Vec<u8> {
+        return bincode::serialize(msg).unwrap();
+    }
+
+    #[test]
+    fn transaction_gossip_is_best_effort() {
+        assert!(is_best_effort(&bytes(&Message::NewTransactionHashes(vec![generate_random_hash()]))));
+        assert!(!is_best_effort(&bytes(&Message::Transactions(vec![]))));
+        assert!(!is_best_effort(&bytes(&Message::Blocks(vec![generate_random_block(&generate_random_hash())]))));
+        assert!(!is_best_effort(&bytes(&Message::GetTransactions(vec![]))));
+        assert!(!is_best_effort(&bytes(&Message::Compressed(vec![]))));
+        assert!(!is_best_effort(&[]));
+    }
+
+    #[test]
+    fn transaction_requests_wait_behind_blocks_but_are_not_shed() {
+        assert!(is_transaction_traffic(&bytes(&Message::GetMempool)));
+        assert!(is_transaction_traffic(&bytes(&Message::Transactions(vec![]))));
+        assert!(!is_transaction_traffic(&bytes(&Message::NewBlockHashes(vec![]))));
+        assert!(!is_transaction_traffic(&bytes(&Message::Compressed(vec![]))));
+        let (sender, receiver) = channel(2);
+        let (handle, _r) = peer::Handle::test_handle();
+        smol::block_on(sender.send((bytes(&Message::NewTransactionHashes(vec![])), handle.clone()))).unwrap();
+        smol::block_on(sender.send((bytes(&Message::GetTransactions(vec![])), handle.clone()))).unwrap();
+        smol::block_on(sender.send((bytes(&Message::NewTransactionHashes(vec![])), handle.clone()))).unwrap();
+        assert_eq!(sender.stats().shed_tx_messages, 1);
+        //a request waits for room instead of being shed
+        let waiting = std::thread::spawn({
+            let sender = sender.clone();
+            let handle = handle.clone();
+            move || smol::block_on(sender.send((bytes(&Message::GetMempool), handle)))
+        });
+        let (first, _) = smol::block_on(receiver.recv()).unwrap();
+        assert!(matches!(bincode::deserialize(&first).unwrap(), Message::NewTransactionHashes(_)));
+        waiting.join().unwrap().unwrap();
+        assert_eq!(sender.stats().shed_tx_messages, 1);
+        let order: Vec<Message> = (0..2).map(|_| bincode::deserialize(&smol::block_on(receiver.recv()).unwrap().0).unwrap()).collect();
+        assert!(matches!(order[..], [Message::GetTransactions(_), Message::GetMempool]));
+    }
+
+    #[test]
+    fn transaction_replies_wait_for_room_instead_of_being_shed() {
+        let (sender, receiver) = channel(1);
+        let (handle, _r) = peer::Handle::test_handle();
+        smol::block_on(sender.send((bytes(&Message::NewTransactionHashes(vec![])), handle.clone()))).unwrap();
+        let waiting = std::thread::spawn({
+            let sender = sender.clone();
+            move || smol::block_on(sender.send((bytes(&Message::Transactions(vec![])), handle)))
+        });
+        let (first, _) = smol::block_on(receiver.recv()).unwrap();
+        assert!(matches!(bincode::deserialize(&first).unwrap(), Message::NewTransactionHashes(_)));
+        waiting.join().unwrap().unwrap();
+        let (reply, _) = smol::block_on(receiver.recv()).unwrap();
+        assert!(matches!(bincode::deserialize(&reply).unwrap(), Message::Transactions(_)));
+        assert_eq!(sender.stats().shed_tx_messages, 0);
+    }
+
+    #[test]
+    fn block_is_dequeued_before_a_thousand_transaction_messages() {
+        let (sender, receiver) = channel(2000);
+        let (handle, _r) = peer::Handle::test_handle();
+        for i in 0..1000 {
+            let msg = if i % 2 == 0 { Message::NewTransactionHashes(vec![generate_random_hash()]) } else { Message::GetTransactions(vec![]) };
+            smol::block_on(sender.send((bytes(&msg), handle.clone()))).unwrap();
+        }
+        smol::block_on(sender.send((bytes(&Message::Blocks(vec![generate_random_block(&generate_random_hash())])), handle.clone()))).unwrap();
+        let (first, _) = smol::block_on(receiver.recv()).unwrap();
+        assert!(matches!(bincode::deserialize(&first).unwrap(), Message::Blocks(_)));
+        //the transactions follow in the order they came in
+        for i in 0..1000 {
+            let msg: Message = bincode::deserialize(&smol::block_on(receiver.recv()).unwrap().0).unwrap();
+            assert_eq!(matches!(msg, Message::NewTransactionHashes(_)), i % 2 == 0);
+        }
+    }
+
+    #[test]
+    fn full_best_effort_lane_sheds_and_counts() {
+        let (sender, receiver) = channel(3);
+        let (handle, _r) = peer::Handle::test_handle();
+        for _ in 0..5 {
+            smol::block_on(sender.send((bytes(&Message::NewTransactionHashes(vec![])), handle.clone()))).unwrap();
+        }
+        smol::block_on(sender.send((bytes(&Message::Ping(1)), handle.clone()))).unwrap();
+        let stats = sender.stats();
+        assert_eq!((stats.queued_best_effort, stats.queued_priority, stats.shed_tx_messages), (3, 1, 2));
+        //the block lane is served first even though it was filled last
+        let (first, _) = smol::block_on(receiver.recv()).unwrap();
+        assert!(matches!(bincode::deserialize(&first).unwrap(), Message::Ping(1)));
+        for _ in 0..3 {
+            let (msg, _) = smol::block_on(receiver.recv()).unwrap();
+            assert!(matches!(bincode::deserialize(&msg).unwrap(), Message::NewTransactionHashes(_)));
+        }
+    }
+
+    #[test]
+    fn closed_queue_drains_then_ends() {
+        let (sender, receiver) = channel(3);
+        let (handle, _r) = peer::Handle::test_handle();
+        smol::block_on(sender.send((bytes(&Message::Ping(1)), handle.clone()))).unwrap();
+        smol::block_on(sender.send((bytes(&Message::Transactions(vec![])), handle.clone()))).unwrap();
+        sender.close();
+        assert!(smol::block_on(receiver.recv()).is_ok());
+        assert!(smol::block_on(receiver.recv()).is_ok());
+        assert!(smol::block_on(receiver.recv()).is_err());
+        assert!(smol::block_on(sender.send((bytes(&Message::Ping(2)), handle))).is_err());
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
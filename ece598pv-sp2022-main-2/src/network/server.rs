@@ -1,31 +1,150 @@
 use crate::types::address::Address;
+use crate::types::block::Block;
+use crate::types::hash::{H256, Hashable};
 use super::peer;
 use super::message;
+use super::noise;
+use super::queue::{MsgSender, QueueStats};
+use super::frame;
+use super::socks5;
+use super::impairment::{Impairment, Impairments};
+use super::traffic::{PeerTraffic, TrafficBreakdown, TrafficCounters, TrafficStats, TRAFFIC_TABLE_CAPACITY};
+use super::addr_book::{unix_secs, AddrBook, ADDR_BOOK_MAX_AGE_SECS};
+use super::ban_list::BanList;
 
 use async_dup::Arc as AsyncArc;
-use futures::io::{AsyncReadExt, AsyncWriteExt};
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use futures::io::sink;
 use futures::io::{BufReader, BufWriter};
 use futures::{channel::oneshot, stream::StreamExt};
-use smol::{Async, Executor};
-use log::{debug, info, trace};
+use smol::{Async, Executor, Timer};
+use snow::Keypair;
+use tracing::{debug, info, trace, warn};
+use lru::LruCache;
+use rand::Rng;
+use rand::seq::SliceRandom;
+use std::collections::{HashMap, HashSet};
 use std::net;
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+//most address book entries dialed at once at startup, however many outbound slots are free
+pub static ADDR_BOOK_MAX_DIALS: usize = 8;
+//most addresses taken into the address book from one message, the rest are ignored
+pub static MAX_LEARNED_ADDRS_PER_MESSAGE: usize = 20;
+//default for how long a banned peer's IP is refused before it may connect again
+pub static BAN_DURATION_SECS: u64 = 600;
+//peers whose misbehavior score reaches this are banned
+pub static BAN_SCORE_THRESHOLD: u32 = 100;
+//how often the address book is written if it changed
+pub static ADDR_BOOK_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+//how long after a ban or misbehavior the ban list is written, so a burst of them is written once
+pub static BAN_LIST_SAVE_DELAY: Duration = Duration::from_secs(1);
+//how many block/transaction hashes we remember each peer knowing about
+pub static KNOWN_INVENTORY_CAPACITY: usize = 5000;
+//larger blocks are announced by hash even to peers that asked for them in full
+pub static MAX_PUSHED_BLOCK_SIZE: usize = 64 * 1024;
+//how often every peer is pinged to measure latency
+pub static PING_INTERVAL_SECS: u64 = 30;
+//peers that leave this many pings in a row unanswered are dropped
+pub static MAX_MISSED_PINGS: u32 = 3;
+//a peer silent for this long is pinged to check it is still there
+pub static KEEPALIVE_IDLE_SECS: u64 = 60;
+//a keepalive ping left unanswered this long, with nothing else heard from the peer, drops it
+pub static KEEPALIVE_TIMEOUT_SECS: u64 = 20;
+//default cap on a single frame, far above a full Blocks message at BLOCK_SIZE_LIMIT
+pub static DEFAULT_MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+//peers sending this many frames over the cap are disconnected
+pub static MAX_OVERSIZED_FRAMES: u32 = 3;
+//peers sending this many frames whose payload fails the checksum are disconnected
+pub static MAX_CORRUPT_FRAMES: u32 = 3;
+//reconnection attempts to a persistent peer back off 1s, 2s, 4s, ... up to this
+pub static RECONNECT_BACKOFF_CAP_SECS: u64 = 60;
+//each reconnection delay gets up to this fraction added at random, so peers that dropped together don't redial in step
+pub static RECONNECT_JITTER: f64 = 0.25;
+//a connection to a persistent peer lasting this long resets its backoff, shorter ones count as failed dials
+pub static STABLE_CONNECTION_UPTIME: Duration = Duration::from_secs(30);
+//default caps on connected peers per direction; persistent peers may go over the outbound one
+pub static DEFAULT_MAX_INBOUND: usize = 117;
+pub static DEFAULT_MAX_OUTBOUND: usize = 8;
+//how long a turned away peer gets to read our Disconnect before the socket is closed
+pub static TURN_AWAY_LINGER: Duration = Duration::from_secs(1);
+//how long the SOCKS5 proxy gets to connect us to a peer
+pub static PROXY_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+//how long a direct TCP connect to a peer may take, a blackholed address would otherwise wait out the kernel's SYN retries
+pub static CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 
+/// Builds the first message written to a persistent peer after every (re)connection, given the peer's address
+pub type Greeting = Arc<dyn Fn(&net::SocketAddr) -> message::Message + Send + Sync>;
+
+//the two halves of a connection, after the encrypted handshake if there is one
+type Transport = (Box<dyn AsyncRead + Unpin + Send>, Box<dyn AsyncWrite + Unpin + Send>);
+
+/// Parse a P2P address, also accepting IPv6 literals with an interface name as zone, e.g. `[fe80::1%eth0]:6000`
+pub fn parse_addr(addr: &str) -> std::io::Result<net::SocketAddr> {
+    use std::net::ToSocketAddrs;
+    if let Ok(addr) = addr.parse::<net::SocketAddr>() {
+        return Ok(addr);
+    }
+    let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("invalid socket address {}", addr));
+    //std only parses numeric zones, the resolver also knows interface names
+    let (host, port) = addr.rsplit_once(':').ok_or_else(invalid)?;
+    let host = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).ok_or_else(invalid)?;
+    let port = port.parse::<u16>().map_err(|_| invalid())?;
+    return (host, port).to_socket_addrs()?.next().ok_or_else(invalid);
+}
+
+/// A server listening on every one of `addrs`, accepted peers all end up in `msg_sink`
 pub fn new(
-    addr: std::net::SocketAddr,
-    msg_sink: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+    addrs: Vec<std::net::SocketAddr>,
+    msg_sink: MsgSender,
+    max_message_size: usize,
 ) -> std::io::Result<(Context, Handle)> {
+    if addrs.is_empty() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "no P2P address to listen on"));
+    }
     let (control_signal_sender, control_signal_receiver) = smol::channel::bounded(10000);
+    let (dial_schedule_sender, dial_schedule_receiver) = smol::channel::unbounded();
     let handle = Handle {
         control_chan: control_signal_sender.clone(),
     };
     let ctx = Context {
         peers: std::collections::HashMap::new(),
-        addr,
+        ban_list: BanList::new(),
+        ban_list_path: None,
+        ban_list_dirty: false,
+        ban_duration: Duration::from_secs(BAN_DURATION_SECS),
+        known_inv: HashMap::new(),
+        peer_stats: HashMap::new(),
+        handshaked: HashSet::new(),
+        nodes: HashMap::new(),
+        connections: HashMap::new(),
+        traffic: HashMap::new(),
+        impairments: Arc::new(RwLock::new(Impairments::default())),
+        persistent: HashMap::new(),
+        dialing: HashSet::new(),
+        shutting_down: false,
+        max_message_size,
+        keepalive_idle: Duration::from_secs(KEEPALIVE_IDLE_SECS),
+        keepalive_timeout: Duration::from_secs(KEEPALIVE_TIMEOUT_SECS),
+        max_inbound: DEFAULT_MAX_INBOUND,
+        max_outbound: DEFAULT_MAX_OUTBOUND,
+        encryption: None,
+        proxy: None,
+        proxy_names: HashMap::new(),
+        relay_fanout: None,
+        relay_fanout_blocks: false,
+        addr_book: AddrBook::new(),
+        addr_book_path: None,
+        addr_book_dirty: false,
+        addrs,
         control_chan: control_signal_receiver,
         control_sender: control_signal_sender,
+        dial_schedule: dial_schedule_sender,
+        dial_schedule_receiver,
         new_msg_chan: msg_sink,
     };
     Ok((ctx, handle))
@@ -33,19 +152,164 @@ pub fn new(
 
 pub struct Context {
     peers: std::collections::HashMap<std::net::SocketAddr, peer::Handle>,
-    addr: std::net::SocketAddr,
+    //banned peer IPs and misbehavior scores, written to ban_list_path if there is one
+    ban_list: BanList,
+    ban_list_path: Option<PathBuf>,
+    //the list changed since it was last written, a write is already scheduled
+    ban_list_dirty: bool,
+    ban_duration: Duration,
+    //hashes each peer has announced to us or we have announced to it
+    known_inv: HashMap<std::net::SocketAddr, LruCache<H256, ()>>,
+    peer_stats: HashMap<std::net::SocketAddr, PeerStats>,
+    //peers that completed the Version/VerAck handshake, only these get broadcasts
+    handshaked: HashSet<std::net::SocketAddr>,
+    //node nonce from a peer's Version -> the connection to that node, at most one per node
+    nodes: HashMap<u64, std::net::SocketAddr>,
+    connections: HashMap<std::net::SocketAddr, ConnectionStats>,
+    //traffic per peer address, kept after the peer disconnects so a reconnecting peer keeps adding to it
+    traffic: HashMap<std::net::SocketAddr, Arc<TrafficCounters>>,
+    //latency and loss injected into what is written to peers, read by the writer tasks for every message
+    impairments: Arc<RwLock<Impairments>>,
+    //peers we keep reconnecting to whenever their connection drops
+    persistent: HashMap<std::net::SocketAddr, PersistentPeer>,
+    //peers a dial task is connecting to, they report back with Dialed
+    dialing: HashSet<std::net::SocketAddr>,
+    //set once the node is shutting down, no new peers are accepted after that
+    shutting_down: bool,
+    //frames longer than this are dropped before being buffered
+    max_message_size: usize,
+    keepalive_idle: Duration,
+    keepalive_timeout: Duration,
+    max_inbound: usize,
+    max_outbound: usize,
+    //static key for the encrypted transport, None to talk plaintext
+    encryption: Option<Arc<Keypair>>,
+    //outbound connections go through this SOCKS5 proxy, inbound ones are unaffected
+    proxy: Option<std::net::SocketAddr>,
+    //peers known by name only, under the stand-in address they are keyed by; the proxy resolves the name
+    proxy_names: HashMap<std::net::SocketAddr, String>,
+    //new transaction hashes go to this many randomly picked peers instead of all of them, None floods every peer
+    relay_fanout: Option<usize>,
+    //new blocks go to relay_fanout random peers as well, otherwise every peer gets them
+    relay_fanout_blocks: bool,
+    //peer addresses learned so far, written to addr_book_path if there is one
+    addr_book: AddrBook,
+    addr_book_path: Option<PathBuf>,
+    //the book changed since it was last written
+    addr_book_dirty: bool,
+    //every address we accept peers on, IPv4 and IPv6 alike
+    addrs: Vec<std::net::SocketAddr>,
     control_chan: smol::channel::Receiver<ControlSignal>,
     control_sender: smol::channel::Sender<ControlSignal>,
-    new_msg_chan: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+    //persistent peers and when they are due to be dialed again, for the dialer task
+    dial_schedule: smol::channel::Sender<(std::net::SocketAddr, Instant)>,
+    dial_schedule_receiver: smol::channel::Receiver<(std::net::SocketAddr, Instant)>,
+    new_msg_chan: MsgSender,
 }
 
 impl Context {
+    pub fn set_ban_duration(&mut self, ban_duration: Duration) {
+        self.ban_duration = ban_duration;
+    }
+
+    /// Ping peers silent for `idle` and drop the ones that stay silent `timeout` longer
+    pub fn set_keepalive(&mut self, idle: Duration, timeout: Duration) {
+        self.keepalive_idle = idle;
+        self.keepalive_timeout = timeout;
+    }
+
+    pub fn set_connection_limits(&mut self, max_inbound: usize, max_outbound: usize) {
+        self.max_inbound = max_inbound;
+        self.max_outbound = max_outbound;
+    }
+
+    /// Remember peer addresses in `path`, starting from the ones saved there by an earlier run
+    pub fn set_addr_book(&mut self, path: PathBuf) -> std::io::Result<()> {
+        let mut addr_book = AddrBook::load(&path)?;
+        let pruned = addr_book.prune(unix_secs(), ADDR_BOOK_MAX_AGE_SECS);
+        info!("Loaded {} peer addresses from {}, {} stale ones dropped", addr_book.len(), path.display(), pruned);
+        self.addr_book = addr_book;
+        self.addr_book_path = Some(path);
+        self.addr_book_dirty = pruned > 0;
+        return Ok(());
+    }
+
+    /// Keep bans and misbehavior scores in `path`, starting from the ones saved there by an earlier run
+    pub fn set_ban_list(&mut self, path: PathBuf) -> std::io::Result<()> {
+        let mut ban_list = BanList::load(&path)?;
+        let expired = ban_list.prune(unix_millis());
+        info!("Loaded {} banned peers from {}, {} expired ones dropped", ban_list.banned(unix_millis()).len(), path.display(), expired);
+        self.ban_list = ban_list;
+        self.ban_list_path = Some(path);
+        return Ok(());
+    }
+
+    /// Degrade the link to every peer, see impairment.rs
+    pub fn set_impairment(&mut self, impairment: Impairment) {
+        if !impairment.is_none() {
+            warn!("Injecting {} ms of latency and {}% loss into P2P messages", impairment.latency_ms, impairment.loss_pct);
+        }
+        self.impairments.write().unwrap().default = impairment;
+    }
+
+    /// Dial every outbound peer through the SOCKS5 proxy at `proxy`
+    pub fn set_proxy(&mut self, proxy: std::net::SocketAddr) {
+        info!("Connecting to peers through the SOCKS5 proxy at {}", proxy);
+        self.proxy = Some(proxy);
+    }
+
+    /// Relay each new transaction hash to `fanout` randomly picked peers, and new blocks too if `blocks`, instead of to
+    /// every peer. The peers that get it relay it on in turn, so it still reaches the whole network over several hops
+    pub fn set_relay_fanout(&mut self, fanout: usize, blocks: bool) {
+        info!("Relaying new {} to {} random peers", if blocks { "transactions and blocks" } else { "transactions" }, fanout);
+        self.relay_fanout = Some(fanout);
+        self.relay_fanout_blocks = blocks;
+    }
+
+    /// The address to connect to `peer` at, given as on the command line. With a proxy set, a host name is left for
+    /// the proxy to resolve and the peer is known by a stand-in address, see socks5::virtual_addr
+    pub fn resolve_peer(&mut self, peer: &str) -> std::io::Result<std::net::SocketAddr> {
+        if self.proxy.is_none() {
+            return parse_addr(peer);
+        }
+        if let Ok(addr) = peer.parse::<net::SocketAddr>() {
+            return Ok(addr);
+        }
+        let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("invalid peer address {}", peer));
+        let (host, port) = peer.rsplit_once(':').ok_or_else(invalid)?;
+        let port = port.parse::<u16>().map_err(|_| invalid())?;
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        let addr = socks5::virtual_addr(host, port);
+        debug!("Peer {} is known as {} until the proxy connects to it", peer, addr);
+        self.proxy_names.insert(addr, host.to_string());
+        return Ok(addr);
+    }
+
+    /// Encrypt every connection with a key generated now; peers must do the same or they are disconnected
+    pub fn enable_encryption(&mut self) {
+        let keypair = noise::generate_keypair();
+        info!("P2P transport encrypted, static public key {}", hex::encode(&keypair.public));
+        self.encryption = Some(Arc::new(keypair));
+    }
+
     /// Start a new server context.
     pub fn start(self) -> std::io::Result<()> {
-        // initialize the server socket
-        let listener = Async::<net::TcpListener>::bind(self.addr)?;
-        info!("P2P server listening at {}", self.addr);
+        // initialize the server sockets, all of them before anything runs so a bad address fails start
+        let mut listeners = Vec::new();
+        for addr in self.addrs.iter() {
+            listeners.push(Async::<net::TcpListener>::bind(*addr)?);
+            info!("P2P server listening at {}", addr);
+        }
         let control_chan = self.control_sender.clone();
+        let self_ping_chan = self.control_sender.clone();
+        let keepalive_chan = self.control_sender.clone();
+        let dialer_chan = self.control_sender.clone();
+        let dial_schedule = self.dial_schedule_receiver.clone();
+        let addr_book_chan = self.control_sender.clone();
+        //check often enough that a dead peer is dropped close to the timeout
+        let keepalive_period = (self.keepalive_idle.min(self.keepalive_timeout) / 4).max(Duration::from_millis(10));
         let ex = Executor::new();
         let ex = Arc::new(ex);
         let ex_clone = ex.clone();
@@ -53,8 +317,29 @@ impl Context {
             self.dispatch_control(ex_clone).await.unwrap();
         })
             .detach();
+        //accepted connections from every listener go through the same dispatcher
+        for listener in listeners {
+            let control_chan = control_chan.clone();
+            ex.spawn(async move {
+                Self::listener_loop(listener, control_chan).await.unwrap();
+            })
+                .detach();
+        }
+        let ping_chan = self_ping_chan;
+        ex.spawn(async move {
+            Self::ping_loop(ping_chan).await;
+        })
+            .detach();
+        ex.spawn(async move {
+            Self::keepalive_loop(keepalive_chan, keepalive_period).await;
+        })
+            .detach();
         ex.spawn(async move {
-            Self::listener_loop(listener, control_chan).await.unwrap();
+            Self::dialer_loop(dial_schedule, dialer_chan).await;
+        })
+            .detach();
+        ex.spawn(async move {
+            Self::addr_book_loop(addr_book_chan).await;
         })
             .detach();
         thread::spawn(move || smol::block_on(ex.run(futures::future::pending::<()>())));
@@ -76,29 +361,471 @@ impl Context {
         }
     }
 
+    /// the loop that periodically asks the dispatcher to ping every peer
+    async fn ping_loop(control_chan: smol::channel::Sender<ControlSignal>) {
+        loop {
+            smol::Timer::after(Duration::from_secs(PING_INTERVAL_SECS)).await;
+            if control_chan.send(ControlSignal::PingPeers).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    /// the loop that periodically asks the dispatcher to check on silent peers
+    async fn keepalive_loop(control_chan: smol::channel::Sender<ControlSignal>, period: Duration) {
+        loop {
+            smol::Timer::after(period).await;
+            if control_chan.send(ControlSignal::CheckIdlePeers).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    /// the loop that periodically asks the dispatcher to write the address book
+    async fn addr_book_loop(control_chan: smol::channel::Sender<ControlSignal>) {
+        loop {
+            smol::Timer::after(ADDR_BOOK_SAVE_INTERVAL).await;
+            if control_chan.send(ControlSignal::SaveAddrBook).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    /// the loop that keeps when each persistent peer is due to be dialed again and asks the dispatcher to dial it then
+    async fn dialer_loop(
+        schedule: smol::channel::Receiver<(std::net::SocketAddr, Instant)>,
+        control_chan: smol::channel::Sender<ControlSignal>,
+    ) {
+        let mut due: HashMap<std::net::SocketAddr, Instant> = HashMap::new();
+        loop {
+            let next = due.values().min().cloned();
+            let scheduled = async { Some(schedule.recv().await) };
+            let wake = async {
+                match next {
+                    Some(next) => {
+                        Timer::at(next).await;
+                    }
+                    None => futures::future::pending::<()>().await,
+                }
+                return None;
+            };
+            match smol::future::or(scheduled, wake).await {
+                Some(Ok((addr, at))) => {
+                    due.insert(addr, at);
+                }
+                //the dispatcher is gone
+                Some(Err(_)) => break,
+                None => {
+                    let now = Instant::now();
+                    let ready: Vec<std::net::SocketAddr> = due.iter().filter(|(_, at)| **at <= now).map(|(addr, _)| *addr).collect();
+                    for addr in ready {
+                        due.remove(&addr);
+                        if control_chan.send(ControlSignal::DialPersistentPeer(addr)).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
     async fn dispatch_control(mut self, ex: Arc<Executor<'_>>) -> std::io::Result<()> {
         // read the next control signal
         while let Ok(ctrl) = self.control_chan.recv().await {
             match ctrl {
                 ControlSignal::ConnectNewPeer(addr, result_chan) => {
                     trace!("Processing ConnectNewPeer command");
-                    let handle = self.connect(&addr, ex.clone()).await;
-                    result_chan.send(handle).unwrap();
+                    if self.shutting_down {
+                        let _ = result_chan.send(Err(std::io::Error::new(
+                            std::io::ErrorKind::NotConnected,
+                            "server is shutting down",
+                        )));
+                        continue;
+                    }
+                    if self.connection_counts().outbound >= self.max_outbound {
+                        let _ = result_chan.send(Err(std::io::Error::new(
+                            std::io::ErrorKind::ConnectionRefused,
+                            "outbound connection limit reached",
+                        )));
+                        continue;
+                    }
+                    self.start_dial(addr, DialPurpose::Connect(result_chan), &ex);
+                }
+                ControlSignal::ResolvePeer(peer, result_chan) => {
+                    trace!("Processing ResolvePeer({})", peer);
+                    let _ = result_chan.send(self.resolve_peer(&peer));
+                }
+                ControlSignal::AddPersistentPeer(addr, greeting) => {
+                    trace!("Processing AddPersistentPeer({})", addr);
+                    self.persistent.insert(addr, PersistentPeer::new(greeting));
+                    if !self.peers.contains_key(&addr) && !self.dialing.contains(&addr) {
+                        self.dial_persistent(addr, None, &ex);
+                    }
+                }
+                ControlSignal::ConnectPersistentPeer(addr, greeting, result_chan) => {
+                    trace!("Processing ConnectPersistentPeer({})", addr);
+                    self.persistent.insert(addr, PersistentPeer::new(greeting));
+                    if self.peers.contains_key(&addr) {
+                        let _ = result_chan.send(Ok(()));
+                    } else {
+                        self.dial_persistent(addr, Some(result_chan), &ex);
+                    }
+                }
+                ControlSignal::Dialed(addr, result, purpose) => {
+                    trace!("Processing Dialed({})", addr);
+                    self.dialing.remove(&addr);
+                    //a peer known by name only has a stand-in address, worthless to anyone reading the book, see socks5::virtual_addr
+                    let bookable = !self.proxy_names.contains_key(&addr);
+                    match result {
+                        Ok(stream) if !self.shutting_down && !self.is_banned(&addr.ip()) => {
+                            if bookable {
+                                self.addr_book.record_success(addr, unix_secs());
+                                self.addr_book_dirty = true;
+                            }
+                            let hd = self.register(stream, addr, peer::Direction::Outgoing, ex.clone()).await?;
+                            self.dial_succeeded(hd, purpose);
+                        }
+                        //the server shut down or banned the peer while the dial was under way
+                        Ok(_) => {
+                            let e = std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "connection no longer wanted");
+                            self.dial_failed(addr, e, purpose);
+                        }
+                        Err(e) => {
+                            if bookable {
+                                self.addr_book.record_failure(addr);
+                                self.addr_book_dirty = true;
+                            }
+                            self.dial_failed(addr, e, purpose);
+                        }
+                    }
+                }
+                ControlSignal::DisconnectPeer(addr, result_chan) => {
+                    trace!("Processing DisconnectPeer({})", addr);
+                    //forgotten first, so dropping the connection doesn't schedule a reconnect
+                    let was_persistent = self.persistent.remove(&addr).is_some();
+                    let was_connected = match self.peers.get(&addr) {
+                        Some(hd) => {
+                            hd.disconnect();
+                            true
+                        }
+                        None => false,
+                    };
+                    if was_connected || was_persistent {
+                        info!("Disconnecting from peer {} on request", addr);
+                    }
+                    let _ = result_chan.send(was_connected || was_persistent);
+                }
+                ControlSignal::DialPersistentPeer(addr) => {
+                    trace!("Processing DialPersistentPeer({})", addr);
+                    //the peer may have been removed, or reconnected some other way, since it was scheduled
+                    if self.shutting_down || !self.persistent.contains_key(&addr) || self.peers.contains_key(&addr) || self.dialing.contains(&addr) {
+                        continue;
+                    }
+                    self.dial_persistent(addr, None, &ex);
                 }
                 ControlSignal::BroadcastMessage(msg) => {
                     trace!("Processing BroadcastMessage command");
-                    for (_, hd) in self.peers.iter_mut() {
-                        hd.write(msg.clone());
+                    let relay_targets = self.relay_targets(&msg);
+                    for (addr, hd) in self.peers.iter_mut() {
+                        if !self.handshaked.contains(addr) {
+                            continue;
+                        }
+                        let known_inv = self.known_inv
+                            .entry(*addr)
+                            .or_insert_with(|| LruCache::new(KNOWN_INVENTORY_CAPACITY));
+                        let relayed = |hash: &H256| relay_targets.as_ref().map_or(true, |targets| targets[hash].contains(addr));
+                        //only announce hashes the peer doesn't know about yet
+                        match &msg {
+                            message::Message::NewBlockHashes(hashes) => {
+                                let hashes: Vec<H256> = hashes.iter().filter(|hash| relayed(hash)).cloned().collect();
+                                let unknown = unknown_inventory(known_inv, &hashes);
+                                if !unknown.is_empty() {
+                                    hd.write(message::Message::NewBlockHashes(unknown));
+                                }
+                            }
+                            message::Message::NewTransactionHashes(hashes) => {
+                                let hashes: Vec<H256> = hashes.iter().filter(|hash| relayed(hash)).cloned().collect();
+                                let unknown = unknown_inventory(known_inv, &hashes);
+                                if !unknown.is_empty() {
+                                    hd.write(message::Message::NewTransactionHashes(unknown));
+                                }
+                            }
+                            //relayed as compact blocks or pushed in full to peers that asked for it, announced to the rest
+                            message::Message::Blocks(blocks) => {
+                                let hashes: Vec<H256> = blocks.iter().map(|block| block.hash()).filter(|hash| relayed(hash)).collect();
+                                let unknown = unknown_inventory(known_inv, &hashes);
+                                if unknown.is_empty() {
+                                    continue;
+                                }
+                                if hd.wants_compact_blocks() {
+                                    for block in blocks.iter().filter(|block| unknown.contains(&block.hash())) {
+                                        let tx_hashes = block.content.data.iter().map(|tx| tx.hash()).collect();
+                                        hd.write(message::Message::CompactBlock { header: block.get_header(), tx_hashes });
+                                    }
+                                    continue;
+                                }
+                                let (pushed, announced): (Vec<&Block>, Vec<&Block>) = blocks.iter()
+                                    .filter(|block| unknown.contains(&block.hash()))
+                                    .partition(|block| hd.wants_pushed_blocks() && bincode::serialized_size(block).unwrap() as usize <= MAX_PUSHED_BLOCK_SIZE);
+                                if !pushed.is_empty() {
+                                    hd.write(message::Message::Blocks(pushed.into_iter().cloned().collect()));
+                                }
+                                if !announced.is_empty() {
+                                    hd.write(message::Message::NewBlockHashes(announced.iter().map(|block| block.hash()).collect()));
+                                }
+                            }
+                            _ => hd.write(msg.clone()),
+                        }
+                    }
+                }
+                ControlSignal::AddKnownInventory(addr, hashes) => {
+                    trace!("Processing AddKnownInventory({})", addr);
+                    let known_inv = self.known_inv
+                        .entry(addr)
+                        .or_insert_with(|| LruCache::new(KNOWN_INVENTORY_CAPACITY));
+                    for hash in hashes {
+                        known_inv.put(hash, ());
+                    }
+                }
+                ControlSignal::HandshakeComplete(addr) => {
+                    trace!("Processing HandshakeComplete({})", addr);
+                    if self.peers.contains_key(&addr) {
+                        self.handshaked.insert(addr);
+                    }
+                }
+                ControlSignal::RegisterNode(addr, node_nonce) => {
+                    trace!("Processing RegisterNode({}, {})", addr, node_nonce);
+                    match self.nodes.get(&node_nonce) {
+                        Some(existing) if *existing != addr && self.peers.contains_key(existing) => {
+                            info!("Peer {} is the node already connected through {}, dropping the new connection", addr, existing);
+                            if let Some(hd) = self.peers.get(&addr) {
+                                hd.disconnect();
+                            }
+                        }
+                        _ => {
+                            self.nodes.insert(node_nonce, addr);
+                        }
+                    }
+                }
+                ControlSignal::GetHandshakedPeers(result_chan) => {
+                    trace!("Processing GetHandshakedPeers command");
+                    let _ = result_chan.send(self.handshaked.iter().cloned().collect());
+                }
+                ControlSignal::LearnAddrs(from, addrs) => {
+                    trace!("Processing LearnAddrs({}, {} addresses)", from, addrs.len());
+                    //only connected peers are listened to, a handful of addresses at a time
+                    if !self.peers.contains_key(&from) {
+                        continue;
+                    }
+                    let now = unix_secs();
+                    for addr in addrs.into_iter().take(MAX_LEARNED_ADDRS_PER_MESSAGE) {
+                        if !is_learnable(&addr, &from) || self.addrs.contains(&addr) || self.is_banned(&addr.ip()) {
+                            continue;
+                        }
+                        self.addr_book_dirty |= self.addr_book.learn(addr, now);
+                    }
+                }
+                ControlSignal::ConnectFromAddrBook(greeting, result_chan) => {
+                    trace!("Processing ConnectFromAddrBook command");
+                    let free = self.max_outbound.saturating_sub(self.connection_counts().outbound + self.dialing.len());
+                    let mut dialed = 0;
+                    for addr in self.addr_book.candidates() {
+                        if self.shutting_down || dialed >= free.min(ADDR_BOOK_MAX_DIALS) {
+                            break;
+                        }
+                        if self.peers.contains_key(&addr) || self.addrs.contains(&addr) || self.dialing.contains(&addr) {
+                            continue;
+                        }
+                        self.start_dial(addr, DialPurpose::AddrBook(Arc::clone(&greeting)), &ex);
+                        dialed += 1;
+                    }
+                    let _ = result_chan.send(dialed);
+                }
+                ControlSignal::PingPeers => {
+                    trace!("Processing PingPeers command");
+                    let nonce = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros() as u64;
+                    let now = Instant::now();
+                    for (addr, hd) in self.peers.iter_mut() {
+                        let stats = self.peer_stats.entry(*addr).or_insert_with(PeerStats::new);
+                        //a ping asked for through the API soon after the last one waits for its answer
+                        if stats.ping_in_flight(now) {
+                            continue;
+                        }
+                        if !stats.ping_sent(nonce, now) {
+                            info!("Peer {} missed {} pings in a row, disconnecting", addr, MAX_MISSED_PINGS);
+                            hd.disconnect();
+                            continue;
+                        }
+                        hd.write(message::Message::Ping(nonce));
                     }
                 }
+                ControlSignal::CheckIdlePeers => {
+                    let now = Instant::now();
+                    let now_millis = unix_millis();
+                    for (addr, hd) in self.peers.iter_mut() {
+                        let connection = match self.connections.get(addr) {
+                            Some(connection) => connection,
+                            None => continue,
+                        };
+                        let silent_for = Duration::from_millis(now_millis.saturating_sub(connection.last_received.load(Ordering::Relaxed)));
+                        let stats = self.peer_stats.entry(*addr).or_insert_with(PeerStats::new);
+                        if stats.ping_timed_out(silent_for, self.keepalive_timeout, now) {
+                            info!("Peer {} silent for {} ms and not answering pings, disconnecting", addr, silent_for.as_millis());
+                            hd.disconnect();
+                            continue;
+                        }
+                        if silent_for >= self.keepalive_idle && stats.outstanding_ping.is_none() {
+                            let nonce = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros() as u64;
+                            stats.ping_sent(nonce, now);
+                            hd.write(message::Message::Ping(nonce));
+                        }
+                    }
+                }
+                ControlSignal::PongReceived(addr, nonce) => {
+                    trace!("Processing PongReceived({}, {})", addr, nonce);
+                    if let Some(stats) = self.peer_stats.get_mut(&addr) {
+                        stats.pong_received(nonce, Instant::now());
+                    }
+                }
+                ControlSignal::GetPeerInfo(result_chan) => {
+                    trace!("Processing GetPeerInfo command");
+                    let mut peers = Vec::new();
+                    for (addr, hd) in self.peers.iter() {
+                        let connection = match self.connections.get(addr) {
+                            Some(connection) => connection,
+                            None => continue,
+                        };
+                        peers.push(PeerInfo {
+                            addr: *addr,
+                            direction: hd.direction(),
+                            connected_since: connection.connected_since,
+                            messages_sent: connection.messages_sent.load(Ordering::Relaxed),
+                            messages_received: connection.messages_received.load(Ordering::Relaxed),
+                            latency: self.peer_stats.get(addr).and_then(|stats| stats.peer_latency()),
+                            compression: hd.compression_stats(),
+                        });
+                    }
+                    peers.sort_by(|a, b| a.addr.cmp(&b.addr));
+                    let _ = result_chan.send(peers);
+                }
+                ControlSignal::GetConnectionCounts(result_chan) => {
+                    trace!("Processing GetConnectionCounts command");
+                    let _ = result_chan.send(self.connection_counts());
+                }
+                ControlSignal::GetTrafficStats(result_chan) => {
+                    trace!("Processing GetTrafficStats command");
+                    let _ = result_chan.send(self.traffic_stats());
+                }
+                ControlSignal::SetPeerImpairment(addr, impairment) => {
+                    trace!("Processing SetPeerImpairment({})", addr);
+                    let mut impairments = self.impairments.write().unwrap();
+                    match impairment {
+                        Some(impairment) => {
+                            info!("Injecting {} ms of latency and {}% loss into messages to {}", impairment.latency_ms, impairment.loss_pct, addr);
+                            impairments.per_peer.insert(addr, impairment);
+                        }
+                        None => {
+                            impairments.per_peer.remove(&addr);
+                        }
+                    }
+                }
+                ControlSignal::GetImpairments(result_chan) => {
+                    trace!("Processing GetImpairments command");
+                    let _ = result_chan.send(self.impairments.read().unwrap().clone());
+                }
+                ControlSignal::GetQueueStats(result_chan) => {
+                    trace!("Processing GetQueueStats command");
+                    let _ = result_chan.send(self.new_msg_chan.stats());
+                }
+                ControlSignal::GetPeerLatencies(result_chan) => {
+                    trace!("Processing GetPeerLatencies command");
+                    let mut latencies = HashMap::new();
+                    for (addr, stats) in self.peer_stats.iter() {
+                        if let Some(latency) = stats.peer_latency() {
+                            latencies.insert(*addr, latency);
+                        }
+                    }
+                    let _ = result_chan.send(latencies);
+                }
                 ControlSignal::GetNewPeer(stream) => {
                     trace!("Processing GetNewPeer command");
+                    let addr = peer::remote_addr(&stream)?;
+                    if self.shutting_down {
+                        info!("Refusing incoming peer {}: server is shutting down", addr);
+                        continue;
+                    }
+                    if self.is_banned(&addr.ip()) {
+                        info!("Refusing incoming peer {}: address is banned", addr);
+                        continue;
+                    }
+                    if self.connection_counts().inbound >= self.max_inbound {
+                        //a peer we were told to connect to takes the slot of the one that has been quiet the longest, other
+                        //nodes sharing its IP (behind the same NAT, or on this machine) don't
+                        if !self.persistent.contains_key(&addr) || !self.evict_idle_inbound() {
+                            info!("Refusing incoming peer {}: {} inbound peers already", addr, self.max_inbound);
+                            self.turn_away(stream, message::DisconnectReason::TooManyPeers, &ex);
+                            continue;
+                        }
+                    }
                     self.accept(stream, ex.clone()).await?;
                 }
+                ControlSignal::BanPeer(addr) => {
+                    trace!("Processing BanPeer({})", addr);
+                    self.ban(addr.ip());
+                    self.ban_list_changed(&ex);
+                }
+                ControlSignal::Misbehaving(addr, score) => {
+                    trace!("Processing Misbehaving({}, {})", addr, score);
+                    let total = self.ban_list.add_misbehavior(addr.ip(), score, unix_millis());
+                    info!("Peer {} misbehavior score is now {}", addr, total);
+                    if total >= BAN_SCORE_THRESHOLD {
+                        self.ban(addr.ip());
+                    }
+                    self.ban_list_changed(&ex);
+                }
+                ControlSignal::SaveAddrBook => {
+                    trace!("Processing SaveAddrBook command");
+                    self.save_addr_book();
+                }
+                ControlSignal::SaveBanList => {
+                    trace!("Processing SaveBanList command");
+                    self.save_ban_list();
+                }
+                ControlSignal::GetBanned(result_chan) => {
+                    trace!("Processing GetBanned command");
+                    let now = unix_millis();
+                    let banned: Vec<BannedPeer> = self.ban_list.banned(now).into_iter()
+                        .map(|(ip, expires_at)| BannedPeer { ip, expires_in: Duration::from_millis(expires_at - now), expires_at })
+                        .collect();
+                    let _ = result_chan.send(banned);
+                }
                 ControlSignal::DroppedPeer(addr) => {
                     trace!("Processing DroppedPeer({})", addr);
                     self.peers.remove(&addr);
+                    self.known_inv.remove(&addr);
+                    self.peer_stats.remove(&addr);
+                    self.handshaked.remove(&addr);
+                    self.nodes.retain(|_, connection| *connection != addr);
+                    self.connections.remove(&addr);
                     info!("Peer {} disconnected", addr);
+                    let shutting_down = self.shutting_down;
+                    if let Some(persistent) = self.persistent.get_mut(&addr).filter(|_| !shutting_down) {
+                        let delay = persistent.dropped(Instant::now(), &mut rand::thread_rng());
+                        info!("Redialing persistent peer {} in {:.1} seconds", addr, delay.as_secs_f64());
+                        self.schedule_dial(addr, delay);
+                    }
+                }
+                ControlSignal::Shutdown => {
+                    trace!("Processing Shutdown command");
+                    self.shutting_down = true;
+                    for (_, hd) in self.peers.iter() {
+                        hd.disconnect();
+                    }
+                    //closing the message channel lets the network workers finish
+                    self.new_msg_chan.close();
+                    self.save_addr_book();
+                    self.save_ban_list();
+                    info!("P2P server stopped accepting messages");
                 }
                 ControlSignal::SendToPeer((_receiver, _msg)) => {
                     unimplemented!()
@@ -108,17 +835,248 @@ impl Context {
         return Ok(());
     }
 
-    /// Connect to a peer, and register this peer
-    async fn connect(
-        &mut self,
-        addr: &std::net::SocketAddr,
-        ex: Arc<Executor<'_>>,
-    ) -> std::io::Result<peer::Handle> {
+    /// The peers each hash in `msg` is relayed to when relaying to a random few, None when every peer gets it.
+    /// Peers that already know a hash are never picked, so broadcasting it again reaches peers it hasn't gone to yet
+    fn relay_targets(&self, msg: &message::Message) -> Option<HashMap<H256, HashSet<std::net::SocketAddr>>> {
+        let fanout = self.relay_fanout?;
+        let hashes: Vec<H256> = match msg {
+            message::Message::NewTransactionHashes(hashes) => hashes.clone(),
+            message::Message::NewBlockHashes(hashes) if self.relay_fanout_blocks => hashes.clone(),
+            message::Message::Blocks(blocks) if self.relay_fanout_blocks => blocks.iter().map(|block| block.hash()).collect(),
+            _ => return None,
+        };
+        let mut rng = rand::thread_rng();
+        let mut targets = HashMap::new();
+        for hash in hashes {
+            let candidates: Vec<std::net::SocketAddr> = self.handshaked.iter()
+                .filter(|addr| self.known_inv.get(addr).map_or(true, |known_inv| !known_inv.contains(&hash)))
+                .cloned()
+                .collect();
+            targets.insert(hash, candidates.choose_multiple(&mut rng, fanout).cloned().collect());
+        }
+        return Some(targets);
+    }
+
+    /// Write the address book if it changed and we have a file for it
+    fn save_addr_book(&mut self) {
+        let path = match &self.addr_book_path {
+            Some(path) if self.addr_book_dirty => path,
+            _ => return,
+        };
+        self.addr_book.prune(unix_secs(), ADDR_BOOK_MAX_AGE_SECS);
+        match self.addr_book.save(path) {
+            Ok(()) => self.addr_book_dirty = false,
+            Err(e) => warn!("Error saving peer addresses to {}: {}", path.display(), e),
+        }
+    }
+
+    /// Schedule writing the ban list after BAN_LIST_SAVE_DELAY, unless a write is already due
+    fn ban_list_changed(&mut self, ex: &Arc<Executor<'_>>) {
+        if self.ban_list_path.is_none() || self.ban_list_dirty {
+            return;
+        }
+        self.ban_list_dirty = true;
+        let control_chan = self.control_sender.clone();
+        ex.spawn(async move {
+            Timer::after(BAN_LIST_SAVE_DELAY).await;
+            let _ = control_chan.send(ControlSignal::SaveBanList).await;
+        })
+            .detach();
+    }
+
+    fn save_ban_list(&mut self) {
+        let path = match &self.ban_list_path {
+            Some(path) => path,
+            None => return,
+        };
+        self.ban_list.prune(unix_millis());
+        match self.ban_list.save(path) {
+            Ok(()) => self.ban_list_dirty = false,
+            Err(e) => warn!("Error saving banned peers to {}: {}", path.display(), e),
+        }
+    }
+
+    /// The traffic counters of a peer address, made room for by forgetting a disconnected address if the table is full
+    fn traffic_counters(&mut self, addr: std::net::SocketAddr) -> Arc<TrafficCounters> {
+        if !self.traffic.contains_key(&addr) && self.traffic.len() >= TRAFFIC_TABLE_CAPACITY {
+            let peers = &self.peers;
+            if let Some(gone) = self.traffic.keys().find(|known| !peers.contains_key(known)).cloned() {
+                self.traffic.remove(&gone);
+            }
+        }
+        return Arc::clone(self.traffic.entry(addr).or_default());
+    }
+
+    /// Every peer address in the traffic table, and the sum over all of them
+    fn traffic_stats(&self) -> TrafficStats {
+        let mut totals = TrafficBreakdown::default();
+        let mut peers = Vec::new();
+        for (addr, counters) in self.traffic.iter() {
+            let traffic = counters.snapshot();
+            totals.add(&traffic);
+            peers.push(PeerTraffic { addr: *addr, connected: self.peers.contains_key(addr), traffic });
+        }
+        peers.sort_by(|a, b| a.addr.cmp(&b.addr));
+        return TrafficStats { totals, peers };
+    }
+
+    /// Connected peers per direction, not counting the ones already being disconnected
+    fn connection_counts(&self) -> ConnectionCounts {
+        let mut counts = ConnectionCounts {
+            inbound: 0,
+            outbound: 0,
+            max_inbound: self.max_inbound,
+            max_outbound: self.max_outbound,
+        };
+        for hd in self.peers.values().filter(|hd| !hd.is_disconnected()) {
+            match hd.direction() {
+                peer::Direction::Incoming => counts.inbound += 1,
+                peer::Direction::Outgoing => counts.outbound += 1,
+            }
+        }
+        return counts;
+    }
+
+    /// Disconnect the inbound peer we heard from least recently, returns false if there is none
+    fn evict_idle_inbound(&mut self) -> bool {
+        let idlest = self.peers.iter()
+            .filter(|(_, hd)| hd.direction() == peer::Direction::Incoming && !hd.is_disconnected())
+            .filter_map(|(addr, hd)| {
+                let connection = self.connections.get(addr)?;
+                Some((connection.last_received.load(Ordering::Relaxed), hd.clone()))
+            })
+            .min_by_key(|(last_received, _)| *last_received);
+        match idlest {
+            Some((_, mut hd)) => {
+                info!("Evicting idle inbound peer {} to make room for a configured peer", hd.addr());
+                hd.write(message::Message::Disconnect(message::DisconnectReason::Evicted));
+                hd.disconnect();
+                return true;
+            }
+            None => return false,
+        }
+    }
+
+    /// Accept a connection only to tell the peer why it is turned away, then close it
+    fn turn_away(&self, stream: Async<net::TcpStream>, reason: message::DisconnectReason, ex: &Arc<Executor<'_>>) {
+        let encryption = self.encryption.clone();
+        ex.spawn(async move {
+            let stream = AsyncArc::new(stream);
+            if let Ok((mut reader, mut writer)) = open_transport(&stream, peer::Direction::Incoming, encryption).await {
+                let msg = bincode::serialize(&message::Message::Disconnect(reason)).unwrap();
+                let _ = writer.write_all(&frame::encode(&msg)).await;
+                let _ = writer.flush().await;
+                let _ = stream.get_ref().shutdown(net::Shutdown::Write);
+                //closing with unread data would reset the connection before the peer reads our message
+                let drain = async {
+                    let _ = futures::io::copy(&mut reader, &mut sink()).await;
+                };
+                smol::future::or(drain, async { Timer::after(TURN_AWAY_LINGER).await; }).await;
+            }
+            let _ = stream.get_ref().shutdown(net::Shutdown::Both);
+        })
+            .detach();
+    }
+
+    /// Refuse an IP for the ban duration and disconnect every peer connected from it
+    fn ban(&mut self, ip: net::IpAddr) {
+        self.ban_list.ban(ip, unix_millis() + self.ban_duration.as_millis() as u64);
+        for (addr, hd) in self.peers.iter() {
+            if addr.ip() == ip {
+                hd.disconnect();
+            }
+        }
+        self.handshaked.retain(|addr| addr.ip() != ip);
+        info!("Peer {} banned for {} seconds", ip, self.ban_duration.as_secs());
+    }
+
+    /// Check whether an IP is currently banned, forgetting the ban once it has expired
+    fn is_banned(&mut self, ip: &net::IpAddr) -> bool {
+        return self.ban_list.is_banned(ip, unix_millis());
+    }
+
+    /// Start dialing a persistent peer, backing off before the next attempt if it fails
+    fn dial_persistent(&mut self, addr: std::net::SocketAddr, result_chan: Option<oneshot::Sender<std::io::Result<()>>>, ex: &Arc<Executor<'_>>) {
+        if let Some(persistent) = self.persistent.get(&addr) {
+            info!("Connecting to persistent peer {} (attempt {})", addr, persistent.failures + 1);
+        }
+        self.start_dial(addr, DialPurpose::Persistent(result_chan), ex);
+    }
+
+    /// Have the dialer task dial a persistent peer again after `delay`
+    fn schedule_dial(&self, addr: std::net::SocketAddr, delay: Duration) {
+        let _ = self.dial_schedule.try_send((addr, Instant::now() + delay));
+    }
+
+    /// Dial a peer on a task of its own so the dispatcher keeps serving other signals, the task reports back with Dialed
+    fn start_dial(&mut self, addr: std::net::SocketAddr, purpose: DialPurpose, ex: &Arc<Executor<'_>>) {
+        if self.is_banned(&addr.ip()) {
+            let e = std::io::Error::new(std::io::ErrorKind::PermissionDenied, "peer address is banned");
+            self.dial_failed(addr, e, purpose);
+            return;
+        }
         debug!("Establishing connection to peer {}", addr);
-        let stream = Async::<std::net::TcpStream>::connect(addr.clone()).await?;
+        self.dialing.insert(addr);
+        let proxy = self.proxy;
+        let host = self.proxy_names.get(&addr).cloned();
+        let control_chan = self.control_sender.clone();
+        ex.spawn(async move {
+            let result = dial(addr, proxy, host).await;
+            let _ = control_chan.send(ControlSignal::Dialed(addr, result, purpose)).await;
+        })
+            .detach();
+    }
+
+    /// Hand a new outbound connection to whoever asked for it
+    fn dial_succeeded(&mut self, mut hd: peer::Handle, purpose: DialPurpose) {
+        let addr = *hd.addr();
+        match purpose {
+            DialPurpose::Connect(result_chan) => {
+                let _ = result_chan.send(Ok(hd));
+            }
+            DialPurpose::AddrBook(greeting) => {
+                info!("Connected to stored peer {}", addr);
+                hd.write(greeting(&addr));
+            }
+            DialPurpose::Persistent(result_chan) => {
+                let result = match self.persistent.get_mut(&addr) {
+                    Some(persistent) => {
+                        info!("Connected to persistent peer {}", addr);
+                        persistent.connected(Instant::now());
+                        hd.write((persistent.greeting)(&addr));
+                        Ok(())
+                    }
+                    //disconnected on request while the dial was under way
+                    None => {
+                        hd.disconnect();
+                        Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "peer was disconnected while dialing"))
+                    }
+                };
+                if let Some(result_chan) = result_chan {
+                    let _ = result_chan.send(result);
+                }
+            }
+        }
+    }
 
-        // register the new peer
-        self.register(stream, peer::Direction::Outgoing, ex).await
+    /// Report a failed dial to whoever asked for it, a persistent peer is dialed again after its backoff
+    fn dial_failed(&mut self, addr: std::net::SocketAddr, e: std::io::Error, purpose: DialPurpose) {
+        match purpose {
+            DialPurpose::Connect(result_chan) => {
+                let _ = result_chan.send(Err(e));
+            }
+            DialPurpose::AddrBook(_) => debug!("Error connecting to stored peer {}: {}", addr, e),
+            DialPurpose::Persistent(result_chan) => {
+                if let Some(persistent) = self.persistent.get_mut(&addr) {
+                    let delay = persistent.dial_failed(&mut rand::thread_rng());
+                    info!("Error connecting to persistent peer {}: {}, retrying in {:.1} seconds", addr, e, delay.as_secs_f64());
+                    self.schedule_dial(addr, delay);
+                }
+                if let Some(result_chan) = result_chan {
+                    let _ = result_chan.send(Err(e));
+                }
+            }
+        }
     }
 
     async fn accept(
@@ -126,95 +1084,205 @@ impl Context {
         stream: Async<net::TcpStream>,
         ex: Arc<Executor<'_>>,
     ) -> std::io::Result<()> {
-        self.register(stream, peer::Direction::Incoming, ex).await?;
+        let addr = peer::remote_addr(&stream)?;
+        self.register(stream, addr, peer::Direction::Incoming, ex).await?;
         Ok(())
     }
 
+    /// Start the reader and writer of a connection to the peer at `addr`. For peers dialed through the proxy that
+    /// is not where the stream goes
     async fn register(
         &mut self,
         stream: Async<net::TcpStream>,
-        _direction: peer::Direction,
+        addr: std::net::SocketAddr,
+        direction: peer::Direction,
         ex: Arc<Executor<'_>>,
     ) -> std::io::Result<peer::Handle> {
-        let (mut write_queue, handle) = peer::new(&stream)?;
+        let (mut write_queue, handle) = peer::new(addr, direction);
 
         let stream = AsyncArc::new(stream);
         let new_msg_chan = self.new_msg_chan.clone();
         let handle_copy = handle.clone();
         let control_chan = self.control_sender.clone();
-        let addr = stream.get_ref().peer_addr()?;
+        let connection = ConnectionStats::new();
+        let messages_received = Arc::clone(&connection.messages_received);
+        let messages_sent = Arc::clone(&connection.messages_sent);
+        let last_received = Arc::clone(&connection.last_received);
+        let traffic = self.traffic_counters(addr);
+        let impairments = Arc::clone(&self.impairments);
+        let max_message_size = self.max_message_size;
+        let plaintext = self.encryption.is_none();
+
+        let encryption = self.encryption.clone();
 
         // start the reactor for this peer
-        // first, start a task that keeps reading from this guy
-        let mut reader = BufReader::new(stream.clone());
         ex.spawn(async move {
-            // the buffer to store the frame header, which contains the length of the frame
-            let mut size_buffer: [u8; 4] = [0; 4];
-            // the buffer to store the message content
-            let mut msg_buffer: Vec<u8> = vec![];
-            loop {
-                // first, read exactly 4 bytes to get the frame header
-                let msg_size = match reader.read_exact(&mut size_buffer).await {
-                    Ok(_) => u32::from_be_bytes(size_buffer),
-                    Err(_) => {
+            // the framing below is the same whatever the transport
+            let (mut reader, mut writer) = match open_transport(&stream, direction, encryption).await {
+                Ok(transport) => transport,
+                Err(e) => {
+                    warn!("Encrypted handshake with peer {} failed, is it running without --p2p-tls? {}", addr, e);
+                    handle_copy.disconnect();
+                    let _ = stream.get_ref().shutdown(net::Shutdown::Both);
+                    let _ = control_chan.send(ControlSignal::DroppedPeer(addr)).await;
+                    return;
+                }
+            };
+
+            // first, a task that keeps reading from this guy
+            let read = async {
+                // the buffer to store the frame header, see frame.rs for its layout
+                let mut header_buffer: [u8; frame::HEADER_LEN] = [0; frame::HEADER_LEN];
+                // the buffer to store the message content
+                let mut msg_buffer: Vec<u8> = vec![];
+                let mut oversized_frames = 0;
+                let mut corrupt_frames = 0;
+                let mut first_frame = true;
+                loop {
+                    // first, read exactly the frame header; the magic alone first, an encrypted peer only
+                    // sends its 8 byte prologue before waiting for ours
+                    if reader.read_exact(&mut header_buffer[..4]).await.is_err() {
                         break;
                     }
-                };
-                // then, read exactly msg_size bytes to get the whole message
-                if msg_buffer.len() < msg_size as usize {
-                    msg_buffer.resize(msg_size as usize, 0);
-                }
-                match reader
-                    .read_exact(&mut msg_buffer[0..msg_size as usize])
-                    .await
-                {
-                    Ok(_) => {
-                        let new_payload: Vec<u8> = msg_buffer[0..msg_size as usize].to_vec();
-                        new_msg_chan
-                            .send((new_payload, handle_copy.clone()))
-                            .await
-                            .unwrap();
-                    }
-                    Err(_) => {
+                    if first_frame && plaintext && header_buffer[..4] == noise::PROLOGUE[..4] {
+                        warn!("Peer {} is using the encrypted transport, start with --p2p-tls to talk to it", addr);
                         break;
                     }
-                }
-            }
-            // the peer is disconnected
-        })
-            .detach();
-
-        // second, start a task that keeps writing to this guy
-        let mut writer = BufWriter::new(stream.clone());
-        ex.spawn(async move {
-            loop {
-                // first, get a message to write from the queue
-                let new_msg = write_queue.next().await.unwrap();
-
-                // second, encode the length of the message
-                let size_buffer = (new_msg.len() as u32).to_be_bytes();
-
-                // third, write the frame header and the payload
-                match writer.write_all(&size_buffer).await {
-                    Ok(_) => {}
-                    Err(_) => {
+                    first_frame = false;
+                    if reader.read_exact(&mut header_buffer[4..]).await.is_err() {
                         break;
                     }
+                    let header = match frame::FrameHeader::parse(&header_buffer) {
+                        Ok(header) => header,
+                        Err(e) => {
+                            warn!("Peer {} sent a bad frame header, disconnecting: {}", addr, e);
+                            break;
+                        }
+                    };
+                    let msg_size = header.length;
+                    // drop oversized frames without buffering them
+                    if msg_size as usize > max_message_size {
+                        oversized_frames += 1;
+                        warn!("Peer {} sent a {} byte frame, over the {} byte limit", addr, msg_size, max_message_size);
+                        if oversized_frames >= MAX_OVERSIZED_FRAMES {
+                            warn!("Peer {} sent {} oversized frames, disconnecting", addr, oversized_frames);
+                            break;
+                        }
+                        if futures::io::copy((&mut reader).take(msg_size as u64), &mut sink()).await.is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                    // then, read exactly msg_size bytes to get the whole message
+                    if msg_buffer.len() < msg_size as usize {
+                        msg_buffer.resize(msg_size as usize, 0);
+                    }
+                    match reader
+                        .read_exact(&mut msg_buffer[0..msg_size as usize])
+                        .await
+                    {
+                        Ok(_) => {
+                            if let Err(e) = header.verify(&msg_buffer[0..msg_size as usize]) {
+                                corrupt_frames += 1;
+                                warn!("Peer {} sent a corrupt frame, dropping it: {}", addr, e);
+                                if corrupt_frames >= MAX_CORRUPT_FRAMES {
+                                    warn!("Peer {} sent {} corrupt frames, disconnecting", addr, corrupt_frames);
+                                    break;
+                                }
+                                continue;
+                            }
+                            let new_payload: Vec<u8> = msg_buffer[0..msg_size as usize].to_vec();
+                            messages_received.fetch_add(1, Ordering::Relaxed);
+                            traffic.record_received(&new_payload);
+                            last_received.store(unix_millis(), Ordering::Relaxed);
+                            if new_msg_chan
+                                .send((new_payload, handle_copy.clone()))
+                                .await
+                                .is_err() {
+                                //the workers have shut down
+                                break;
+                            }
+                        }
+                        Err(_) => {
+                            break;
+                        }
+                    }
                 }
-                match writer.write_all(&new_msg).await {
-                    Ok(_) => {}
-                    Err(_) => {
+                // the peer is disconnected, stop the writer as well so the server drops it
+                handle_copy.disconnect();
+            };
+
+            // second, a task that keeps writing to this guy; the messages first go through the injected
+            // latency and loss, if any, with the time each one may be written
+            let (delayed_sender, delayed_receiver) = smol::channel::unbounded::<(Instant, Vec<u8>)>();
+            let impair = async {
+                let mut last_due = Instant::now();
+                loop {
+                    // first, get a message to write from the queue; it ends when the peer is disconnected
+                    let new_msg = match write_queue.next().await {
+                        Some(msg) => msg,
+                        None => {
+                            break;
+                        }
+                    };
+                    // count it as sent before it is held back or lost, the stats show what we tried to send
+                    messages_sent.fetch_add(1, Ordering::Relaxed);
+                    traffic.record_sent(&new_msg);
+                    let impairment = impairments.read().unwrap().for_peer(&addr);
+                    if impairment.loses(&new_msg, &mut rand::thread_rng()) {
+                        trace!("Injected loss of a message to peer {}", addr);
+                        continue;
+                    }
+                    // a sampled latency never lets a message overtake the one before it
+                    let due = (Instant::now() + impairment.sample_latency(&mut rand::thread_rng())).max(last_due);
+                    last_due = due;
+                    if delayed_sender.send((due, new_msg)).await.is_err() {
                         break;
                     }
                 }
-                match writer.flush().await {
-                    Ok(_) => {}
-                    Err(_) => {
-                        break;
+                delayed_sender.close();
+            };
+            let write = async {
+                loop {
+                    let (due, new_msg) = match delayed_receiver.recv().await {
+                        Ok(delayed) => delayed,
+                        Err(_) => {
+                            break;
+                        }
+                    };
+                    if due > Instant::now() {
+                        Timer::at(due).await;
+                    }
+
+                    // second, build the frame header: magic, version, length and checksum
+                    let header = frame::FrameHeader::new(&new_msg).to_bytes();
+
+                    // third, write the frame header and the payload
+                    match writer.write_all(&header).await {
+                        Ok(_) => {}
+                        Err(_) => {
+                            break;
+                        }
+                    }
+                    match writer.write_all(&new_msg).await {
+                        Ok(_) => {}
+                        Err(_) => {
+                            break;
+                        }
+                    }
+                    match writer.flush().await {
+                        Ok(_) => {}
+                        Err(_) => {
+                            break;
+                        }
                     }
                 }
-            }
-            // the peer is disconnected
+                // the peer is disconnected, make sure the reader stops as well
+                delayed_receiver.close();
+                let _ = stream.get_ref().shutdown(net::Shutdown::Both);
+            };
+
+            futures::join!(read, impair, write);
             control_chan
                 .send(ControlSignal::DroppedPeer(addr))
                 .await
@@ -224,10 +1292,260 @@ impl Context {
 
         // insert the peer handle so that we can broadcast to this guy later
         self.peers.insert(addr, handle.clone());
+        self.connections.insert(addr, connection);
         Ok(handle)
     }
 }
 
+/// Whether an address gossiped by the peer at `from` is worth keeping. Loopback addresses only make sense coming
+/// from a peer on the same host
+fn is_learnable(addr: &std::net::SocketAddr, from: &std::net::SocketAddr) -> bool {
+    let ip = addr.ip();
+    if ip.is_unspecified() || ip.is_multicast() || addr.port() == 0 {
+        return false;
+    }
+    return !ip.is_loopback() || from.ip().is_loopback();
+}
+
+/// Open a TCP connection to a peer, directly or through the proxy, `host` being the name the proxy resolves if the
+/// peer is only known by one. A proxy that fails to connect us counts as the peer being unreachable
+async fn dial(addr: std::net::SocketAddr, proxy: Option<std::net::SocketAddr>, host: Option<String>) -> std::io::Result<Async<net::TcpStream>> {
+    let proxy = match proxy {
+        Some(proxy) => proxy,
+        None => {
+            let timeout = async {
+                Timer::after(CONNECT_TIMEOUT).await;
+                return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "the peer did not answer in time"));
+            };
+            return smol::future::or(Async::<std::net::TcpStream>::connect(addr), timeout).await;
+        }
+    };
+    let target = match host {
+        Some(host) => socks5::Target::Domain(host, addr.port()),
+        None => socks5::Target::Addr(addr),
+    };
+    let handshake = async {
+        let mut stream = Async::<std::net::TcpStream>::connect(proxy).await?;
+        socks5::handshake(&mut stream, &target).await?;
+        return Ok(stream);
+    };
+    let timeout = async {
+        Timer::after(PROXY_HANDSHAKE_TIMEOUT).await;
+        return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "the proxy did not answer in time"));
+    };
+    return smol::future::or(handshake, timeout).await;
+}
+
+/// Wrap a connection for the transport in use, running the encrypted handshake if there is one
+async fn open_transport(
+    stream: &AsyncArc<Async<net::TcpStream>>,
+    direction: peer::Direction,
+    encryption: Option<Arc<Keypair>>,
+) -> std::io::Result<Transport> {
+    let keypair = match encryption {
+        Some(keypair) => keypair,
+        None => return Ok((Box::new(BufReader::new(stream.clone())), Box::new(BufWriter::new(stream.clone())))),
+    };
+    let handshake = noise::handshake(BufReader::new(stream.clone()), stream.clone(), direction, &keypair);
+    let timeout = async {
+        Timer::after(noise::HANDSHAKE_TIMEOUT).await;
+        Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "handshake timed out"))
+    };
+    let (reader, writer) = smol::future::or(handshake, timeout).await?;
+    return Ok((Box::new(reader), Box::new(writer)));
+}
+
+fn unix_millis() -> u64 {
+    return SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+}
+
+/// Round trip bookkeeping for the pings sent to one peer
+struct PeerStats {
+    //nonce and send time of the ping we are still waiting on
+    outstanding_ping: Option<(u64, Instant)>,
+    missed_pings: u32,
+    latency: Option<Duration>,
+    //sum and count of every measured round trip, for the average
+    total_latency: Duration,
+    samples: u32,
+}
+
+/// Connection bookkeeping shared with a peer's reader and writer tasks
+struct ConnectionStats {
+    //milliseconds since the unix epoch
+    connected_since: u128,
+    messages_sent: Arc<AtomicU64>,
+    messages_received: Arc<AtomicU64>,
+    //milliseconds since the unix epoch of the last message received, or of the connection
+    last_received: Arc<AtomicU64>,
+}
+
+impl ConnectionStats {
+    fn new() -> Self {
+        return ConnectionStats {
+            connected_since: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis(),
+            messages_sent: Arc::new(AtomicU64::new(0)),
+            messages_received: Arc::new(AtomicU64::new(0)),
+            last_received: Arc::new(AtomicU64::new(unix_millis())),
+        };
+    }
+}
+
+/// Connected peers per direction and the caps on them, as reported by Handle::connection_counts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConnectionCounts {
+    pub inbound: usize,
+    pub outbound: usize,
+    pub max_inbound: usize,
+    pub max_outbound: usize,
+}
+
+/// A connected peer as reported by Handle::peer_info
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub addr: std::net::SocketAddr,
+    pub direction: peer::Direction,
+    //milliseconds since the unix epoch
+    pub connected_since: u128,
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    //None until the peer has answered a ping
+    pub latency: Option<PeerLatency>,
+    pub compression: peer::CompressionStats,
+}
+
+/// An IP refused by the server, as reported by Handle::banned
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BannedPeer {
+    pub ip: net::IpAddr,
+    pub expires_in: Duration,
+    //unix time in milliseconds the ban ends
+    pub expires_at: u64,
+}
+
+/// Round trip times measured for a peer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PeerLatency {
+    pub latest: Duration,
+    pub average: Duration,
+}
+
+impl PeerStats {
+    fn new() -> Self {
+        return PeerStats { outstanding_ping: None, missed_pings: 0, latency: None, total_latency: Duration::from_secs(0), samples: 0 };
+    }
+
+    /// Whether the outstanding ping was sent less than a ping interval ago, it isn't missed yet
+    fn ping_in_flight(&self, now: Instant) -> bool {
+        return matches!(self.outstanding_ping, Some((_, sent_at)) if now.duration_since(sent_at) < Duration::from_secs(PING_INTERVAL_SECS));
+    }
+
+    /// Record a new ping, returns false once the peer has missed MAX_MISSED_PINGS pings in a row
+    fn ping_sent(&mut self, nonce: u64, now: Instant) -> bool {
+        if self.outstanding_ping.is_some() {
+            self.missed_pings += 1;
+            if self.missed_pings >= MAX_MISSED_PINGS {
+                return false;
+            }
+        }
+        self.outstanding_ping = Some((nonce, now));
+        return true;
+    }
+
+    /// Whether the outstanding ping has waited past the timeout with nothing heard from the peer since it was sent
+    fn ping_timed_out(&self, silent_for: Duration, timeout: Duration, now: Instant) -> bool {
+        match self.outstanding_ping {
+            Some((_, sent_at)) => {
+                let waited = now.duration_since(sent_at);
+                return waited >= timeout && silent_for >= waited;
+            }
+            None => return false,
+        }
+    }
+
+    /// Latest and average round trip time, None until a pong has been received
+    fn peer_latency(&self) -> Option<PeerLatency> {
+        let latest = self.latency?;
+        return Some(PeerLatency { latest, average: self.total_latency / self.samples });
+    }
+
+    /// Record the round trip time if the pong answers the outstanding ping
+    fn pong_received(&mut self, nonce: u64, now: Instant) {
+        if let Some((expected, sent_at)) = self.outstanding_ping {
+            if expected == nonce {
+                let latency = now.duration_since(sent_at);
+                self.latency = Some(latency);
+                self.total_latency += latency;
+                self.samples += 1;
+                self.outstanding_ping = None;
+                self.missed_pings = 0;
+            }
+        }
+    }
+}
+
+/// Delay before the next reconnection attempt: 1s, 2s, 4s, ... capped at RECONNECT_BACKOFF_CAP_SECS
+fn reconnect_backoff(attempt: u32) -> Duration {
+    let secs = 1u64.checked_shl(attempt).unwrap_or(u64::MAX);
+    return Duration::from_secs(secs.min(RECONNECT_BACKOFF_CAP_SECS));
+}
+
+/// reconnect_backoff plus up to RECONNECT_JITTER of it at random
+fn jittered_backoff<R: Rng>(attempt: u32, rng: &mut R) -> Duration {
+    let delay = reconnect_backoff(attempt);
+    return delay + delay.mul_f64(rng.gen_range(0.0..RECONNECT_JITTER));
+}
+
+/// A peer we keep a connection to, and how long to wait before dialing it again
+struct PersistentPeer {
+    greeting: Greeting,
+    //failed dials and short-lived connections in a row, each one doubles the wait before the next dial;
+    //a connection lasting STABLE_CONNECTION_UPTIME resets it
+    failures: u32,
+    //when the current connection was made, None while not connected
+    connected_at: Option<Instant>,
+}
+
+impl PersistentPeer {
+    fn new(greeting: Greeting) -> Self {
+        return PersistentPeer { greeting, failures: 0, connected_at: None };
+    }
+
+    /// Back off before the next dial, longer after every failure in a row; returns the wait
+    fn dial_failed<R: Rng>(&mut self, rng: &mut R) -> Duration {
+        let delay = jittered_backoff(self.failures, rng);
+        self.failures = self.failures.saturating_add(1);
+        return delay;
+    }
+
+    fn connected(&mut self, now: Instant) {
+        self.connected_at = Some(now);
+    }
+
+    /// The connection went away, returns the wait before dialing again. Only a connection that lasted resets the
+    /// backoff, a peer that accepts us and drops us straight away, say because it banned us, keeps backing off
+    fn dropped<R: Rng>(&mut self, now: Instant, rng: &mut R) -> Duration {
+        if let Some(connected_at) = self.connected_at.take() {
+            if now.duration_since(connected_at) >= STABLE_CONNECTION_UPTIME {
+                self.failures = 0;
+            }
+        }
+        return self.dial_failed(rng);
+    }
+}
+
+/// Return the hashes missing from a peer's known inventory, recording them as known
+fn unknown_inventory(known_inv: &mut LruCache<H256, ()>, hashes: &[H256]) -> Vec<H256> {
+    let mut unknown = Vec::<H256>::new();
+    for hash in hashes {
+        if known_inv.get(hash).is_none() {
+            known_inv.put(*hash, ());
+            unknown.push(*hash);
+        }
+    }
+    return unknown;
+}
+
 #[derive(Clone)]
 pub struct Handle {
     control_chan: smol::channel::Sender<ControlSignal>,
@@ -246,6 +1564,17 @@ impl TestReceiver {
             _ => None,
         }
     }
+
+    /// Misbehavior scores reported so far, every other pending signal is dropped
+    pub fn misbehavior(&self) -> Vec<(std::net::SocketAddr, u32)> {
+        let mut scores = Vec::new();
+        while let Ok(sig) = self.control_chan.try_recv() {
+            if let ControlSignal::Misbehaving(addr, score) = sig {
+                scores.push((addr, score));
+            }
+        }
+        return scores;
+    }
 }
 
 impl Handle {
@@ -259,10 +1588,151 @@ impl Handle {
         smol::block_on(receiver).unwrap()
     }
 
+    /// The address to connect to `peer` at, see Context::resolve_peer. Host names are left to the proxy if there is one
+    pub fn resolve_peer(&self, peer: &str) -> std::io::Result<std::net::SocketAddr> {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::ResolvePeer(peer.to_string(), sender))).unwrap();
+        return smol::block_on(receiver).unwrap();
+    }
+
+    /// Keep a connection to this peer, redialing with backoff whenever it drops or can't be reached
+    pub fn add_persistent_peer(&self, addr: std::net::SocketAddr, greeting: Greeting) {
+        smol::block_on(self.control_chan.send(ControlSignal::AddPersistentPeer(addr, greeting))).unwrap();
+    }
+
+    /// Like add_persistent_peer, but waits for the first connection attempt and returns its result.
+    /// The server keeps retrying with backoff if it fails
+    pub fn add_persistent_peer_now(&self, addr: std::net::SocketAddr, greeting: Greeting) -> std::io::Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::ConnectPersistentPeer(addr, greeting, sender))).unwrap();
+        return smol::block_on(receiver).unwrap();
+    }
+
+    /// Close the connection to this peer and stop reconnecting to it, returns false if we had neither
+    pub fn disconnect(&self, addr: std::net::SocketAddr) -> bool {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::DisconnectPeer(addr, sender))).unwrap();
+        return smol::block_on(receiver).unwrap();
+    }
+
     pub fn broadcast(&self, msg: message::Message) {
         smol::block_on(self.control_chan.send(ControlSignal::BroadcastMessage(msg))).unwrap();
     }
 
+    /// Record that a peer already knows about these block/transaction hashes so we don't announce them back
+    pub fn add_known_inventory(&self, addr: std::net::SocketAddr, hashes: Vec<H256>) {
+        smol::block_on(self.control_chan.send(ControlSignal::AddKnownInventory(addr, hashes))).unwrap();
+    }
+
+    /// Mark a peer as having completed the handshake so it starts receiving broadcasts
+    pub fn handshake_complete(&self, addr: std::net::SocketAddr) {
+        smol::block_on(self.control_chan.send(ControlSignal::HandshakeComplete(addr))).unwrap();
+    }
+
+    /// Tell the server which node a peer is, a second connection to the same node is closed
+    pub fn register_node(&self, addr: std::net::SocketAddr, node_nonce: u64) {
+        smol::block_on(self.control_chan.send(ControlSignal::RegisterNode(addr, node_nonce))).unwrap();
+    }
+
+    /// Add peer listening addresses heard of in handshakes or gossip from the peer at `from` to the address book
+    pub fn learn_addrs(&self, from: std::net::SocketAddr, addrs: Vec<std::net::SocketAddr>) {
+        smol::block_on(self.control_chan.send(ControlSignal::LearnAddrs(from, addrs))).unwrap();
+    }
+
+    /// Start dialing the address book's most reliable entries, at most ADDR_BOOK_MAX_DIALS and no more than the free
+    /// outbound slots, greeting every peer reached. Returns how many dials were started, they finish in the background
+    pub fn connect_from_addr_book(&self, greeting: Greeting) -> usize {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::ConnectFromAddrBook(greeting, sender))).unwrap();
+        return smol::block_on(receiver).unwrap();
+    }
+
+    /// Addresses of the connected peers that completed the handshake
+    pub fn handshaked_peers(&self) -> Vec<std::net::SocketAddr> {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::GetHandshakedPeers(sender))).unwrap();
+        return smol::block_on(receiver).unwrap();
+    }
+
+    /// Report a pong from a peer so its round trip time can be measured
+    pub fn pong_received(&self, addr: std::net::SocketAddr, nonce: u64) {
+        smol::block_on(self.control_chan.send(ControlSignal::PongReceived(addr, nonce))).unwrap();
+    }
+
+    /// Ask the server to ping every peer now instead of waiting for the next round
+    pub fn ping_peers(&self) {
+        smol::block_on(self.control_chan.send(ControlSignal::PingPeers)).unwrap();
+    }
+
+    /// Every connected peer with its direction, connection time and traffic
+    pub fn peer_info(&self) -> Vec<PeerInfo> {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::GetPeerInfo(sender))).unwrap();
+        return smol::block_on(receiver).unwrap();
+    }
+
+    /// How many peers are connected in each direction, against the limits
+    pub fn connection_counts(&self) -> ConnectionCounts {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::GetConnectionCounts(sender))).unwrap();
+        return smol::block_on(receiver).unwrap();
+    }
+
+    /// Degrade the link to one peer, or with None make it follow the node-wide impairment again
+    pub fn set_peer_impairment(&self, addr: std::net::SocketAddr, impairment: Option<Impairment>) {
+        smol::block_on(self.control_chan.send(ControlSignal::SetPeerImpairment(addr, impairment))).unwrap();
+    }
+
+    /// The node-wide impairment and the ones set for single peers
+    pub fn impairments(&self) -> Impairments {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::GetImpairments(sender))).unwrap();
+        return smol::block_on(receiver).unwrap();
+    }
+
+    /// Messages and bytes exchanged with every peer address so far, by class of message, and node-wide totals
+    pub fn traffic_stats(&self) -> TrafficStats {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::GetTrafficStats(sender))).unwrap();
+        return smol::block_on(receiver).unwrap();
+    }
+
+    /// Messages waiting for the workers in each lane, and how many transaction messages were shed
+    pub fn queue_stats(&self) -> QueueStats {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::GetQueueStats(sender))).unwrap();
+        return smol::block_on(receiver).unwrap();
+    }
+
+    /// Latest and average round trip time of every peer that has answered a ping
+    pub fn peer_latencies(&self) -> HashMap<std::net::SocketAddr, PeerLatency> {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::GetPeerLatencies(sender))).unwrap();
+        return smol::block_on(receiver).unwrap();
+    }
+
+    /// Disconnect every peer, refuse new ones and close the channel feeding the network workers
+    pub fn shutdown(&self) {
+        smol::block_on(self.control_chan.send(ControlSignal::Shutdown)).unwrap();
+    }
+
+    /// Disconnect a misbehaving peer and refuse connections from its IP for the ban duration
+    pub fn ban(&self, addr: std::net::SocketAddr) {
+        smol::block_on(self.control_chan.send(ControlSignal::BanPeer(addr))).unwrap();
+    }
+
+    /// Add to a peer's misbehavior score, the peer is banned once it reaches BAN_SCORE_THRESHOLD
+    pub fn misbehaving(&self, addr: std::net::SocketAddr, score: u32) {
+        smol::block_on(self.control_chan.send(ControlSignal::Misbehaving(addr, score))).unwrap();
+    }
+
+    /// IPs currently refused, with the time left on their ban
+    pub fn banned(&self) -> Vec<BannedPeer> {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::GetBanned(sender))).unwrap();
+        return smol::block_on(receiver).unwrap();
+    }
+
     pub fn send(&self, receiver: Address, msg: message::Message) {
         smol::block_on(self.control_chan.send(ControlSignal::SendToPeer((receiver, msg)))).unwrap();
     }
@@ -276,13 +1746,983 @@ impl Handle {
     }
 }
 
+/// What to do with the connection once a dial started by start_dial finishes
+enum DialPurpose {
+    //Handle::connect is waiting for it
+    Connect(oneshot::Sender<std::io::Result<peer::Handle>>),
+    //a persistent peer, Handle::add_persistent_peer_now waits on the first attempt
+    Persistent(Option<oneshot::Sender<std::io::Result<()>>>),
+    //an address book entry, greeted once connected
+    AddrBook(Greeting),
+}
+
 enum ControlSignal {
     ConnectNewPeer(
         std::net::SocketAddr,
         oneshot::Sender<std::io::Result<peer::Handle>>,
     ),
+    ResolvePeer(String, oneshot::Sender<std::io::Result<std::net::SocketAddr>>),
+    AddPersistentPeer(std::net::SocketAddr, Greeting),
+    ConnectPersistentPeer(std::net::SocketAddr, Greeting, oneshot::Sender<std::io::Result<()>>),
+    DisconnectPeer(std::net::SocketAddr, oneshot::Sender<bool>),
+    DialPersistentPeer(std::net::SocketAddr),
+    Dialed(std::net::SocketAddr, std::io::Result<Async<net::TcpStream>>, DialPurpose),
     BroadcastMessage(message::Message),
     GetNewPeer(Async<net::TcpStream>),
     DroppedPeer(std::net::SocketAddr),
+    BanPeer(std::net::SocketAddr),
+    Misbehaving(std::net::SocketAddr, u32),
+    GetBanned(oneshot::Sender<Vec<BannedPeer>>),
+    SaveBanList,
+    SaveAddrBook,
+    AddKnownInventory(std::net::SocketAddr, Vec<H256>),
+    LearnAddrs(std::net::SocketAddr, Vec<std::net::SocketAddr>),
+    ConnectFromAddrBook(Greeting, oneshot::Sender<usize>),
+    HandshakeComplete(std::net::SocketAddr),
+    RegisterNode(std::net::SocketAddr, u64),
+    GetHandshakedPeers(oneshot::Sender<Vec<std::net::SocketAddr>>),
+    PingPeers,
+    CheckIdlePeers,
+    PongReceived(std::net::SocketAddr, u64),
+    GetPeerLatencies(oneshot::Sender<HashMap<std::net::SocketAddr, PeerLatency>>),
+    GetPeerInfo(oneshot::Sender<Vec<PeerInfo>>),
+    GetConnectionCounts(oneshot::Sender<ConnectionCounts>),
+    GetQueueStats(oneshot::Sender<QueueStats>),
+    GetTrafficStats(oneshot::Sender<TrafficStats>),
+    SetPeerImpairment(std::net::SocketAddr, Option<Impairment>),
+    GetImpairments(oneshot::Sender<Impairments>),
+    Shutdown,
     SendToPeer((Address,message::Message)),
 }
+
+#[cfg(test)]
+mod test {
+    use lru::LruCache;
+    use crate::types::hash::generate_random_hash;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use std::io::{Read, Write};
+    use std::net::{Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+    use std::sync::Arc;
+    use ntest::timeout;
+    use crate::network::message::{DisconnectReason, Message};
+    use crate::miner::BLOCK_SIZE_LIMIT;
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::Hashable;
+    use crate::types::transaction::{generate_random_transaction, SignedTransaction};
+    use super::super::addr_book::{unix_secs, AddrBook, ADDR_BOOK_MAX_AGE_SECS};
+    use super::super::ban_list::BanList;
+    use super::super::frame::{encode, read_frame, FrameHeader};
+    use super::super::queue;
+    use super::super::impairment::Impairment;
+    use super::super::traffic::{TrafficBreakdown, TrafficClass};
+    use super::{is_learnable, parse_addr, reconnect_backoff, unix_millis, unknown_inventory, PeerStats, PersistentPeer, BAN_SCORE_THRESHOLD, STABLE_CONNECTION_UPTIME, DEFAULT_MAX_MESSAGE_SIZE, MAX_CORRUPT_FRAMES, MAX_MISSED_PINGS, MAX_OVERSIZED_FRAMES, PING_INTERVAL_SECS, RECONNECT_BACKOFF_CAP_SECS, RECONNECT_JITTER};
+
+    #[test]
+    fn known_inventory_is_not_announced_again() {
+        let mut known_inv = LruCache::new(10);
+        let h1 = generate_random_hash();
+        let h2 = generate_random_hash();
+        known_inv.put(h1, ());
+        assert_eq!(unknown_inventory(&mut known_inv, &[h1, h2]), vec![h2]);
+        //h2 was just announced so the peer knows it now
+        assert!(unknown_inventory(&mut known_inv, &[h1, h2]).is_empty());
+    }
+
+    #[test]
+    fn known_inventory_forgets_least_recently_used() {
+        let mut known_inv = LruCache::new(2);
+        let h1 = generate_random_hash();
+        let h2 = generate_random_hash();
+        let h3 = generate_random_hash();
+        unknown_inventory(&mut known_inv, &[h1, h2, h3]);
+        assert_eq!(unknown_inventory(&mut known_inv, &[h1]), vec![h1]);
+    }
+
+    #[test]
+    fn pong_sets_latency() {
+        let mut stats = PeerStats::new();
+        let sent_at = Instant::now();
+        assert!(stats.ping_sent(7, sent_at));
+        //a pong for some other ping is ignored
+        stats.pong_received(8, sent_at + Duration::from_millis(5));
+        assert_eq!(stats.latency, None);
+        stats.pong_received(7, sent_at + Duration::from_millis(20));
+        assert_eq!(stats.latency, Some(Duration::from_millis(20)));
+    }
+
+    #[test]
+    fn simulated_pong_records_bounded_latency() {
+        let mut stats = PeerStats::new();
+        assert_eq!(stats.peer_latency(), None);
+        for nonce in 0..2 {
+            assert!(stats.ping_sent(nonce, Instant::now()));
+            thread::sleep(Duration::from_millis(5));
+            stats.pong_received(nonce, Instant::now());
+        }
+        let latency = stats.peer_latency().unwrap();
+        assert!(latency.latest >= Duration::from_millis(5));
+        assert!(latency.average >= Duration::from_millis(5));
+        assert!(latency.latest < Duration::from_secs(1));
+        assert!(latency.average < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn unanswered_pings_drop_peer() {
+        let mut stats = PeerStats::new();
+        let now = Instant::now();
+        assert!(stats.ping_sent(1, now));
+        for i in 1..MAX_MISSED_PINGS {
+            assert!(stats.ping_sent(1 + i as u64, now));
+        }
+        assert!(!stats.ping_sent(10, now));
+    }
+
+    #[test]
+    fn ping_in_flight_is_not_missed_yet() {
+        let mut stats = PeerStats::new();
+        let now = Instant::now();
+        assert!(!stats.ping_in_flight(now));
+        assert!(stats.ping_sent(1, now));
+        assert!(stats.ping_in_flight(now + Duration::from_secs(1)));
+        assert!(!stats.ping_in_flight(now + Duration::from_secs(PING_INTERVAL_SECS)));
+        stats.pong_received(1, now);
+        assert!(!stats.ping_in_flight(now));
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn quick_pings_on_request_keep_silent_peers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (msg_tx, _msg_rx) = queue::channel(100);
+        let (ctx, server) = super::new(vec!["127.0.0.1:6170".parse().unwrap()], msg_tx, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+        ctx.start().unwrap();
+        let _peer = server.connect(listener.local_addr().unwrap()).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        for _ in 0..MAX_MISSED_PINGS + 2 {
+            server.ping_peers();
+        }
+        //only the first ping goes out, the others wait for its answer instead of counting it missed
+        let msg: Message = bincode::deserialize(&read_frame(&mut stream).unwrap()).unwrap();
+        assert!(matches!(msg, Message::Ping(_)));
+        stream.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+        assert!(read_frame(&mut stream).is_err());
+        assert_eq!(server.connection_counts().outbound, 1);
+    }
+
+    #[test]
+    fn pong_resets_missed_pings() {
+        let mut stats = PeerStats::new();
+        let now = Instant::now();
+        assert!(stats.ping_sent(1, now));
+        assert!(stats.ping_sent(2, now));
+        stats.pong_received(2, now);
+        for i in 0..MAX_MISSED_PINGS {
+            assert!(stats.ping_sent(3 + i as u64, now));
+        }
+    }
+
+    #[test]
+    fn ping_times_out_only_if_peer_stays_silent() {
+        let mut stats = PeerStats::new();
+        let now = Instant::now();
+        let timeout = Duration::from_secs(20);
+        assert!(!stats.ping_timed_out(Duration::from_secs(100), timeout, now));
+        assert!(stats.ping_sent(1, now));
+        assert!(!stats.ping_timed_out(Duration::from_secs(100), timeout, now + Duration::from_secs(10)));
+        assert!(stats.ping_timed_out(Duration::from_secs(100), timeout, now + timeout));
+        //heard from the peer after the ping went out, it is busy rather than gone
+        assert!(!stats.ping_timed_out(Duration::from_secs(5), timeout, now + timeout));
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn silent_peer_is_dropped() {
+        let listener = TcpListener::bind("127.0.0.1:6111").unwrap();
+        let (msg_tx, _msg_rx) = queue::channel(100);
+        let (mut ctx, server) = super::new(vec!["127.0.0.1:6112".parse().unwrap()], msg_tx, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+        let (idle, timeout) = (Duration::from_millis(200), Duration::from_millis(300));
+        ctx.set_keepalive(idle, timeout);
+        ctx.start().unwrap();
+        let _peer = server.connect(listener.local_addr().unwrap()).unwrap();
+        //accepts the connection but never answers anything
+        let (_stream, _) = listener.accept().unwrap();
+        let connected = Instant::now();
+        assert_eq!(server.peer_info().len(), 1);
+        while !server.peer_info().is_empty() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let elapsed = connected.elapsed();
+        assert!(elapsed >= idle + timeout);
+        assert!(elapsed < (idle + timeout) * 3);
+    }
+
+    #[test]
+    fn reconnect_backoff_doubles_up_to_cap() {
+        assert_eq!(reconnect_backoff(0), Duration::from_secs(1));
+        assert_eq!(reconnect_backoff(1), Duration::from_secs(2));
+        assert_eq!(reconnect_backoff(2), Duration::from_secs(4));
+        assert_eq!(reconnect_backoff(20), Duration::from_secs(RECONNECT_BACKOFF_CAP_SECS));
+        assert_eq!(reconnect_backoff(100), Duration::from_secs(RECONNECT_BACKOFF_CAP_SECS));
+    }
+
+    #[test]
+    fn persistent_peer_backoff_grows_and_resets_after_a_stable_connection() {
+        let mut rng = rand::thread_rng();
+        let mut peer = PersistentPeer::new(Arc::new(|_| Message::Ping(42)));
+        let now = Instant::now();
+        let mut last = Duration::from_secs(0);
+        for failures in 0..10 {
+            let delay = peer.dial_failed(&mut rng);
+            let backoff = reconnect_backoff(failures);
+            assert!(delay >= backoff && delay <= backoff.mul_f64(1.0 + RECONNECT_JITTER));
+            //jitter never makes a wait shorter than the one before, until both are at the cap
+            if backoff < Duration::from_secs(RECONNECT_BACKOFF_CAP_SECS) {
+                assert!(delay > last);
+            }
+            last = delay;
+        }
+
+        //a connection dropped right away is one more failure
+        let mut peer = PersistentPeer::new(Arc::new(|_| Message::Ping(42)));
+        peer.dial_failed(&mut rng);
+        peer.connected(now);
+        let delay = peer.dropped(now + Duration::from_secs(1), &mut rng);
+        assert!(delay >= reconnect_backoff(1));
+
+        //one that lasted is redialed after the shortest backoff again
+        peer.connected(now);
+        let delay = peer.dropped(now + STABLE_CONNECTION_UPTIME, &mut rng);
+        assert!(delay <= reconnect_backoff(0).mul_f64(1.0 + RECONNECT_JITTER));
+        assert_eq!(peer.failures, 1);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn persistent_peer_dropping_us_straight_away_is_redialed_with_backoff() {
+        let listener = TcpListener::bind("127.0.0.1:6169").unwrap();
+        let (msg_tx, _msg_rx) = queue::channel(100);
+        let (ctx, server) = super::new(vec!["127.0.0.1:6168".parse().unwrap()], msg_tx, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+        ctx.start().unwrap();
+        server.add_persistent_peer(listener.local_addr().unwrap(), Arc::new(|_| Message::Ping(42)));
+        //accept and close at once, every time
+        let accepted: Vec<Instant> = listener.incoming().take(3).map(|_| Instant::now()).collect();
+        let (first_wait, second_wait) = (accepted[1] - accepted[0], accepted[2] - accepted[1]);
+        assert!(first_wait >= reconnect_backoff(0), "{:?}", first_wait);
+        assert!(second_wait >= reconnect_backoff(1), "{:?}", second_wait);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn persistent_peer_is_dialed_once_it_comes_up() {
+        let (msg_tx, _msg_rx) = queue::channel(100);
+        let (ctx, server) = super::new(vec!["127.0.0.1:6160".parse().unwrap()], msg_tx, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+        ctx.start().unwrap();
+        //nobody listens yet, the first dial fails
+        let addr = "127.0.0.1:6161".parse().unwrap();
+        assert!(server.add_persistent_peer_now(addr, Arc::new(|_| Message::Ping(42))).is_err());
+
+        let listener = TcpListener::bind(addr).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        let msg: Message = bincode::deserialize(&read_frame(&mut stream).unwrap()).unwrap();
+        assert!(matches!(msg, Message::Ping(42)));
+    }
+
+    #[test]
+    fn gossiped_addresses_are_checked() {
+        let remote: SocketAddr = "10.0.0.1:6000".parse().unwrap();
+        let local: SocketAddr = "127.0.0.1:6000".parse().unwrap();
+        assert!(is_learnable(&"10.0.0.2:6000".parse().unwrap(), &remote));
+        assert!(is_learnable(&"127.0.0.1:6001".parse().unwrap(), &local));
+        //a remote peer can't reach our loopback for us
+        assert!(!is_learnable(&"127.0.0.1:6001".parse().unwrap(), &remote));
+        assert!(!is_learnable(&"0.0.0.0:6000".parse().unwrap(), &remote));
+        assert!(!is_learnable(&"10.0.0.2:0".parse().unwrap(), &remote));
+        assert!(!is_learnable(&"224.0.0.1:6000".parse().unwrap(), &remote));
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn restart_dials_stored_addresses() {
+        let path = std::env::temp_dir().join(format!("addr_book_restart_{}.json", std::process::id()));
+        let listener = TcpListener::bind("127.0.0.1:6130").unwrap();
+        let live = listener.local_addr().unwrap();
+        //nothing listens there, it stands for a peer that went away while we were down
+        let dead: SocketAddr = "127.0.0.1:6131".parse().unwrap();
+        let mut stored = AddrBook::new();
+        stored.record_success(live, unix_secs());
+        stored.learn(dead, unix_secs());
+        //seen too long ago to be worth keeping
+        stored.learn("127.0.0.1:6132".parse().unwrap(), unix_secs() - ADDR_BOOK_MAX_AGE_SECS - 1);
+        stored.save(&path).unwrap();
+
+        let (msg_tx, _msg_rx) = queue::channel(100);
+        let (mut ctx, server) = super::new(vec!["127.0.0.1:6129".parse().unwrap()], msg_tx, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+        ctx.set_addr_book(path.clone()).unwrap();
+        ctx.start().unwrap();
+        assert_eq!(server.connect_from_addr_book(Arc::new(|_| Message::Ping(7))), 2);
+        let (mut stream, _) = listener.accept().unwrap();
+        let msg: Message = bincode::deserialize(&read_frame(&mut stream).unwrap()).unwrap();
+        assert!(matches!(msg, Message::Ping(7)));
+        //the refused dial reports back right away
+        thread::sleep(Duration::from_millis(200));
+
+        //the book is written on shutdown, with the outcome of both attempts
+        server.shutdown();
+        let saved = loop {
+            let saved = AddrBook::load(&path).unwrap();
+            if saved.get(&live).map(|entry| entry.successes) == Some(2) {
+                break saved;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(saved.get(&dead).unwrap().failures, 1);
+        assert_eq!(saved.len(), 2);
+    }
+    #[test]
+    #[timeout(60000)]
+    fn bans_outlast_a_restart() {
+        let path = std::env::temp_dir().join(format!("ban_list_restart_{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let banned_peer: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+        let scored_peer: SocketAddr = "127.0.0.2:40000".parse().unwrap();
+        let (msg_tx, _msg_rx) = queue::channel(100);
+        let (mut ctx, server) = super::new(vec!["127.0.0.1:6164".parse().unwrap()], msg_tx, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+        ctx.set_ban_list(path.clone()).unwrap();
+        ctx.start().unwrap();
+        server.ban(banned_peer);
+        server.misbehaving(scored_peer, BAN_SCORE_THRESHOLD - 10);
+        let expires_at = server.banned()[0].expires_at;
+        //written shortly after the change, without waiting for shutdown
+        while BanList::load(&path).unwrap().misbehavior(&scored_peer.ip(), unix_millis()) == 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        server.shutdown();
+
+        //a fresh server reading the same file still refuses the peer
+        let (msg_tx, _msg_rx) = queue::channel(100);
+        let (mut ctx, server) = super::new(vec!["127.0.0.1:6165".parse().unwrap()], msg_tx, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+        ctx.set_ban_list(path.clone()).unwrap();
+        ctx.start().unwrap();
+        let banned = server.banned();
+        assert_eq!(banned.len(), 1);
+        assert_eq!((banned[0].ip, banned[0].expires_at), (banned_peer.ip(), expires_at));
+        let mut stream = TcpStream::connect("127.0.0.1:6165").unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        assert!(matches!(stream.read(&mut [0u8; 1]), Ok(0)));
+        //and the other peer's score carries on from where it was
+        server.misbehaving(scored_peer, 10);
+        assert_eq!(server.banned().len(), 2);
+        server.shutdown();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn persistent_peer_is_reconnected() {
+        //the remote side is a bare listener so the test can kill the connection itself
+        let listener = TcpListener::bind("127.0.0.1:6090").unwrap();
+        let (msg_tx, _msg_rx) = queue::channel(100);
+        let (ctx, server) = super::new(vec!["127.0.0.1:6089".parse().unwrap()], msg_tx, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+        ctx.start().unwrap();
+        server.add_persistent_peer(listener.local_addr().unwrap(), Arc::new(|_| Message::Ping(42)));
+
+        let (stream, _) = listener.accept().unwrap();
+        drop(stream);
+        //the server notices the drop and dials again, greeting the peer on the new connection
+        let (mut stream, _) = listener.accept().unwrap();
+        let msg: Message = bincode::deserialize(&read_frame(&mut stream).unwrap()).unwrap();
+        assert!(matches!(msg, Message::Ping(42)));
+    }
+
+    #[test]
+    fn full_block_fits_default_message_size() {
+        let mut block = generate_random_block(&generate_random_hash());
+        let mut size = 0;
+        while size < BLOCK_SIZE_LIMIT {
+            let tx = SignedTransaction { transaction: generate_random_transaction(), signature: vec![0; 64], public_key: vec![0; 32] };
+            size += bincode::serialize(&tx).unwrap().len();
+            block.content.data.push(tx);
+        }
+        let bytes = bincode::serialize(&Message::Blocks(vec![block])).unwrap();
+        assert!(bytes.len() * 100 < DEFAULT_MAX_MESSAGE_SIZE);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    #[cfg(feature = "compression")]
+    fn compressed_messages_round_trip_over_the_wire() {
+        use crate::network::message::decompress;
+        let listener = TcpListener::bind("127.0.0.1:6081").unwrap();
+        let (msg_tx, _msg_rx) = queue::channel(100);
+        let (ctx, server) = super::new(vec!["127.0.0.1:6084".parse().unwrap()], msg_tx, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+        ctx.start().unwrap();
+        let mut peer = server.connect(listener.local_addr().unwrap()).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut read_frame = || {
+            let msg: Message = bincode::deserialize(&read_frame(&mut stream).unwrap()).unwrap();
+            msg
+        };
+
+        let txs: Vec<SignedTransaction> = (0..50)
+            .map(|_| SignedTransaction { transaction: generate_random_transaction(), signature: vec![0; 64], public_key: vec![0; 32] })
+            .collect();
+        let raw = bincode::serialize(&Message::Transactions(txs.clone())).unwrap();
+        //not negotiated yet, so sent as is
+        peer.write(Message::Transactions(txs.clone()));
+        assert!(matches!(read_frame(), Message::Transactions(_)));
+
+        //asked for, but not agreed on in the handshake
+        peer.enable_compression();
+        peer.write(Message::Transactions(txs.clone()));
+        assert!(matches!(read_frame(), Message::Transactions(_)));
+
+        peer.set_features(crate::network::message::FEATURE_COMPRESSION);
+        peer.write(Message::Transactions(txs.clone()));
+        //small messages are not worth compressing
+        peer.write(Message::Ping(42));
+        let payload = match read_frame() {
+            Message::Compressed(payload) => payload,
+            _ => panic!(),
+        };
+        assert_eq!(decompress(&payload).unwrap(), raw);
+        assert!(matches!(read_frame(), Message::Ping(42)));
+
+        let stats = server.peer_info()[0].compression;
+        assert!(stats.enabled);
+        assert_eq!(stats.raw_bytes_sent, raw.len() as u64);
+        assert_eq!(stats.compressed_bytes_sent, payload.len() as u64);
+        assert!(stats.compressed_bytes_sent < stats.raw_bytes_sent);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn encrypted_peers_exchange_blocks() {
+        let (msg_tx1, msg_rx1) = queue::channel(100);
+        let (mut ctx1, _server1) = super::new(vec!["127.0.0.1:6104".parse().unwrap()], msg_tx1, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+        ctx1.enable_encryption();
+        ctx1.start().unwrap();
+        let (msg_tx2, msg_rx2) = queue::channel(100);
+        let (mut ctx2, server2) = super::new(vec!["127.0.0.1:6105".parse().unwrap()], msg_tx2, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+        ctx2.enable_encryption();
+        ctx2.start().unwrap();
+
+        let block = generate_random_block(&generate_random_hash());
+        let mut peer = server2.connect("127.0.0.1:6104".parse().unwrap()).unwrap();
+        peer.write(Message::Blocks(vec![block.clone()]));
+        let (payload, mut reply_to) = smol::block_on(msg_rx1.recv()).unwrap();
+        match bincode::deserialize::<Message>(&payload).unwrap() {
+            Message::Blocks(blocks) => assert_eq!(blocks[0].hash(), block.hash()),
+            _ => panic!(),
+        }
+
+        let reply = generate_random_block(&block.hash());
+        reply_to.write(Message::Blocks(vec![reply.clone()]));
+        let (payload, _) = smol::block_on(msg_rx2.recv()).unwrap();
+        match bincode::deserialize::<Message>(&payload).unwrap() {
+            Message::Blocks(blocks) => assert_eq!(blocks[0].hash(), reply.hash()),
+            _ => panic!(),
+        }
+    }
+
+    fn wait_for_traffic(server: &super::Handle, addr: SocketAddr, expected: impl Fn(&TrafficBreakdown) -> bool) -> TrafficBreakdown {
+        loop {
+            if let Some(peer) = server.traffic_stats().peers.into_iter().find(|peer| peer.addr == addr) {
+                if expected(&peer.traffic) {
+                    return peer.traffic;
+                }
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn traffic_is_counted_per_peer_on_both_sides() {
+        let (msg_tx1, msg_rx1) = queue::channel(100);
+        let (ctx1, server1) = super::new(vec!["127.0.0.1:6138".parse().unwrap()], msg_tx1, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+        ctx1.start().unwrap();
+        let (msg_tx2, msg_rx2) = queue::channel(100);
+        let (ctx2, server2) = super::new(vec!["127.0.0.1:6139".parse().unwrap()], msg_tx2, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+        ctx2.start().unwrap();
+        let server1_addr: SocketAddr = "127.0.0.1:6138".parse().unwrap();
+
+        let frame_len = |msg: &Message| (encode(&bincode::serialize(msg).unwrap()).len()) as u64;
+        let block = Message::Blocks(vec![generate_random_block(&generate_random_hash())]);
+        let hashes = Message::NewTransactionHashes(vec![generate_random_hash(), generate_random_hash()]);
+        let mut peer = server2.connect(server1_addr).unwrap();
+        peer.write(block.clone());
+        peer.write(block.clone());
+        for _ in 0..3 {
+            peer.write(hashes.clone());
+        }
+        peer.write(Message::Ping(1));
+        let mut reply_to = None;
+        for _ in 0..6 {
+            reply_to = Some(smol::block_on(msg_rx1.recv()).unwrap().1);
+        }
+        let mut reply_to = reply_to.unwrap();
+        reply_to.write(Message::Pong(1));
+        reply_to.write(Message::Transactions(vec![]));
+        for _ in 0..2 {
+            smol::block_on(msg_rx2.recv()).unwrap();
+        }
+
+        //the dialing side counts under the address it dialed, the other side under the dialer's ephemeral port
+        let sent = wait_for_traffic(&server2, server1_addr, |traffic| traffic.control.messages_received == 1 && traffic.control.messages_sent == 1);
+        assert_eq!(sent.blocks.messages_sent, 2);
+        assert_eq!(sent.blocks.bytes_sent, 2 * frame_len(&block));
+        assert_eq!(sent.transactions.messages_sent, 3);
+        assert_eq!(sent.transactions.bytes_sent, 3 * frame_len(&hashes));
+        assert_eq!(sent.control.bytes_sent, frame_len(&Message::Ping(1)));
+        assert_eq!(sent.transactions.messages_received, 1);
+        assert_eq!(sent.transactions.bytes_received, frame_len(&Message::Transactions(vec![])));
+        let received = wait_for_traffic(&server1, *reply_to.addr(), |traffic| traffic.transactions.messages_sent == 1);
+        for (ours, theirs) in [(sent.blocks, received.blocks), (sent.transactions, received.transactions), (sent.control, received.control)] {
+            assert_eq!((ours.messages_sent, ours.bytes_sent), (theirs.messages_received, theirs.bytes_received));
+            assert_eq!((ours.messages_received, ours.bytes_received), (theirs.messages_sent, theirs.bytes_sent));
+        }
+        let stats = server1.traffic_stats();
+        assert_eq!(stats.totals, received);
+        assert!(stats.peers[0].connected);
+
+        //a reconnection keeps adding to what was counted for the address
+        assert!(server2.disconnect(server1_addr));
+        while server2.traffic_stats().peers[0].connected {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(server2.traffic_stats().peers[0].traffic, sent);
+        let mut peer = server2.connect(server1_addr).unwrap();
+        peer.write(Message::Ping(2));
+        smol::block_on(msg_rx1.recv()).unwrap();
+        let again = wait_for_traffic(&server2, server1_addr, |traffic| traffic.control.messages_sent == 2);
+        assert_eq!(again.control.bytes_sent, 2 * frame_len(&Message::Ping(1)));
+        assert_eq!(again.blocks, sent.blocks);
+        assert_eq!(server2.traffic_stats().peers.len(), 1);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn relayed_hashes_go_to_a_different_random_peer_each_time() {
+        let addr: SocketAddr = "127.0.0.1:6156".parse().unwrap();
+        let (msg_tx, _msg_rx) = queue::channel(100);
+        let (mut ctx, server) = super::new(vec![addr], msg_tx, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+        ctx.set_relay_fanout(1, false);
+        ctx.start().unwrap();
+        let mut receivers = vec![];
+        for port in 6157..6160 {
+            let (peer_tx, peer_rx) = queue::channel(100);
+            let (peer_ctx, peer_server) = super::new(vec![format!("127.0.0.1:{}", port).parse().unwrap()], peer_tx, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+            peer_ctx.start().unwrap();
+            peer_server.connect(addr).unwrap();
+            receivers.push((peer_server, peer_rx));
+        }
+        while server.peer_info().len() < 3 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        for peer in server.peer_info() {
+            server.handshake_complete(peer.addr);
+        }
+
+        //one peer at a time until every peer has it, then no one gets it again
+        let hash = generate_random_hash();
+        for _ in 0..4 {
+            server.broadcast(Message::NewTransactionHashes(vec![hash]));
+        }
+        server.broadcast(Message::Ping(1));
+        for (_, peer_rx) in receivers.iter() {
+            let mut received = Vec::new();
+            for _ in 0..2 {
+                received.push(bincode::deserialize::<Message>(&smol::block_on(peer_rx.recv()).unwrap().0).unwrap());
+            }
+            //the receiving queue hands control messages over ahead of transaction ones, the ping may come first
+            received.sort_by_key(|msg| matches!(msg, Message::Ping(_)));
+            match &received[..] {
+                [Message::NewTransactionHashes(hashes), Message::Ping(1)] => assert_eq!(hashes, &vec![hash]),
+                _ => panic!(),
+            }
+        }
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn injected_transaction_loss_still_lets_blocks_through() {
+        let (msg_tx1, msg_rx1) = queue::channel(100);
+        let (ctx1, _server1) = super::new(vec!["127.0.0.1:6140".parse().unwrap()], msg_tx1, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+        ctx1.start().unwrap();
+        let (msg_tx2, _msg_rx2) = queue::channel(100);
+        let (mut ctx2, server2) = super::new(vec!["127.0.0.1:6141".parse().unwrap()], msg_tx2, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+        ctx2.set_impairment(Impairment::new(0, 100.0, Some(TrafficClass::Transactions)).unwrap());
+        ctx2.start().unwrap();
+
+        let block = generate_random_block(&generate_random_hash());
+        let mut peer = server2.connect("127.0.0.1:6140".parse().unwrap()).unwrap();
+        for _ in 0..10 {
+            peer.write(Message::Transactions(vec![]));
+            peer.write(Message::NewTransactionHashes(vec![generate_random_hash()]));
+        }
+        peer.write(Message::Blocks(vec![block.clone()]));
+        peer.write(Message::Ping(1));
+        let mut received = Vec::new();
+        for _ in 0..2 {
+            received.push(bincode::deserialize::<Message>(&smol::block_on(msg_rx1.recv()).unwrap().0).unwrap());
+        }
+        match &received[..] {
+            [Message::Blocks(blocks), Message::Ping(1)] => assert_eq!(blocks[0].hash(), block.hash()),
+            _ => panic!(),
+        }
+        //the lost messages still count as sent, the stats show what was tried
+        let traffic = server2.traffic_stats().totals;
+        assert_eq!(traffic.transactions.messages_sent, 20);
+        assert_eq!(traffic.blocks.messages_sent, 1);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn injected_latency_delays_delivery() {
+        let (msg_tx1, msg_rx1) = queue::channel(100);
+        let (ctx1, _server1) = super::new(vec!["127.0.0.1:6142".parse().unwrap()], msg_tx1, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+        ctx1.start().unwrap();
+        let (msg_tx2, _msg_rx2) = queue::channel(100);
+        let (ctx2, server2) = super::new(vec!["127.0.0.1:6143".parse().unwrap()], msg_tx2, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+        ctx2.start().unwrap();
+        let server1_addr: SocketAddr = "127.0.0.1:6142".parse().unwrap();
+        let mut peer = server2.connect(server1_addr).unwrap();
+
+        let start = Instant::now();
+        peer.write(Message::Ping(1));
+        smol::block_on(msg_rx1.recv()).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(200));
+
+        server2.set_peer_impairment(server1_addr, Some(Impairment::new(400, 0.0, None).unwrap()));
+        assert_eq!(server2.impairments().for_peer(&server1_addr).latency_ms, 400);
+        let start = Instant::now();
+        for nonce in 0..5 {
+            peer.write(Message::Ping(nonce));
+        }
+        for nonce in 0..5 {
+            //held back at least half the latency, and never reordered
+            let msg = bincode::deserialize::<Message>(&smol::block_on(msg_rx1.recv()).unwrap().0).unwrap();
+            assert!(matches!(msg, Message::Ping(n) if n == nonce));
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(200));
+        //the messages wait out their latency side by side, not one after the other
+        assert!(elapsed < Duration::from_millis(5 * 200));
+
+        server2.set_peer_impairment(server1_addr, None);
+        assert_eq!(server2.impairments().for_peer(&server1_addr), Impairment::default());
+        let start = Instant::now();
+        peer.write(Message::Ping(6));
+        smol::block_on(msg_rx1.recv()).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(200));
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn plaintext_peer_is_refused_by_encrypted_peer() {
+        let (msg_tx1, msg_rx1) = queue::channel(100);
+        let (mut ctx1, _server1) = super::new(vec!["127.0.0.1:6106".parse().unwrap()], msg_tx1, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+        ctx1.enable_encryption();
+        ctx1.start().unwrap();
+        let (msg_tx2, _msg_rx2) = queue::channel(100);
+        let (ctx2, server2) = super::new(vec!["127.0.0.1:6107".parse().unwrap()], msg_tx2, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+        ctx2.start().unwrap();
+
+        let mut peer = server2.connect("127.0.0.1:6106".parse().unwrap()).unwrap();
+        peer.write(Message::Ping(42));
+        //the encrypted side hangs up as soon as the first bytes aren't its prologue
+        while !peer.is_disconnected() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(msg_rx1.try_recv().is_err());
+    }
+
+    #[test]
+    fn addresses_of_both_families_parse() {
+        assert_eq!(parse_addr("127.0.0.1:6000").unwrap(), "127.0.0.1:6000".parse().unwrap());
+        assert_eq!(parse_addr("[::1]:6000").unwrap(), SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 6000));
+        assert!(parse_addr("::1:6000").is_err());
+        assert!(parse_addr("[::1]").is_err());
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn listens_on_ipv4_and_ipv6_at_once() {
+        let v4: SocketAddr = "127.0.0.1:6119".parse().unwrap();
+        let v6: SocketAddr = "[::1]:6120".parse().unwrap();
+        let (msg_tx, msg_rx) = queue::channel(100);
+        let (ctx, server) = super::new(vec![v4, v6], msg_tx, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+        ctx.start().unwrap();
+
+        let (other_tx, _other_rx) = queue::channel(100);
+        let (other_ctx, other) = super::new(vec!["[::1]:6121".parse().unwrap()], other_tx, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+        other_ctx.start().unwrap();
+        let mut over_v6 = other.connect(v6).unwrap();
+        let mut over_v4 = TcpStream::connect(v4).unwrap();
+        let bytes = bincode::serialize(&Message::Ping(4)).unwrap();
+        over_v4.write_all(&encode(&bytes)).unwrap();
+        over_v6.write(Message::Ping(6));
+
+        //both connections feed the same message channel, each peer keyed by an address of its own family
+        let mut senders = Vec::new();
+        for _ in 0..2 {
+            let (payload, peer) = smol::block_on(msg_rx.recv()).unwrap();
+            match bincode::deserialize::<Message>(&payload).unwrap() {
+                Message::Ping(4) => assert!(peer.addr().is_ipv4()),
+                Message::Ping(6) => assert!(peer.addr().is_ipv6()),
+                _ => panic!(),
+            }
+            senders.push(*peer.addr());
+        }
+        let mut peers: Vec<SocketAddr> = server.peer_info().into_iter().map(|peer| peer.addr).collect();
+        peers.sort();
+        senders.sort();
+        assert_eq!(peers, senders);
+    }
+
+    fn read_disconnect(stream: &mut TcpStream) -> Option<DisconnectReason> {
+        let msg_buffer = read_frame(stream).ok()?;
+        match bincode::deserialize(&msg_buffer).unwrap() {
+            Message::Disconnect(reason) => return Some(reason),
+            _ => return None,
+        }
+    }
+
+    /// Connect to `addr` from the local address `from`, like a node whose outgoing connections leave from its listening port
+    fn connect_from(from: SocketAddr, addr: SocketAddr) -> TcpStream {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_io().build().unwrap();
+        return runtime.block_on(async {
+            let socket = tokio::net::TcpSocket::new_v4().unwrap();
+            //the connection from an earlier run may still be in TIME_WAIT
+            socket.set_reuseaddr(true).unwrap();
+            socket.bind(from).unwrap();
+            return socket.connect(addr).await.unwrap().into_std().unwrap();
+        });
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn inbound_peers_over_the_limit_are_turned_away() {
+        let (msg_tx, _msg_rx) = queue::channel(100);
+        let (mut ctx, server) = super::new(vec!["127.0.0.1:6108".parse().unwrap()], msg_tx, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+        ctx.set_connection_limits(2, 8);
+        ctx.start().unwrap();
+        let connecting: Vec<_> = (0..5)
+            .map(|_| thread::spawn(|| TcpStream::connect("127.0.0.1:6108").unwrap()))
+            .collect();
+        let mut streams: Vec<TcpStream> = connecting.into_iter().map(|t| t.join().unwrap()).collect();
+
+        let mut turned_away = 0;
+        for stream in streams.iter_mut() {
+            stream.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+            if let Some(reason) = read_disconnect(stream) {
+                assert_eq!(reason, DisconnectReason::TooManyPeers);
+                turned_away += 1;
+            }
+        }
+        assert_eq!(turned_away, 3);
+        let counts = server.connection_counts();
+        assert_eq!((counts.inbound, counts.max_inbound), (2, 2));
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn configured_peer_evicts_idle_inbound_peer() {
+        let (msg_tx, _msg_rx) = queue::channel(100);
+        let (mut ctx, server) = super::new(vec!["127.0.0.1:6109".parse().unwrap()], msg_tx, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+        ctx.set_connection_limits(1, 8);
+        ctx.start().unwrap();
+        let mut idle = TcpStream::connect("127.0.0.1:6109").unwrap();
+        while server.connection_counts().inbound < 1 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        //nothing listens there, the configured peer connects to us from that address instead
+        let configured_addr: SocketAddr = "127.0.0.1:6110".parse().unwrap();
+        server.add_persistent_peer(configured_addr, Arc::new(|_| Message::Ping(42)));
+        //another node on the same IP is not the configured peer
+        let mut stranger = TcpStream::connect("127.0.0.1:6109").unwrap();
+        assert_eq!(read_disconnect(&mut stranger), Some(DisconnectReason::TooManyPeers));
+
+        let mut configured = connect_from(configured_addr, "127.0.0.1:6109".parse().unwrap());
+        assert_eq!(read_disconnect(&mut idle), Some(DisconnectReason::Evicted));
+        configured.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+        assert_eq!(read_disconnect(&mut configured), None);
+        assert_eq!(server.connection_counts().inbound, 1);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn oversized_frames_disconnect_peer() {
+        let (msg_tx, msg_rx) = queue::channel(100);
+        let (ctx, _server) = super::new(vec!["127.0.0.1:6085".parse().unwrap()], msg_tx, 16).unwrap();
+        ctx.start().unwrap();
+        let mut stream = TcpStream::connect("127.0.0.1:6085").unwrap();
+        for _ in 0..MAX_OVERSIZED_FRAMES {
+            let _ = stream.write_all(&encode(&[0u8; 32]));
+        }
+        //the server closes the connection once the last oversized header arrives
+        let mut rest = Vec::new();
+        let _ = stream.read_to_end(&mut rest);
+        assert!(rest.is_empty());
+        assert!(msg_rx.try_recv().is_err());
+    }
+    #[test]
+    #[timeout(60000)]
+    fn corrupt_frames_are_dropped_then_disconnect_peer() {
+        let (msg_tx, msg_rx) = queue::channel(100);
+        let (ctx, _server) = super::new(vec!["127.0.0.1:6135".parse().unwrap()], msg_tx, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+        ctx.start().unwrap();
+        let mut stream = TcpStream::connect("127.0.0.1:6135").unwrap();
+        let corrupt = || {
+            let mut frame = encode(&bincode::serialize(&Message::Ping(1)).unwrap());
+            let last = frame.len() - 1;
+            frame[last] ^= 1;
+            frame
+        };
+        //a corrupt frame is skipped, the next good one still gets through
+        stream.write_all(&corrupt()).unwrap();
+        stream.write_all(&encode(&bincode::serialize(&Message::Ping(2)).unwrap())).unwrap();
+        let (payload, _) = smol::block_on(msg_rx.recv()).unwrap();
+        assert!(matches!(bincode::deserialize(&payload).unwrap(), Message::Ping(2)));
+        for _ in 1..MAX_CORRUPT_FRAMES {
+            let _ = stream.write_all(&corrupt());
+        }
+        let mut rest = Vec::new();
+        let _ = stream.read_to_end(&mut rest);
+        assert!(rest.is_empty());
+        assert!(msg_rx.try_recv().is_err());
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn frames_without_magic_disconnect_peer() {
+        let (msg_tx, msg_rx) = queue::channel(100);
+        let (ctx, _server) = super::new(vec!["127.0.0.1:6136".parse().unwrap()], msg_tx, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+        ctx.start().unwrap();
+        let mut stream = TcpStream::connect("127.0.0.1:6136").unwrap();
+        let bytes = bincode::serialize(&Message::Ping(1)).unwrap();
+        let mut header = FrameHeader::new(&bytes).to_bytes();
+        header[0] = 0;
+        let _ = stream.write_all(&header);
+        let _ = stream.write_all(&bytes);
+        let mut rest = Vec::new();
+        let _ = stream.read_to_end(&mut rest);
+        assert!(rest.is_empty());
+        assert!(msg_rx.try_recv().is_err());
+    }
+
+    /// A SOCKS5 proxy that takes one connection, records the target asked for, connects it to `peer` whatever that
+    /// target was and relays both ways; it answers with `reply_code` and stops there if that is not 0
+    fn relaying_proxy(peer: SocketAddr, reply_code: u8) -> (SocketAddr, std::sync::mpsc::Receiver<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let (request_tx, request_rx) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            let (mut client, _) = listener.accept().unwrap();
+            let mut greeting = [0u8; 3];
+            client.read_exact(&mut greeting).unwrap();
+            client.write_all(&[5, 0]).unwrap();
+            let mut request = vec![0u8; 5];
+            client.read_exact(&mut request).unwrap();
+            //a domain name, the only target the test dials
+            let mut rest = vec![0u8; request[4] as usize + 2];
+            client.read_exact(&mut rest).unwrap();
+            request.extend_from_slice(&rest);
+            request_tx.send(request).unwrap();
+            client.write_all(&[5, reply_code, 0, 1, 127, 0, 0, 1, 0, 0]).unwrap();
+            if reply_code != 0 {
+                return;
+            }
+            let mut upstream = TcpStream::connect(peer).unwrap();
+            let (mut client_read, mut upstream_write) = (client.try_clone().unwrap(), upstream.try_clone().unwrap());
+            thread::spawn(move || std::io::copy(&mut client_read, &mut upstream_write));
+            let _ = std::io::copy(&mut upstream, &mut client);
+        });
+        return (proxy, request_rx);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn outbound_peers_are_dialed_through_proxy() {
+        let (msg_tx1, msg_rx1) = queue::channel(100);
+        let (ctx1, _server1) = super::new(vec!["127.0.0.1:6144".parse().unwrap()], msg_tx1, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+        ctx1.start().unwrap();
+        let (proxy, request_rx) = relaying_proxy("127.0.0.1:6144".parse().unwrap(), 0);
+        let (msg_tx2, msg_rx2) = queue::channel(100);
+        let (mut ctx2, server2) = super::new(vec!["127.0.0.1:6145".parse().unwrap()], msg_tx2, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+        let path = std::env::temp_dir().join(format!("addr_book_proxy_{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        ctx2.set_addr_book(path.clone()).unwrap();
+        ctx2.set_proxy(proxy);
+        assert_eq!(ctx2.resolve_peer("127.0.0.1:6001").unwrap(), "127.0.0.1:6001".parse().unwrap());
+        ctx2.start().unwrap();
+        //never resolved here, the proxy gets the name, also when asked through the handle like /network/connect does
+        let addr = server2.resolve_peer("node-a.invalid:6144").unwrap();
+
+        let mut peer = server2.connect(addr).unwrap();
+        let mut expected = vec![5, 1, 0, 3, 14];
+        expected.extend_from_slice(b"node-a.invalid");
+        expected.extend_from_slice(&6144u16.to_be_bytes());
+        assert_eq!(request_rx.recv().unwrap(), expected);
+        //the peer is known by its stand-in address, not the proxy's
+        assert_eq!(*peer.addr(), addr);
+        peer.write(Message::Ping(1));
+        let (payload, mut reply_to) = smol::block_on(msg_rx1.recv()).unwrap();
+        assert!(matches!(bincode::deserialize(&payload).unwrap(), Message::Ping(1)));
+        reply_to.write(Message::Pong(1));
+        let (payload, from) = smol::block_on(msg_rx2.recv()).unwrap();
+        assert!(matches!(bincode::deserialize(&payload).unwrap(), Message::Pong(1)));
+        assert_eq!(*from.addr(), addr);
+
+        //the stand-in address is not worth keeping in the address book
+        server2.shutdown();
+        server2.connection_counts();
+        let saved = AddrBook::load(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert!(saved.get(&addr).is_none());
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn proxy_failures_are_connection_failures() {
+        let (proxy, request_rx) = relaying_proxy("127.0.0.1:6146".parse().unwrap(), 4);
+        let (msg_tx, _msg_rx) = queue::channel(100);
+        let (mut ctx, server) = super::new(vec!["127.0.0.1:6147".parse().unwrap()], msg_tx, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+        ctx.set_proxy(proxy);
+        let addr = ctx.resolve_peer("node-b.invalid:6146").unwrap();
+        assert!(ctx.resolve_peer("node-b.invalid").is_err());
+        ctx.start().unwrap();
+        let e = server.connect(addr).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::ConnectionRefused);
+        assert!(request_rx.recv().is_ok());
+        assert_eq!(server.connection_counts().outbound, 0);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn slow_dial_does_not_hold_up_the_server() {
+        //accepts, then never answers the SOCKS5 greeting
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = silent.local_addr().unwrap();
+        thread::spawn(move || {
+            let _held: Vec<TcpStream> = silent.incoming().map(|stream| stream.unwrap()).collect();
+        });
+        let (msg_tx, _msg_rx) = queue::channel(100);
+        let (mut ctx, server) = super::new(vec!["127.0.0.1:6166".parse().unwrap()], msg_tx, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+        ctx.set_proxy(proxy);
+        ctx.start().unwrap();
+        let dialing = thread::spawn({
+            let server = server.clone();
+            move || server.connect("127.0.0.1:6167".parse().unwrap())
+        });
+        thread::sleep(Duration::from_millis(100));
+        //the dispatcher answers while the dial waits on the proxy
+        let start = Instant::now();
+        assert_eq!(server.connection_counts().outbound, 0);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        let e = dialing.join().unwrap().unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,288 @@
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use ring::digest;
+use std::net::{Ipv6Addr, SocketAddr};
+
+// The client side of a SOCKS5 CONNECT (RFC 1928), without authentication:
+//
+//   client -> proxy   05 01 00                          version 5, one method offered: no authentication
+//   proxy -> client   05 00                             no authentication chosen, FF if it wants another method
+//   client -> proxy   05 01 00 ATYP DST.ADDR DST.PORT   CONNECT to an IPv4 (01), domain name (03) or IPv6 (04) target
+//   proxy -> client   05 REP 00 ATYP BND.ADDR BND.PORT  REP 00 once the proxy is connected to the target
+//
+// After that the connection carries the target's bytes as if it had been dialed directly.
+
+static SOCKS_VERSION: u8 = 5;
+static NO_AUTHENTICATION: u8 = 0;
+static NO_ACCEPTABLE_METHOD: u8 = 0xff;
+static CONNECT: u8 = 1;
+static ATYP_IPV4: u8 = 1;
+static ATYP_DOMAIN: u8 = 3;
+static ATYP_IPV6: u8 = 4;
+
+/// What the proxy should connect to
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    Addr(SocketAddr),
+    //resolved by the proxy, never locally
+    Domain(String, u16),
+}
+
+#[derive(Debug)]
+pub enum Socks5Error {
+    Io(std::io::Error),
+    //the proxy does not speak SOCKS5, or sent something that isn't a valid reply
+    BadReply,
+    //the proxy wants authentication
+    NoAcceptableMethod,
+    //the proxy could not connect to the target, with the reply code it gave
+    Refused(u8),
+    //a domain name longer than the 255 bytes SOCKS5 can carry
+    NameTooLong,
+}
+
+impl std::fmt::Display for Socks5Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        return match self {
+            Socks5Error::Io(e) => write!(f, "proxy connection failed: {}", e),
+            Socks5Error::BadReply => write!(f, "the proxy sent an invalid SOCKS5 reply"),
+            Socks5Error::NoAcceptableMethod => write!(f, "the proxy requires authentication"),
+            Socks5Error::Refused(code) => write!(f, "the proxy could not reach the peer: {}", reply_reason(*code)),
+            Socks5Error::NameTooLong => write!(f, "the peer's name is too long for SOCKS5"),
+        };
+    }
+}
+
+impl From<std::io::Error> for Socks5Error {
+    fn from(e: std::io::Error) -> Self {
+        return Socks5Error::Io(e);
+    }
+}
+
+/// The server treats a failed proxy handshake like a failed direct connection
+impl From<Socks5Error> for std::io::Error {
+    fn from(e: Socks5Error) -> Self {
+        return match e {
+            Socks5Error::Io(e) => e,
+            Socks5Error::Refused(_) => std::io::Error::new(std::io::ErrorKind::ConnectionRefused, e.to_string()),
+            _ => std::io::Error::new(std::io::ErrorKind::Other, e.to_string()),
+        };
+    }
+}
+
+fn reply_reason(code: u8) -> &'static str {
+    return match code {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    };
+}
+
+/// The method negotiation the client opens with
+pub fn greeting() -> [u8; 3] {
+    return [SOCKS_VERSION, 1, NO_AUTHENTICATION];
+}
+
+/// The CONNECT request for `target`
+pub fn connect_request(target: &Target) -> Result<Vec<u8>, Socks5Error> {
+    let mut request = vec![SOCKS_VERSION, CONNECT, 0];
+    let port = match target {
+        Target::Addr(SocketAddr::V4(addr)) => {
+            request.push(ATYP_IPV4);
+            request.extend_from_slice(&addr.ip().octets());
+            addr.port()
+        }
+        Target::Addr(SocketAddr::V6(addr)) => {
+            request.push(ATYP_IPV6);
+            request.extend_from_slice(&addr.ip().octets());
+            addr.port()
+        }
+        Target::Domain(name, port) => {
+            if name.len() > 255 {
+                return Err(Socks5Error::NameTooLong);
+            }
+            request.push(ATYP_DOMAIN);
+            request.push(name.len() as u8);
+            request.extend_from_slice(name.as_bytes());
+            *port
+        }
+    };
+    request.extend_from_slice(&port.to_be_bytes());
+    return Ok(request);
+}
+
+/// Ask the proxy at the other end of `stream` to connect to `target`. Once this returns Ok the stream talks to the target
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, target: &Target) -> Result<(), Socks5Error> {
+    let request = connect_request(target)?;
+    stream.write_all(&greeting()).await?;
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice[0] != SOCKS_VERSION {
+        return Err(Socks5Error::BadReply);
+    }
+    if choice[1] == NO_ACCEPTABLE_METHOD {
+        return Err(Socks5Error::NoAcceptableMethod);
+    }
+    if choice[1] != NO_AUTHENTICATION {
+        return Err(Socks5Error::BadReply);
+    }
+
+    stream.write_all(&request).await?;
+    stream.flush().await?;
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != SOCKS_VERSION {
+        return Err(Socks5Error::BadReply);
+    }
+    if reply[1] != 0 {
+        return Err(Socks5Error::Refused(reply[1]));
+    }
+    //the address the proxy connected from, of no use to us but it has to be read past
+    let bound_len = match reply[3] {
+        atyp if atyp == ATYP_IPV4 => 4,
+        atyp if atyp == ATYP_IPV6 => 16,
+        atyp if atyp == ATYP_DOMAIN => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await?;
+            len[0] as usize
+        }
+        _ => return Err(Socks5Error::BadReply),
+    };
+    let mut bound = vec![0u8; bound_len + 2];
+    stream.read_exact(&mut bound).await?;
+    return Ok(());
+}
+
+/// A stand-in address for a peer known by name only, which only the proxy resolves. It is taken from the unique
+/// local range fd00::/8, so it can't be the address of a peer on the internet
+pub fn virtual_addr(name: &str, port: u16) -> SocketAddr {
+    let hash = digest::digest(&digest::SHA256, name.as_bytes());
+    let mut octets = [0u8; 16];
+    octets[0] = 0xfd;
+    octets[1..].copy_from_slice(&hash.as_ref()[..15]);
+    return SocketAddr::new(Ipv6Addr::from(octets).into(), port);
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod tests {
+    use super::{connect_request, greeting, handshake, virtual_addr, Socks5Error, Target};
+    use smol::Async;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::thread;
+
+    /// A proxy that checks the client's bytes against `expected_request`, answers with `choice` and `reply`, then
+    /// echoes whatever comes after
+    fn mock_proxy(choice: [u8; 2], expected_request: Vec<u8>, reply: Vec<u8>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut offered = [0u8; 3];
+            stream.read_exact(&mut offered).unwrap();
+            assert_eq!(offered, greeting());
+            stream.write_all(&choice).unwrap();
+            if choice[1] != 0 {
+                return;
+            }
+            let mut request = vec![0u8; expected_request.len()];
+            stream.read_exact(&mut request).unwrap();
+            assert_eq!(request, expected_request);
+            stream.write_all(&reply).unwrap();
+            let mut echo = [0u8; 4];
+            if stream.read_exact(&mut echo).is_ok() {
+                stream.write_all(&echo).unwrap();
+            }
+        });
+        return addr;
+    }
+
+    fn connect_through(proxy: SocketAddr, target: &Target) -> Result<Async<TcpStream>, Socks5Error> {
+        return smol::block_on(async {
+            let mut stream = Async::<TcpStream>::connect(proxy).await?;
+            handshake(&mut stream, target).await?;
+            return Ok(stream);
+        });
+    }
+
+    #[test]
+    fn request_bytes() {
+        let v4 = Target::Addr("10.1.2.3:6000".parse().unwrap());
+        assert_eq!(connect_request(&v4).unwrap(), vec![5, 1, 0, 1, 10, 1, 2, 3, 0x17, 0x70]);
+        let v6 = Target::Addr("[::1]:80".parse().unwrap());
+        let mut expected = vec![5, 1, 0, 4];
+        expected.extend_from_slice(&[0; 15]);
+        expected.extend_from_slice(&[1, 0, 80]);
+        assert_eq!(connect_request(&v6).unwrap(), expected);
+        let named = Target::Domain("node-b.lab".to_string(), 6001);
+        let mut expected = vec![5, 1, 0, 3, 10];
+        expected.extend_from_slice(b"node-b.lab");
+        expected.extend_from_slice(&[0x17, 0x71]);
+        assert_eq!(connect_request(&named).unwrap(), expected);
+        assert!(matches!(connect_request(&Target::Domain("a".repeat(256), 1)), Err(Socks5Error::NameTooLong)));
+    }
+
+    #[test]
+    fn connects_through_mock_proxy() {
+        let target = Target::Addr("10.1.2.3:6000".parse().unwrap());
+        //the proxy answers with the IPv4 address it connected from
+        let proxy = mock_proxy([5, 0], connect_request(&target).unwrap(), vec![5, 0, 0, 1, 192, 168, 0, 1, 0x9c, 0x40]);
+        let mut stream = connect_through(proxy, &target).unwrap();
+        //nothing of the reply is left over, the stream now carries the target's bytes
+        smol::block_on(async {
+            use futures::io::{AsyncReadExt, AsyncWriteExt};
+            stream.write_all(b"ping").await.unwrap();
+            let mut echo = [0u8; 4];
+            stream.read_exact(&mut echo).await.unwrap();
+            assert_eq!(&echo, b"ping");
+        });
+    }
+
+    #[test]
+    fn names_are_sent_to_the_proxy() {
+        let target = Target::Domain("node-b.lab".to_string(), 6001);
+        //a reply bound to a domain name is read past as well
+        let mut reply = vec![5, 0, 0, 3, 5];
+        reply.extend_from_slice(b"proxy");
+        reply.extend_from_slice(&[0, 1]);
+        let proxy = mock_proxy([5, 0], connect_request(&target).unwrap(), reply);
+        assert!(connect_through(proxy, &target).is_ok());
+    }
+
+    #[test]
+    fn proxy_errors() {
+        let target = Target::Addr("10.1.2.3:6000".parse().unwrap());
+        let proxy = mock_proxy([5, 0xff], vec![], vec![]);
+        assert!(matches!(connect_through(proxy, &target), Err(Socks5Error::NoAcceptableMethod)));
+        let proxy = mock_proxy([5, 0], connect_request(&target).unwrap(), vec![5, 5, 0, 1, 0, 0, 0, 0, 0, 0]);
+        let refused = connect_through(proxy, &target).unwrap_err();
+        assert!(matches!(refused, Socks5Error::Refused(5)));
+        assert_eq!(std::io::Error::from(refused).kind(), std::io::ErrorKind::ConnectionRefused);
+        let proxy = mock_proxy([4, 0], vec![], vec![]);
+        assert!(matches!(connect_through(proxy, &target), Err(Socks5Error::BadReply)));
+        //nobody listening, the error the server backs off on
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        assert!(matches!(connect_through(closed, &target), Err(Socks5Error::Io(_))));
+    }
+
+    #[test]
+    fn virtual_addrs_are_stable_and_local() {
+        let addr = virtual_addr("node-b.lab", 6001);
+        assert_eq!(addr, virtual_addr("node-b.lab", 6001));
+        assert_ne!(addr, virtual_addr("node-c.lab", 6001));
+        assert_eq!(addr.port(), 6001);
+        match addr {
+            SocketAddr::V6(v6) => assert_eq!(v6.ip().octets()[0], 0xfd),
+            _ => panic!(),
+        }
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -1,51 +1,577 @@
-use super::message::Message;
+use super::message::{self, Capabilities, Message, MessageClass, RejectReason, FEATURE_COMPACT_BLOCKS, FEATURE_COMPRESSION, FEATURE_PUSH_BLOCKS, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, USER_AGENT};
 use super::peer;
+use super::propagation::PropagationStats;
+use super::queue::MsgReceiver;
 use super::server::Handle as ServerHandle;
-use crate::miner::Mempool;
-use crate::types::block::{Block, BlockState};
+use crate::miner::{Mempool, MempoolAdmission};
+use crate::types::block::{Block, BlockState, Content, Header};
 use crate::types::hash::{H256, Hashable};
-use crate::types::transaction::{SignedTransaction, verify};
-use std::sync::{Arc, Mutex};
-use crate::blockchain::{Blockchain, DIFFICULTY};
+use crate::types::transaction::SignedTransaction;
+use crate::types::merkle::MerkleTree;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use crate::blockchain::{Blockchain, MAX_BLOCK_SIZE};
 
-use log::{debug, warn, error};
+use tracing::{debug, info, info_span, warn};
+use rand::seq::SliceRandom;
 
 use std::thread;
 
-#[cfg(any(test,test_utilities))]
+#[cfg(test)]
 use super::peer::TestReceiver as PeerTestReceiver;
-#[cfg(any(test,test_utilities))]
+#[cfg(test)]
 use super::server::TestReceiver as ServerTestReceiver;
+#[cfg(test)]
+use super::queue::MsgSender;
+//most transaction hashes in one NewTransactionHashes answering GetMempool, bigger pools are sent in several
+pub static MEMPOOL_ANNOUNCE_CHUNK: usize = 1000;
+//most peer addresses sent in reply to a single GetAddr
+pub static ADDR_SAMPLE_SIZE: usize = 100;
+//most new connections opened for a single Addr message, avoids connection storms
+pub static MAX_CONNECTIONS_PER_ADDR_MSG: usize = 2;
+//messages dropped for exceeding a budget before the peer is disconnected
+pub static MAX_MISBEHAVIOR: u32 = 100;
+//added to a peer's misbehavior score in the server for every invalid block / transaction it sends
+pub static INVALID_BLOCK_SCORE: u32 = 50;
+pub static INVALID_TRANSACTION_SCORE: u32 = 20;
+pub static MALFORMED_MESSAGE_SCORE: u32 = 20;
+//most blocks asked for at once while syncing
+pub static SYNC_BATCH_SIZE: usize = 16;
+//most blocks sent in reply to a single GetBlocksAfter, the peer asks again from the last one for more
+pub static GET_BLOCKS_AFTER_LIMIT: usize = 500;
+//a GetBlocksAfter reply stops growing past this many bytes, well under the default message size cap
+pub static MAX_BLOCKS_REPLY_BYTES: usize = 2 * 1024 * 1024;
+//most headers sent in reply to a single GetHeaders, a syncing node asks again until it has caught up
+pub static HEADERS_BATCH_SIZE: usize = 500;
+//most compact blocks waiting for missing transactions at once
+pub static MAX_PENDING_COMPACT_BLOCKS: usize = 16;
+//most blocks waiting for their parent at once, the oldest make room for new ones
+pub static MAX_ORPHAN_BLOCKS: usize = 200;
+//parents of an orphan are asked for one by one up to this many blocks back, longer gaps are left to sync
+pub static MAX_ORPHAN_DEPTH: usize = 32;
+//how long a peer has to answer a request for blocks or transactions it announced before another peer is asked
+pub static FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+//peers an announced hash is asked of before it is given up on
+pub static MAX_FETCH_ATTEMPTS: usize = 3;
+//added to a peer's misbehavior score in the server when it leaves a request for announced hashes unanswered
+pub static UNANSWERED_FETCH_SCORE: u32 = 10;
+
 #[derive(Clone)]
 pub struct Worker {
-    msg_chan: smol::channel::Receiver<(Vec<u8>, peer::Handle)>,
+    msg_chan: MsgReceiver,
     num_worker: usize,
     server: ServerHandle,
-    blockchain: Arc<Mutex<Blockchain>>,
+    blockchain: Arc<RwLock<Blockchain>>,
     mempool: Arc<Mutex<Mempool>>,
-    block_state_map: Arc<Mutex<BlockState>>
+    block_state_map: Arc<Mutex<BlockState>>,
+    max_msgs_per_sec: u32,
+    max_block_msgs_per_sec: u32,
+    max_tx_msgs_per_sec: u32,
+    //separate block, transaction and other budgets per peer, so transaction floods can't delay blocks.
+    //Shared by all worker threads
+    peer_budgets: Arc<Mutex<HashMap<SocketAddr, PeerBudget>>>,
+    //our own P2P addresses, the one matching a peer's address family is announced in Version messages
+    local_addrs: Vec<SocketAddr>,
+    //how far a peer's protocol version may be from ours before we drop it
+    version_tolerance: u32,
+    //peer address -> what the peer told us in its Version message
+    peer_versions: Arc<Mutex<HashMap<SocketAddr, PeerVersion>>>,
+    //peers whose VerAck arrived; with worker threads racing it may be handled before their Version
+    veracks: Arc<Mutex<HashSet<SocketAddr>>>,
+    sync: SyncStatus,
+    //random identity sent in our Version messages, a Version carrying it means we dialed ourselves
+    node_nonce: u64,
+    //ask peers to push new blocks in full, saving the round trip of fetching announced ones
+    push_blocks: bool,
+    //ask peers to relay new blocks as compact blocks, rebuilt from our mempool
+    compact_blocks: bool,
+    //ask peers for their pending transactions once the handshake is done
+    mempool_sync: bool,
+    //block hash -> compact block waiting for the transactions we asked its sender for
+    pending_compact: Arc<Mutex<HashMap<H256, PendingCompactBlock>>>,
+    //blocks whose parent we don't have yet, shared by all worker threads
+    orphans: Arc<Mutex<OrphanBuffer>>,
+    //announced blocks and transactions asked for and not received yet
+    fetches: Arc<Mutex<FetchTracker>>,
+    //how long blocks from peers took to get here
+    propagation: PropagationStats,
+    //held while taking a message off the channel, so tickets are handed out in arrival order
+    receiving: Arc<Mutex<()>>,
+    sequencer: PeerSequencer
+}
+
+/// Hands out per-peer tickets in arrival order, so each peer's messages take effect in the order it sent
+/// them even though the worker threads validate them in parallel
+#[derive(Clone, Default)]
+struct PeerSequencer {
+    //peer address -> (next ticket to hand out, ticket whose turn it is), dropped once the peer has nothing in flight
+    turns: Arc<(Mutex<HashMap<SocketAddr, (u64, u64)>>, Condvar)>
+}
+
+impl PeerSequencer {
+    fn ticket(&self, addr: SocketAddr) -> Ticket {
+        let mut turns = self.turns.0.lock().unwrap();
+        let entry = turns.entry(addr).or_insert((0, 0));
+        let number = entry.0;
+        entry.0 += 1;
+        return Ticket { sequencer: self.clone(), addr, number, waited: false };
+    }
+}
+
+/// A message's place in its peer's queue; dropping it lets the peer's next message go
+struct Ticket {
+    sequencer: PeerSequencer,
+    addr: SocketAddr,
+    number: u64,
+    waited: bool
+}
+
+impl Ticket {
+    /// Block until every earlier message from the peer has been handled
+    fn wait_turn(&mut self) {
+        let (turns, turn_changed) = &*self.sequencer.turns;
+        let mut turns = turns.lock().unwrap();
+        while turns[&self.addr].1 != self.number {
+            turns = turn_changed.wait(turns).unwrap();
+        }
+        self.waited = true;
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        //messages dropped early still have to pass their turn on in order
+        if !self.waited {
+            self.wait_turn();
+        }
+        let (turns, turn_changed) = &*self.sequencer.turns;
+        let mut turns = turns.lock().unwrap();
+        let entry = turns.get_mut(&self.addr).unwrap();
+        entry.1 += 1;
+        if entry.0 == entry.1 {
+            turns.remove(&self.addr);
+        }
+        turn_changed.notify_all();
+    }
+}
+
+/// Checks of a Blocks or Transactions message that need no shared state, one verdict per block or transaction.
+/// Run before waiting for the peer's turn so the worker threads do the hashing and signature checks in parallel
+fn prevalidate(msg: &Message, known_blocks: &HashSet<H256>) -> Vec<Result<(), RejectReason>> {
+    return match msg {
+        Message::Blocks(blocks) => blocks.iter().map(|block| {
+            //already in the chain, dropped as a duplicate when handled
+            if known_blocks.contains(&block.hash()) {
+                return Ok(());
+            }
+            if block.size_bytes() > MAX_BLOCK_SIZE {
+                return Err(RejectReason::BlockTooLarge);
+            }
+            //whether the difficulty is the expected one is checked against the chain later
+            if block.hash() > block.get_difficulty() {
+                return Err(RejectReason::InvalidPoW);
+            }
+            if MerkleTree::new(&block.content.data).root() != block.get_merkle_root() {
+                return Err(RejectReason::MerkleRootMismatch);
+            }
+            for transaction in block.content.data.iter() {
+                transaction.verify_integrity()?;
+            }
+            return Ok(());
+        }).collect(),
+        Message::Transactions(txs) => txs.iter().map(|tx| tx.verify_integrity().map_err(RejectReason::from)).collect(),
+        _ => Vec::new()
+    };
+}
+
+/// The peer we download the chain from after connecting, if it is ahead of us
+#[derive(Clone, Default)]
+pub struct SyncStatus {
+    //the sync peer and the height it advertised in its Version message
+    peer: Arc<Mutex<Option<(peer::Handle, u32)>>>,
+    bodies: Arc<Mutex<BodyDownload>>
+}
+
+/// Blocks to fetch from the sync peer once their headers checked out
+#[derive(Default)]
+struct BodyDownload {
+    //hashes of validated headers whose blocks haven't been asked for yet, parent first
+    wanted: VecDeque<H256>,
+    //blocks asked for and not received yet
+    in_flight: HashSet<H256>,
+    //when the blocks in flight were last asked for, and how many times
+    asked_at: Option<Instant>,
+    attempts: usize
+}
+
+/// What to do about blocks the sync peer didn't send in time
+enum StalledSync {
+    //ask it for them again
+    Retry(peer::Handle, Vec<H256>),
+    //it was asked MAX_FETCH_ATTEMPTS times, stop syncing from it
+    GiveUp(peer::Handle)
+}
+
+impl SyncStatus {
+    /// Whether we are still catching up with a connected peer
+    pub fn is_syncing(&self) -> bool {
+        return match &*self.peer.lock().unwrap() {
+            Some((peer, _)) => !peer.is_disconnected(),
+            None => false
+        };
+    }
+
+    /// Sync from this peer unless we already sync from another connected one
+    fn begin(&self, peer: &peer::Handle, target_height: u32) -> bool {
+        let mut current = self.peer.lock().unwrap();
+        if let Some((sync_peer, _)) = &*current {
+            if !sync_peer.is_disconnected() {
+                return false;
+            }
+        }
+        *current = Some((peer.clone(), target_height));
+        *self.bodies.lock().unwrap() = BodyDownload::default();
+        return true;
+    }
+
+    /// The height we are syncing to, if addr is our sync peer
+    fn target_height(&self, addr: &SocketAddr) -> Option<u32> {
+        return match &*self.peer.lock().unwrap() {
+            Some((peer, target_height)) if peer.addr() == addr => Some(*target_height),
+            _ => None
+        };
+    }
+
+    fn finish(&self) {
+        *self.peer.lock().unwrap() = None;
+        *self.bodies.lock().unwrap() = BodyDownload::default();
+    }
+
+    /// Queue the blocks of validated headers for download
+    fn want_bodies(&self, hashes: Vec<H256>) {
+        self.bodies.lock().unwrap().wanted.extend(hashes);
+    }
+
+    /// Take up to limit queued blocks we still don't have and mark them in flight, None once the queue is empty
+    fn next_bodies(&self, limit: usize, blockchain: &Blockchain) -> Option<Vec<H256>> {
+        let mut bodies = self.bodies.lock().unwrap();
+        let mut next = Vec::new();
+        while next.len() < limit {
+            match bodies.wanted.pop_front() {
+                Some(hash) if !blockchain.contains(&hash) => next.push(hash),
+                Some(_) => continue,
+                None => break,
+            }
+        }
+        if next.is_empty() {
+            return None;
+        }
+        bodies.in_flight.extend(next.iter().cloned());
+        bodies.asked_at = Some(Instant::now());
+        bodies.attempts = 1;
+        return Some(next);
+    }
+
+    /// Check on the blocks in flight, None unless they were asked for `timeout` ago or longer and the sync peer is
+    /// still connected. Retrying marks them asked for again from `now`
+    fn stalled(&self, now: Instant, timeout: Duration) -> Option<StalledSync> {
+        let current = self.peer.lock().unwrap();
+        let peer = match &*current {
+            Some((peer, _)) if !peer.is_disconnected() => peer.clone(),
+            _ => return None
+        };
+        let mut bodies = self.bodies.lock().unwrap();
+        match bodies.asked_at {
+            Some(asked_at) if !bodies.in_flight.is_empty() && now >= asked_at + timeout => {}
+            _ => return None
+        }
+        if bodies.attempts >= MAX_FETCH_ATTEMPTS {
+            return Some(StalledSync::GiveUp(peer));
+        }
+        bodies.asked_at = Some(now);
+        bodies.attempts += 1;
+        return Some(StalledSync::Retry(peer, bodies.in_flight.iter().cloned().collect()));
+    }
+
+    /// Mark blocks as received, true if this completed the batch in flight
+    fn bodies_received(&self, hashes: &[H256]) -> bool {
+        let mut bodies = self.bodies.lock().unwrap();
+        let before = bodies.in_flight.len();
+        for hash in hashes {
+            bodies.in_flight.remove(hash);
+        }
+        return bodies.in_flight.len() < before && bodies.in_flight.is_empty();
+    }
+}
+
+pub struct PeerVersion {
+    //the newest protocol version the peer speaks
+    pub protocol_version: u32,
+    //the FEATURE_* bits both sides support
+    pub features: u32,
+    //height of the peer's longest chain when it connected, used to pick whom to sync from
+    pub tip_height: u32,
+    pub user_agent: String,
+    pub peer_addr: SocketAddr
+}
+
+/// Token bucket allowing `rate` messages per second with bursts of up to `burst` messages
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant
+}
+
+impl RateLimiter {
+    pub fn new(rate: u32, burst: u32) -> Self {
+        return RateLimiter {
+            rate: rate as f64,
+            burst: burst as f64,
+            tokens: burst as f64,
+            last_refill: Instant::now()
+        }
+    }
+
+    /// Take one token from the bucket, returns false if the bucket is empty
+    pub fn try_acquire(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last_refill = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        return true;
+    }
+}
+
+/// Per peer token buckets for block traffic, transaction traffic and every other message
+struct PeerBudget {
+    blocks: RateLimiter,
+    transactions: RateLimiter,
+    others: RateLimiter,
+    //how many messages were dropped for going over budget
+    misbehavior: u32
+}
+
+/// A compact block from a peer and the transactions found for it so far
+struct PendingCompactBlock {
+    peer: SocketAddr,
+    header: Header,
+    tx_hashes: Vec<H256>,
+    found: HashMap<H256, SignedTransaction>
+}
+
+impl PendingCompactBlock {
+    fn missing(&self) -> Vec<H256> {
+        let mut asked = HashSet::new();
+        return self.tx_hashes.iter().filter(|hash| !self.found.contains_key(hash) && asked.insert(**hash)).cloned().collect();
+    }
+
+    /// The full block, None if a transaction is still missing or they don't match the header's merkle root
+    fn rebuild(&self) -> Option<Block> {
+        let data = self.tx_hashes.iter().map(|hash| self.found.get(hash).cloned()).collect::<Option<Vec<SignedTransaction>>>()?;
+        if MerkleTree::new(&data).root() != self.header.merkle_root {
+            return None;
+        }
+        return Some(Block::new(self.header.clone(), Content { data }));
+    }
 }
 
+/// Blocks received before their parent, each with how many blocks back from the first orphan of its branch it is
 pub struct OrphanBuffer {
-    pub orphans: Vec<Block>
+    orphans: HashMap<H256, (Block, usize)>,
+    //parent hash -> hashes of the orphans waiting for it
+    waiting_for: HashMap<H256, Vec<H256>>,
+    //oldest first, for eviction
+    arrival: VecDeque<H256>,
 }
 
 impl OrphanBuffer {
     pub fn new() -> Self {
         return Self {
-            orphans: Vec::<Block>::new()
+            orphans: HashMap::new(),
+            waiting_for: HashMap::new(),
+            arrival: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        return self.orphans.len();
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        return self.orphans.contains_key(hash);
+    }
+
+    /// Keep a block until its parent arrives. Returns the parent to ask the sender for, None if an earlier
+    /// orphan of the same branch already asked or the branch is too deep to chase block by block
+    pub fn park(&mut self, block: Block) -> Option<H256> {
+        let hash = block.hash();
+        let parent = block.get_parent();
+        if self.orphans.contains_key(&hash) {
+            return None;
+        }
+        //one further back than the orphans that were waiting for this block
+        let depth = 1 + self.waiting_for.get(&hash)
+            .map(|children| children.iter().map(|child| self.orphans[child].1).max().unwrap_or(0))
+            .unwrap_or(0);
+        while self.orphans.len() >= MAX_ORPHAN_BLOCKS {
+            let oldest = self.arrival.pop_front().unwrap();
+            self.remove(&oldest);
+        }
+        self.orphans.insert(hash, (block, depth));
+        self.waiting_for.entry(parent).or_insert_with(Vec::new).push(hash);
+        self.arrival.push_back(hash);
+        if self.orphans.contains_key(&parent) {
+            return None;
+        }
+        if depth > MAX_ORPHAN_DEPTH {
+            debug!("Orphan branch ending at {} is over {} blocks deep, not asking for its parent", hash, MAX_ORPHAN_DEPTH);
+            return None;
+        }
+        return Some(parent);
+    }
+
+    /// Take out the orphans that were waiting for `parent`
+    pub fn take_children(&mut self, parent: &H256) -> Vec<Block> {
+        let children = self.waiting_for.remove(parent).unwrap_or_default();
+        self.arrival.retain(|hash| !children.contains(hash));
+        return children.iter().filter_map(|hash| self.orphans.remove(hash)).map(|(block, _)| block).collect();
+    }
+
+    fn remove(&mut self, hash: &H256) {
+        if let Some((block, _)) = self.orphans.remove(hash) {
+            if let Some(siblings) = self.waiting_for.get_mut(&block.get_parent()) {
+                siblings.retain(|sibling| sibling != hash);
+                if siblings.is_empty() {
+                    self.waiting_for.remove(&block.get_parent());
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum FetchKind {
+    Block,
+    Transaction,
+}
+
+/// An announced hash we asked a peer for
+struct Fetch {
+    kind: FetchKind,
+    //the peer asked last, and when its answer is due
+    asked: peer::Handle,
+    deadline: Instant,
+    //peers that announced the hash too and haven't been asked for it yet, in the order they announced it
+    candidates: Vec<peer::Handle>,
+    attempts: usize,
+}
+
+/// Requests for announced blocks and transactions waiting for an answer. Each hash is asked of one peer at a time,
+/// the next peer that announced it is asked if that one doesn't answer in time or disconnects
+pub struct FetchTracker {
+    fetches: HashMap<H256, Fetch>,
+    timeout: Duration,
+}
+
+/// Requests whose peer didn't answer in time, and what to do about them
+#[derive(Default)]
+struct ExpiredFetches {
+    //hashes to ask the next peer for
+    retries: Vec<(peer::Handle, FetchKind, H256)>,
+    //peers that let a request time out while still connected
+    unresponsive: HashSet<SocketAddr>,
+    //hashes given up on, no peer that announced them is left to ask
+    dropped: Vec<H256>,
+}
+
+impl FetchTracker {
+    pub fn new(timeout: Duration) -> Self {
+        return Self {
+            fetches: HashMap::new(),
+            timeout,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        return self.fetches.len();
+    }
+
+    /// Record that `peer` announced `hash`. Returns whether to ask it for the hash now: not if another peer is
+    /// being asked already, it is kept in case that one doesn't answer. The peer being asked is asked again,
+    /// without moving its deadline
+    fn announced(&mut self, kind: FetchKind, hash: H256, peer: &peer::Handle, now: Instant) -> bool {
+        if let Some(fetch) = self.fetches.get_mut(&hash) {
+            if fetch.asked.addr() == peer.addr() {
+                return true;
+            }
+            if !fetch.candidates.iter().any(|candidate| candidate.addr() == peer.addr()) {
+                fetch.candidates.push(peer.clone());
+            }
+            return false;
+        }
+        self.fetches.insert(hash, Fetch { kind, asked: peer.clone(), deadline: now + self.timeout, candidates: Vec::new(), attempts: 1 });
+        return true;
+    }
+
+    fn received(&mut self, hashes: &[H256]) {
+        for hash in hashes {
+            self.fetches.remove(hash);
+        }
+    }
+
+    /// Move the requests past their deadline, or whose peer disconnected, on to the next peer that announced the
+    /// hash. Hashes `have` says arrived some other way are forgotten
+    fn expire(&mut self, now: Instant, have: impl Fn(FetchKind, &H256) -> bool) -> ExpiredFetches {
+        let mut expired = ExpiredFetches::default();
+        let mut done = Vec::new();
+        for (hash, fetch) in self.fetches.iter_mut() {
+            let disconnected = fetch.asked.is_disconnected();
+            if !disconnected && fetch.deadline > now {
+                continue;
+            }
+            if have(fetch.kind, hash) {
+                done.push(*hash);
+                continue;
+            }
+            if !disconnected {
+                expired.unresponsive.insert(*fetch.asked.addr());
+            }
+            fetch.candidates.retain(|candidate| !candidate.is_disconnected());
+            if fetch.attempts >= MAX_FETCH_ATTEMPTS || fetch.candidates.is_empty() {
+                expired.dropped.push(*hash);
+                continue;
+            }
+            fetch.asked = fetch.candidates.remove(0);
+            fetch.deadline = now + self.timeout;
+            fetch.attempts += 1;
+            expired.retries.push((fetch.asked.clone(), fetch.kind, *hash));
         }
+        self.received(&done);
+        self.received(&expired.dropped);
+        return expired;
     }
 }
 
 impl Worker {
     pub fn new(
         num_worker: usize,
-        msg_src: smol::channel::Receiver<(Vec<u8>, peer::Handle)>,
+        msg_src: MsgReceiver,
         server: &ServerHandle,
-        blockchain: &Arc<Mutex<Blockchain>>,
+        blockchain: &Arc<RwLock<Blockchain>>,
         mempool: &Arc<Mutex<Mempool>>,
-        block_state_map: &Arc<Mutex<BlockState>>
+        block_state_map: &Arc<Mutex<BlockState>>,
+        max_msgs_per_sec: u32,
+        max_block_msgs_per_sec: u32,
+        max_tx_msgs_per_sec: u32,
+        local_addrs: Vec<SocketAddr>,
+        version_tolerance: u32
     ) -> Self {
         Self {
             msg_chan: msg_src,
@@ -53,67 +579,668 @@ impl Worker {
             server: server.clone(),
             blockchain: Arc::clone(blockchain),
             mempool: Arc::clone(mempool),
-            block_state_map: Arc::clone(block_state_map)
+            block_state_map: Arc::clone(block_state_map),
+            max_msgs_per_sec,
+            max_block_msgs_per_sec,
+            max_tx_msgs_per_sec,
+            peer_budgets: Arc::new(Mutex::new(HashMap::new())),
+            local_addrs,
+            version_tolerance,
+            peer_versions: Arc::new(Mutex::new(HashMap::new())),
+            veracks: Arc::new(Mutex::new(HashSet::new())),
+            sync: SyncStatus::default(),
+            node_nonce: rand::random(),
+            push_blocks: false,
+            compact_blocks: false,
+            mempool_sync: true,
+            pending_compact: Arc::new(Mutex::new(HashMap::new())),
+            orphans: Arc::new(Mutex::new(OrphanBuffer::new())),
+            fetches: Arc::new(Mutex::new(FetchTracker::new(FETCH_TIMEOUT))),
+            propagation: PropagationStats::new(),
+            receiving: Arc::new(Mutex::new(())),
+            sequencer: PeerSequencer::default()
+        }
+    }
+
+    pub fn set_push_blocks(&mut self, enabled: bool) {
+        self.push_blocks = enabled;
+    }
+
+    pub fn set_compact_blocks(&mut self, enabled: bool) {
+        self.compact_blocks = enabled;
+    }
+
+    /// Ask another peer for announced blocks and transactions the first one asked didn't send within `timeout`
+    pub fn set_fetch_timeout(&mut self, timeout: Duration) {
+        self.fetches = Arc::new(Mutex::new(FetchTracker::new(timeout)));
+    }
+
+    pub fn set_mempool_sync(&mut self, enabled: bool) {
+        self.mempool_sync = enabled;
+    }
+
+    pub fn node_nonce(&self) -> u64 {
+        return self.node_nonce;
+    }
+
+    /// Shared view of whether the worker is still downloading the chain from a peer
+    pub fn sync_status(&self) -> SyncStatus {
+        return self.sync.clone();
+    }
+
+    /// Shared record of how long blocks from peers took to arrive
+    pub fn propagation_stats(&self) -> PropagationStats {
+        return self.propagation.clone();
+    }
+
+    /// The address a peer can reach us on, one of the same family as the peer's if we listen on one
+    pub fn advertised_addr(local_addrs: &[SocketAddr], peer: &SocketAddr) -> SocketAddr {
+        return *local_addrs.iter().find(|addr| addr.is_ipv4() == peer.is_ipv4()).unwrap_or(&local_addrs[0]);
+    }
+
+    /// Build the Version message announcing the protocol versions and features we speak, our genesis, current chain
+    /// height and node nonce
+    pub fn version_message(blockchain: &Arc<RwLock<Blockchain>>, local_addr: SocketAddr, node_nonce: u64) -> Message {
+        let blockchain = blockchain.read().unwrap();
+        return Message::Version {
+            protocol_version: MIN_PROTOCOL_VERSION,
+            genesis_hash: blockchain.genesis_hash(),
+            tip_height: blockchain.height,
+            user_agent: USER_AGENT.to_string(),
+            peer_addr: local_addr,
+            node_nonce,
+            max_protocol_version: PROTOCOL_VERSION,
+            features: message::supported_features(),
+        };
+    }
+
+    /// Once a peer's Version was accepted and its VerAck received, let the server broadcast to it
+    /// and catch up on its pending transactions. Must be called with peer_versions locked.
+    fn finish_handshake(&self, peer: &mut peer::Handle, peer_versions: &HashMap<SocketAddr, PeerVersion>) {
+        let addr = *peer.addr();
+        let mut veracks = self.veracks.lock().unwrap();
+        if !peer_versions.contains_key(&addr) || !veracks.contains(&addr) {
+            return;
+        }
+        veracks.remove(&addr);
+        drop(veracks);
+        debug!("Handshake with {} complete", addr);
+        self.server.handshake_complete(addr);
+        if self.mempool_sync {
+            peer.write(Message::GetMempool);
+        }
+        peer.write(Message::GetAddr);
+        //a peer without a feature would not understand the message asking for it
+        let features = peer_versions[&addr].features;
+        if features & FEATURE_COMPRESSION != 0 {
+            peer.write(Message::SendCompressed);
+        }
+        if self.push_blocks && features & FEATURE_PUSH_BLOCKS != 0 {
+            peer.write(Message::SendBlocks);
+        }
+        if self.compact_blocks && features & FEATURE_COMPACT_BLOCKS != 0 {
+            peer.write(Message::SendCompactBlocks);
+        }
+        self.start_sync(peer, peer_versions[&addr].tip_height);
+    }
+
+    /// Start downloading the chain from a peer that is ahead of us, one sync peer at a time
+    fn start_sync(&self, peer: &mut peer::Handle, peer_height: u32) {
+        let blockchain = self.blockchain.read().unwrap();
+        if peer_height <= blockchain.height || !self.sync.begin(peer, peer_height) {
+            return;
+        }
+        info!(chain.height = blockchain.height, target_height = peer_height, "Syncing from {}", peer.addr());
+        peer.write(Message::GetHeaders(blockchain.locator()));
+    }
+
+    /// Check headers from the sync peer link up and meet the proof of work, then fetch the blocks of the ones we don't have
+    fn handle_headers(&self, peer: &mut peer::Handle, headers: Vec<Header>) {
+        let target_height = match self.sync.target_height(peer.addr()) {
+            Some(target_height) => target_height,
+            None => {
+                debug!("Ignoring {} headers from {}, not syncing from it", headers.len(), peer.addr());
+                return;
+            }
+        };
+        let blockchain = self.blockchain.read().unwrap();
+        //an empty reply means the peer has nothing past our chain
+        let last = match headers.last() {
+            Some(last) => last.hash(),
+            None => {
+                info!(chain.tip = %blockchain.tip(), chain.height = blockchain.height, "Finished syncing from {}", peer.addr());
+                self.sync.finish();
+                return;
+            }
+        };
+        //height of every header in the batch, so each one can link to the one before it
+        let mut heights: HashMap<H256, u32> = HashMap::new();
+        let mut wanted = Vec::new();
+        for header in headers.iter() {
+            let hash = header.hash();
+            let parent_height = match blockchain.block_map.get(&header.parent) {
+                Some((_, height)) => Some(*height),
+                None => heights.get(&header.parent).copied(),
+            };
+            let parent_height = match parent_height {
+                Some(height) => height,
+                None => {
+                    warn!("Peer {} sent headers that don't link to our chain, stopping sync", peer.addr());
+                    self.server.misbehaving(*peer.addr(), INVALID_BLOCK_SCORE);
+                    self.sync.finish();
+                    return;
+                }
+            };
+            if hash > header.difficulty || header.difficulty != blockchain.expected_difficulty(&header.parent) {
+                warn!("Peer {} sent header {} failing proof of work, stopping sync", peer.addr(), hash);
+                peer.write(Message::Reject { rejected_hash: hash, reason: RejectReason::InvalidPoW });
+                self.server.misbehaving(*peer.addr(), INVALID_BLOCK_SCORE);
+                self.sync.finish();
+                return;
+            }
+            heights.insert(hash, parent_height + 1);
+            if !blockchain.contains(&hash) {
+                wanted.push(hash);
+            }
+        }
+        //a full batch may be followed by headers that do make the peer's chain longer
+        if heights[&last] <= blockchain.height && headers.len() < HEADERS_BATCH_SIZE {
+            info!(chain.height = blockchain.height, peer_height = heights[&last], "Peer {} has no longer chain, finished syncing", peer.addr());
+            self.sync.finish();
+            return;
+        }
+        debug!("Headers --- Peer: {} --- {} headers, {} new", peer.addr(), headers.len(), wanted.len());
+        self.server.add_known_inventory(*peer.addr(), heights.into_keys().collect());
+        self.sync.want_bodies(wanted);
+        self.continue_sync(peer, &blockchain, target_height);
+    }
+
+    /// Ask the sync peer for the next batch of blocks whose headers checked out, then for more headers
+    /// once those are in, until we reach the height it advertised
+    fn continue_sync(&self, peer: &mut peer::Handle, blockchain: &Blockchain, target_height: u32) {
+        if let Some(hashes) = self.sync.next_bodies(SYNC_BATCH_SIZE, blockchain) {
+            peer.write(Message::GetBlocks(hashes));
+        } else if blockchain.height >= target_height {
+            info!(chain.tip = %blockchain.tip(), chain.height = blockchain.height, "Finished syncing from {}", peer.addr());
+            self.sync.finish();
+        } else {
+            info!(chain.height = blockchain.height, target_height, "Syncing from {}", peer.addr());
+            peer.write(Message::GetHeaders(blockchain.locator()));
+        }
+    }
+
+    /// P2P addresses of the handshaked peers, keyed by the address of their connection to us
+    fn connected_peer_addrs(&self) -> HashMap<SocketAddr, SocketAddr> {
+        let handshaked = self.server.handshaked_peers();
+        let peer_versions = self.peer_versions.lock().unwrap();
+        return handshaked.into_iter()
+            .filter_map(|addr| peer_versions.get(&addr).map(|version| (addr, version.peer_addr)))
+            .collect();
+    }
+
+    /// Dial a few of the gossiped addresses we are not connected to yet, up to the outbound target
+    fn connect_to_gossiped(&self, addrs: Vec<SocketAddr>) {
+        let counts = self.server.connection_counts();
+        if counts.outbound >= counts.max_outbound {
+            return;
+        }
+        let peers = self.server.peer_info();
+        let mut known: HashSet<SocketAddr> = peers.iter().map(|p| p.addr).collect();
+        known.extend(self.connected_peer_addrs().values());
+        known.extend(self.local_addrs.iter().cloned());
+        let budget = (counts.max_outbound - counts.outbound).min(MAX_CONNECTIONS_PER_ADDR_MSG);
+        let mut opened = 0;
+        for addr in addrs {
+            if opened >= budget {
+                break;
+            }
+            if !known.insert(addr) {
+                continue;
+            }
+            match self.server.connect(addr) {
+                Ok(mut new_peer) => {
+                    info!("Connected to gossiped peer {}", addr);
+                    new_peer.write(Self::version_message(&self.blockchain, Self::advertised_addr(&self.local_addrs, &addr), self.node_nonce));
+                    opened += 1;
+                }
+                Err(e) => debug!("Error connecting to gossiped peer {}: {}", addr, e),
+            }
+        }
+    }
+
+    /// Charge a message of this class to the peer's budget for its kind. When the budget is used up the
+    /// message must be dropped, and the peer's misbehavior count is returned as the error.
+    fn allow_message(&self, addr: &SocketAddr, class: MessageClass) -> Result<(), u32> {
+        let mut peer_budgets = self.peer_budgets.lock().unwrap();
+        let (max_msgs, max_block, max_tx) = (self.max_msgs_per_sec, self.max_block_msgs_per_sec, self.max_tx_msgs_per_sec);
+        let budget = peer_budgets.entry(*addr).or_insert_with(|| PeerBudget {
+            blocks: RateLimiter::new(max_block, max_block),
+            transactions: RateLimiter::new(max_tx, max_tx),
+            others: RateLimiter::new(max_msgs, max_msgs),
+            misbehavior: 0
+        });
+        let allowed = match class {
+            MessageClass::Block => budget.blocks.try_acquire(),
+            MessageClass::Transaction => budget.transactions.try_acquire(),
+            _ => budget.others.try_acquire(),
+        };
+        if allowed {
+            return Ok(());
+        }
+        budget.misbehavior += 1;
+        return Err(budget.misbehavior);
+    }
+
+    /// Check a block whose parent we have against its parent's state and insert it; a block with invalid
+    /// transactions is rejected instead. Returns whether it was inserted
+    fn connect_block(&self, peer: &mut peer::Handle, blockchain: &mut Blockchain, block: &Block) -> bool {
+        //check balances and nonces
+        let state = self.block_state_map.lock().unwrap().validate_block(block, block.get_parent());
+        let state = match state {
+            Ok(state) => state,
+            Err(errors) => {
+                warn!("Block {} rejected: {} invalid transactions ({:?})", block.hash(), errors.len(), errors);
+                peer.write(Message::Reject { rejected_hash: block.hash(), reason: RejectReason::InsufficientBalance });
+                return false;
+            }
+        };
+        self.block_state_map.lock().unwrap().block_state_map.insert(block.hash(), state);
+        blockchain.insert(block);
+        self.propagation.record(block.hash(), block.get_timestamp());
+        let mut mempool = self.mempool.lock().unwrap();
+        for tx in block.content.data.iter() {
+            mempool.remove(&tx.hash());
+        }
+        return true;
+    }
+
+    /// Validate and insert blocks from a peer, along with any orphans they are the parent of. The parent of a
+    /// block we can't connect is asked from the peer, and so on back until the branch connects
+    fn handle_blocks(&self, peer: &mut peer::Handle, blocks: Vec<Block>, verdicts: Vec<Result<(), RejectReason>>) {
+        let received: Vec<H256> = blocks.iter().map(|block| block.hash()).collect();
+        self.fetches.lock().unwrap().received(&received);
+        //the sender has these, don't announce them back to it
+        self.server.add_known_inventory(*peer.addr(), blocks.iter().map(|block| block.hash()).collect());
+        let mut broadcast_blocks: Vec<H256> = Vec::<H256>::new();
+        let mut parent_blocks: Vec<H256> = Vec::<H256>::new();
+        let mut blockchain = self.blockchain.write().unwrap();
+        for (block, verdict) in blocks.into_iter().zip(verdicts) {
+            if self.orphans.lock().unwrap().contains(&block.hash()) {
+                continue;
+            }
+            if blockchain.contains(&block.hash()) {
+                peer.write(Message::Reject { rejected_hash: block.hash(), reason: RejectReason::DuplicateBlock });
+            } else {
+                //Proof of Work, merkle root and transaction signatures were checked by prevalidate
+                let verdict = verdict.and_then(|()| {
+                    if block.get_difficulty() != blockchain.expected_difficulty(&block.get_parent()) {
+                        return Err(RejectReason::InvalidPoW);
+                    }
+                    return Ok(());
+                });
+                if let Err(reason) = verdict {
+                    peer.write(Message::Reject { rejected_hash: block.hash(), reason });
+                    self.server.misbehaving(*peer.addr(), INVALID_BLOCK_SCORE);
+                    continue;
+                }
+
+                //Parent Check/Orphan Block Check
+                if !blockchain.block_map.contains_key(&block.get_parent()) {
+                    if let Some(parent) = self.orphans.lock().unwrap().park(block) {
+                        if !parent_blocks.contains(&parent) {
+                            parent_blocks.push(parent);
+                        }
+                    }
+                    continue;
+                }
+                if !self.connect_block(peer, &mut blockchain, &block) {
+                    continue;
+                }
+                broadcast_blocks.push(block.hash());
+
+                //Orphan Buffer Check: the orphans waiting for this block, then the ones waiting for those
+                let mut connected = vec![block.hash()];
+                while let Some(parent) = connected.pop() {
+                    let children = self.orphans.lock().unwrap().take_children(&parent);
+                    for child in children {
+                        if self.connect_block(peer, &mut blockchain, &child) {
+                            broadcast_blocks.push(child.hash());
+                            connected.push(child.hash());
+                        }
+                    }
+                }
+            }
+        }
+
+        if parent_blocks.len() != 0 {
+            peer.write(Message::GetBlocks(parent_blocks));
+        }
+        //https://piazza.com/class/kykjhx727ab1ge?cid=84
+        if broadcast_blocks.len() != 0 {
+            self.server.broadcast(Message::NewBlockHashes(broadcast_blocks));
+            //the new blocks may have advanced nonces that orphan transactions were waiting for
+            let tip_state = self.block_state_map.lock().unwrap().block_state_map.get(&blockchain.tip()).unwrap().clone();
+            let promoted = self.mempool.lock().unwrap().promote_orphans(&tip_state);
+            if promoted.len() != 0 {
+                self.server.broadcast(Message::NewTransactionHashes(promoted));
+            }
+        }
+        //the next batch is only asked for once every block of this one came in
+        if let Some(target_height) = self.sync.target_height(peer.addr()) {
+            if self.sync.bodies_received(&received) {
+                self.continue_sync(peer, &blockchain, target_height);
+            }
+        }
+    }
+
+    /// Fill in the compact blocks from this peer that were waiting for some of these transactions,
+    /// returns the ones that are done waiting: either complete, or the peer answered without all we asked for
+    fn fill_compact_blocks(&self, addr: &SocketAddr, txs: &[SignedTransaction]) -> Vec<PendingCompactBlock> {
+        let mut pending_compact = self.pending_compact.lock().unwrap();
+        let mut done = Vec::new();
+        let waiting: Vec<H256> = pending_compact.iter().filter(|(_, pending)| pending.peer == *addr).map(|(hash, _)| *hash).collect();
+        for hash in waiting {
+            let pending = pending_compact.get_mut(&hash).unwrap();
+            let missing: HashSet<H256> = pending.missing().into_iter().collect();
+            let mut answered = false;
+            for tx in txs {
+                if missing.contains(&tx.hash()) {
+                    pending.found.insert(tx.hash(), tx.clone());
+                    answered = true;
+                }
+            }
+            if answered || pending.missing().is_empty() {
+                done.push(pending_compact.remove(&hash).unwrap());
+            }
+        }
+        return done;
+    }
+
+    /// Rebuild a compact block and insert it, or fetch it in full if that fails
+    fn complete_compact_block(&self, peer: &mut peer::Handle, pending: PendingCompactBlock) {
+        let hash = pending.header.hash();
+        if self.blockchain.read().unwrap().contains(&hash) {
+            return;
         }
+        let block = match pending.rebuild() {
+            Some(block) => block,
+            None => {
+                debug!("Couldn't rebuild compact block {}, fetching it in full --- Peer: {}", hash, peer.addr());
+                peer.write(Message::GetBlocks(vec![hash]));
+                return;
+            }
+        };
+        let blocks = vec![block];
+        let verdicts = prevalidate(&Message::Blocks(blocks.clone()), &HashSet::new());
+        self.handle_blocks(peer, blocks, verdicts);
     }
 
-    pub fn start(self) {
+    /// Spawn the worker threads, they run until the server closes the message channel
+    pub fn start(self) -> Vec<thread::JoinHandle<()>> {
         let num_worker = self.num_worker;
+        let mut handles = Vec::new();
         for i in 0..num_worker {
             let cloned = self.clone();
-            thread::spawn(move || {
+            handles.push(thread::spawn(move || {
                 cloned.worker_loop();
-                warn!("Worker thread {} exited", i);
-            });
+                info!("Worker thread {} exited", i);
+            }));
+        }
+        handles.push(thread::spawn(move || {
+            self.fetch_timeout_loop();
+        }));
+        return handles;
+    }
+
+    /// the loop that looks for unanswered requests until the node shuts down
+    fn fetch_timeout_loop(&self) {
+        //check often enough that a request is retried close to its deadline, and shutdown isn't held up long
+        let period = (self.fetches.lock().unwrap().timeout / 4).clamp(Duration::from_millis(10), Duration::from_secs(1));
+        while !self.msg_chan.is_closed() {
+            thread::sleep(period);
+            self.retry_expired_fetches();
+            self.retry_stalled_sync();
+        }
+    }
+
+    /// Ask the sync peer again for the blocks it didn't send in time, and stop syncing from it if it never does
+    fn retry_stalled_sync(&self) {
+        let timeout = self.fetches.lock().unwrap().timeout;
+        match self.sync.stalled(Instant::now(), timeout) {
+            Some(StalledSync::Retry(mut peer, hashes)) => {
+                info!("Sync peer {} didn't send {} blocks in time, asking again", peer.addr(), hashes.len());
+                self.server.misbehaving(*peer.addr(), UNANSWERED_FETCH_SCORE);
+                peer.write(Message::GetBlocks(hashes));
+            }
+            Some(StalledSync::GiveUp(peer)) => {
+                warn!("Sync peer {} never sent the blocks it was asked for, stopping sync", peer.addr());
+                self.server.misbehaving(*peer.addr(), UNANSWERED_FETCH_SCORE);
+                self.sync.finish();
+            }
+            None => {}
+        }
+    }
+
+    /// Ask the next peer for the hashes whose request timed out, and penalize the peers that let it
+    fn retry_expired_fetches(&self) {
+        let expired = self.fetches.lock().unwrap().expire(Instant::now(), |kind, hash| match kind {
+            FetchKind::Block => self.blockchain.read().unwrap().contains(hash),
+            FetchKind::Transaction => self.mempool.lock().unwrap().has_seen(hash),
+        });
+        for addr in expired.unresponsive {
+            info!("Peer {} didn't send announced data it was asked for in time", addr);
+            self.server.misbehaving(addr, UNANSWERED_FETCH_SCORE);
+        }
+        //one request per peer for all its hashes of a kind
+        let mut retries: HashMap<(SocketAddr, FetchKind), (peer::Handle, Vec<H256>)> = HashMap::new();
+        for (peer, kind, hash) in expired.retries {
+            retries.entry((*peer.addr(), kind)).or_insert_with(|| (peer, Vec::new())).1.push(hash);
+        }
+        for ((addr, kind), (mut peer, hashes)) in retries {
+            debug!("Asking peer {} for {} {:?} hashes another peer didn't send", addr, hashes.len(), kind);
+            match kind {
+                FetchKind::Block => peer.write(Message::GetBlocks(hashes)),
+                FetchKind::Transaction => peer.write(Message::GetTransactions(hashes)),
+            }
+        }
+        for hash in expired.dropped {
+            debug!("No peer left to ask for {}, giving up on it", hash);
         }
     }
 
+    /// Deserialize a message, unwrapping it first if the peer sent it compressed; None for a message type
+    /// added by a newer protocol version, which is skipped
+    fn decode(peer: &peer::Handle, bytes: &[u8]) -> Result<Option<Message>, String> {
+        match Message::variant_of(bytes) {
+            Some(variant) if variant >= message::MESSAGE_VARIANTS => {
+                debug!("Peer {} sent message type {} unknown to this version, skipping", peer.addr(), variant);
+                return Ok(None);
+            }
+            _ => {}
+        }
+        let payload = match Message::decode(bytes).map_err(|e| e.to_string())? {
+            Message::Compressed(payload) => payload,
+            msg => {
+                msg.check_limits()?;
+                return Ok(Some(msg));
+            }
+        };
+        let raw = message::decompress(&payload).map_err(|e| e.to_string())?;
+        peer.record_compressed_received(raw.len(), payload.len());
+        let msg: Message = bincode::deserialize(&raw).map_err(|e| e.to_string())?;
+        if !msg.is_compressible() {
+            return Err("compressed payload is not a Blocks or Transactions message".to_string());
+        }
+        msg.check_limits()?;
+        return Ok(Some(msg));
+    }
+
     fn worker_loop(&self) {
         loop {
+            let receiving = self.receiving.lock().unwrap();
             let result = smol::block_on(self.msg_chan.recv());
-            if let Err(e) = result {
-                error!("network worker terminated {}", e);
+            if result.is_err() {
+                //the server closed the channel, the node is shutting down
                 break;
             }
             let msg = result.unwrap();
             let (msg, mut peer) = msg;
-            let msg: Message = bincode::deserialize(&msg).unwrap();
+            let mut ticket = self.sequencer.ticket(*peer.addr());
+            drop(receiving);
+            //everything logged while handling the message carries the sender
+            let span = info_span!("message", peer = %peer.addr());
+            let _enter = span.enter();
+            //drop whatever a disconnected peer still had queued
+            if peer.is_disconnected() {
+                continue;
+            }
+            let decoded = Self::decode(&peer, &msg);
+            //every message is charged once, to the budget of its kind; one that can't be decoded counts as any other
+            let class = match &decoded {
+                Ok(Some(msg)) => msg.message_type().class(),
+                _ => MessageClass::Control,
+            };
+            if let Err(misbehavior) = self.allow_message(peer.addr(), class) {
+                if misbehavior >= MAX_MISBEHAVIOR {
+                    warn!("Peer {} keeps exceeding its message budget, disconnecting", peer.addr());
+                    peer.disconnect();
+                    self.server.ban(*peer.addr());
+                } else {
+                    debug!("Peer {} is over its message budget, dropping message", peer.addr());
+                }
+                continue;
+            }
+            let msg: Message = match decoded {
+                Ok(Some(msg)) => msg,
+                Ok(None) => continue,
+                Err(e) => {
+                    //the server bans the peer once malformed messages push its score over the threshold
+                    warn!("Peer {} sent a message that can't be decoded, dropping it: {}", peer.addr(), e);
+                    self.server.misbehaving(*peer.addr(), MALFORMED_MESSAGE_SCORE);
+                    continue;
+                }
+            };
+            //a pushed block may also have been fetched after its announcement, don't check it twice
+            let known_blocks: HashSet<H256> = match &msg {
+                Message::Blocks(blocks) => {
+                    let blockchain = self.blockchain.read().unwrap();
+                    blocks.iter().map(|block| block.hash()).filter(|hash| blockchain.contains(hash)).collect()
+                }
+                _ => HashSet::new(),
+            };
+            let verdicts = prevalidate(&msg, &known_blocks);
+            ticket.wait_turn();
             match msg {
                 Message::Ping(nonce) => {
                     debug!("Ping: {}", nonce);
-                    peer.write(Message::Pong(nonce.to_string()));
+                    peer.write(Message::Pong(nonce));
                 }
                 Message::Pong(nonce) => {
                     debug!("Pong: {}", nonce);
+                    self.server.pong_received(*peer.addr(), nonce);
+                }
+                Message::Version { protocol_version, genesis_hash, tip_height, user_agent, peer_addr, node_nonce, max_protocol_version, features } => {
+                    if node_nonce == self.node_nonce {
+                        info!("Peer {} is ourselves, disconnecting", peer.addr());
+                        peer.disconnect();
+                        continue;
+                    }
+                    let theirs = Capabilities { min_version: protocol_version, max_version: max_protocol_version, features };
+                    let agreement = match Capabilities::ours().negotiate(&theirs, self.version_tolerance) {
+                        Ok(agreement) => agreement,
+                        Err(mismatch) => {
+                            warn!("Peer {} {}, disconnecting", peer.addr(), mismatch);
+                            peer.disconnect();
+                            continue;
+                        }
+                    };
+                    let genesis = self.blockchain.read().unwrap().genesis_hash();
+                    if genesis_hash != genesis {
+                        warn!("Peer {} has genesis {} (ours is {}), disconnecting", peer.addr(), genesis_hash, genesis);
+                        peer.disconnect();
+                        continue;
+                    }
+                    debug!("Version: {}-{} --- agreed {} --- features {:#x} --- tip height {} --- agent {} --- Peer: {}", protocol_version, max_protocol_version, agreement.version, agreement.features, tip_height, user_agent, peer.addr());
+                    //in place before our VerAck, so before the peer can ask for any feature
+                    peer.set_features(agreement.features);
+                    //the server drops this connection if it already has one to the same node
+                    self.server.register_node(*peer.addr(), node_nonce);
+                    //the dialing side already sent its Version, the accepting side answers with its own
+                    if peer.direction() == peer::Direction::Incoming {
+                        peer.write(Self::version_message(&self.blockchain, Self::advertised_addr(&self.local_addrs, peer.addr()), self.node_nonce));
+                    }
+                    peer.write(Message::VerAck);
+                    self.server.learn_addrs(*peer.addr(), vec![peer_addr]);
+                    let mut peer_versions = self.peer_versions.lock().unwrap();
+                    peer_versions.insert(*peer.addr(), PeerVersion { protocol_version: max_protocol_version, features: agreement.features, tip_height, user_agent, peer_addr });
+                    self.finish_handshake(&mut peer, &peer_versions);
+                }
+                Message::VerAck => {
+                    debug!("VerAck --- Peer: {}", peer.addr());
+                    let peer_versions = self.peer_versions.lock().unwrap();
+                    self.veracks.lock().unwrap().insert(*peer.addr());
+                    self.finish_handshake(&mut peer, &peer_versions);
+                }
+                Message::SendCompressed => {
+                    debug!("SendCompressed --- Peer: {}", peer.addr());
+                    //we can only compress if we were built with the feature, otherwise keep sending plain messages
+                    if cfg!(feature = "compression") {
+                        peer.enable_compression();
+                    }
+                }
+                Message::SendBlocks => {
+                    debug!("SendBlocks --- Peer: {}", peer.addr());
+                    peer.enable_push_blocks();
+                }
+                Message::SendCompactBlocks => {
+                    debug!("SendCompactBlocks --- Peer: {}", peer.addr());
+                    peer.enable_compact_blocks();
+                }
+                Message::GetMempool => {
+                    let mempool = self.mempool.lock().unwrap();
+                    //lowest nonces first, so the peer fetches each sender's transactions before the ones depending on them
+                    let mut txs: Vec<&SignedTransaction> = mempool.transaction_map.values().collect();
+                    txs.sort_by_key(|tx| tx.transaction.account_nonce);
+                    let tx_hashes: Vec<H256> = txs.iter().map(|tx| tx.hash()).collect();
+                    drop(mempool);
+                    for chunk in tx_hashes.chunks(MEMPOOL_ANNOUNCE_CHUNK) {
+                        self.server.add_known_inventory(*peer.addr(), chunk.to_vec());
+                        peer.write(Message::NewTransactionHashes(chunk.to_vec()));
+                    }
                 }
                 Message::NewBlockHashes(block_hashes) => {
+                    self.server.add_known_inventory(*peer.addr(), block_hashes.clone());
                     let mut missing_blocks: Vec<H256> = Vec::<H256>::new();
-                    let block_map = self.blockchain.lock().unwrap().block_map.clone(); 
+                    let block_map = self.blockchain.read().unwrap().block_map.clone(); 
+                    let now = Instant::now();
+                    let mut fetches = self.fetches.lock().unwrap();
                     for block in block_hashes {
-                        if !block_map.contains_key(&block) {
+                        if !block_map.contains_key(&block) && fetches.announced(FetchKind::Block, block, &peer, now) {
                             missing_blocks.push(block);
                         }
                     }
+                    drop(fetches);
                     //https://piazza.com/class/kykjhx727ab1ge?cid=84
                     if missing_blocks.len() != 0 {
                         peer.write(Message::GetBlocks(missing_blocks));
                     }
                 }
                 Message::NewTransactionHashes(tx_hashes) => {
-                    let mut missing_txs: Vec<H256> = Vec::<H256>::new();
-                    let tx_set = self.mempool.lock().unwrap().transaction_set.clone();
-                    for tx in tx_hashes {
-                        if !tx_set.contains(&tx) {
-                            missing_txs.push(tx);
-                        }
-                    }
+                    self.server.add_known_inventory(*peer.addr(), tx_hashes.clone());
+                    let mempool = self.mempool.lock().unwrap();
+                    let unseen: Vec<H256> = tx_hashes.into_iter().filter(|tx| !mempool.has_seen(tx)).collect();
+                    drop(mempool);
+                    let now = Instant::now();
+                    let mut fetches = self.fetches.lock().unwrap();
+                    let missing_txs: Vec<H256> = unseen.into_iter().filter(|tx| fetches.announced(FetchKind::Transaction, *tx, &peer, now)).collect();
+                    drop(fetches);
                     if missing_txs.len() != 0 {
                         peer.write(Message::GetTransactions(missing_txs));
                     }
                 }
                 Message::GetBlocks(blocks) => {
                     let mut send_blocks: Vec<Block> = Vec::<Block>::new();
-                    let block_map = self.blockchain.lock().unwrap().block_map.clone(); 
+                    let block_map = self.blockchain.read().unwrap().block_map.clone(); 
                     for block in blocks {
                         if block_map.contains_key(&block) {
                             let result: &(Block, u32) = block_map.get(&block).unwrap();
@@ -122,159 +1249,140 @@ impl Worker {
                     }
                     //https://piazza.com/class/kykjhx727ab1ge?cid=84
                     if send_blocks.len() != 0 {
+                        self.server.add_known_inventory(*peer.addr(), send_blocks.iter().map(|block| block.hash()).collect());
                         peer.write(Message::Blocks(send_blocks));
                     }
                 }
                 Message::GetTransactions(transactions) => {
                     let mut send_transactions: Vec<SignedTransaction> = Vec::<SignedTransaction>::new();
                     let tx_map = self.mempool.lock().unwrap().transaction_map.clone();
+                    let blockchain = self.blockchain.read().unwrap();
                     for transaction in transactions {
                         if tx_map.contains_key(&transaction) {
                             let result: &SignedTransaction = tx_map.get(&transaction).unwrap();
                             send_transactions.push(result.clone());
+                        } else if let Some(mined) = blockchain.transaction(&transaction) {
+                            //already left our mempool, a peer rebuilding a compact block still needs it
+                            send_transactions.push(mined);
                         }
                     }
+                    drop(blockchain);
                     if send_transactions.len() != 0 {
+                        self.server.add_known_inventory(*peer.addr(), send_transactions.iter().map(|tx| tx.hash()).collect());
                         peer.write(Message::Transactions(send_transactions));
                     }
                 }
+                Message::GetBlocksAfter { locator, stop_hash } => {
+                    let blocks = self.blockchain.read().unwrap().blocks_after(&locator, stop_hash, GET_BLOCKS_AFTER_LIMIT);
+                    let mut size = 0;
+                    let blocks: Vec<Block> = blocks.into_iter().take_while(|block| {
+                        size += bincode::serialized_size(block).unwrap() as usize;
+                        return size <= MAX_BLOCKS_REPLY_BYTES;
+                    }).collect();
+                    debug!("GetBlocksAfter --- Peer: {} --- replying with {} blocks", peer.addr(), blocks.len());
+                    self.server.add_known_inventory(*peer.addr(), blocks.iter().map(|block| block.hash()).collect());
+                    //an empty reply tells the peer it has caught up
+                    peer.write(Message::Blocks(blocks));
+                }
+                Message::GetHeaders(locator) => {
+                    let headers = self.blockchain.read().unwrap().headers_after(&locator, HEADERS_BATCH_SIZE);
+                    debug!("GetHeaders --- Peer: {} --- replying with {} headers", peer.addr(), headers.len());
+                    //an empty reply tells the peer it has caught up
+                    peer.write(Message::Headers(headers));
+                }
+                Message::Headers(headers) => {
+                    self.handle_headers(&mut peer, headers);
+                }
                 Message::Blocks(blocks) => {
-                    let mut broadcast_blocks: Vec<H256> = Vec::<H256>::new();
-                    let mut parent_blocks: Vec<H256> = Vec::<H256>::new();
-                    let mut blockchain = self.blockchain.lock().unwrap();
-                    //process_blocks represents blocks to process for orphan blocks
-                    let mut process_blocks = Vec::<Block>::new();
-                    let mut orphan_buffer: OrphanBuffer = OrphanBuffer::new();
-                    'block:for block in blocks {
-                        if !blockchain.block_map.contains_key(&block.hash()) {
-                            //Proof of Work
-                            if !(block.hash() <= DIFFICULTY.into()) {
-                                continue;
-                            }
-
-                            ///////////////Transaction Checks////////////////////////////////////////////////
-                            //here only check for signature
-                            for transaction in block.get_content().data {
-                                if !verify(&transaction.transaction, &transaction.public_key, &transaction.signature) {
-                                    continue 'block;
-                                }
-                            }
-                            //////////////////////////////////////////////////////////////////////////////////
-                            
-                            //Parent Check/Orphan Block Check
-                            let parent_hash = block.get_parent();
-                            if blockchain.block_map.contains_key(&parent_hash) {
-                                //////////TRANSACTION Checks//////////////////////
-                                // here check balance and nonce
-                                let mut parent_state = self.block_state_map.lock().unwrap().block_state_map.get(&parent_hash).unwrap().clone();
-                                for tx in block.get_content().data {
-                                    let sender = tx.transaction.sender;
-                                    let sender_state;
-                                    if parent_state.contains_key(&sender) {
-                                        sender_state = parent_state.get(&sender).unwrap().clone();
-                                    } else {
-                                        sender_state = (0, 0);
-                                    }
-                                    if (tx.transaction.value > sender_state.1) || (tx.transaction.account_nonce != sender_state.0 + 1) {
-                                        continue 'block;
-                                    }
-                                    //at this point the transaction is valid so update local state copy
-                                    parent_state.insert(tx.transaction.sender, (sender_state.0 + 1, sender_state.1 - &tx.transaction.value));
-                                    let receiver_state;
-                                    if parent_state.contains_key(&tx.transaction.receiver) {
-                                        receiver_state = parent_state.get(&tx.transaction.receiver).unwrap().clone();
-                                    } else {
-                                        receiver_state = (0, 0);
-                                    }
-                                    parent_state.insert(tx.transaction.receiver, (receiver_state.0, receiver_state.1 + &tx.transaction.value));
-                                }
-                                //////////////////////////////////////////////////
-                                self.block_state_map.lock().unwrap().block_state_map.insert(block.hash(), parent_state);
-                                blockchain.insert(&block);
-                                let mut mempool = self.mempool.lock().unwrap();
-                                for tx in block.content.data.clone() {
-                                    mempool.remove(&tx.hash());
-                                }
-                                broadcast_blocks.push(block.hash());
-                                //need to check for orphans
-                                process_blocks.push(block.clone());
-                            } else {
-                                orphan_buffer.orphans.push(block.clone());
-                                parent_blocks.push(parent_hash.clone());
-                            }
-
-                            //Orphan Buffer Check
-                            let mut keep_orphans = Vec::<Block>::new();
-                            while !process_blocks.is_empty() {
-                                let block = process_blocks.pop().unwrap();
-                                for orphan in orphan_buffer.orphans.clone() {
-                                    //block is parent, don't keep orphan
-                                    if orphan.get_parent() == block.hash() {
-                                        //////////TRANSACTION Checks//////////////////////
-                                        // here check balance and nonce
-                                        let mut parent_state = self.block_state_map.lock().unwrap().block_state_map.get(&block.hash()).unwrap().clone();
-                                        for tx in orphan.get_content().data {
-                                            let sender = tx.transaction.sender;
-                                            let sender_state;
-                                            if parent_state.contains_key(&sender) {
-                                                sender_state = parent_state.get(&sender).unwrap().clone();
-                                            } else {
-                                                sender_state = (0, 0);
-                                            }
-                                            if (tx.transaction.value > sender_state.1) || (tx.transaction.account_nonce != sender_state.0 + 1) {
-                                                continue 'block;
-                                            }
-                                            //at this point the transaction is valid so update local state copy
-                                            parent_state.insert(tx.transaction.sender, (sender_state.0 + 1, sender_state.1 - &tx.transaction.value));
-                                            let receiver_state;
-                                            if parent_state.contains_key(&tx.transaction.receiver) {
-                                                receiver_state = parent_state.get(&tx.transaction.receiver).unwrap().clone();
-                                            } else {
-                                                receiver_state = (0, 0);
-                                            }
-                                            parent_state.insert(tx.transaction.receiver, (receiver_state.0, receiver_state.1 + &tx.transaction.value));
-                                        }
-                                        //////////////////////////////////////////////////
-                                        self.block_state_map.lock().unwrap().block_state_map.insert(orphan.hash(), parent_state);
-                                        blockchain.insert(&orphan);
-                                        let mut mempool = self.mempool.lock().unwrap();
-                                        for tx in block.content.data.clone() {
-                                            mempool.remove(&tx.hash());
-                                        }
-                                        broadcast_blocks.push(block.hash());
-                                        process_blocks.push(block.clone());
-                                    } 
-                                    //block isn't parent, keep orphan
-                                    else { keep_orphans.push(orphan); }
-                                }
-                                //update orphan buffer with kept orphans & reset keep_orpans
-                                orphan_buffer.orphans = keep_orphans.clone();
-                                keep_orphans = Vec::<Block>::new();
-                            }
-                        }
+                    self.handle_blocks(&mut peer, blocks, verdicts);
+                }
+                Message::CompactBlock { header, tx_hashes } => {
+                    let hash = header.hash();
+                    let mut known = tx_hashes.clone();
+                    known.push(hash);
+                    self.server.add_known_inventory(*peer.addr(), known);
+                    if self.blockchain.read().unwrap().contains(&hash) {
+                        continue;
                     }
-
-                    if parent_blocks.len() != 0 {
-                        peer.write(Message::GetBlocks(parent_blocks));
+                    let mempool = self.mempool.lock().unwrap();
+                    let found: HashMap<H256, SignedTransaction> = tx_hashes.iter()
+                        .filter_map(|tx_hash| mempool.transaction_map.get(tx_hash).map(|tx| (*tx_hash, tx.clone())))
+                        .collect();
+                    drop(mempool);
+                    let pending = PendingCompactBlock { peer: *peer.addr(), header, tx_hashes, found };
+                    let missing = pending.missing();
+                    if missing.is_empty() {
+                        self.complete_compact_block(&mut peer, pending);
+                        continue;
                     }
-                    //https://piazza.com/class/kykjhx727ab1ge?cid=84
-                    if broadcast_blocks.len() != 0 {
-                        self.server.broadcast(Message::NewBlockHashes(broadcast_blocks));
+                    debug!("CompactBlock {} --- missing {} of {} transactions --- Peer: {}", hash, missing.len(), pending.tx_hashes.len(), peer.addr());
+                    let mut pending_compact = self.pending_compact.lock().unwrap();
+                    if pending_compact.len() >= MAX_PENDING_COMPACT_BLOCKS {
+                        //a peer that never answers can't grow this without bound
+                        let stale = *pending_compact.keys().next().unwrap();
+                        pending_compact.remove(&stale);
                     }
+                    pending_compact.insert(hash, pending);
+                    drop(pending_compact);
+                    peer.write(Message::GetTransactions(missing));
                 }
                 Message::Transactions(txs) => {
+                    let tx_hashes: Vec<H256> = txs.iter().map(|tx| tx.hash()).collect();
+                    self.fetches.lock().unwrap().received(&tx_hashes);
+                    self.server.add_known_inventory(*peer.addr(), tx_hashes);
+                    let compact_blocks = self.fill_compact_blocks(peer.addr(), &txs);
                     let mut broadcast_transactions: Vec<H256> = Vec::<H256>::new();
+                    let tip = self.blockchain.read().unwrap().tip();
+                    let tip_state = self.block_state_map.lock().unwrap().block_state_map.get(&tip).unwrap().clone();
                     let mut mempool = self.mempool.lock().unwrap();
-                    for tx in txs {
-                        if verify(&tx.transaction, &tx.public_key, &tx.signature) {
-                            broadcast_transactions.push(tx.hash());
-                            mempool.insert(&tx);
+                    for (tx, verdict) in txs.into_iter().zip(verdicts) {
+                        if let Err(reason) = verdict {
+                            peer.write(Message::Reject { rejected_hash: tx.hash(), reason });
+                            self.server.misbehaving(*peer.addr(), INVALID_TRANSACTION_SCORE);
+                            continue;
+                        }
+                        //only rebroadcast transactions the mempool actually accepted
+                        match mempool.insert_validated(&tx, &tip_state) {
+                            Ok(MempoolAdmission::Pooled { promoted }) => {
+                                broadcast_transactions.push(tx.hash());
+                                broadcast_transactions.extend(promoted);
+                            }
+                            Ok(MempoolAdmission::Orphaned) => debug!("Parked orphan transaction {}", tx.hash()),
+                            Err(e) => debug!("Rejected transaction {}: {:?}", tx.hash(), e)
                         }
                     }
+                    drop(mempool);
 
                     if broadcast_transactions.len() != 0 {
                         self.server.broadcast(Message::NewTransactionHashes(broadcast_transactions));
                     }
+                    for pending in compact_blocks {
+                        self.complete_compact_block(&mut peer, pending);
+                    }
+                }
+                Message::Reject { rejected_hash, reason } => {
+                    warn!("Peer {} rejected {}: {:?}", peer.addr(), rejected_hash, reason);
+                }
+                Message::GetAddr => {
+                    let requester = *peer.addr();
+                    let mut addrs: Vec<SocketAddr> = self.connected_peer_addrs()
+                        .into_iter()
+                        .filter(|(connection, _)| *connection != requester)
+                        .map(|(_, peer_addr)| peer_addr)
+                        .collect();
+                    addrs.shuffle(&mut rand::thread_rng());
+                    addrs.truncate(ADDR_SAMPLE_SIZE);
+                    debug!("GetAddr --- Peer: {} --- replying with {} addresses", requester, addrs.len());
+                    peer.write(Message::Addr(addrs));
+                }
+                Message::Addr(addrs) => {
+                    debug!("Addr: {} addresses --- Peer: {}", addrs.len(), peer.addr());
+                    self.server.learn_addrs(*peer.addr(), addrs.clone());
+                    self.connect_to_gossiped(addrs);
+                }
+                Message::Disconnect(reason) => {
+                    info!("Peer {} is closing the connection: {:?}", peer.addr(), reason);
+                    peer.disconnect();
                 }
                 _ => unimplemented!(),
             }
@@ -282,14 +1390,14 @@ impl Worker {
     }
 }
 
-#[cfg(any(test,test_utilities))]
+#[cfg(test)]
 struct TestMsgSender {
-    s: smol::channel::Sender<(Vec<u8>, peer::Handle)>
+    s: MsgSender
 }
-#[cfg(any(test,test_utilities))]
+#[cfg(test)]
 impl TestMsgSender {
-    fn new() -> (TestMsgSender, smol::channel::Receiver<(Vec<u8>, peer::Handle)>) {
-        let (s,r) = smol::channel::unbounded();
+    fn new() -> (TestMsgSender, MsgReceiver) {
+        let (s,r) = super::queue::channel(super::queue::PRIORITY_QUEUE_CAPACITY);
         (TestMsgSender {s}, r)
     }
 
@@ -299,21 +1407,185 @@ impl TestMsgSender {
         smol::block_on(self.s.send((bytes, handle))).unwrap();
         r
     }
+
+    /// Like send, but on a handle the test keeps to look at the peer's state
+    #[cfg(feature = "compression")]
+    fn send_on(&self, handle: &peer::Handle, msg: Message) {
+        let bytes = bincode::serialize(&msg).unwrap();
+        smol::block_on(self.s.send((bytes, handle.clone()))).unwrap();
+    }
+
+    /// Like send, but with bytes that need not be a valid message
+    fn send_raw(&self, bytes: Vec<u8>) -> PeerTestReceiver {
+        let (handle, r) = peer::Handle::test_handle();
+        smol::block_on(self.s.send((bytes, handle))).unwrap();
+        r
+    }
+
+    fn queue_stats(&self) -> super::queue::QueueStats {
+        return self.s.stats();
+    }
+
+    fn send_from(&self, addr: SocketAddr, msg: Message) -> PeerTestReceiver {
+        let bytes = bincode::serialize(&msg).unwrap();
+        let (handle, r) = peer::Handle::test_handle_at(addr);
+        smol::block_on(self.s.send((bytes, handle))).unwrap();
+        r
+    }
+
+    fn send_burst(&self, msg: Message, count: usize) -> PeerTestReceiver {
+        let bytes = bincode::serialize(&msg).unwrap();
+        let (handle, r) = peer::Handle::test_handle();
+        for _ in 0..count {
+            smol::block_on(self.s.send((bytes.clone(), handle.clone()))).unwrap();
+        }
+        r
+    }
+
+    fn send_burst_from(&self, addr: SocketAddr, msg: Message, count: usize) -> PeerTestReceiver {
+        let bytes = bincode::serialize(&msg).unwrap();
+        let (handle, r) = peer::Handle::test_handle_at(addr);
+        for _ in 0..count {
+            smol::block_on(self.s.send((bytes.clone(), handle.clone()))).unwrap();
+        }
+        r
+    }
 }
-#[cfg(any(test,test_utilities))]
+#[cfg(test)]
 /// returns two structs used by tests, and an ordered vector of hashes of all blocks in the blockchain
 fn generate_test_worker_and_start() -> (TestMsgSender, ServerTestReceiver, Vec<H256>) {
+    let test_worker = generate_test_worker(TestWorkerOptions::default()).started();
+    let tip = test_worker.blockchain.read().unwrap().tip();
+    (test_worker.msg_sender, test_worker.server_receiver, vec![tip])
+}
+
+#[cfg(test)]
+/// How generate_test_worker sets up a worker, the defaults are those of generate_test_worker_and_start
+struct TestWorkerOptions {
+    //threads handling messages
+    num_worker: usize,
+    max_msgs_per_sec: u32,
+    max_block_msgs_per_sec: u32,
+    max_tx_msgs_per_sec: u32,
+    //accounts at genesis, no state is recorded for genesis without it
+    genesis_state: Option<HashMap<crate::types::address::Address, (u32, u32)>>,
+}
+
+#[cfg(test)]
+impl Default for TestWorkerOptions {
+    fn default() -> Self {
+        return TestWorkerOptions {
+            num_worker: 1,
+            max_msgs_per_sec: 100,
+            max_block_msgs_per_sec: 100,
+            max_tx_msgs_per_sec: 100,
+            genesis_state: None,
+        };
+    }
+}
+
+#[cfg(test)]
+/// A worker made by generate_test_worker, on a test server and fed by a test message sender
+struct TestWorker {
+    msg_sender: TestMsgSender,
+    server_receiver: ServerTestReceiver,
+    blockchain: Arc<RwLock<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+    //not started, so a test can set it up or queue messages first
+    worker: Worker,
+}
+
+#[cfg(test)]
+impl TestWorker {
+    /// Start the worker, keeping the rest for the test
+    fn started(self) -> Self {
+        self.worker.clone().start();
+        return self;
+    }
+}
+
+#[cfg(test)]
+/// A worker on a fresh chain and an empty mempool, left for the test to start
+fn generate_test_worker(options: TestWorkerOptions) -> TestWorker {
     let (server, server_receiver) = ServerHandle::new_for_test();
-    let (test_msg_sender, msg_chan) = TestMsgSender::new();
-    let blockchain = Blockchain::new();
-    let blockchain = Arc::new(Mutex::new(blockchain));
-    let mempool = Mempool::new();
-    let mempool = Arc::new(Mutex::new(mempool));
-    let tip = blockchain.lock().unwrap().tip();
+    let (msg_sender, msg_chan) = TestMsgSender::new();
+    let blockchain = Arc::new(RwLock::new(Blockchain::new()));
+    let mempool = Arc::new(Mutex::new(Mempool::new()));
+    let tip = blockchain.read().unwrap().tip();
     let block_state_map = Arc::new(Mutex::new(BlockState::new()));
-    let worker = Worker::new(1, msg_chan, &server, &blockchain, &mempool, &block_state_map);
-    worker.start(); 
-    (test_msg_sender, server_receiver, vec![tip])
+    if let Some(genesis_state) = options.genesis_state {
+        block_state_map.lock().unwrap().block_state_map.insert(tip, genesis_state);
+    }
+    let local_addr = "127.0.0.1:6000".parse().unwrap();
+    let worker = Worker::new(options.num_worker, msg_chan, &server, &blockchain, &mempool, &block_state_map,
+        options.max_msgs_per_sec, options.max_block_msgs_per_sec, options.max_tx_msgs_per_sec, vec![local_addr], 0);
+    return TestWorker { msg_sender, server_receiver, blockchain, mempool, worker };
+}
+
+#[cfg(test)]
+/// How start_test_node sets up a node, the defaults are those of a node started without options
+pub(crate) struct TestNodeOptions {
+    //banned peers are refused this long
+    pub ban_duration: std::time::Duration,
+    //accounts at genesis
+    pub genesis_state: HashMap<crate::types::address::Address, (u32, u32)>,
+    //shared with the node
+    pub mempool: Arc<Mutex<Mempool>>,
+    //ask peers for their mempool after the handshake
+    pub mempool_sync: bool,
+    //relay new transactions to this many random peers instead of announcing them to all
+    pub relay_fanout: Option<usize>,
+    //most peers the node connects to itself
+    pub max_outbound: usize,
+}
+
+#[cfg(test)]
+impl Default for TestNodeOptions {
+    fn default() -> Self {
+        return TestNodeOptions {
+            ban_duration: std::time::Duration::from_secs(super::server::BAN_DURATION_SECS),
+            genesis_state: HashMap::new(),
+            mempool: Arc::new(Mutex::new(Mempool::new())),
+            mempool_sync: true,
+            relay_fanout: None,
+            max_outbound: super::server::DEFAULT_MAX_OUTBOUND,
+        };
+    }
+}
+
+#[cfg(test)]
+/// A node started by start_test_node
+pub(crate) struct TestNode {
+    pub server: ServerHandle,
+    pub blockchain: Arc<RwLock<Blockchain>>,
+    //whether the worker is syncing
+    pub sync: SyncStatus,
+    //the node's block propagation delays
+    pub propagation: PropagationStats,
+    pub block_state_map: Arc<Mutex<BlockState>>,
+}
+
+#[cfg(test)]
+/// start a real P2P server and one worker on `addr` with `blockchain` as its chain
+pub(crate) fn start_test_node(addr: SocketAddr, blockchain: Blockchain, options: TestNodeOptions) -> TestNode {
+    let (msg_tx, msg_rx) = super::queue::channel(super::queue::DEFAULT_TX_HIGH_WATER);
+    let (mut server_ctx, server) = super::server::new(vec![addr], msg_tx, super::server::DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+    server_ctx.set_ban_duration(options.ban_duration);
+    server_ctx.set_connection_limits(super::server::DEFAULT_MAX_INBOUND, options.max_outbound);
+    if let Some(fanout) = options.relay_fanout {
+        server_ctx.set_relay_fanout(fanout, false);
+    }
+    server_ctx.start().unwrap();
+    let tip = blockchain.tip();
+    let blockchain = Arc::new(RwLock::new(blockchain));
+    let block_state_map = Arc::new(Mutex::new(BlockState::new()));
+    block_state_map.lock().unwrap().block_state_map.insert(tip, options.genesis_state);
+    let mut worker = Worker::new(1, msg_rx, &server, &blockchain, &options.mempool, &block_state_map, 100, 100, 100, vec![addr], 0);
+    worker.set_mempool_sync(options.mempool_sync);
+    let sync = worker.sync_status();
+    let propagation = worker.propagation_stats();
+    worker.start();
+    return TestNode { server, blockchain, sync, propagation, block_state_map };
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
@@ -321,11 +1593,224 @@ fn generate_test_worker_and_start() -> (TestMsgSender, ServerTestReceiver, Vec<H
 #[cfg(test)]
 mod test {
     use ntest::timeout;
-    use crate::types::block::generate_random_block;
+    use crate::types::block::{apply_block_to_state, generate_mined_block, generate_random_block, Block, Content};
+    use crate::types::merkle::MerkleTree;
     use crate::types::hash::{Hashable, H256};
 
-    use super::super::message::Message;
-    use super::generate_test_worker_and_start;
+    use super::super::message::{supported_features, Message, FEATURE_PUSH_BLOCKS, RejectReason, MAX_INVENTORY_ITEMS, MAX_USER_AGENT_LEN, MESSAGE_VARIANTS, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+    use crate::blockchain::DIFFICULTY;
+    use crate::types::address::Address;
+    use crate::types::key_pair;
+    use crate::types::transaction::{generate_random_transaction, sign, SignedTransaction, TransactionBuilder, MIN_TX_FEE};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use rand::seq::SliceRandom;
+    use rand::Rng;
+    use crate::blockchain::Blockchain;
+    use crate::types::hash::generate_random_hash;
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::thread;
+    use std::time::Duration;
+    use super::super::peer;
+    use super::super::server::BAN_SCORE_THRESHOLD;
+    use std::time::Instant;
+    use std::sync::{Arc, Mutex, RwLock};
+    use crate::miner::Mempool;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use super::super::frame::{encode, read_frame};
+    use super::{generate_test_worker, generate_test_worker_and_start, start_test_node, TestNode, TestNodeOptions, TestWorker, TestWorkerOptions, INVALID_TRANSACTION_SCORE, MALFORMED_MESSAGE_SCORE, MAX_ORPHAN_BLOCKS, MAX_ORPHAN_DEPTH, OrphanBuffer, FetchKind, FetchTracker, MAX_FETCH_ATTEMPTS, UNANSWERED_FETCH_SCORE, RateLimiter, Worker};
+
+    fn write_frame(stream: &mut TcpStream, msg: &Message) {
+        let payload = bincode::serialize(msg).unwrap();
+        stream.write_all(&encode(&payload)).unwrap();
+    }
+
+    /// Every message the node sends on this connection until it has been quiet for the given time
+    fn read_frames_until_quiet(stream: &mut TcpStream, quiet: Duration) -> Vec<Message> {
+        stream.set_read_timeout(Some(quiet)).unwrap();
+        let mut msgs = Vec::new();
+        while let Ok(msg_buffer) = read_frame(stream) {
+            msgs.push(bincode::deserialize(&msg_buffer).unwrap());
+        }
+        return msgs;
+    }
+
+    /// A bare TCP connection to the node at addr that has completed the handshake
+    fn handshaked_raw_peer(addr: SocketAddr, genesis_hash: H256) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let peer_addr = stream.local_addr().unwrap();
+        write_frame(&mut stream, &Message::Version { protocol_version: PROTOCOL_VERSION, genesis_hash, tip_height: 0, user_agent: "test".to_string(), peer_addr, node_nonce: rand::random(), max_protocol_version: PROTOCOL_VERSION, features: supported_features() });
+        write_frame(&mut stream, &Message::VerAck);
+        //the node's Version, VerAck and post handshake requests
+        read_frames_until_quiet(&mut stream, Duration::from_millis(300));
+        return stream;
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn unknown_message_types_are_skipped() {
+        //the last variant is the one counted by MESSAGE_VARIANTS
+        let header = generate_random_block(&generate_random_hash()).get_header();
+        let last = bincode::serialize(&Message::CompactBlock { header, tx_hashes: vec![] }).unwrap();
+        assert_eq!(Message::variant_of(&last), Some(MESSAGE_VARIANTS - 1));
+
+        let addr: SocketAddr = "127.0.0.1:6137".parse().unwrap();
+        let chain = Blockchain::new();
+        let genesis = chain.tip();
+        let TestNode { server: _server, .. } = start_test_node(addr, chain, TestNodeOptions::default());
+        let mut stream = handshaked_raw_peer(addr, genesis);
+        //a message type from a newer protocol version, with a body this node can't parse
+        let mut unknown = (MESSAGE_VARIANTS + 5).to_le_bytes().to_vec();
+        unknown.extend_from_slice(&[0xff; 40]);
+        stream.write_all(&encode(&unknown)).unwrap();
+        write_frame(&mut stream, &Message::Ping(5));
+        let msgs = read_frames_until_quiet(&mut stream, Duration::from_millis(500));
+        assert!(msgs.iter().any(|msg| matches!(msg, Message::Pong(5))));
+
+        //a known type that doesn't parse is dropped as well, counting against the peer
+        let mut malformed = 4u32.to_le_bytes().to_vec();
+        malformed.extend_from_slice(&[0xff; 40]);
+        stream.write_all(&encode(&malformed)).unwrap();
+        write_frame(&mut stream, &Message::Ping(6));
+        let msgs = read_frames_until_quiet(&mut stream, Duration::from_millis(500));
+        assert!(msgs.iter().any(|msg| matches!(msg, Message::Pong(6))));
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn malformed_messages_never_kill_the_worker() {
+        let TestWorker { msg_sender: test_msg_sender, server_receiver, blockchain, .. } = generate_test_worker(TestWorkerOptions { max_msgs_per_sec: 1_000_000, max_block_msgs_per_sec: 1_000_000, max_tx_msgs_per_sec: 1_000_000, ..Default::default() }).started();
+        let tip = blockchain.read().unwrap().tip();
+        let mut rng = rand::thread_rng();
+        let mut payloads: Vec<Vec<u8>> = vec![vec![]];
+        //every known type cut short; the ones without a body parse whatever follows, so they are left out
+        for variant in 0..MESSAGE_VARIANTS {
+            if bincode::deserialize::<Message>(&variant.to_le_bytes()).is_err() {
+                let mut payload = variant.to_le_bytes().to_vec();
+                payload.extend_from_slice(&[0xff, 0xff]);
+                payloads.push(payload);
+            }
+        }
+        //a Blocks message claiming ten million blocks
+        let mut huge = 4u32.to_le_bytes().to_vec();
+        huge.extend_from_slice(&10_000_000u64.to_le_bytes());
+        huge.extend_from_slice(&[0u8; 64]);
+        payloads.push(huge);
+        //well formed, but over the per message caps
+        payloads.push(bincode::serialize(&Message::NewBlockHashes(vec![tip; MAX_INVENTORY_ITEMS + 1])).unwrap());
+        let mut long_agent = version(PROTOCOL_VERSION, tip);
+        if let Message::Version { user_agent, .. } = &mut long_agent {
+            *user_agent = "x".repeat(MAX_USER_AGENT_LEN + 1);
+        }
+        payloads.push(bincode::serialize(&long_agent).unwrap());
+        let rejected = payloads.len();
+        //random bytes, a few of which may happen to decode
+        for _ in 0..1000 {
+            let len = rng.gen_range(0..200);
+            payloads.push((0..len).map(|_| rng.gen::<u8>()).collect());
+        }
+        //kept so the senders don't look disconnected
+        let _receivers: Vec<_> = payloads.into_iter().map(|payload| test_msg_sender.send_raw(payload)).collect();
+
+        //the single worker is still there to answer
+        let mut peer_receiver = test_msg_sender.send(Message::Ping(9));
+        assert!(matches!(peer_receiver.recv(), Message::Pong(9)));
+        let scores = server_receiver.misbehavior();
+        assert!(scores.len() >= rejected);
+        assert!(scores.iter().all(|(_, score)| *score == MALFORMED_MESSAGE_SCORE));
+    }
+
+    /// A transfer of 10 from the key's account to a random address, paying the minimum fee
+    fn transfer(key: &Ed25519KeyPair, nonce: u32) -> SignedTransaction {
+        return TransactionBuilder::new()
+            .sender(key)
+            .receiver(Address::from(rand::random::<[u8; 20]>()))
+            .value(10)
+            .fee(MIN_TX_FEE)
+            .nonce(nonce)
+            .build(key)
+            .unwrap();
+    }
+
+    /// Transfers from the key's account with nonces 1..=count, and a mined block on genesis holding them
+    fn block_of_transfers(key: &Ed25519KeyPair, count: u32) -> (Vec<SignedTransaction>, Block) {
+        let txs: Vec<SignedTransaction> = (1..=count).map(|nonce| transfer(key, nonce)).collect();
+        let mut block = generate_mined_block(&Blockchain::new().tip());
+        block.header_mut().merkle_root = MerkleTree::new(&txs).root();
+        block.content = Content { data: txs.clone() };
+        while block.hash() > block.get_difficulty() {
+            block.header_mut().nonce = block.get_nonce().wrapping_add(1);
+        }
+        return (txs, block);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn block_overtakes_transaction_flood() {
+        let key = key_pair::random();
+        let TestWorker { msg_sender: test_msg_sender, server_receiver: _server_receiver, blockchain, .. } = generate_test_worker(funded(Address::from_public_key_bytes(key.public_key().as_ref()), 1000)).started();
+        let (_, block) = block_of_transfers(&key, 1);
+        let (pool, _) = pending_transactions();
+        let txs: Vec<SignedTransaction> = pool.transaction_map.values().cloned().collect();
+        //spread over many peers so none of them goes over its rate limit
+        let mut receivers = Vec::new();
+        for i in 0..500 {
+            let addr = SocketAddr::new("127.0.0.1".parse().unwrap(), 10000 + (i % 50) as u16);
+            receivers.push(test_msg_sender.send_from(addr, Message::Transactions(txs.clone())));
+        }
+        let start = Instant::now();
+        receivers.push(test_msg_sender.send(Message::Blocks(vec![block])));
+        wait_for_height(&blockchain, 1);
+        //the block went ahead of the transactions still queued
+        assert!(test_msg_sender.queue_stats().queued_best_effort > 0);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn queued_block_is_handled_before_queued_transactions() {
+        let key = key_pair::random();
+        let TestWorker { msg_sender: test_msg_sender, server_receiver: _server_receiver, blockchain, worker, .. } = generate_test_worker(funded(Address::from_public_key_bytes(key.public_key().as_ref()), 1000));
+        let (_, block) = block_of_transfers(&key, 1);
+        let mut receivers = Vec::new();
+        for i in 0..1000 {
+            let addr = SocketAddr::new("127.0.0.1".parse().unwrap(), 10000 + (i % 50) as u16);
+            receivers.push(test_msg_sender.send_from(addr, Message::NewTransactionHashes(vec![generate_random_hash()])));
+        }
+        let _block_receiver = test_msg_sender.send(Message::Blocks(vec![block]));
+        worker.start();
+        //the first transaction message is answered only after the block queued behind it is in the chain
+        assert!(matches!(receivers[0].recv(), Message::GetTransactions(_)));
+        assert_eq!(blockchain.read().unwrap().height, 1);
+    }
+
+    /// Options for a worker with `address` holding `balance` at genesis
+    fn funded(address: Address, balance: u32) -> TestWorkerOptions {
+        return TestWorkerOptions { genesis_state: Some(HashMap::from([(address, (0, balance))])), ..Default::default() };
+    }
+
+    fn wait_for_height(blockchain: &Arc<RwLock<Blockchain>>, height: u32) {
+        while blockchain.read().unwrap().height != height {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn version(protocol_version: u32, genesis_hash: H256) -> Message {
+        let peer_addr = "127.0.0.1:6001".parse().unwrap();
+        return Message::Version { protocol_version, genesis_hash, tip_height: 3, user_agent: "test".to_string(), peer_addr, node_nonce: rand::random(), max_protocol_version: protocol_version, features: supported_features() };
+    }
+
+    #[test]
+    fn advertised_addr_matches_peer_family() {
+        let v4: SocketAddr = "127.0.0.1:6000".parse().unwrap();
+        let v6: SocketAddr = "[::1]:6000".parse().unwrap();
+        let local = [v4, v6];
+        assert_eq!(Worker::advertised_addr(&local, &"10.0.0.1:7000".parse().unwrap()), v4);
+        assert_eq!(Worker::advertised_addr(&local, &"[fe80::1]:7000".parse().unwrap()), v6);
+        //nothing of the peer's family, the first address is the best we have
+        assert_eq!(Worker::advertised_addr(&[v4], &"[fe80::1]:7000".parse().unwrap()), v4);
+    }
 
     #[test]
     #[timeout(60000)]
@@ -445,6 +1930,1131 @@ mod test {
             panic!();
         }
     }
+    #[test]
+    fn rate_limiter_allows_burst_then_limits() {
+        let mut limiter = RateLimiter::new(1, 5);
+        for _ in 0..5 {
+            assert!(limiter.try_acquire());
+        }
+        assert!(!limiter.try_acquire());
+    }
+    #[test]
+    #[timeout(60000)]
+    //a peer flooding the worker past its rate gets disconnected
+    fn flooding_peer_is_disconnected() {
+        let (test_msg_sender, _server_receiver, _v) = generate_test_worker_and_start();
+        let sent = 500;
+        let mut peer_receiver = test_msg_sender.send_burst(Message::Ping(0), sent);
+        let mut replies = 0;
+        while let Some(reply) = peer_receiver.recv_or_closed() {
+            if let Message::Pong(_) = reply {
+                replies += 1;
+            }
+        }
+        assert!(replies < sent);
+    }
+    #[test]
+    #[timeout(60000)]
+    //a peer flooding transaction hashes is throttled while another peer's blocks still go through
+    fn transaction_flood_does_not_delay_blocks() {
+        let TestWorker { msg_sender: test_msg_sender, server_receiver: _server_receiver, blockchain, .. } = generate_test_worker(TestWorkerOptions { max_msgs_per_sec: 10000, max_block_msgs_per_sec: 10, max_tx_msgs_per_sec: 10, ..Default::default() }).started();
+        let tip = blockchain.read().unwrap().tip();
+        let flooder: SocketAddr = "127.0.0.1:12322".parse().unwrap();
+        let honest: SocketAddr = "127.0.0.1:12323".parse().unwrap();
+        let sent = 500;
+        let mut flood_receiver = test_msg_sender.send_burst_from(flooder, Message::NewTransactionHashes(vec![generate_random_hash()]), sent);
+        let block = generate_random_block(&tip);
+        let start = Instant::now();
+        let mut peer_receiver = test_msg_sender.send_from(honest, Message::NewBlockHashes(vec![block.hash()]));
+        if let Message::GetBlocks(hashes) = peer_receiver.recv() {
+            assert_eq!(hashes, vec![block.hash()]);
+        } else {
+            panic!();
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+        //the flooder only gets its burst through, then is dropped for misbehaving
+        let mut replies = 0;
+        while let Some(reply) = flood_receiver.recv_or_closed() {
+            if let Message::GetTransactions(_) = reply {
+                replies += 1;
+            }
+        }
+        assert!(replies < sent);
+    }
+    #[test]
+    #[timeout(60000)]
+    //block messages only count against the block budget, not also against the one for other messages
+    fn block_messages_are_charged_once() {
+        let TestWorker { msg_sender: test_msg_sender, server_receiver: _server_receiver, blockchain, .. } = generate_test_worker(TestWorkerOptions { max_msgs_per_sec: 1, max_block_msgs_per_sec: 100, max_tx_msgs_per_sec: 100, ..Default::default() }).started();
+        let tip = blockchain.read().unwrap().tip();
+        let sent = 20;
+        let mut peer_receiver = test_msg_sender.send_burst(Message::GetBlocks(vec![tip]), sent);
+        for _ in 0..sent {
+            if let Message::Blocks(blocks) = peer_receiver.recv() {
+                assert_eq!(blocks[0].hash(), tip);
+            } else {
+                panic!();
+            }
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn reply_compatible_version() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        let mut peer_receiver = test_msg_sender.send(version(PROTOCOL_VERSION, v[0]));
+        if let Message::Version { protocol_version, genesis_hash, tip_height, max_protocol_version, .. } = peer_receiver.recv() {
+            assert_eq!((protocol_version, max_protocol_version), (MIN_PROTOCOL_VERSION, PROTOCOL_VERSION));
+            assert_eq!(genesis_hash, v[0]);
+            assert_eq!(tip_height, 0);
+        } else {
+            panic!();
+        }
+        if let Message::VerAck = peer_receiver.recv() {
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn reject_incompatible_version() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        let mut peer_receiver = test_msg_sender.send(version(PROTOCOL_VERSION + 1, v[0]));
+        //connection is closed without a reply
+        assert!(peer_receiver.recv_or_closed().is_none());
+    }
+    #[test]
+    #[timeout(60000)]
+    fn reject_version_below_minimum() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        let mut peer_receiver = test_msg_sender.send(version(MIN_PROTOCOL_VERSION - 1, v[0]));
+        assert!(peer_receiver.recv_or_closed().is_none());
+    }
+    #[test]
+    #[timeout(60000)]
+    fn reject_different_genesis() {
+        let (test_msg_sender, _server_receiver, _v) = generate_test_worker_and_start();
+        let mut peer_receiver = test_msg_sender.send(version(PROTOCOL_VERSION, generate_random_hash()));
+        assert!(peer_receiver.recv_or_closed().is_none());
+    }
+    #[test]
+    #[timeout(60000)]
+    fn reject_block_with_invalid_pow() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        let mut block = generate_random_block(v.last().unwrap());
+        let difficulty: H256 = DIFFICULTY.into();
+        while block.hash() <= difficulty {
+            block = generate_random_block(v.last().unwrap());
+        }
+        let mut peer_receiver = test_msg_sender.send(Message::Blocks(vec![block.clone()]));
+        if let Message::Reject { rejected_hash, reason } = peer_receiver.recv() {
+            assert_eq!(rejected_hash, block.hash());
+            assert_eq!(reason, RejectReason::InvalidPoW);
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn reject_block_with_easy_difficulty() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        //every hash meets this target, so only the difficulty itself is wrong
+        let mut block = generate_random_block(v.last().unwrap());
+        block.header_mut().difficulty = H256::from([255; 32]);
+        let mut peer_receiver = test_msg_sender.send(Message::Blocks(vec![block.clone()]));
+        if let Message::Reject { rejected_hash, reason } = peer_receiver.recv() {
+            assert_eq!(rejected_hash, block.hash());
+            assert_eq!(reason, RejectReason::InvalidPoW);
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn accept_block_with_valid_pow() {
+        let TestWorker { msg_sender: test_msg_sender, server_receiver, .. } = generate_test_worker(TestWorkerOptions { genesis_state: Some(HashMap::new()), ..Default::default() }).started();
+        let block = generate_mined_block(&Blockchain::new().tip());
+        let _peer_receiver = test_msg_sender.send(Message::Blocks(vec![block.clone()]));
+        //skip control signals that aren't broadcasts
+        let reply = loop {
+            if let Some(msg) = server_receiver.recv() {
+                break msg;
+            }
+        };
+        if let Message::NewBlockHashes(v) = reply {
+            assert_eq!(v, vec![block.hash()]);
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn reject_forged_and_badly_signed_transactions() {
+        let TestWorker { msg_sender: test_msg_sender, server_receiver: _server_receiver, mempool, .. } = generate_test_worker(TestWorkerOptions { genesis_state: Some(HashMap::new()), ..Default::default() }).started();
+        let key = key_pair::random();
+        //validly signed, but spending from an account the key doesn't own
+        let mut t = generate_random_transaction();
+        t.sender = Address::from_public_key_bytes(key_pair::random().public_key().as_ref());
+        let signature = sign(&t, &key).as_ref().to_vec();
+        let forged = SignedTransaction { transaction: t, signature, public_key: key.public_key().as_ref().to_vec() };
+        //spending from the key's own account, with a corrupted signature
+        let mut t = generate_random_transaction();
+        t.sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        let mut signature = sign(&t, &key).as_ref().to_vec();
+        signature[0] ^= 1;
+        let badly_signed = SignedTransaction { transaction: t, signature, public_key: key.public_key().as_ref().to_vec() };
+
+        let mut peer_receiver = test_msg_sender.send(Message::Transactions(vec![forged.clone(), badly_signed.clone()]));
+        for (tx, expected) in [(&forged, RejectReason::SenderMismatch), (&badly_signed, RejectReason::InvalidSignature)] {
+            if let Message::Reject { rejected_hash, reason } = peer_receiver.recv() {
+                assert_eq!(rejected_hash, tx.hash());
+                assert_eq!(reason, expected);
+            } else {
+                panic!();
+            }
+        }
+        assert!(mempool.lock().unwrap().transaction_map.is_empty());
+    }
+    #[test]
+    #[timeout(60000)]
+    fn parallel_workers_pool_every_valid_transaction_once() {
+        let TestWorker { msg_sender: test_msg_sender, server_receiver, mempool, .. } = generate_test_worker(TestWorkerOptions { num_worker: 4, genesis_state: Some(HashMap::new()), ..Default::default() }).started();
+        let signed_by = |key: &Ed25519KeyPair| {
+            let mut t = generate_random_transaction();
+            t.sender = Address::from_public_key_bytes(key.public_key().as_ref());
+            t.account_nonce = 1;
+            let signature = sign(&t, key).as_ref().to_vec();
+            SignedTransaction { transaction: t, signature, public_key: key.public_key().as_ref().to_vec() }
+        };
+        let valid: Vec<SignedTransaction> = (0..200).map(|_| signed_by(&key_pair::random())).collect();
+        let invalid: Vec<SignedTransaction> = (0..50).map(|_| {
+            let mut tx = signed_by(&key_pair::random());
+            tx.signature[0] ^= 1;
+            tx
+        }).collect();
+        //every valid transaction arrives twice, from different peers, mixed with invalid ones
+        let mut txs: Vec<SignedTransaction> = valid.iter().chain(valid.iter()).chain(invalid.iter()).cloned().collect();
+        txs.shuffle(&mut rand::thread_rng());
+        let mut peer_receivers = Vec::new();
+        for (i, chunk) in txs.chunks(5).enumerate() {
+            let addr = SocketAddr::new("127.0.0.1".parse().unwrap(), 20000 + (i % 10) as u16);
+            peer_receivers.push(test_msg_sender.send_from(addr, Message::Transactions(chunk.to_vec())));
+        }
+
+        let mut announced = Vec::new();
+        while announced.len() < valid.len() {
+            if let Some(Message::NewTransactionHashes(hashes)) = server_receiver.recv() {
+                announced.extend(hashes);
+            }
+        }
+        let mut expected: Vec<H256> = valid.iter().map(|tx| tx.hash()).collect();
+        expected.sort();
+        announced.sort();
+        assert_eq!(announced, expected);
+        let mut pooled: Vec<H256> = mempool.lock().unwrap().transaction_map.keys().cloned().collect();
+        pooled.sort();
+        assert_eq!(pooled, expected);
+    }
+    #[test]
+    #[timeout(60000)]
+    fn peer_sending_invalid_transactions_is_banned() {
+        let addr: SocketAddr = "127.0.0.1:6083".parse().unwrap();
+        let ban_duration = Duration::from_secs(2);
+        let TestNode { server, .. } = start_test_node(addr, Blockchain::new(), TestNodeOptions { ban_duration, ..Default::default() });
+        //enough badly signed transactions to cross the ban threshold
+        let key = key_pair::random();
+        let txs: Vec<SignedTransaction> = (0..(BAN_SCORE_THRESHOLD / INVALID_TRANSACTION_SCORE))
+            .map(|_| {
+                let t = generate_random_transaction();
+                let mut signature = sign(&t, &key).as_ref().to_vec();
+                signature[0] ^= 1;
+                SignedTransaction { transaction: t, signature, public_key: key.public_key().as_ref().to_vec() }
+            })
+            .collect();
+        let payload = bincode::serialize(&Message::Transactions(txs)).unwrap();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(&encode(&payload)).unwrap();
+        //the Rejects come back, then the server hangs up
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        let _ = stream.read_to_end(&mut Vec::new());
+        let banned = server.banned();
+        assert_eq!(banned.len(), 1);
+        assert_eq!(banned[0].ip, addr.ip());
+
+        //while banned the server drops the connection right away
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        assert!(matches!(stream.read(&mut [0u8; 1]), Ok(0)));
+
+        thread::sleep(ban_duration + Duration::from_millis(500));
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+        let read = stream.read(&mut [0u8; 1]).unwrap_err();
+        assert!(read.kind() == std::io::ErrorKind::WouldBlock || read.kind() == std::io::ErrorKind::TimedOut);
+        assert!(server.banned().is_empty());
+    }
+    #[test]
+    #[timeout(60000)]
+    fn request_mempool_after_handshake() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        let mut peer_receiver = test_msg_sender.send(version(PROTOCOL_VERSION, v[0]));
+        //our Version and VerAck
+        peer_receiver.recv();
+        peer_receiver.recv();
+        let mut peer_receiver = test_msg_sender.send(Message::VerAck);
+        if let Message::GetMempool = peer_receiver.recv() {
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    #[cfg(feature = "compression")]
+    fn compression_is_negotiated_after_handshake() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        let mut peer_receiver = test_msg_sender.send(version(PROTOCOL_VERSION, v[0]));
+        peer_receiver.recv();
+        peer_receiver.recv();
+        let mut peer_receiver = test_msg_sender.send(Message::VerAck);
+        peer_receiver.recv();
+        peer_receiver.recv();
+        if let Message::SendCompressed = peer_receiver.recv() {
+        } else {
+            panic!();
+        }
+        //and the other way round, the peer asks us to compress
+        let (handle, _peer_receiver) = peer::Handle::test_handle();
+        assert!(!handle.compression_stats().enabled);
+        test_msg_sender.send_on(&handle, Message::SendCompressed);
+        while !handle.compression_stats().enabled {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    #[cfg(feature = "compression")]
+    fn compressed_transactions_are_decompressed() {
+        use super::super::message::compress;
+        let TestWorker { msg_sender: test_msg_sender, server_receiver: _server_receiver, .. } = generate_test_worker(TestWorkerOptions { genesis_state: Some(HashMap::new()), ..Default::default() }).started();
+        let key = key_pair::random();
+        let t = generate_random_transaction();
+        let mut signature = sign(&t, &key).as_ref().to_vec();
+        signature[0] ^= 1;
+        let tx = SignedTransaction { transaction: t, signature, public_key: key.public_key().as_ref().to_vec() };
+        let raw = bincode::serialize(&Message::Transactions(vec![tx.clone()])).unwrap();
+        //the transaction reaches the usual handler, which rejects its signature
+        let mut peer_receiver = test_msg_sender.send(Message::Compressed(compress(&raw)));
+        if let Message::Reject { rejected_hash, reason } = peer_receiver.recv() {
+            assert_eq!(rejected_hash, tx.hash());
+            assert_eq!(reason, RejectReason::InvalidSignature);
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    #[cfg(feature = "compression")]
+    fn compressed_payload_must_be_blocks_or_transactions() {
+        use super::super::message::compress;
+        let (test_msg_sender, _server_receiver, _v) = generate_test_worker_and_start();
+        let raw = bincode::serialize(&Message::Ping(1)).unwrap();
+        let mut peer_receiver = test_msg_sender.send(Message::Compressed(compress(&raw)));
+        assert!(peer_receiver.recv_or_closed().is_none());
+        let mut peer_receiver = test_msg_sender.send(Message::Compressed(vec![0xff; 16]));
+        assert!(peer_receiver.recv_or_closed().is_none());
+    }
+    #[test]
+    #[timeout(60000)]
+    fn fresh_node_syncs_chain_after_connecting() {
+        let addr_a: SocketAddr = "127.0.0.1:6082".parse().unwrap();
+        let addr_b: SocketAddr = "127.0.0.1:6080".parse().unwrap();
+        //more blocks than are asked for at once
+        let mut chain = Blockchain::new();
+        for _ in 0..20 {
+            let block = generate_mined_block(&chain.tip());
+            chain.insert(&block);
+        }
+        let tip = chain.tip();
+        let TestNode { server: _server_a, .. } = start_test_node(addr_a, chain, TestNodeOptions::default());
+        let TestNode { server: server_b, blockchain: blockchain_b, sync: sync_b, .. } = start_test_node(addr_b, Blockchain::new(), TestNodeOptions { ban_duration: Duration::from_secs(60), ..Default::default() });
+        assert!(!sync_b.is_syncing());
+        let mut peer = server_b.connect(addr_a).unwrap();
+        peer.write(Worker::version_message(&blockchain_b, addr_b, rand::random()));
+        while blockchain_b.read().unwrap().tip() != tip {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(blockchain_b.read().unwrap().height, 20);
+        //the last batch is checked against the target right after it is inserted
+        while sync_b.is_syncing() {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn fresh_node_syncs_headers_first() {
+        let addr_a: SocketAddr = "127.0.0.1:6122".parse().unwrap();
+        let addr_b: SocketAddr = "127.0.0.1:6123".parse().unwrap();
+        let key = key_pair::random();
+        let funded = Address::from_public_key_bytes(key.public_key().as_ref());
+        let genesis_state = HashMap::from([(funded, (0, 1000))]);
+        //every block spends from the funded account, so the synced state shows whether all of them were applied
+        let mut chain = Blockchain::new();
+        let mut state = genesis_state.clone();
+        for nonce in 1..=50 {
+            let mut block = generate_mined_block(&chain.tip());
+            block.content = Content { data: vec![transfer(&key, nonce)] };
+            block.header_mut().merkle_root = MerkleTree::new(&block.content.data).root();
+            while block.hash() > block.get_difficulty() {
+                block.header_mut().nonce = block.get_nonce().wrapping_add(1);
+            }
+            state = apply_block_to_state(&state, &block).unwrap();
+            chain.insert(&block);
+        }
+        let tip = chain.tip();
+        let ban_duration = Duration::from_secs(60);
+        let TestNode { server: _server_a, .. } = start_test_node(addr_a, chain, TestNodeOptions { ban_duration, genesis_state: genesis_state.clone(), ..Default::default() });
+        let TestNode { server: server_b, blockchain: blockchain_b, sync: sync_b, block_state_map: states_b, .. } = start_test_node(addr_b, Blockchain::new(), TestNodeOptions { ban_duration, genesis_state, ..Default::default() });
+        let mut peer = server_b.connect(addr_a).unwrap();
+        peer.write(Worker::version_message(&blockchain_b, addr_b, rand::random()));
+        wait_for_height(&blockchain_b, 50);
+        while sync_b.is_syncing() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(blockchain_b.read().unwrap().tip(), tip);
+        assert_eq!(states_b.lock().unwrap().block_state_map[&tip], state);
+        assert_eq!(state[&funded], (50, 450));
+    }
+    #[test]
+    #[timeout(60000)]
+    fn serves_fresh_peer_blocks_after_genesis() {
+        let addr: SocketAddr = "127.0.0.1:6124".parse().unwrap();
+        let mut chain = Blockchain::new();
+        let genesis = chain.tip();
+        let mut hashes = Vec::new();
+        for _ in 0..100 {
+            let block = generate_random_block(&chain.tip());
+            hashes.push(block.hash());
+            chain.insert(&block);
+        }
+        let TestNode { server: _server, .. } = start_test_node(addr, chain, TestNodeOptions::default());
+        let mut stream = handshaked_raw_peer(addr, genesis);
+        let mut blocks_after = |locator: Vec<H256>, stop_hash: Option<H256>| -> Vec<H256> {
+            write_frame(&mut stream, &Message::GetBlocksAfter { locator, stop_hash });
+            return read_frames_until_quiet(&mut stream, Duration::from_millis(300)).into_iter()
+                .find_map(|msg| match msg {
+                    Message::Blocks(blocks) => Some(blocks.iter().map(|block| block.hash()).collect()),
+                    _ => None,
+                })
+                .unwrap();
+        };
+        assert_eq!(blocks_after(vec![genesis], None), hashes);
+        assert_eq!(blocks_after(vec![genesis], Some(hashes[39])), hashes[..40].to_vec());
+        //a locator with unknown hashes first starts after the highest one we have
+        assert_eq!(blocks_after(vec![generate_random_hash(), hashes[59], hashes[9], genesis], Some(hashes[69])), hashes[60..70].to_vec());
+        assert!(blocks_after(vec![hashes[99]], None).is_empty());
+    }
+    #[test]
+    #[timeout(60000)]
+    fn propagation_delay_is_measured_between_nodes() {
+        let addr_a: SocketAddr = "127.0.0.1:6113".parse().unwrap();
+        let addr_b: SocketAddr = "127.0.0.1:6114".parse().unwrap();
+        let TestNode { server: server_a, blockchain: blockchain_a, .. } = start_test_node(addr_a, Blockchain::new(), TestNodeOptions::default());
+        let TestNode { server: server_b, blockchain: blockchain_b, propagation: propagation_b, .. } = start_test_node(addr_b, Blockchain::new(), TestNodeOptions { ban_duration: Duration::from_secs(60), ..Default::default() });
+        let mut peer = server_b.connect(addr_a).unwrap();
+        peer.write(Worker::version_message(&blockchain_b, addr_b, rand::random()));
+        while server_a.handshaked_peers().len() != 1 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        //stamped in milliseconds like the miner does
+        let mut block = generate_mined_block(&blockchain_a.read().unwrap().tip());
+        block.header_mut().timestamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis();
+        while block.hash() > block.get_difficulty() {
+            block.header_mut().nonce = block.get_nonce().wrapping_add(1);
+        }
+        //stands in for the time the block spends on the wire
+        let delay = Duration::from_millis(300);
+        thread::sleep(delay);
+        blockchain_a.write().unwrap().insert(&block);
+        server_a.broadcast(Message::NewBlockHashes(vec![block.hash()]));
+        while blockchain_b.read().unwrap().tip() != block.hash() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let summary = propagation_b.summary(10);
+        assert_eq!(summary.recorded, 1);
+        assert_eq!(summary.negative, 0);
+        assert_eq!(summary.recent[0].block, block.hash());
+        assert!(summary.recent[0].delay >= delay);
+        assert!(summary.recent[0].delay < delay + Duration::from_secs(5));
+        assert_eq!(summary.p50, Some(summary.recent[0].delay));
+    }
+    #[test]
+    #[timeout(60000)]
+    fn gossip_does_not_echo_to_sender() {
+        let addr: SocketAddr = "127.0.0.1:6079".parse().unwrap();
+        let TestNode { server, blockchain, .. } = start_test_node(addr, Blockchain::new(), TestNodeOptions::default());
+        let genesis = blockchain.read().unwrap().genesis;
+        let mut sender = handshaked_raw_peer(addr, genesis);
+        let mut other = handshaked_raw_peer(addr, genesis);
+        while server.handshaked_peers().len() != 2 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let announced = |msgs: &[Message], hash: H256| msgs.iter()
+            .filter(|msg| matches!(msg, Message::NewBlockHashes(hashes) if hashes.contains(&hash)))
+            .count();
+
+        //a block pushed without announcing it first
+        let block = generate_mined_block(&genesis);
+        write_frame(&mut sender, &Message::Blocks(vec![block.clone()]));
+        let to_other = read_frames_until_quiet(&mut other, Duration::from_millis(500));
+        assert_eq!(announced(&to_other, block.hash()), 1);
+        //the other peer relays it back like a node would, which must not reach either link again
+        write_frame(&mut other, &Message::NewBlockHashes(vec![block.hash()]));
+        let to_other = read_frames_until_quiet(&mut other, Duration::from_millis(500));
+        assert!(!to_other.iter().any(|msg| matches!(msg, Message::GetBlocks(_))));
+        let to_sender = read_frames_until_quiet(&mut sender, Duration::from_millis(500));
+        assert_eq!(announced(&to_sender, block.hash()), 0);
+    }
+    #[test]
+    #[timeout(60000)]
+    fn blocks_are_pushed_to_peers_that_ask() {
+        let addr: SocketAddr = "127.0.0.1:6115".parse().unwrap();
+        let TestNode { server, blockchain, .. } = start_test_node(addr, Blockchain::new(), TestNodeOptions::default());
+        let genesis = blockchain.read().unwrap().genesis;
+        let mut pushed = handshaked_raw_peer(addr, genesis);
+        let mut announced = handshaked_raw_peer(addr, genesis);
+        write_frame(&mut pushed, &Message::SendBlocks);
+        //messages from a peer are handled in order, so SendBlocks took effect once the pong is back
+        write_frame(&mut pushed, &Message::Ping(1));
+        assert!(read_frames_until_quiet(&mut pushed, Duration::from_millis(300)).iter().any(|msg| matches!(msg, Message::Pong(1))));
+        while server.handshaked_peers().len() != 2 {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        //what the miner worker does with a block it just mined
+        let block = generate_mined_block(&genesis);
+        server.broadcast(Message::Blocks(vec![block.clone()]));
+        let to_pushed = read_frames_until_quiet(&mut pushed, Duration::from_millis(300));
+        assert_eq!(to_pushed.len(), 1);
+        assert!(matches!(&to_pushed[0], Message::Blocks(blocks) if blocks.len() == 1 && blocks[0].hash() == block.hash()));
+        let to_announced = read_frames_until_quiet(&mut announced, Duration::from_millis(300));
+        assert_eq!(to_announced.len(), 1);
+        assert!(matches!(&to_announced[0], Message::NewBlockHashes(hashes) if *hashes == vec![block.hash()]));
+    }
+    #[test]
+    #[timeout(60000)]
+    fn old_peers_fall_back_to_hash_announcements() {
+        let addr: SocketAddr = "127.0.0.1:6148".parse().unwrap();
+        let TestNode { server, blockchain, .. } = start_test_node(addr, Blockchain::new(), TestNodeOptions::default());
+        let genesis = blockchain.read().unwrap().genesis;
+        //a node of protocol version 3, whose Version ends at the node nonce
+        let mut old = TcpStream::connect(addr).unwrap();
+        let old_version = (11u32, MIN_PROTOCOL_VERSION, genesis, 0u32, "old".to_string(), old.local_addr().unwrap(), rand::random::<u64>());
+        old.write_all(&encode(&bincode::serialize(&old_version).unwrap())).unwrap();
+        write_frame(&mut old, &Message::VerAck);
+        //it reads our Version like its own, ignoring the fields it doesn't know, and accepts it as it would have
+        //itself: with no --version-tolerance, only a version equal to its own
+        let (variant, protocol_version, genesis_hash, _, _, _, _): (u32, u32, H256, u32, String, SocketAddr, u64) = bincode::deserialize(&read_frame(&mut old).unwrap()).unwrap();
+        assert_eq!((variant, genesis_hash), (11, genesis));
+        assert_eq!(protocol_version, 3, "a version 3 node would disconnect from a node announcing {}", protocol_version);
+        //and is never asked for a feature it may not have
+        let to_old = read_frames_until_quiet(&mut old, Duration::from_millis(300));
+        assert!(matches!(to_old[0], Message::VerAck));
+        assert!(!to_old.iter().any(|msg| matches!(msg, Message::SendCompressed | Message::SendBlocks | Message::SendCompactBlocks)));
+        let mut new = handshaked_raw_peer(addr, genesis);
+        //even if it asks, blocks only go to it as hashes
+        write_frame(&mut old, &Message::SendBlocks);
+        write_frame(&mut old, &Message::SendCompactBlocks);
+        write_frame(&mut old, &Message::Ping(1));
+        assert!(read_frames_until_quiet(&mut old, Duration::from_millis(300)).iter().any(|msg| matches!(msg, Message::Pong(1))));
+        write_frame(&mut new, &Message::SendCompactBlocks);
+        write_frame(&mut new, &Message::Ping(2));
+        assert!(read_frames_until_quiet(&mut new, Duration::from_millis(300)).iter().any(|msg| matches!(msg, Message::Pong(2))));
+        while server.handshaked_peers().len() != 2 {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let block = generate_mined_block(&genesis);
+        server.broadcast(Message::Blocks(vec![block.clone()]));
+        let to_old = read_frames_until_quiet(&mut old, Duration::from_millis(300));
+        assert_eq!(to_old.len(), 1);
+        assert!(matches!(&to_old[0], Message::NewBlockHashes(hashes) if *hashes == vec![block.hash()]));
+        let to_new = read_frames_until_quiet(&mut new, Duration::from_millis(300));
+        assert_eq!(to_new.len(), 1);
+        assert!(matches!(&to_new[0], Message::CompactBlock { header, .. } if header.hash() == block.hash()));
+    }
+    #[test]
+    #[timeout(60000)]
+    fn features_are_the_ones_both_sides_support() {
+        let addr: SocketAddr = "127.0.0.1:6149".parse().unwrap();
+        let TestNode { server, blockchain, .. } = start_test_node(addr, Blockchain::new(), TestNodeOptions::default());
+        let genesis = blockchain.read().unwrap().genesis;
+        //a newer node that pushes blocks but has no compact blocks
+        let mut peer = TcpStream::connect(addr).unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+        write_frame(&mut peer, &Message::Version { protocol_version: MIN_PROTOCOL_VERSION, genesis_hash: genesis, tip_height: 0, user_agent: "newer".to_string(), peer_addr, node_nonce: rand::random(), max_protocol_version: PROTOCOL_VERSION + 1, features: FEATURE_PUSH_BLOCKS | 1 << 20 });
+        write_frame(&mut peer, &Message::VerAck);
+        read_frames_until_quiet(&mut peer, Duration::from_millis(300));
+        write_frame(&mut peer, &Message::SendCompactBlocks);
+        write_frame(&mut peer, &Message::SendBlocks);
+        write_frame(&mut peer, &Message::Ping(1));
+        assert!(read_frames_until_quiet(&mut peer, Duration::from_millis(300)).iter().any(|msg| matches!(msg, Message::Pong(1))));
+        while server.handshaked_peers().len() != 1 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let block = generate_mined_block(&genesis);
+        server.broadcast(Message::Blocks(vec![block.clone()]));
+        let to_peer = read_frames_until_quiet(&mut peer, Duration::from_millis(300));
+        assert_eq!(to_peer.len(), 1);
+        assert!(matches!(&to_peer[0], Message::Blocks(blocks) if blocks[0].hash() == block.hash()));
+    }
+    #[test]
+    #[timeout(60000)]
+    fn branch_received_tip_first_is_fetched_back_and_connected() {
+        let addr: SocketAddr = "127.0.0.1:6150".parse().unwrap();
+        let TestNode { server: _server, blockchain, .. } = start_test_node(addr, Blockchain::new(), TestNodeOptions::default());
+        let genesis = blockchain.read().unwrap().genesis;
+        let mut branch = vec![generate_mined_block(&genesis)];
+        for _ in 1..5 {
+            branch.push(generate_mined_block(&branch.last().unwrap().hash()));
+        }
+        let mut peer = handshaked_raw_peer(addr, genesis);
+        write_frame(&mut peer, &Message::Blocks(vec![branch[4].clone()]));
+        //each parent is asked for on its own as the one before it turns out to be an orphan too
+        let mut asked = vec![];
+        while asked.len() < 4 {
+            let parents: Vec<H256> = read_frames_until_quiet(&mut peer, Duration::from_millis(300)).into_iter()
+                .filter_map(|msg| match msg {
+                    Message::GetBlocks(hashes) => Some(hashes),
+                    _ => None,
+                })
+                .flatten()
+                .collect();
+            assert_eq!(parents.len(), 1);
+            asked.push(parents[0]);
+            let parent = branch.iter().find(|block| block.hash() == parents[0]).unwrap();
+            write_frame(&mut peer, &Message::Blocks(vec![parent.clone()]));
+        }
+        assert_eq!(asked, branch[..4].iter().rev().map(|block| block.hash()).collect::<Vec<H256>>());
+        wait_for_height(&blockchain, 5);
+        let blockchain = blockchain.read().unwrap();
+        assert_eq!(blockchain.tip(), branch[4].hash());
+        assert_eq!(blockchain.all_blocks_in_longest_chain()[1..], branch.iter().map(|block| block.hash()).collect::<Vec<H256>>()[..]);
+    }
+    #[test]
+    #[timeout(60000)]
+    fn unanswered_block_request_goes_to_another_announcer() {
+        let TestWorker { msg_sender: test_msg_sender, server_receiver, blockchain, mut worker, .. } = generate_test_worker(funded(Address::from([1; 20]), 0));
+        worker.set_fetch_timeout(Duration::from_millis(200));
+        worker.start();
+        let block = generate_mined_block(&blockchain.read().unwrap().tip());
+        let silent: SocketAddr = "127.0.0.1:12401".parse().unwrap();
+        let helpful: SocketAddr = "127.0.0.1:12402".parse().unwrap();
+        //the first peer to announce the block is asked for it and swallows the request
+        let mut silent_receiver = test_msg_sender.send_from(silent, Message::NewBlockHashes(vec![block.hash()]));
+        assert!(matches!(silent_receiver.recv(), Message::GetBlocks(hashes) if hashes == vec![block.hash()]));
+        let mut helpful_receiver = test_msg_sender.send_from(helpful, Message::NewBlockHashes(vec![block.hash()]));
+        let asked_at = Instant::now();
+        assert!(matches!(helpful_receiver.recv(), Message::GetBlocks(hashes) if hashes == vec![block.hash()]));
+        assert!(asked_at.elapsed() >= Duration::from_millis(100));
+        //keep the receiver alive, the worker skips messages from peers whose queue is closed
+        let _helpful_receiver = test_msg_sender.send_from(helpful, Message::Blocks(vec![block.clone()]));
+        wait_for_height(&blockchain, 1);
+        assert_eq!(blockchain.read().unwrap().tip(), block.hash());
+        assert_eq!(server_receiver.misbehavior(), vec![(silent, UNANSWERED_FETCH_SCORE)]);
+    }
+    #[test]
+    #[timeout(60000)]
+    fn sync_peer_is_asked_again_for_missing_blocks() {
+        let TestWorker { msg_sender: test_msg_sender, server_receiver, blockchain, mut worker, .. } = generate_test_worker(funded(Address::from([1; 20]), 0));
+        worker.set_fetch_timeout(Duration::from_millis(200));
+        worker.start();
+        let genesis = blockchain.read().unwrap().tip();
+        let first = generate_mined_block(&genesis);
+        let second = generate_mined_block(&first.hash());
+        let sync_peer: SocketAddr = "127.0.0.1:12403".parse().unwrap();
+        let _version_receiver = test_msg_sender.send_from(sync_peer, version(PROTOCOL_VERSION, genesis));
+        //the handshake finishes on the verack, so this is the handle the sync peer is remembered by
+        let mut sync_receiver = test_msg_sender.send_from(sync_peer, Message::VerAck);
+        while !matches!(sync_receiver.recv(), Message::GetHeaders(_)) {}
+        let mut headers_receiver = test_msg_sender.send_from(sync_peer, Message::Headers(vec![first.header().clone(), second.header().clone()]));
+        assert!(matches!(headers_receiver.recv(), Message::GetBlocks(hashes) if hashes == vec![first.hash(), second.hash()]));
+        //the peer only sends the first block, the second is asked for again once the request times out
+        let _blocks_receiver = test_msg_sender.send_from(sync_peer, Message::Blocks(vec![first.clone()]));
+        wait_for_height(&blockchain, 1);
+        assert!(matches!(sync_receiver.recv(), Message::GetBlocks(hashes) if hashes == vec![second.hash()]));
+        let _blocks_receiver = test_msg_sender.send_from(sync_peer, Message::Blocks(vec![second.clone()]));
+        wait_for_height(&blockchain, 2);
+        assert_eq!(blockchain.read().unwrap().tip(), second.hash());
+        assert_eq!(server_receiver.misbehavior(), vec![(sync_peer, UNANSWERED_FETCH_SCORE)]);
+    }
+    #[test]
+    fn fetches_are_given_up_after_max_attempts() {
+        let mut fetches = FetchTracker::new(Duration::from_secs(1));
+        let hash = generate_random_hash();
+        let start = Instant::now();
+        let mut receivers = Vec::new();
+        for i in 0..MAX_FETCH_ATTEMPTS + 1 {
+            let (peer, receiver) = peer::Handle::test_handle_at(format!("127.0.0.1:{}", 12410 + i).parse().unwrap());
+            assert_eq!(fetches.announced(FetchKind::Transaction, hash, &peer, start), i == 0);
+            receivers.push(receiver);
+        }
+        //not due yet
+        assert!(fetches.expire(start, |_, _| false).retries.is_empty());
+        for attempt in 1..MAX_FETCH_ATTEMPTS {
+            let expired = fetches.expire(start + Duration::from_secs(attempt as u64), |_, _| false);
+            assert_eq!(expired.retries.len(), 1);
+            assert_eq!(*expired.retries[0].0.addr(), format!("127.0.0.1:{}", 12410 + attempt).parse::<SocketAddr>().unwrap());
+            assert_eq!(expired.unresponsive.len(), 1);
+        }
+        let expired = fetches.expire(start + Duration::from_secs(MAX_FETCH_ATTEMPTS as u64), |_, _| false);
+        assert!(expired.retries.is_empty());
+        assert_eq!(expired.dropped, vec![hash]);
+        assert_eq!(fetches.len(), 0);
+
+        //a request to a peer that disconnected moves on right away, and one answered some other way is forgotten
+        let (first, _first_receiver) = peer::Handle::test_handle_at("127.0.0.1:12420".parse().unwrap());
+        let (second, _second_receiver) = peer::Handle::test_handle_at("127.0.0.1:12421".parse().unwrap());
+        fetches.announced(FetchKind::Block, hash, &first, start);
+        fetches.announced(FetchKind::Block, hash, &second, start);
+        first.disconnect();
+        let expired = fetches.expire(start, |_, _| false);
+        assert_eq!(*expired.retries[0].0.addr(), *second.addr());
+        assert!(expired.unresponsive.is_empty());
+        let expired = fetches.expire(start + Duration::from_secs(1), |kind, _| kind == FetchKind::Block);
+        assert!(expired.retries.is_empty() && expired.dropped.is_empty() && expired.unresponsive.is_empty());
+        assert_eq!(fetches.len(), 0);
+    }
+    #[test]
+    fn orphan_buffer_is_bounded() {
+        let genesis = Blockchain::new().tip();
+        let mut buffer = OrphanBuffer::new();
+        //a branch received tip first, one parent request per block until the depth cap
+        let mut branch = vec![generate_random_block(&genesis)];
+        for _ in 0..MAX_ORPHAN_DEPTH + 1 {
+            branch.push(generate_random_block(&branch.last().unwrap().hash()));
+        }
+        for (depth, block) in branch.iter().skip(1).rev().enumerate() {
+            let asked = buffer.park(block.clone());
+            if depth < MAX_ORPHAN_DEPTH {
+                assert_eq!(asked, Some(block.get_parent()));
+            } else {
+                assert_eq!(asked, None);
+            }
+        }
+        //a second child of an orphan doesn't ask again
+        assert_eq!(buffer.park(generate_random_block(&branch[2].hash())), None);
+        assert_eq!(buffer.park(branch[5].clone()), None);
+        assert_eq!(buffer.take_children(&branch[2].hash()).len(), 2);
+        assert!(!buffer.contains(&branch[3].hash()));
+
+        for _ in 0..MAX_ORPHAN_BLOCKS {
+            buffer.park(generate_random_block(&generate_random_hash()));
+        }
+        assert_eq!(buffer.len(), MAX_ORPHAN_BLOCKS);
+        assert!(!buffer.contains(&branch[4].hash()));
+    }
+    #[test]
+    #[timeout(60000)]
+    fn block_received_pushed_and_fetched_is_inserted_once() {
+        let addr: SocketAddr = "127.0.0.1:6116".parse().unwrap();
+        let TestNode { server: _server, blockchain, propagation, .. } = start_test_node(addr, Blockchain::new(), TestNodeOptions { ban_duration: Duration::from_secs(60), ..Default::default() });
+        let genesis = blockchain.read().unwrap().genesis;
+        let mut peer = handshaked_raw_peer(addr, genesis);
+        let block = generate_mined_block(&genesis);
+        //pushed, then announced and sent again as if the announcement had been fetched
+        write_frame(&mut peer, &Message::Blocks(vec![block.clone()]));
+        write_frame(&mut peer, &Message::NewBlockHashes(vec![block.hash()]));
+        write_frame(&mut peer, &Message::Blocks(vec![block.clone()]));
+        let replies = read_frames_until_quiet(&mut peer, Duration::from_millis(500));
+        assert!(!replies.iter().any(|msg| matches!(msg, Message::GetBlocks(_))));
+        assert!(replies.iter().any(|msg| matches!(msg, Message::Reject { rejected_hash, reason: RejectReason::DuplicateBlock } if *rejected_hash == block.hash())));
+        assert_eq!(blockchain.read().unwrap().height, 1);
+        assert_eq!(propagation.summary(10).recorded, 1);
+    }
+    #[test]
+    #[timeout(60000)]
+    fn compact_block_is_rebuilt_from_mempool() {
+        let key = key_pair::random();
+        let TestWorker { msg_sender: test_msg_sender, server_receiver: _server_receiver, mempool, blockchain, .. } = generate_test_worker(funded(Address::from_public_key_bytes(key.public_key().as_ref()), 1000)).started();
+        let (txs, block) = block_of_transfers(&key, 3);
+        for tx in txs.iter() {
+            mempool.lock().unwrap().insert(tx);
+        }
+        let tx_hashes = txs.iter().map(|tx| tx.hash()).collect();
+        let _peer_receiver = test_msg_sender.send(Message::CompactBlock { header: block.get_header(), tx_hashes });
+        wait_for_height(&blockchain, 1);
+        assert_eq!(blockchain.read().unwrap().tip(), block.hash());
+        assert!(mempool.lock().unwrap().transaction_map.is_empty());
+    }
+    #[test]
+    #[timeout(60000)]
+    fn compact_block_fetches_missing_transactions() {
+        let key = key_pair::random();
+        let TestWorker { msg_sender: test_msg_sender, server_receiver: _server_receiver, mempool, blockchain, .. } = generate_test_worker(funded(Address::from_public_key_bytes(key.public_key().as_ref()), 1000)).started();
+        let (txs, block) = block_of_transfers(&key, 3);
+        mempool.lock().unwrap().insert(&txs[0]);
+        let peer_addr: SocketAddr = "127.0.0.1:12322".parse().unwrap();
+        let tx_hashes = txs.iter().map(|tx| tx.hash()).collect();
+        let mut peer_receiver = test_msg_sender.send_from(peer_addr, Message::CompactBlock { header: block.get_header(), tx_hashes });
+        match peer_receiver.recv() {
+            Message::GetTransactions(missing) => assert_eq!(missing, vec![txs[1].hash(), txs[2].hash()]),
+            _ => panic!(),
+        }
+        let _peer_receiver = test_msg_sender.send_from(peer_addr, Message::Transactions(txs[1..].to_vec()));
+        wait_for_height(&blockchain, 1);
+        assert_eq!(blockchain.read().unwrap().tip(), block.hash());
+    }
+    #[test]
+    #[timeout(60000)]
+    fn compact_block_with_wrong_merkle_root_is_fetched_in_full() {
+        let key = key_pair::random();
+        let TestWorker { msg_sender: test_msg_sender, server_receiver: _server_receiver, mempool, blockchain, .. } = generate_test_worker(funded(Address::from_public_key_bytes(key.public_key().as_ref()), 1000)).started();
+        let (txs, block) = block_of_transfers(&key, 3);
+        for tx in txs.iter() {
+            mempool.lock().unwrap().insert(tx);
+        }
+        //every transaction is in the mempool, but in an order the header doesn't commit to
+        let tx_hashes = txs.iter().rev().map(|tx| tx.hash()).collect();
+        let mut peer_receiver = test_msg_sender.send(Message::CompactBlock { header: block.get_header(), tx_hashes });
+        match peer_receiver.recv() {
+            Message::GetBlocks(hashes) => assert_eq!(hashes, vec![block.hash()]),
+            _ => panic!(),
+        }
+        assert_eq!(blockchain.read().unwrap().height, 0);
+        assert_eq!(mempool.lock().unwrap().transaction_map.len(), 3);
+    }
+    #[test]
+    #[timeout(60000)]
+    fn nodes_with_same_genesis_complete_handshake() {
+        let addr_a: SocketAddr = "127.0.0.1:6094".parse().unwrap();
+        let addr_b: SocketAddr = "127.0.0.1:6095".parse().unwrap();
+        let TestNode { server: server_a, blockchain: blockchain_a, .. } = start_test_node(addr_a, Blockchain::new(), TestNodeOptions::default());
+        let TestNode { server: server_b, .. } = start_test_node(addr_b, Blockchain::new(), TestNodeOptions::default());
+        let mut peer = server_a.connect(addr_b).unwrap();
+        peer.write(Worker::version_message(&blockchain_a, addr_a, rand::random()));
+        while server_a.handshaked_peers().len() != 1 || server_b.handshaked_peers().len() != 1 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!peer.is_disconnected());
+    }
+    #[test]
+    #[timeout(60000)]
+    fn peer_info_shows_direction() {
+        let addr_a: SocketAddr = "127.0.0.1:6098".parse().unwrap();
+        let addr_b: SocketAddr = "127.0.0.1:6099".parse().unwrap();
+        let TestNode { server: server_a, blockchain: blockchain_a, .. } = start_test_node(addr_a, Blockchain::new(), TestNodeOptions::default());
+        let TestNode { server: server_b, .. } = start_test_node(addr_b, Blockchain::new(), TestNodeOptions::default());
+        let mut peer = server_a.connect(addr_b).unwrap();
+        peer.write(Worker::version_message(&blockchain_a, addr_a, rand::random()));
+        while server_a.handshaked_peers().len() != 1 || server_b.handshaked_peers().len() != 1 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let peers_a = server_a.peer_info();
+        let peers_b = server_b.peer_info();
+        assert_eq!(peers_a.len(), 1);
+        assert_eq!(peers_b.len(), 1);
+        assert_eq!(peers_a[0].addr, addr_b);
+        assert_eq!(peers_a[0].direction, peer::Direction::Outgoing);
+        assert_eq!(peers_b[0].direction, peer::Direction::Incoming);
+        assert!(peers_a[0].messages_sent > 0);
+        assert!(peers_b[0].messages_received > 0);
+
+        //both sides forget the connection once it is closed
+        peer.disconnect();
+        while !server_a.peer_info().is_empty() || !server_b.peer_info().is_empty() {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn addr_gossip_connects_to_unknown_peer() {
+        let addr_a: SocketAddr = "127.0.0.1:6086".parse().unwrap();
+        let addr_b: SocketAddr = "127.0.0.1:6087".parse().unwrap();
+        let addr_c: SocketAddr = "127.0.0.1:6088".parse().unwrap();
+        let TestNode { server: server_a, .. } = start_test_node(addr_a, Blockchain::new(), TestNodeOptions::default());
+        let TestNode { server: server_b, blockchain: blockchain_b, .. } = start_test_node(addr_b, Blockchain::new(), TestNodeOptions::default());
+        let TestNode { server: server_c, blockchain: blockchain_c, .. } = start_test_node(addr_c, Blockchain::new(), TestNodeOptions::default());
+        let mut peer = server_b.connect(addr_a).unwrap();
+        peer.write(Worker::version_message(&blockchain_b, addr_b, rand::random()));
+        while server_a.handshaked_peers().len() != 1 || server_b.handshaked_peers().len() != 1 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        //C only knows B, B tells it about A
+        let mut peer = server_c.connect(addr_b).unwrap();
+        peer.write(Worker::version_message(&blockchain_c, addr_c, rand::random()));
+        while !server_c.peer_info().iter().any(|p| p.addr == addr_a) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let peers_c = server_c.peer_info();
+        assert_eq!(peers_c.len(), 2);
+        assert!(peers_c.iter().all(|p| p.direction == peer::Direction::Outgoing));
+    }
+    #[test]
+    #[timeout(60000)]
+    fn nodes_with_different_genesis_drop_each_other() {
+        let addr_a: SocketAddr = "127.0.0.1:6096".parse().unwrap();
+        let addr_b: SocketAddr = "127.0.0.1:6097".parse().unwrap();
+        let TestNode { server: server_a, blockchain: blockchain_a, .. } = start_test_node(addr_a, Blockchain::new(), TestNodeOptions::default());
+        //stands in for a node built with a different genesis block
+        let mut other_chain = Blockchain::new();
+        other_chain.genesis = generate_random_hash();
+        let TestNode { server: server_b, .. } = start_test_node(addr_b, other_chain, TestNodeOptions::default());
+        let mut peer = server_a.connect(addr_b).unwrap();
+        peer.write(Worker::version_message(&blockchain_a, addr_a, rand::random()));
+        while !peer.is_disconnected() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(server_a.handshaked_peers().is_empty());
+        assert!(server_b.handshaked_peers().is_empty());
+    }
+    #[test]
+    #[timeout(60000)]
+    fn mempools_converge() {
+        let TestWorker { msg_sender: sender_a, server_receiver: _server_receiver_a, mempool: mempool_a, .. } = generate_test_worker(TestWorkerOptions { genesis_state: Some(HashMap::new()), ..Default::default() }).started();
+        let TestWorker { msg_sender: sender_b, server_receiver: server_receiver_b, mempool: mempool_b, .. } = generate_test_worker(TestWorkerOptions { genesis_state: Some(HashMap::new()), ..Default::default() }).started();
+        let key = key_pair::random();
+        for nonce in 1..4 {
+            mempool_a.lock().unwrap().insert(&transfer(&key, nonce));
+        }
+
+        //relay the GetMempool exchange between the two workers by hand
+        let announced = match sender_a.send(Message::GetMempool).recv() {
+            Message::NewTransactionHashes(hashes) => hashes,
+            _ => panic!(),
+        };
+        assert_eq!(announced.len(), 3);
+        let requested = match sender_b.send(Message::NewTransactionHashes(announced)).recv() {
+            Message::GetTransactions(hashes) => hashes,
+            _ => panic!(),
+        };
+        let txs = match sender_a.send(Message::GetTransactions(requested)).recv() {
+            Message::Transactions(txs) => txs,
+            _ => panic!(),
+        };
+        //keep the receiver alive, the worker skips messages from peers whose queue is closed
+        let _peer_receiver_b = sender_b.send(Message::Transactions(txs));
+        //b rebroadcasts once the transactions are in its mempool, skip the inventory bookkeeping before that
+        loop {
+            match server_receiver_b.recv() {
+                Some(Message::NewTransactionHashes(_)) => break,
+                Some(_) => panic!(),
+                None => continue,
+            }
+        }
+
+        let mut pending_a: Vec<H256> = mempool_a.lock().unwrap().transaction_map.keys().cloned().collect();
+        let mut pending_b: Vec<H256> = mempool_b.lock().unwrap().transaction_map.keys().cloned().collect();
+        pending_a.sort();
+        pending_b.sort();
+        assert_eq!(pending_a, pending_b);
+    }
+    /// node A's mempool: 10 funded accounts with 10 pending transactions each, and the accounts' genesis state
+    fn pending_transactions() -> (Mempool, HashMap<Address, (u32, u32)>) {
+        let mut mempool = Mempool::new();
+        let mut genesis_state = HashMap::new();
+        for _ in 0..10 {
+            let key = key_pair::random();
+            let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+            genesis_state.insert(sender, (0, 1000));
+            for nonce in 1..=10 {
+                mempool.insert(&transfer(&key, nonce));
+            }
+        }
+        return (mempool, genesis_state);
+    }
+    #[test]
+    #[timeout(60000)]
+    fn fresh_node_fetches_peer_mempool() {
+        let addr_a: SocketAddr = "127.0.0.1:6125".parse().unwrap();
+        let addr_b: SocketAddr = "127.0.0.1:6126".parse().unwrap();
+        let (pool, genesis_state) = pending_transactions();
+        let mempool_a = Arc::new(Mutex::new(pool));
+        let mempool_b = Arc::new(Mutex::new(Mempool::new()));
+        let ban_duration = Duration::from_secs(60);
+        let TestNode { server: _server_a, .. } = start_test_node(addr_a, Blockchain::new(), TestNodeOptions { ban_duration, genesis_state: genesis_state.clone(), mempool: Arc::clone(&mempool_a), ..Default::default() });
+        let TestNode { server: server_b, blockchain: blockchain_b, .. } = start_test_node(addr_b, Blockchain::new(), TestNodeOptions { ban_duration, genesis_state, mempool: Arc::clone(&mempool_b), ..Default::default() });
+        let mut peer = server_b.connect(addr_a).unwrap();
+        peer.write(Worker::version_message(&blockchain_b, addr_b, rand::random()));
+        while mempool_b.lock().unwrap().transaction_map.len() < 100 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let mut pending_a: Vec<H256> = mempool_a.lock().unwrap().transaction_map.keys().cloned().collect();
+        let mut pending_b: Vec<H256> = mempool_b.lock().unwrap().transaction_map.keys().cloned().collect();
+        pending_a.sort();
+        pending_b.sort();
+        assert_eq!(pending_a.len(), 100);
+        assert_eq!(pending_a, pending_b);
+    }
+    #[test]
+    #[timeout(60000)]
+    fn transaction_gossiped_to_one_peer_reaches_a_line_of_nodes() {
+        let key = key_pair::random();
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        let mut genesis_state = HashMap::new();
+        genesis_state.insert(sender, (0, 1000));
+        let ban_duration = Duration::from_secs(60);
+        let mut nodes = vec![];
+        //every node dials the next one and no other, the last one dials none, so gossiped addresses add no shortcuts.
+        //A node in the middle has two peers and relays to one, the one it didn't hear the transaction from
+        for port in 6151..6156 {
+            let addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+            let mempool = Arc::new(Mutex::new(Mempool::new()));
+            let max_outbound = if port == 6155 { 0 } else { 1 };
+            let TestNode { server, blockchain, .. } = start_test_node(addr, Blockchain::new(), TestNodeOptions { ban_duration, genesis_state: genesis_state.clone(), mempool: Arc::clone(&mempool), mempool_sync: false, relay_fanout: Some(1), max_outbound, ..Default::default() });
+            nodes.push((addr, server, blockchain, mempool));
+        }
+        //starting from the end, so a node has no peers yet whose addresses could fill its slot before it dials
+        for pair in nodes.windows(2).rev() {
+            let (addr, server, blockchain, _) = &pair[0];
+            let mut peer = server.connect(pair[1].0).unwrap();
+            peer.write(Worker::version_message(blockchain, *addr, rand::random()));
+        }
+        for (i, (_, server, _, _)) in nodes.iter().enumerate() {
+            let expected = if i == 0 || i == nodes.len() - 1 { 1 } else { 2 };
+            while server.handshaked_peers().len() != expected {
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+
+        let tx = transfer(&key, 1);
+        let (_, first_server, _, first_mempool) = &nodes[0];
+        first_mempool.lock().unwrap().insert(&tx);
+        first_server.broadcast(Message::NewTransactionHashes(vec![tx.hash()]));
+        for (_, _, _, mempool) in nodes.iter() {
+            while !mempool.lock().unwrap().transaction_map.contains_key(&tx.hash()) {
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn get_transactions_omits_unknown_hashes() {
+        let TestWorker { msg_sender: test_msg_sender, server_receiver: _server_receiver, mempool, .. } = generate_test_worker(TestWorkerOptions { genesis_state: Some(HashMap::new()), ..Default::default() }).started();
+        let tx = SignedTransaction { transaction: generate_random_transaction(), signature: vec![], public_key: vec![] };
+        mempool.lock().unwrap().insert(&tx);
+        let unknown = generate_random_hash();
+        match test_msg_sender.send(Message::GetTransactions(vec![unknown, tx.hash()])).recv() {
+            Message::Transactions(txs) => assert_eq!(txs.iter().map(|tx| tx.hash()).collect::<Vec<H256>>(), vec![tx.hash()]),
+            _ => panic!(),
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn generated_transaction_reaches_peer_mempool() {
+        use crate::api::Events;
+        use crate::transaction_generator;
+        let key = key_pair::random();
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        let mut genesis_state = HashMap::new();
+        genesis_state.insert(sender, (0, 1_000_000));
+        let addr_a: SocketAddr = "127.0.0.1:6162".parse().unwrap();
+        let addr_b: SocketAddr = "127.0.0.1:6163".parse().unwrap();
+        let mempool_a = Arc::new(Mutex::new(Mempool::new()));
+        let mempool_b = Arc::new(Mutex::new(Mempool::new()));
+        let ban_duration = Duration::from_secs(60);
+        //no mempool sync, so b only learns of the transaction through its announcement
+        let TestNode { server: server_a, blockchain: blockchain_a, block_state_map: states_a, .. } = start_test_node(addr_a, Blockchain::new(), TestNodeOptions { ban_duration, genesis_state: genesis_state.clone(), mempool: Arc::clone(&mempool_a), mempool_sync: false, ..Default::default() });
+        let TestNode { server: server_b, blockchain: blockchain_b, .. } = start_test_node(addr_b, Blockchain::new(), TestNodeOptions { ban_duration, genesis_state, mempool: Arc::clone(&mempool_b), mempool_sync: false, ..Default::default() });
+        let mut peer = server_b.connect(addr_a).unwrap();
+        peer.write(Worker::version_message(&blockchain_b, addr_b, rand::random()));
+        while server_a.handshaked_peers().is_empty() || server_b.handshaked_peers().is_empty() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let receivers = vec![Address::from([2; 20]), Address::from([3; 20])];
+        let (generator_ctx, generator, finished_tx_chan) = transaction_generator::new(&blockchain_a, &mempool_a, &sender, key, &states_a, receivers, transaction_generator::DEFAULT_MEMPOOL_HIGH_WATER);
+        let generator_worker = transaction_generator::worker::Worker::new(&server_a, finished_tx_chan, &blockchain_a, &mempool_a, &states_a, &Events::new());
+        generator_ctx.start();
+        generator_worker.start();
+        generator.start(100).unwrap();
+        //b asks a for the announced hash, validates the body and pools it
+        while mempool_b.lock().unwrap().transaction_map.is_empty() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        generator.exit().unwrap();
+        let received: Vec<H256> = mempool_b.lock().unwrap().transaction_map.keys().cloned().collect();
+        let mempool_a = mempool_a.lock().unwrap();
+        for hash in received {
+            assert_eq!(mempool_a.transaction_map[&hash].transaction.sender, sender);
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn mempool_sync_can_be_disabled() {
+        let addr_a: SocketAddr = "127.0.0.1:6127".parse().unwrap();
+        let addr_b: SocketAddr = "127.0.0.1:6128".parse().unwrap();
+        let (pool, genesis_state) = pending_transactions();
+        let mempool_a = Arc::new(Mutex::new(pool));
+        let mempool_b = Arc::new(Mutex::new(Mempool::new()));
+        let ban_duration = Duration::from_secs(60);
+        let TestNode { server: _server_a, .. } = start_test_node(addr_a, Blockchain::new(), TestNodeOptions { ban_duration, genesis_state: genesis_state.clone(), mempool: Arc::clone(&mempool_a), ..Default::default() });
+        let TestNode { server: server_b, blockchain: blockchain_b, .. } = start_test_node(addr_b, Blockchain::new(), TestNodeOptions { ban_duration, genesis_state, mempool: Arc::clone(&mempool_b), mempool_sync: false, ..Default::default() });
+        let mut peer = server_b.connect(addr_a).unwrap();
+        peer.write(Worker::version_message(&blockchain_b, addr_b, rand::random()));
+        while server_b.handshaked_peers().is_empty() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        thread::sleep(Duration::from_millis(500));
+        assert!(mempool_b.lock().unwrap().transaction_map.is_empty());
+    }
+    #[test]
+    #[timeout(60000)]
+    fn node_dialing_itself_drops_the_connection() {
+        let addr: SocketAddr = "127.0.0.1:6133".parse().unwrap();
+        let TestNode { server, blockchain, .. } = start_test_node(addr, Blockchain::new(), TestNodeOptions::default());
+        let genesis_hash = blockchain.read().unwrap().genesis;
+        //learn the node's nonce from the Version it answers a bare peer with
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let raw_addr = stream.local_addr().unwrap();
+        write_frame(&mut stream, &Message::Version { protocol_version: PROTOCOL_VERSION, genesis_hash, tip_height: 0, user_agent: "test".to_string(), peer_addr: raw_addr, node_nonce: rand::random(), max_protocol_version: PROTOCOL_VERSION, features: supported_features() });
+        let node_nonce = read_frames_until_quiet(&mut stream, Duration::from_millis(300)).into_iter()
+            .find_map(|msg| match msg {
+                Message::Version { node_nonce, .. } => Some(node_nonce),
+                _ => None,
+            })
+            .unwrap();
+
+        //dial our own listening address, greeting ourselves like main does
+        let mut peer = server.connect(addr).unwrap();
+        peer.write(Worker::version_message(&blockchain, addr, node_nonce));
+        while !peer.is_disconnected() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        //both ends of the loop are gone, only the bare peer is left
+        while server.peer_info().len() != 1 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(server.peer_info()[0].addr, raw_addr);
+    }
+    #[test]
+    #[timeout(60000)]
+    fn second_connection_to_same_node_is_closed() {
+        let addr: SocketAddr = "127.0.0.1:6134".parse().unwrap();
+        let TestNode { server, blockchain, .. } = start_test_node(addr, Blockchain::new(), TestNodeOptions::default());
+        let genesis_hash = blockchain.read().unwrap().genesis;
+        let node_nonce: u64 = rand::random();
+        let version = |stream: &TcpStream| Message::Version { protocol_version: PROTOCOL_VERSION, genesis_hash, tip_height: 0, user_agent: "test".to_string(), peer_addr: stream.local_addr().unwrap(), node_nonce, max_protocol_version: PROTOCOL_VERSION, features: supported_features() };
+        let mut first = TcpStream::connect(addr).unwrap();
+        let hello = version(&first);
+        write_frame(&mut first, &hello);
+        write_frame(&mut first, &Message::VerAck);
+        read_frames_until_quiet(&mut first, Duration::from_millis(300));
+
+        //the same node dialing again, e.g. because it learned our address from gossip
+        let mut second = TcpStream::connect(addr).unwrap();
+        let hello = version(&second);
+        write_frame(&mut second, &hello);
+        second.set_read_timeout(None).unwrap();
+        let mut rest = Vec::new();
+        second.read_to_end(&mut rest).unwrap();
+        while server.peer_info().len() != 1 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(server.peer_info()[0].addr, first.local_addr().unwrap());
+        //the first connection still works
+        write_frame(&mut first, &Message::Ping(7));
+        let replies = read_frames_until_quiet(&mut first, Duration::from_millis(300));
+        assert!(replies.iter().any(|msg| matches!(msg, Message::Pong(7))));
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
\ No newline at end of file
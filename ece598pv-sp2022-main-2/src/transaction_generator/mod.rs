@@ -1,6 +1,6 @@
 pub mod worker;
 
-use log::info;
+use tracing::{debug, info, warn};
 
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 use ring::signature::Ed25519KeyPair;
@@ -10,14 +10,21 @@ use std::thread;
 
 use crate::types::address::Address;
 use crate::blockchain::{Blockchain};
+use crate::miner::{ControlError, Mempool};
 use crate::types::block::BlockState;
-use crate::types::transaction::{SignedTransaction, Transaction, sign};
-use ring::signature::{KeyPair};
-use std::sync::{Arc, Mutex};
+use crate::types::transaction::{SignedTransaction, TransactionBuilder, MIN_TX_FEE};
+use std::sync::{Arc, Mutex, RwLock};
 use rand::Rng;
+
+//default number of pending transactions past which the generator waits instead of adding more
+pub static DEFAULT_MEMPOOL_HIGH_WATER: usize = 50000;
+//how long the generator waits for the mempool to drain when theta doesn't space transactions out
+static MEMPOOL_FULL_WAIT: time::Duration = time::Duration::from_millis(10);
+
 enum ControlSignal {
     Start(u64), // the number controls the lambda of interval between block generation
-    Update, // update the block in mining, it may due to new blockchain tip or new transaction
+    Update, // the tip changed, build the next transaction on its state
+    Stop, // stop generating until the next Start, without exiting
     Exit,
 }
 
@@ -32,11 +39,17 @@ pub struct Context {
     control_chan: Receiver<ControlSignal>,
     operating_state: OperatingState,
     finished_tx_chan: Sender<SignedTransaction>,
-    blockchain: Arc<Mutex<Blockchain>>,
+    blockchain: Arc<RwLock<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+    //no transaction is generated while the mempool holds this many
+    high_water_mark: usize,
     address: Address,
     keypair: Ed25519KeyPair,
     block_state_map: Arc<Mutex<BlockState>>,
-    receiver_addresses: [Address; 2]
+    //transactions pay these addresses in turn
+    receiver_addresses: Vec<Address>,
+    //index into receiver_addresses of the next receiver
+    receiver_index: usize
 }
 
 #[derive(Clone)]
@@ -45,11 +58,15 @@ pub struct Handle {
     control_chan: Sender<ControlSignal>,
 }
 
-pub fn new(blockchain: &Arc<Mutex<Blockchain>>,
+/// `receiver_addresses` must not be empty, the generated transactions pay each of them in turn
+pub fn new(blockchain: &Arc<RwLock<Blockchain>>,
+           mempool: &Arc<Mutex<Mempool>>,
            address: &Address,
            keypair: Ed25519KeyPair,
            block_state_map: &Arc<Mutex<BlockState>>,
-           receiver_addresses: [Address; 2]) -> (Context, Handle, Receiver<SignedTransaction>) {
+           receiver_addresses: Vec<Address>,
+           high_water_mark: usize) -> (Context, Handle, Receiver<SignedTransaction>) {
+    assert!(!receiver_addresses.is_empty(), "the transaction generator needs at least one receiver address");
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
     let (finished_tx_sender, finished_tx_receiver) = unbounded();
 
@@ -58,10 +75,13 @@ pub fn new(blockchain: &Arc<Mutex<Blockchain>>,
         operating_state: OperatingState::Paused,
         finished_tx_chan: finished_tx_sender,
         blockchain: Arc::clone(blockchain),
+        mempool: Arc::clone(mempool),
+        high_water_mark,
         address: address.clone(),
         keypair: keypair,
         block_state_map: Arc::clone(block_state_map),
-        receiver_addresses: receiver_addresses
+        receiver_addresses: receiver_addresses,
+        receiver_index: 0
     };
 
     let handle = Handle {
@@ -72,34 +92,54 @@ pub fn new(blockchain: &Arc<Mutex<Blockchain>>,
 }
 
 impl Handle {
-    pub fn exit(&self) {
-        self.control_chan.send(ControlSignal::Exit).unwrap();
+    pub fn exit(&self) -> Result<(), ControlError> {
+        return self.control_chan.send(ControlSignal::Exit).map_err(|_| ControlError::Disconnected);
     }
 
-    pub fn start(&self, theta: u64) {
-        self.control_chan
+    pub fn start(&self, theta: u64) -> Result<(), ControlError> {
+        return self.control_chan
             .send(ControlSignal::Start(theta))
-            .unwrap();
+            .map_err(|_| ControlError::Disconnected);
+    }
+
+    pub fn update(&self) -> Result<(), ControlError> {
+        return self.control_chan.send(ControlSignal::Update).map_err(|_| ControlError::Disconnected);
     }
 
-    pub fn update(&self) {
-        self.control_chan.send(ControlSignal::Update).unwrap();
+    /// Stop generating transactions until the next start
+    pub fn stop(&self) -> Result<(), ControlError> {
+        return self.control_chan.send(ControlSignal::Stop).map_err(|_| ControlError::Disconnected);
     }
 }
 
 impl Context {
-    pub fn start(mut self) {
-        thread::Builder::new()
+    pub fn start(mut self) -> thread::JoinHandle<()> {
+        let handle = thread::Builder::new()
             .name("transaction_generator".to_string())
             .spawn(move || {
                 self.transaction_generator_loop();
             })
             .unwrap();
         info!("Transaction generator initialized into paused mode");
+        return handle;
+    }
+
+    /// Time between two transactions while running
+    fn interval(&self) -> time::Duration {
+        return match self.operating_state {
+            OperatingState::Run(i) => time::Duration::from_micros(i),
+            _ => time::Duration::ZERO,
+        };
+    }
+
+    /// The receiver of the next transaction, going round the receiver addresses
+    fn next_receiver(&mut self) -> Address {
+        let receiver = self.receiver_addresses[self.receiver_index];
+        self.receiver_index = (self.receiver_index + 1) % self.receiver_addresses.len();
+        return receiver;
     }
 
     fn transaction_generator_loop(&mut self) {
-        let mut receiver_index = 0;
         // main transaction_generator loop
         loop {
             // check and react to control signals
@@ -115,7 +155,7 @@ impl Context {
                             info!("Transaction generator starting in continuous mode with theta {}", i);
                             self.operating_state = OperatingState::Run(i);
                         }
-                        ControlSignal::Update => {
+                        ControlSignal::Update | ControlSignal::Stop => {
                             // in paused state, don't need to update
                         }
                     };
@@ -136,7 +176,14 @@ impl Context {
                                 self.operating_state = OperatingState::Run(i);
                             }
                             ControlSignal::Update => {
-                                unimplemented!()
+                                // the tip state and the sender's balance are read again below, nothing is kept
+                                // from the previous transaction but the receiver rotation
+                                debug!("Transaction generator building on the new tip");
+                            }
+                            ControlSignal::Stop => {
+                                info!("Transaction generator stopped");
+                                self.operating_state = OperatingState::Paused;
+                                continue;
                             }
                         };
                     }
@@ -148,10 +195,16 @@ impl Context {
                 return;
             }
 
+            //let the miners catch up rather than have the mempool evict older transactions
+            if self.mempool.lock().unwrap().transaction_map.len() >= self.high_water_mark {
+                debug!("Mempool at its high-water mark of {} transactions, not generating", self.high_water_mark);
+                thread::sleep(self.interval().max(MEMPOOL_FULL_WAIT));
+                continue;
+            }
+
             //generate valid transactions based off current tip state
-            let tip = self.blockchain.lock().unwrap().tip().clone();
+            let tip = self.blockchain.read().unwrap().tip().clone();
             let tip_state = self.block_state_map.lock().unwrap().block_state_map.get(&tip).unwrap().clone();
-            let receiver = self.receiver_addresses[receiver_index];
             let sender_balance; 
             if tip_state.contains_key(&self.address) {
                 sender_balance = tip_state.get(&self.address).unwrap();
@@ -170,29 +223,135 @@ impl Context {
             } else {
                 nonce = 0;
             }
-            let tx = Transaction {
-                sender: self.address,
-                receiver: receiver,
-                value: rng.gen_range(1..val),
-                account_nonce: nonce + 1
-            };
-            let key_pair = &self.keypair;
-            let signature_ = sign(&tx, &key_pair);
-            let signed_tx = SignedTransaction {
-                transaction: tx,
-                signature: signature_.as_ref().to_vec(),
-                public_key: key_pair.public_key().as_ref().to_vec()
+            //the sender may be one of the receivers, but a transaction can't pay its own sender
+            let mut receiver = self.next_receiver();
+            if receiver == self.address {
+                receiver = self.next_receiver();
+            }
+            let signed_tx = match TransactionBuilder::new()
+                .sender(&self.keypair)
+                .receiver(receiver)
+                .value(rng.gen_range(1..=val))
+                .fee(rng.gen_range(MIN_TX_FEE..=10))
+                .nonce(nonce + 1)
+                .build(&self.keypair) {
+                Ok(tx) => tx,
+                Err(e) => {
+                    warn!("Could not build transaction: {}", e);
+                    continue;
+                }
             };
             self.finished_tx_chan.send(signed_tx.clone()).expect("Send finished transaction error");
-            if receiver_index == 0 { receiver_index = 1; }
-            else { receiver_index = 0; }
 
-            if let OperatingState::Run(i) = self.operating_state {
-                if i != 0 {
-                    let interval = time::Duration::from_micros(i as u64);
-                    thread::sleep(interval);
-                }
+            let interval = self.interval();
+            if interval > time::Duration::ZERO {
+                thread::sleep(interval);
             }
         }
     }
 }
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod test {
+    use super::DEFAULT_MEMPOOL_HIGH_WATER;
+    use crate::blockchain::Blockchain;
+    use crate::miner::Mempool;
+    use crate::types::address::Address;
+    use crate::types::block::BlockState;
+    use crate::types::hash::Hashable;
+    use crate::types::key_pair;
+    use crate::types::transaction::{generate_random_transaction, SignedTransaction};
+    use ntest::timeout;
+    use ring::signature::KeyPair;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex, RwLock};
+    use std::time::Duration;
+
+    #[test]
+    fn receivers_are_picked_in_rotation() {
+        let blockchain = Arc::new(RwLock::new(Blockchain::new()));
+        let block_state_map = Arc::new(Mutex::new(BlockState::new()));
+        let receivers: Vec<Address> = (1..=5).map(|i| Address::from([i as u8; 20])).collect();
+        let (mut ctx, _handle, _finished) = super::new(&blockchain, &Arc::new(Mutex::new(Mempool::new())), &receivers[0], key_pair::random(), &block_state_map, receivers.clone(), DEFAULT_MEMPOOL_HIGH_WATER);
+        for round in 0..3 {
+            for (i, receiver) in receivers.iter().enumerate() {
+                assert_eq!(ctx.next_receiver(), *receiver, "receiver {} of round {}", i, round);
+            }
+        }
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn generator_starts_stops_and_restarts() {
+        let blockchain = Arc::new(RwLock::new(Blockchain::new()));
+        let key = key_pair::random();
+        let address = Address::from_public_key_bytes(key.public_key().as_ref());
+        let block_state_map = Arc::new(Mutex::new(BlockState::new()));
+        let tip = blockchain.read().unwrap().tip();
+        block_state_map.lock().unwrap().block_state_map.insert(tip, HashMap::from([(address, (0, 1000))]));
+        let (ctx, handle, finished) = super::new(&blockchain, &Arc::new(Mutex::new(Mempool::new())), &address, key, &block_state_map, vec![Address::from([1; 20])], DEFAULT_MEMPOOL_HIGH_WATER);
+        ctx.start();
+
+        handle.start(1000).unwrap();
+        assert_eq!(finished.recv().unwrap().transaction.sender, address);
+        handle.update().unwrap();
+        assert!(finished.recv().is_ok());
+        handle.stop().unwrap();
+        //whatever was generated before the stop was seen, then nothing
+        while finished.recv_timeout(Duration::from_millis(200)).is_ok() {}
+        assert!(finished.recv_timeout(Duration::from_millis(200)).is_err());
+        handle.start(1000).unwrap();
+        assert!(finished.recv().is_ok());
+        handle.exit().unwrap();
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn sender_among_receivers_is_skipped() {
+        let blockchain = Arc::new(RwLock::new(Blockchain::new()));
+        let key = key_pair::random();
+        let address = Address::from_public_key_bytes(key.public_key().as_ref());
+        let block_state_map = Arc::new(Mutex::new(BlockState::new()));
+        let tip = blockchain.read().unwrap().tip();
+        block_state_map.lock().unwrap().block_state_map.insert(tip, HashMap::from([(address, (0, 1000))]));
+        let receivers = vec![address, Address::from([1; 20]), Address::from([2; 20])];
+        let (ctx, handle, finished) = super::new(&blockchain, &Arc::new(Mutex::new(Mempool::new())), &address, key, &block_state_map, receivers, DEFAULT_MEMPOOL_HIGH_WATER);
+        ctx.start();
+
+        handle.start(1000).unwrap();
+        let paid: Vec<Address> = (0..4).map(|_| finished.recv().unwrap().transaction.outputs[0].0).collect();
+        assert_eq!(paid, vec![Address::from([1; 20]), Address::from([2; 20]), Address::from([1; 20]), Address::from([2; 20])]);
+        handle.exit().unwrap();
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn generator_waits_while_mempool_is_full() {
+        let blockchain = Arc::new(RwLock::new(Blockchain::new()));
+        let key = key_pair::random();
+        let address = Address::from_public_key_bytes(key.public_key().as_ref());
+        let block_state_map = Arc::new(Mutex::new(BlockState::new()));
+        let tip = blockchain.read().unwrap().tip();
+        block_state_map.lock().unwrap().block_state_map.insert(tip, HashMap::from([(address, (0, 1000))]));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let pending: Vec<_> = (0..10)
+            .map(|_| SignedTransaction { transaction: generate_random_transaction(), signature: vec![0; 64], public_key: vec![0; 32] })
+            .collect();
+        for tx in pending.iter() {
+            mempool.lock().unwrap().insert(tx);
+        }
+        let (ctx, handle, finished) = super::new(&blockchain, &mempool, &address, key, &block_state_map, vec![Address::from([1; 20])], 10);
+        ctx.start();
+
+        handle.start(0).unwrap();
+        assert!(finished.recv_timeout(Duration::from_millis(300)).is_err());
+        //once the miners take some, generation resumes
+        mempool.lock().unwrap().remove(&pending[0].hash());
+        assert_eq!(finished.recv().unwrap().transaction.sender, address);
+        handle.exit().unwrap();
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
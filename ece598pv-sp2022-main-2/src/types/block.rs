@@ -1,28 +1,88 @@
 use serde::{Serialize, Deserialize};
 use crate::types::hash::{H256, Hashable};
 use std::collections::HashMap;
+use std::sync::OnceLock;
 use super::address::Address;
-use super::transaction::{SignedTransaction};
+use super::transaction::{SignedTransaction, TxValidationError, ICO};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Block {
-    pub header: Header,
+    //private so it can't change under the cached hash, see header_mut
+    header: Header,
     pub content: Content,
+    //hash of the header, computed on first use
+    #[serde(skip)]
+    cached_hash: OnceLock<H256>,
 }
 
 pub struct BlockState {
     //block hash -> block state (account address -> (account nonce, account balance))
-    pub block_state_map: HashMap<H256, HashMap<Address, (u32, u32)>>
+    pub block_state_map: HashMap<H256, HashMap<Address, (u32, u32)>>,
+    //set by apply_genesis
+    genesis: Option<H256>
+}
+
+#[derive(Debug, PartialEq)]
+pub enum GenesisError {
+    AlreadyApplied,
+}
+
+impl std::fmt::Display for GenesisError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let reason = match self {
+            GenesisError::AlreadyApplied => "genesis state has already been applied",
+        };
+        write!(f, "{}", reason)
+    }
 }
 
 impl BlockState {
     pub fn new() -> Self {
         return BlockState {
-            block_state_map: HashMap::<H256, HashMap<Address, (u32, u32)>>::new()
+            block_state_map: HashMap::<H256, HashMap<Address, (u32, u32)>>::new(),
+            genesis: None
+        }
+    }
+
+    /// Record the ICO accounts as the genesis block's state, only allowed once
+    pub fn apply_genesis(&mut self, genesis_hash: H256, ico: &ICO) -> Result<(), GenesisError> {
+        if self.has_genesis() || self.block_state_map.contains_key(&genesis_hash) {
+            return Err(GenesisError::AlreadyApplied);
         }
+        self.block_state_map.insert(genesis_hash, ico.state.clone());
+        self.genesis = Some(genesis_hash);
+        return Ok(());
+    }
+
+    pub fn has_genesis(&self) -> bool {
+        return self.genesis.is_some();
+    }
+
+    /// The state after `block` on top of its parent's, or every invalid transaction's error,
+    /// without recording anything. The parent's state must be known
+    pub fn validate_block(&self, block: &Block, parent_hash: H256) -> Result<HashMap<Address, (u32, u32)>, Vec<TxValidationError>> {
+        return apply_block_to_state(&self.block_state_map[&parent_hash], block);
     }
 }
 
+/// Execute a block's transactions on top of its parent's state, returning the resulting state
+/// or the errors of every transaction that overspends or is out of nonce order
+pub fn apply_block_to_state(parent_state: &HashMap<Address, (u32, u32)>, block: &Block) -> Result<HashMap<Address, (u32, u32)>, Vec<TxValidationError>> {
+    let mut state = parent_state.clone();
+    let mut errors = Vec::new();
+    for tx in block.content.data.iter() {
+        //an invalid transaction is skipped so the ones after it are still checked
+        match tx.transaction.validate_against_state(&state) {
+            Ok(()) => tx.transaction.apply_to_state(&mut state),
+            Err(e) => errors.push(e),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    return Ok(state);
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Header {
     pub parent: H256,
@@ -39,8 +99,7 @@ pub struct Content {
 
 impl Hashable for Block {
     fn hash(&self) -> H256 {
-        let header = self.get_header();
-        return header.hash();
+        return *self.cached_hash.get_or_init(|| self.header.hash());
     }
 }
 
@@ -52,6 +111,25 @@ impl Hashable for Header {
 }
 
 impl Block {
+    pub fn new(header: Header, content: Content) -> Self {
+        return Block { header, content, cached_hash: OnceLock::new() };
+    }
+
+    pub fn header(&self) -> &Header {
+        return &self.header;
+    }
+
+    /// Change the header, forgetting the cached hash
+    pub fn header_mut(&mut self) -> &mut Header {
+        self.cached_hash = OnceLock::new();
+        return &mut self.header;
+    }
+
+    /// Serialized size of the whole block, header included, as sent to peers and limited by MAX_BLOCK_SIZE
+    pub fn size_bytes(&self) -> usize {
+        return bincode::serialized_size(self).unwrap() as usize;
+    }
+
     pub fn get_header(&self) -> Header {
         return self.header.clone();
     }
@@ -108,10 +186,149 @@ pub fn generate_random_block(parent: &H256) -> Block {
         data: Vec::<SignedTransaction>::new()
     };
 
-    let new_block = Block {
-        header: header,
-        content: content
-    };
+    let new_block = Block::new(header, content);
 
     return new_block;
 }
+
+/// An empty block on top of parent that passes proof of work
+#[cfg(any(test, test_utilities))]
+pub fn generate_mined_block(parent: &H256) -> Block {
+    let mut block = generate_random_block(parent);
+    block.header_mut().difficulty = crate::blockchain::DIFFICULTY.into();
+    while block.hash() > block.get_difficulty() {
+        block.header_mut().nonce = block.get_nonce().wrapping_add(1);
+    }
+    return block;
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::transaction::Transaction;
+
+    fn block_with(parent: &H256, transactions: Vec<Transaction>) -> Block {
+        let mut block = generate_random_block(parent);
+        block.content.data = transactions.into_iter()
+            .map(|transaction| SignedTransaction { transaction, ..Default::default() })
+            .collect();
+        return block;
+    }
+
+    fn transfer(sender: Address, account_nonce: u32) -> Transaction {
+        return Transaction { sender, account_nonce, outputs: vec![(Address::from([2; 20]), 10)], fee: 1 };
+    }
+
+    #[test]
+    fn size_bytes_counts_header_and_transactions() {
+        let sender = Address::from([1; 20]);
+        let block = block_with(&H256::from([0; 32]), vec![transfer(sender, 1), transfer(sender, 2)]);
+        //parent, nonce, difficulty, timestamp and merkle root
+        let header = 32 + 4 + 32 + 16 + 32;
+        //sender, nonce, one (receiver, value) output behind its length, fee, then empty signature and key behind theirs
+        let transaction = 20 + 4 + (8 + 20 + 4) + 4 + 8 + 8;
+        assert_eq!(block.size_bytes(), header + 8 + 2 * transaction);
+        assert_eq!(block.size_bytes(), bincode::serialize(&block).unwrap().len());
+    }
+
+    #[test]
+    fn genesis_is_applied_once() {
+        let genesis_hash = H256::from([0; 32]);
+        let ico = ICO { state: vec![(Address::from([1; 20]), (0, 100))].into_iter().collect() };
+        let mut block_state = BlockState::new();
+        assert!(!block_state.has_genesis());
+        assert_eq!(block_state.apply_genesis(genesis_hash, &ico), Ok(()));
+        assert!(block_state.has_genesis());
+        assert_eq!(block_state.block_state_map[&genesis_hash], ico.state);
+        assert_eq!(block_state.apply_genesis(genesis_hash, &ico), Err(GenesisError::AlreadyApplied));
+    }
+
+    #[test]
+    fn replayed_transaction_is_rejected() {
+        let sender = Address::from([1; 20]);
+        let mut state = HashMap::new();
+        state.insert(sender, (0, 100));
+        let first = block_with(&H256::from([0; 32]), vec![transfer(sender, 1)]);
+        let state = apply_block_to_state(&state, &first).unwrap();
+        assert_eq!(state[&sender], (1, 89));
+        //the same signed transaction again in a later block
+        let replay = block_with(&first.hash(), vec![transfer(sender, 1)]);
+        assert_eq!(apply_block_to_state(&state, &replay).unwrap_err(), vec![TxValidationError::WrongNonce]);
+    }
+
+    #[test]
+    fn nonce_gap_is_rejected() {
+        let sender = Address::from([1; 20]);
+        let mut state = HashMap::new();
+        state.insert(sender, (0, 100));
+        let block = block_with(&H256::from([0; 32]), vec![transfer(sender, 2)]);
+        assert_eq!(apply_block_to_state(&state, &block).unwrap_err(), vec![TxValidationError::WrongNonce]);
+        let block = block_with(&H256::from([0; 32]), vec![transfer(sender, 1), transfer(sender, 3)]);
+        assert_eq!(apply_block_to_state(&state, &block).unwrap_err(), vec![TxValidationError::WrongNonce]);
+    }
+
+    #[test]
+    fn double_spend_is_applied_once() {
+        use crate::miner::{Mempool, MempoolInsertResult, MempoolRejection};
+        let sender = Address::from([1; 20]);
+        let genesis_hash = H256::from([0; 32]);
+        let mut block_state = BlockState::new();
+        block_state.block_state_map.insert(genesis_hash, HashMap::from([(sender, (0, 100))]));
+        //the same sender and nonce, paying different receivers
+        let first = SignedTransaction { transaction: transfer(sender, 1), ..Default::default() };
+        let mut second = first.clone();
+        second.transaction.outputs[0].0 = Address::from([3; 20]);
+        assert_ne!(first.hash(), second.hash());
+
+        //the mempool keeps only one of them
+        let mut mempool = Mempool::new();
+        assert_eq!(mempool.insert(&first), MempoolInsertResult::Inserted);
+        assert_eq!(mempool.insert(&second), MempoolInsertResult::Conflict(first.hash()));
+
+        //a block spending twice is rejected as a whole, the second transfer's nonce is stale after the first
+        let block = block_with(&genesis_hash, vec![first.transaction.clone(), second.transaction.clone()]);
+        assert_eq!(block_state.validate_block(&block, genesis_hash).unwrap_err(), vec![TxValidationError::WrongNonce]);
+        let block = block_with(&genesis_hash, vec![first.transaction.clone()]);
+        let state = block_state.validate_block(&block, genesis_hash).unwrap();
+        assert_eq!(state[&sender], (1, 89));
+        assert_eq!(state[&Address::from([2; 20])], (0, 10));
+        assert!(!state.contains_key(&Address::from([3; 20])));
+        //once the first is confirmed the second can't get back into the mempool either
+        assert_eq!(Mempool::new().insert_validated(&second, &state).unwrap_err(), MempoolRejection::StaleNonce);
+    }
+
+    #[test]
+    fn every_invalid_transaction_is_reported() {
+        let sender = Address::from([1; 20]);
+        let genesis_hash = H256::from([0; 32]);
+        let mut block_state = BlockState::new();
+        block_state.block_state_map.insert(genesis_hash, HashMap::from([(sender, (0, 100))]));
+        let mut overspend = transfer(sender, 2);
+        overspend.outputs[0].1 = 1000;
+        //both the skipped nonce and the overspend are reported, the valid transfers around them still apply
+        let block = block_with(&genesis_hash, vec![transfer(sender, 1), transfer(sender, 3), overspend, transfer(sender, 2)]);
+        assert_eq!(block_state.validate_block(&block, genesis_hash).unwrap_err(), vec![TxValidationError::WrongNonce, TxValidationError::InsufficientBalance]);
+        //nothing was recorded for the rejected block
+        assert!(!block_state.block_state_map.contains_key(&block.hash()));
+        let block = block_with(&genesis_hash, vec![transfer(sender, 1), transfer(sender, 2)]);
+        assert_eq!(block_state.validate_block(&block, genesis_hash).unwrap()[&sender], (2, 78));
+    }
+
+    #[test]
+    fn cached_hash_follows_header_changes() {
+        let mut block = generate_random_block(&H256::from([0; 32]));
+        let hash = block.hash();
+        assert_eq!(hash, block.header().hash());
+        //the cache is neither serialized nor stale after a round trip
+        let decoded: Block = bincode::deserialize(&bincode::serialize(&block).unwrap()).unwrap();
+        assert_eq!(decoded.hash(), hash);
+        assert_eq!(bincode::serialize(&decoded).unwrap(), bincode::serialize(&block).unwrap());
+        block.header_mut().nonce = block.get_nonce().wrapping_add(1);
+        assert_ne!(block.hash(), hash);
+        assert_eq!(block.hash(), block.header().hash());
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -13,6 +13,20 @@ pub trait Hashable {
 #[derive(Eq, PartialEq, Serialize, Deserialize, Clone, Hash, Default, Copy)]
 pub struct H256([u8; 32]); // big endian u256
 
+impl H256 {
+    /// Number of leading zero bits, so a difficulty target with more of them is harder to meet
+    pub fn leading_zero_bits(&self) -> u32 {
+        let mut bits = 0;
+        for byte in self.0.iter() {
+            bits += byte.leading_zeros();
+            if *byte != 0 {
+                break;
+            }
+        }
+        return bits;
+    }
+}
+
 impl Hashable for H256 {
     fn hash(&self) -> H256 {
         ring::digest::digest(&ring::digest::SHA256, &self.0).into()
@@ -37,6 +51,35 @@ impl std::fmt::Display for H256 {
     }
 }
 
+/// Why a string couldn't be parsed as a hash
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseHashError {
+    //not a hex string
+    InvalidHex,
+    //decoded to this many bytes instead of 32
+    WrongLength(usize),
+}
+
+impl std::fmt::Display for ParseHashError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ParseHashError::InvalidHex => write!(f, "hash is not hex encoded"),
+            ParseHashError::WrongLength(len) => write!(f, "hash is {} bytes long instead of 32", len),
+        }
+    }
+}
+
+/// Parses the 64 hex digits Display writes
+impl std::str::FromStr for H256 {
+    type Err = ParseHashError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s).map_err(|_| ParseHashError::InvalidHex)?;
+        let bytes: [u8; 32] = bytes.as_slice().try_into().map_err(|_| ParseHashError::WrongLength(bytes.len()))?;
+        return Ok(H256(bytes));
+    }
+}
+
 impl std::fmt::Debug for H256 {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
@@ -116,4 +159,28 @@ pub fn generate_random_hash() -> H256 {
     let mut raw_bytes = [0; 32];
     raw_bytes.copy_from_slice(&random_bytes);
     (&raw_bytes).into()
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::{generate_random_hash, ParseHashError, H256};
+
+    #[test]
+    fn leading_zero_bits() {
+        assert_eq!(H256::from([0; 32]).leading_zero_bits(), 256);
+        assert_eq!(H256::from([255; 32]).leading_zero_bits(), 0);
+        let mut bytes = [1; 32];
+        bytes[0] = 0;
+        bytes[1] = 3;
+        assert_eq!(H256::from(bytes).leading_zero_bits(), 14);
+    }
+
+    #[test]
+    fn parses_what_display_writes() {
+        let hash = generate_random_hash();
+        assert_eq!(hash.to_string().parse::<H256>(), Ok(hash));
+        assert_eq!(hash.to_string().to_uppercase().parse::<H256>(), Ok(hash));
+        assert_eq!("not hex".parse::<H256>(), Err(ParseHashError::InvalidHex));
+        assert_eq!("abcd".parse::<H256>(), Err(ParseHashError::WrongLength(2)));
+        assert_eq!("".parse::<H256>(), Err(ParseHashError::WrongLength(0)));
+    }
+}
@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 
 use serde::{Serialize,Deserialize};
-use ring::signature::{Ed25519KeyPair, Signature, self};
+use ring::signature::{Ed25519KeyPair, KeyPair, Signature, self};
 
 use super::address::Address;
 use super::hash::{H256, Hashable};
@@ -10,8 +10,10 @@ use super::hash::{H256, Hashable};
 pub struct Transaction {
     pub sender: Address,
     pub account_nonce: u32,
-    pub receiver: Address,
-    pub value: u32
+    //receiver and value of every payment made by this transaction
+    pub outputs: Vec<(Address, u32)>,
+    //paid on top of the outputs; lets senders bid for block space
+    pub fee: u32
 }
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
@@ -21,6 +23,132 @@ pub struct SignedTransaction {
     pub public_key: Vec<u8>
 }
 
+impl Transaction {
+    pub fn fee(&self) -> u32 {
+        return self.fee;
+    }
+
+    /// Sum of the values of all outputs
+    pub fn total_output(&self) -> u64 {
+        return self.outputs.iter().map(|(_, value)| *value as u64).sum();
+    }
+
+    /// Check that the nonce is the sender's next one and that the outputs plus the fee fit in the
+    /// sender's balance in `state` (account address -> (account nonce, account balance))
+    pub fn validate_against_state(&self, state: &HashMap<Address, (u32, u32)>) -> Result<(), TxValidationError> {
+        let (nonce, balance) = match state.get(&self.sender) {
+            Some(s) => *s,
+            None => (0, 0)
+        };
+        if self.account_nonce != nonce + 1 {
+            return Err(TxValidationError::WrongNonce);
+        }
+        if self.total_output() + self.fee as u64 > balance as u64 {
+            return Err(TxValidationError::InsufficientBalance);
+        }
+        return Ok(());
+    }
+
+    /// Debit the outputs and the fee from the sender and credit every receiver.
+    /// The fee is not credited to anyone. Only call after validate_against_state.
+    pub fn apply_to_state(&self, state: &mut HashMap<Address, (u32, u32)>) {
+        let (nonce, balance) = match state.get(&self.sender) {
+            Some(s) => *s,
+            None => (0, 0)
+        };
+        let spent = self.total_output() as u32 + self.fee;
+        state.insert(self.sender, (nonce + 1, balance - spent));
+        for (receiver, value) in self.outputs.iter() {
+            let receiver_state = match state.get(receiver) {
+                Some(s) => *s,
+                None => (0, 0)
+            };
+            state.insert(*receiver, (receiver_state.0, receiver_state.1 + value));
+        }
+    }
+}
+
+/// Why a transaction can't be applied on top of a state
+#[derive(Debug, PartialEq)]
+pub enum TxValidationError {
+    //the nonce is not the sender's stored nonce + 1, e.g. a replayed or skipped transaction
+    WrongNonce,
+    InsufficientBalance,
+}
+
+/// Why a raw transaction could not be decoded
+#[derive(Debug, PartialEq)]
+pub enum TxParseError {
+    InvalidHex,
+    //the bytes are not a bincode encoded SignedTransaction
+    InvalidEncoding,
+}
+
+impl std::fmt::Display for TxParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let reason = match self {
+            TxParseError::InvalidHex => "transaction is not valid hex",
+            TxParseError::InvalidEncoding => "bytes do not decode to a signed transaction",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+/// Why a signed transaction can't have been authorized by its sender
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum IntegrityError {
+    InvalidSignature,
+    //the signing key doesn't own the sender address the transaction spends from
+    SenderMismatch,
+}
+
+impl std::fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let reason = match self {
+            IntegrityError::InvalidSignature => "transaction signature is invalid",
+            IntegrityError::SenderMismatch => "transaction is signed by a key that doesn't own its sender address",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+impl SignedTransaction {
+    /// Hex encoded bincode of the transaction, for external tools
+    pub fn to_hex(&self) -> String {
+        return hex::encode(bincode::serialize(self).unwrap());
+    }
+
+    pub fn from_hex(s: &str) -> Result<Self, TxParseError> {
+        let bytes = hex::decode(s.trim()).map_err(|_| TxParseError::InvalidHex)?;
+        return Self::from_bytes(&bytes);
+    }
+
+    /// Decode the bincode of a signed transaction, as sent by wallets
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, TxParseError> {
+        return bincode::deserialize(bytes).map_err(|_| TxParseError::InvalidEncoding);
+    }
+
+    /// Address of the account owning the signing key
+    pub fn sender_address(&self) -> Address {
+        return Address::from_public_key_bytes(&self.public_key);
+    }
+
+    pub fn is_valid_signature(&self) -> bool {
+        return verify(&self.transaction, &self.public_key, &self.signature);
+    }
+
+    /// Check the signature and that the signing key belongs to the sender, so nobody can spend from someone else's account
+    pub fn verify_integrity(&self) -> Result<(), IntegrityError> {
+        if !self.is_valid_signature() {
+            return Err(IntegrityError::InvalidSignature);
+        }
+        if self.sender_address() != self.transaction.sender {
+            return Err(IntegrityError::SenderMismatch);
+        }
+        return Ok(());
+    }
+}
+
 impl Hashable for SignedTransaction {
     fn hash(&self) -> H256 {
         let serialized = bincode::serialize(self).unwrap();
@@ -34,18 +162,118 @@ pub struct ICO {
 }
 
 impl ICO {
-    pub fn new(pubkey: &[u8]) -> Self {
-        //Do Initial Coin Offering (ICO); hardcode an account using the given pubkey
-        let account_address = Address::from_public_key_bytes(pubkey);
-        let balance = 1000000;
+    /// Initial Coin Offering (ICO): fund every given (pubkey, balance) account at genesis
+    pub fn new_multi(accounts: &[(&[u8], u32)]) -> Self {
         let mut map = HashMap::new();
-        map.insert(account_address, (0, balance));
+        for (pubkey, balance) in accounts {
+            let account_address = Address::from_public_key_bytes(pubkey);
+            map.insert(account_address, (0, *balance));
+        }
         return ICO {
             state: map
         }
     }
 }
 
+//least fee TransactionBuilder accepts, zero fee transactions are never worth a miner's block space
+pub static MIN_TX_FEE: u32 = 1;
+
+/// Why a TransactionBuilder could not build a signed transaction
+#[derive(Debug, PartialEq)]
+pub enum TxBuildError {
+    MissingSender,
+    MissingReceiver,
+    MissingNonce,
+    ZeroValue,
+    //below MIN_TX_FEE
+    FeeTooLow,
+    //sending to yourself only burns the fee
+    SelfPayment,
+    //the signing key doesn't own the sender address
+    SenderMismatch,
+}
+
+impl std::fmt::Display for TxBuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let reason = match self {
+            TxBuildError::MissingSender => "transaction has no sender",
+            TxBuildError::MissingReceiver => "transaction has no receiver",
+            TxBuildError::MissingNonce => "transaction has no account nonce",
+            TxBuildError::ZeroValue => "transaction value is zero",
+            TxBuildError::FeeTooLow => "transaction fee is below the minimum",
+            TxBuildError::SelfPayment => "transaction pays its own sender",
+            TxBuildError::SenderMismatch => "signing key doesn't own the sender address",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+/// Assembles a single output transaction, checking it is worth sending before signing it
+#[derive(Debug, Default, Clone)]
+pub struct TransactionBuilder {
+    sender: Option<Address>,
+    receiver: Option<Address>,
+    value: u32,
+    fee: u32,
+    nonce: Option<u32>,
+}
+
+impl TransactionBuilder {
+    pub fn new() -> Self {
+        return TransactionBuilder::default();
+    }
+
+    /// Spend from the account owned by `keypair`
+    pub fn sender(&mut self, keypair: &Ed25519KeyPair) -> &mut Self {
+        self.sender = Some(Address::from_public_key_bytes(keypair.public_key().as_ref()));
+        return self;
+    }
+
+    pub fn receiver(&mut self, addr: Address) -> &mut Self {
+        self.receiver = Some(addr);
+        return self;
+    }
+
+    pub fn value(&mut self, v: u32) -> &mut Self {
+        self.value = v;
+        return self;
+    }
+
+    pub fn fee(&mut self, f: u32) -> &mut Self {
+        self.fee = f;
+        return self;
+    }
+
+    /// The sender's account nonce after this transaction, i.e. its current nonce + 1
+    pub fn nonce(&mut self, n: u32) -> &mut Self {
+        self.nonce = Some(n);
+        return self;
+    }
+
+    /// Check the fields and sign the transaction with `keypair`, which must own the sender address
+    pub fn build(&self, keypair: &Ed25519KeyPair) -> Result<SignedTransaction, TxBuildError> {
+        let sender = self.sender.ok_or(TxBuildError::MissingSender)?;
+        let receiver = self.receiver.ok_or(TxBuildError::MissingReceiver)?;
+        let account_nonce = self.nonce.ok_or(TxBuildError::MissingNonce)?;
+        if self.value == 0 {
+            return Err(TxBuildError::ZeroValue);
+        }
+        if self.fee < MIN_TX_FEE {
+            return Err(TxBuildError::FeeTooLow);
+        }
+        if receiver == sender {
+            return Err(TxBuildError::SelfPayment);
+        }
+        let public_key = keypair.public_key().as_ref().to_vec();
+        if Address::from_public_key_bytes(&public_key) != sender {
+            return Err(TxBuildError::SenderMismatch);
+        }
+        let transaction = Transaction { sender, account_nonce, outputs: vec![(receiver, self.value)], fee: self.fee };
+        let signature = sign(&transaction, keypair).as_ref().to_vec();
+        return Ok(SignedTransaction { transaction, signature, public_key });
+    }
+}
+
 /// Create digital signature of a transaction
 pub fn sign(t: &Transaction, key: &Ed25519KeyPair) -> Signature {
     let serialized_transaction: Vec<u8> = bincode::serialize(&t).unwrap();
@@ -66,7 +294,7 @@ pub fn generate_random_transaction() -> Transaction {
     let random_value: u32 = rng.gen::<u32>();
     let random_receiver: [u8; 20] = rng.gen::<[u8; 20]>();
     let random_sender: [u8; 20] = rng.gen::<[u8; 20]>();
-    return Transaction {sender: Address::from(random_sender), receiver: Address::from(random_receiver), value: random_value, account_nonce:0};
+    return Transaction {sender: Address::from(random_sender), outputs: vec![(Address::from(random_receiver), random_value)], account_nonce:0, fee: 0};
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
@@ -75,7 +303,6 @@ pub fn generate_random_transaction() -> Transaction {
 mod tests {
     use super::*;
     use crate::types::key_pair;
-    use ring::signature::KeyPair;
 
 
     #[test]
@@ -95,6 +322,127 @@ mod tests {
         assert!(!verify(&t_2, key.public_key().as_ref(), signature.as_ref()));
         assert!(!verify(&t, key_2.public_key().as_ref(), signature.as_ref()));
     }
+    #[test]
+    fn signed_transaction_helpers() {
+        let key = key_pair::random();
+        let mut t = generate_random_transaction();
+        t.sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        let signature = sign(&t, &key);
+        let mut signed = SignedTransaction { transaction: t, signature: signature.as_ref().to_vec(), public_key: key.public_key().as_ref().to_vec() };
+        assert_eq!(signed.sender_address(), signed.transaction.sender);
+        assert!(signed.is_valid_signature());
+        assert_eq!(signed.verify_integrity(), Ok(()));
+        //flip one bit of the signature
+        signed.signature[0] ^= 1;
+        assert!(!signed.is_valid_signature());
+        assert_eq!(signed.verify_integrity(), Err(IntegrityError::InvalidSignature));
+    }
+    #[test]
+    fn forged_sender_fails_integrity() {
+        //validly signed, but spending from an account the key doesn't own
+        let key = key_pair::random();
+        let mut t = generate_random_transaction();
+        t.sender = Address::from_public_key_bytes(key_pair::random().public_key().as_ref());
+        let signature = sign(&t, &key);
+        let signed = SignedTransaction { transaction: t, signature: signature.as_ref().to_vec(), public_key: key.public_key().as_ref().to_vec() };
+        assert!(signed.is_valid_signature());
+        assert_eq!(signed.verify_integrity(), Err(IntegrityError::SenderMismatch));
+    }
+    #[test]
+    fn ico_funds_every_account() {
+        let pairs: Vec<_> = (0..4u8).map(|i| key_pair::given(&[i; 32])).collect();
+        let balances = [1000000, 500, 0, 42];
+        let accounts: Vec<(&[u8], u32)> = pairs.iter()
+            .zip(balances.iter())
+            .map(|(pair, balance)| (pair.public_key().as_ref(), *balance))
+            .collect();
+        let ico = ICO::new_multi(&accounts);
+        assert_eq!(ico.state.len(), 4);
+        for (pair, balance) in pairs.iter().zip(balances.iter()) {
+            let address = Address::from_public_key_bytes(pair.public_key().as_ref());
+            assert_eq!(ico.state[&address], (0, *balance));
+        }
+        let total_supply: u64 = ico.state.values().map(|(_, balance)| *balance as u64).sum();
+        assert_eq!(total_supply, balances.iter().map(|b| *b as u64).sum::<u64>());
+    }
+    #[test]
+    fn hex_round_trip() {
+        let t = generate_random_transaction();
+        let key = key_pair::random();
+        let signature = sign(&t, &key);
+        let signed = SignedTransaction { transaction: t, signature: signature.as_ref().to_vec(), public_key: key.public_key().as_ref().to_vec() };
+        let decoded = SignedTransaction::from_hex(&signed.to_hex()).unwrap();
+        assert_eq!(decoded.hash(), signed.hash());
+        assert!(decoded.is_valid_signature());
+        assert_eq!(SignedTransaction::from_hex("not hex").unwrap_err(), TxParseError::InvalidHex);
+        assert_eq!(SignedTransaction::from_hex("abcd").unwrap_err(), TxParseError::InvalidEncoding);
+    }
+    #[test]
+    fn two_output_transaction() {
+        let sender = Address::from([1; 20]);
+        let receiver_1 = Address::from([2; 20]);
+        let receiver_2 = Address::from([3; 20]);
+        let mut state = HashMap::new();
+        state.insert(sender, (0, 100));
+        let mut t = Transaction { sender, account_nonce: 1, outputs: vec![(receiver_1, 30), (receiver_2, 50)], fee: 21 };
+        //outputs plus fee exceed the balance
+        assert_eq!(t.validate_against_state(&state), Err(TxValidationError::InsufficientBalance));
+        t.fee = 20;
+        assert_eq!(t.validate_against_state(&state), Ok(()));
+        t.apply_to_state(&mut state);
+        assert_eq!(state.get(&sender), Some(&(1, 0)));
+        assert_eq!(state.get(&receiver_1), Some(&(0, 30)));
+        assert_eq!(state.get(&receiver_2), Some(&(0, 50)));
+    }
+    /// a builder every field of which is valid for `key`
+    fn valid_builder(key: &Ed25519KeyPair) -> TransactionBuilder {
+        let mut builder = TransactionBuilder::new();
+        builder.sender(key).receiver(Address::from([2; 20])).value(10).fee(MIN_TX_FEE).nonce(1);
+        return builder;
+    }
+    #[test]
+    fn builder_signs_valid_transaction() {
+        let key = key_pair::random();
+        let signed = valid_builder(&key).build(&key).unwrap();
+        assert_eq!(signed.verify_integrity(), Ok(()));
+        assert_eq!(signed.transaction.outputs, vec![(Address::from([2; 20]), 10)]);
+        assert_eq!(signed.transaction.account_nonce, 1);
+        assert_eq!(signed.transaction.fee, MIN_TX_FEE);
+    }
+    #[test]
+    fn builder_requires_every_field() {
+        let key = key_pair::random();
+        let mut builder = TransactionBuilder::new();
+        builder.receiver(Address::from([2; 20])).value(10).fee(MIN_TX_FEE).nonce(1);
+        assert_eq!(builder.build(&key).unwrap_err(), TxBuildError::MissingSender);
+        let mut builder = TransactionBuilder::new();
+        builder.sender(&key).value(10).fee(MIN_TX_FEE).nonce(1);
+        assert_eq!(builder.build(&key).unwrap_err(), TxBuildError::MissingReceiver);
+        let mut builder = TransactionBuilder::new();
+        builder.sender(&key).receiver(Address::from([2; 20])).value(10).fee(MIN_TX_FEE);
+        assert_eq!(builder.build(&key).unwrap_err(), TxBuildError::MissingNonce);
+    }
+    #[test]
+    fn builder_rejects_zero_value() {
+        let key = key_pair::random();
+        assert_eq!(valid_builder(&key).value(0).build(&key).unwrap_err(), TxBuildError::ZeroValue);
+    }
+    #[test]
+    fn builder_rejects_low_fee() {
+        let key = key_pair::random();
+        assert_eq!(valid_builder(&key).fee(MIN_TX_FEE - 1).build(&key).unwrap_err(), TxBuildError::FeeTooLow);
+    }
+    #[test]
+    fn builder_rejects_self_payment() {
+        let key = key_pair::random();
+        let own = Address::from_public_key_bytes(key.public_key().as_ref());
+        assert_eq!(valid_builder(&key).receiver(own).build(&key).unwrap_err(), TxBuildError::SelfPayment);
+    }
+    #[test]
+    fn builder_rejects_foreign_key() {
+        let key = key_pair::random();
+        assert_eq!(valid_builder(&key).build(&key_pair::random()).unwrap_err(), TxBuildError::SenderMismatch);
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
\ No newline at end of file
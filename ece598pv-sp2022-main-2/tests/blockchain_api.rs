@@ -0,0 +1,36 @@
+mod common;
+
+use common::{get, start_node};
+
+static API_ADDR: &str = "127.0.0.1:7094";
+
+//a fresh node only has the genesis block, mined at the fixed difficulty, and knows no transactions or forks
+#[test]
+fn height_and_difficulty_of_fresh_chain() {
+    let mut node = start_node(&["--p2p", "127.0.0.1:6092", "--api", API_ADDR]);
+
+    let height = get(API_ADDR, "/blockchain/height");
+    let confirmations = get(API_ADDR, &format!("/blockchain/confirmation-count/{}", "ab".repeat(32)));
+    let difficulty = get(API_ADDR, "/blockchain/difficulty");
+    let stats = get(API_ADDR, "/blockchain/stats");
+    let forks = get(API_ADDR, "/blockchain/forks");
+    let past_tip = get(API_ADDR, "/blockchain/state?block=1");
+    get(API_ADDR, "/node/exit");
+    node.0.wait().unwrap();
+
+    let height: serde_json::Value = serde_json::from_str(&height).unwrap();
+    assert_eq!(height, serde_json::json!({"height": 0}));
+    let confirmations: serde_json::Value = serde_json::from_str(&confirmations).unwrap();
+    assert_eq!(confirmations, serde_json::json!({"confirmations": -1}));
+    let difficulty: serde_json::Value = serde_json::from_str(&difficulty).unwrap();
+    assert_eq!(difficulty["bits"], 14);
+    assert_eq!(difficulty["target"], "0x0003640101010101010101010101010101010101010101010101010101010101");
+    let stats: serde_json::Value = serde_json::from_str(&stats).unwrap();
+    assert_eq!(stats["height"], 0);
+    assert_eq!(stats["difficulty"], difficulty);
+    assert_eq!(stats["total_transactions"], 0);
+    let forks: serde_json::Value = serde_json::from_str(&forks).unwrap();
+    assert_eq!(forks, serde_json::json!([]));
+    let past_tip: serde_json::Value = serde_json::from_str(&past_tip).unwrap();
+    assert_eq!(past_tip["success"], false);
+}
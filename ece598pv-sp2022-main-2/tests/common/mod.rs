@@ -0,0 +1,53 @@
+//helpers shared by the tests that run the node binary, not every test uses all of them
+#![allow(dead_code)]
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The node process, killed when dropped so a failing test doesn't leave it running on the test's ports
+pub struct Node(pub Child);
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        //already gone if the test asked it to exit
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Run the node binary with `args`, its output discarded
+pub fn start_node(args: &[&str]) -> Node {
+    let child = Command::new(env!("CARGO_BIN_EXE_bitcoin"))
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    return Node(child);
+}
+
+/// Send a request with `head` (request line and headers) and `body` to the API at `api_addr`, waiting for the API
+/// server to come up, and return the response body
+pub fn request(api_addr: &str, head: &str, body: &[u8]) -> String {
+    let start = Instant::now();
+    let mut stream = loop {
+        match TcpStream::connect(api_addr) {
+            Ok(stream) => break stream,
+            Err(_) if start.elapsed() < Duration::from_secs(10) => thread::sleep(Duration::from_millis(100)),
+            Err(e) => panic!("API server did not start: {}", e),
+        }
+    };
+    write!(stream, "{}\r\nHost: 127.0.0.1\r\nConnection: close\r\nContent-Length: {}\r\n\r\n", head, body.len()).unwrap();
+    stream.write_all(body).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    //only the body, after the headers
+    return response.split("\r\n\r\n").nth(1).unwrap_or("").to_string();
+}
+
+pub fn get(api_addr: &str, path: &str) -> String {
+    return request(api_addr, &format!("GET {} HTTP/1.1", path), &[]);
+}
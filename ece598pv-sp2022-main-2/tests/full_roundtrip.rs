@@ -0,0 +1,61 @@
+mod common;
+
+use common::start_node;
+use std::thread;
+use std::time::{Duration, Instant};
+
+static API_ADDR: &str = "127.0.0.1:7080";
+
+//how long the node gets to mine the blocks the test waits for
+static ROUNDTRIP_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn get(path: &str) -> String {
+    return common::get(API_ADDR, path);
+}
+
+fn height() -> u64 {
+    let height: serde_json::Value = serde_json::from_str(&get("/blockchain/height")).unwrap();
+    return height["height"].as_u64().unwrap();
+}
+
+//generated transactions go through the mempool into mined blocks, and the chain and its state show them
+#[test]
+fn generated_transactions_are_mined_into_the_chain() {
+    let mut node = start_node(&["--p2p", "127.0.0.1:6080", "--api", API_ADDR]);
+
+    let generator = get("/tx-generator/start?theta=1");
+    //blocks come quickly at lambda 0, the first ones would be empty if the miner started before the generator got going
+    let start = Instant::now();
+    while get("/mempool/transactions") == "[]" {
+        if start.elapsed() > ROUNDTRIP_TIMEOUT {
+            panic!("no transaction was generated within {:?}", ROUNDTRIP_TIMEOUT);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    let miner = get("/miner/start?lambda=0");
+    while height() < 5 {
+        if start.elapsed() > ROUNDTRIP_TIMEOUT {
+            panic!("chain did not reach height 5 within {:?}", ROUNDTRIP_TIMEOUT);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    let chain_txs = get("/blockchain/longest-chain-tx");
+    let state = get("/blockchain/state?block=4");
+    get("/node/exit");
+    node.0.wait().unwrap();
+
+    let generator: serde_json::Value = serde_json::from_str(&generator).unwrap();
+    assert_eq!(generator["success"], true);
+    let miner: serde_json::Value = serde_json::from_str(&miner).unwrap();
+    assert_eq!(miner["success"], true);
+    let chain_txs: Vec<Vec<String>> = serde_json::from_str(&chain_txs).unwrap();
+    assert!(chain_txs.len() > 5);
+    assert!(chain_txs.iter().any(|txs| !txs.is_empty()));
+    //one "(address, nonce, balance)" per funded account
+    let state: Vec<String> = serde_json::from_str(&state).unwrap();
+    assert!(!state.is_empty());
+    for account in state {
+        let balance: u64 = account.trim_end_matches(')').rsplit(", ").next().unwrap().parse().unwrap();
+        assert!(balance > 0);
+    }
+}
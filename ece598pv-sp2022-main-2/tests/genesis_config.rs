@@ -0,0 +1,41 @@
+mod common;
+
+use common::{get, start_node};
+use std::process::{Command, Stdio};
+
+//public key of the first built-in account, which /blockchain/state reports on
+static ACCOUNT0_PUBKEY_HEX: &str = "3b6a27bcceb6a42d62a3a8d02a6f0d73653215771de243a63ac048a18b59da29";
+
+fn write_genesis_config(name: &str, contents: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("{}-{}.json", name, std::process::id()));
+    std::fs::write(&path, contents).unwrap();
+    return path;
+}
+
+//the genesis state holds exactly the balances listed in the file
+#[test]
+fn genesis_config_funds_listed_accounts() {
+    let path = write_genesis_config("genesis-funded", &format!("[{{\"pubkey_hex\": \"{}\", \"balance\": 4242}}]", ACCOUNT0_PUBKEY_HEX));
+    let mut node = start_node(&["--p2p", "127.0.0.1:6093", "--api", "127.0.0.1:7092", "--genesis-config", path.to_str().unwrap()]);
+
+    let state = get("127.0.0.1:7092", "/blockchain/state?block=0");
+    get("127.0.0.1:7092", "/node/exit");
+    node.0.wait().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(state.contains(", 0, 4242)"), "unexpected state: {}", state);
+    //the other built-in accounts were not funded
+    assert_eq!(state.matches("(").count(), 1, "unexpected state: {}", state);
+}
+
+#[test]
+fn invalid_genesis_config_stops_node() {
+    let path = write_genesis_config("genesis-invalid", "[]");
+    let status = Command::new(env!("CARGO_BIN_EXE_bitcoin"))
+        .args(&["--p2p", "127.0.0.1:6093", "--api", "127.0.0.1:7092", "--genesis-config", path.to_str().unwrap()])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(!status.success());
+}
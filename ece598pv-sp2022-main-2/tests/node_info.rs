@@ -0,0 +1,22 @@
+mod common;
+
+use common::{get, start_node};
+
+static API_ADDR: &str = "127.0.0.1:7096";
+
+#[test]
+fn node_info_reports_fresh_node() {
+    let mut node = start_node(&["--p2p", "127.0.0.1:6100", "--api", API_ADDR]);
+
+    let info = get(API_ADDR, "/node/info");
+    get(API_ADDR, "/node/exit");
+    node.0.wait().unwrap();
+
+    let info: serde_json::Value = serde_json::from_str(&info).unwrap();
+    assert_eq!(info["version"], "0.1");
+    assert!(info["uptime_secs"].is_u64());
+    assert!(info["chain_height"].is_u64());
+    assert_eq!(info["connected_peers"], 0);
+    assert_eq!(info["mempool_size"], 0);
+    assert_eq!(info["mining_active"], false);
+}
@@ -0,0 +1,41 @@
+mod common;
+
+use common::start_node;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+//asks a running node to exit through the API and checks it replies and every thread is joined in time
+#[test]
+fn node_exit_terminates_process() {
+    let mut node = start_node(&["--p2p", "127.0.0.1:6091", "--api", "127.0.0.1:7091"]);
+
+    //wait for the API server to come up
+    let start = Instant::now();
+    let mut stream = loop {
+        match TcpStream::connect("127.0.0.1:7091") {
+            Ok(stream) => break stream,
+            Err(_) if start.elapsed() < Duration::from_secs(10) => thread::sleep(Duration::from_millis(100)),
+            Err(e) => panic!("API server did not start: {}", e),
+        }
+    };
+    stream.write_all(b"GET /node/exit HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: close\r\n\r\n").unwrap();
+    //the reply is written before the node starts shutting down
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.contains("\"success\": true"), "{}", response);
+
+    let start = Instant::now();
+    loop {
+        if let Some(status) = node.0.try_wait().unwrap() {
+            assert!(status.success());
+            return;
+        }
+        if start.elapsed() > Duration::from_secs(10) {
+            panic!("node did not shut down within 10 seconds");
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,71 @@
+use ring::digest::{digest, SHA256};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::Serialize;
+
+mod common;
+
+use common::{get, request, start_node};
+
+static API_ADDR: &str = "127.0.0.1:7098";
+
+//same layout as the node's Transaction and SignedTransaction, so bincode encodes them identically
+#[derive(Serialize)]
+struct Transaction {
+    sender: [u8; 20],
+    account_nonce: u32,
+    outputs: Vec<([u8; 20], u32)>,
+    fee: u32,
+}
+
+#[derive(Serialize)]
+struct SignedTransaction {
+    transaction: Transaction,
+    signature: Vec<u8>,
+    public_key: Vec<u8>,
+}
+
+fn address(public_key: &[u8]) -> [u8; 20] {
+    let mut address = [0; 20];
+    address.copy_from_slice(&digest(&SHA256, public_key).as_ref()[12..]);
+    return address;
+}
+
+//the first account funded at genesis
+fn signed_payment(nonce: u32) -> Vec<u8> {
+    let key = Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap();
+    let transaction = Transaction {
+        sender: address(key.public_key().as_ref()),
+        account_nonce: nonce,
+        outputs: vec![([7; 20], 10)],
+        fee: 1,
+    };
+    let signature = key.sign(&bincode::serialize(&transaction).unwrap()).as_ref().to_vec();
+    let signed = SignedTransaction { transaction, signature, public_key: key.public_key().as_ref().to_vec() };
+    return bincode::serialize(&signed).unwrap();
+}
+
+#[test]
+fn submitted_transactions_enter_mempool() {
+    let mut node = start_node(&["--p2p", "127.0.0.1:6101", "--api", API_ADDR]);
+
+    let raw = request(API_ADDR, "POST /tx/submit HTTP/1.1\r\nContent-Type: application/octet-stream", &signed_payment(1));
+    let json = serde_json::json!({ "hex": hex::encode(signed_payment(2)) }).to_string();
+    let from_json = request(API_ADDR, "POST /tx/submit HTTP/1.1\r\nContent-Type: application/json", json.as_bytes());
+    let garbage = request(API_ADDR, "POST /tx/submit HTTP/1.1\r\nContent-Type: application/octet-stream", b"abcd");
+    let mempool = get(API_ADDR, "/mempool/transactions");
+    get(API_ADDR, "/node/exit");
+    node.0.wait().unwrap();
+
+    let raw: serde_json::Value = serde_json::from_str(&raw).unwrap();
+    assert_eq!(raw["success"], true);
+    let from_json: serde_json::Value = serde_json::from_str(&from_json).unwrap();
+    assert_eq!(from_json["success"], true);
+    let garbage: serde_json::Value = serde_json::from_str(&garbage).unwrap();
+    assert_eq!(garbage["success"], false);
+    assert_eq!(garbage["error"], "invalid_encoding");
+
+    let mempool: Vec<String> = serde_json::from_str(&mempool).unwrap();
+    assert_eq!(mempool.len(), 2);
+    assert!(mempool.contains(&raw["hash"].as_str().unwrap().to_string()));
+    assert!(mempool.contains(&from_json["hash"].as_str().unwrap().to_string()));
+}